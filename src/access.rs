@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::metadata::MetadataStore;
+
+/// The metadata directory each instance's fetch counts are stored in.
+const ACCESS_COUNTS_DIR: &str = "access_counts";
+
+/// The metadata directory the last access record of each image is stored in.
+const ACCESS_RECORDS_DIR: &str = "access_records";
+
+/// The number of access records loaded or updated at once.
+const CONCURRENT_RECORDS: usize = 16;
+
+/// The resolution in seconds last access times are recorded at.
+///
+/// Times are rounded down so repeated fetches of a popular image
+/// don't each produce a pending update.
+const LAST_ACCESS_RESOLUTION: i64 = 60 * 60;
+
+/// Counts fetches per image.
+///
+/// Counts are buffered in memory and periodically merged into
+/// the bucket's metadata store to avoid a write per fetch.
+///
+/// Each instance merges its counts into its own document which are
+/// summed when read, so instances never overwrite each other's counts.
+#[derive(Default)]
+pub struct AccessStats {
+    pending: Mutex<HashMap<Uuid, u64>>,
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+impl AccessStats {
+    /// Records a single fetch of the given image.
+    pub fn record(&self, image_id: Uuid) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        *pending.entry(image_id).or_default() += 1;
+    }
+
+    /// Merges the buffered counts into the metadata store.
+    ///
+    /// If the counts fail to persist they're kept in the buffer
+    /// and retried on the next flush.
+    pub async fn flush(&self, store: &MetadataStore) -> anyhow::Result<()> {
+        let _guard = self.flush_lock.lock().await;
+
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        if pending.is_empty() {
+            return Ok(())
+        }
+
+        let key = shard_key(crate::metadata::instance_id());
+        let result = async {
+            let mut counts: HashMap<Uuid, u64> = store.load(&key).await?;
+            for (image_id, count) in pending.iter() {
+                *counts.entry(*image_id).or_default() += count;
+            }
+
+            store.save(&key, &counts).await
+        }.await;
+
+        if result.is_err() {
+            let mut buffered = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            for (image_id, count) in pending {
+                *buffered.entry(image_id).or_default() += count;
+            }
+        }
+
+        result
+    }
+
+    /// The fetch counts of every image, including counts not yet flushed.
+    pub async fn counts(&self, store: &MetadataStore) -> anyhow::Result<HashMap<Uuid, u64>> {
+        let _guard = self.flush_lock.lock().await;
+
+        let mut counts: HashMap<Uuid, u64> = HashMap::new();
+        for instance_id in store.list_all(ACCESS_COUNTS_DIR).await? {
+            let shard: HashMap<Uuid, u64> = store.load(&shard_key(&instance_id)).await?;
+            for (image_id, count) in shard {
+                *counts.entry(image_id).or_default() += count;
+            }
+        }
+
+        let pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        for (image_id, count) in pending.iter() {
+            *counts.entry(*image_id).or_default() += count;
+        }
+
+        Ok(counts)
+    }
+}
+
+/// The key of the document holding the fetch counts of the given instance.
+fn shard_key(instance_id: &str) -> String {
+    format!("{}/{}", ACCESS_COUNTS_DIR, instance_id)
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct AccessRecord {
+    /// The (coarse) unix timestamp the image was last fetched or uploaded.
+    pub last_access: i64,
+
+    #[serde(default)]
+    /// The (coarse) unix timestamp the image was uploaded.
+    ///
+    /// This is `None` for images uploaded before being tracked.
+    pub uploaded_at: Option<i64>,
+
+    #[serde(default)]
+    /// If the generated variants of the image have been dropped
+    /// since it was last accessed.
+    pub tiered_down: bool,
+
+    #[serde(default)]
+    /// If the image has been archived since it was last accessed.
+    pub archived: bool,
+}
+
+#[derive(Copy, Clone)]
+enum PendingAccess {
+    Uploaded(i64),
+    Touched(i64),
+    TieredDown,
+    Archived,
+    Removed,
+}
+
+/// Tracks when each image was last accessed.
+///
+/// Like `AccessStats` updates are buffered in memory and periodically
+/// merged into the bucket's metadata store, each image's record is its
+/// own document so instances only ever race on updates of the same image.
+#[derive(Default)]
+pub struct LastAccess {
+    pending: Mutex<HashMap<Uuid, PendingAccess>>,
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+impl LastAccess {
+    /// Marks the image as uploaded now.
+    pub fn uploaded(&self, image_id: Uuid) {
+        self.update(image_id, PendingAccess::Uploaded(coarse_now()));
+    }
+
+    /// Marks the image as accessed now.
+    pub fn touch(&self, image_id: Uuid) {
+        self.update(image_id, PendingAccess::Touched(coarse_now()));
+    }
+
+    /// Marks the image as having its generated variants dropped.
+    pub fn mark_tiered_down(&self, image_id: Uuid) {
+        self.update(image_id, PendingAccess::TieredDown);
+    }
+
+    /// Marks the image as archived.
+    pub fn mark_archived(&self, image_id: Uuid) {
+        self.update(image_id, PendingAccess::Archived);
+    }
+
+    /// Stops tracking the image, e.g. once it's deleted.
+    pub fn remove(&self, image_id: Uuid) {
+        self.update(image_id, PendingAccess::Removed);
+    }
+
+    fn update(&self, image_id: Uuid, update: PendingAccess) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+
+        match (pending.get(&image_id), update) {
+            // Fetches straight after an upload shouldn't lose the upload time.
+            (Some(PendingAccess::Uploaded(_)), PendingAccess::Touched(_)) => {},
+            // An access since the lifecycle decision was made takes priority.
+            (
+                Some(PendingAccess::Uploaded(_) | PendingAccess::Touched(_)),
+                PendingAccess::TieredDown | PendingAccess::Archived,
+            ) => {},
+            _ => {
+                pending.insert(image_id, update);
+            },
+        }
+    }
+
+    /// Merges the buffered updates into the metadata store.
+    pub async fn flush(&self, store: &MetadataStore) -> anyhow::Result<()> {
+        let _guard = self.flush_lock.lock().await;
+        self.flush_locked(store).await.map(|_| ())
+    }
+
+    /// Flushes any buffered updates and returns the access records
+    /// of every tracked image.
+    pub async fn records(&self, store: &MetadataStore) -> anyhow::Result<HashMap<Uuid, AccessRecord>> {
+        let _guard = self.flush_lock.lock().await;
+        self.flush_locked(store).await
+    }
+
+    async fn flush_locked(&self, store: &MetadataStore) -> anyhow::Result<HashMap<Uuid, AccessRecord>> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+
+        let results: Vec<(Uuid, PendingAccess, anyhow::Result<()>)> = futures::stream::iter(pending)
+            .map(|(image_id, update)| async move {
+                let result = apply_update(store, image_id, update).await;
+                (image_id, update, result)
+            })
+            .buffer_unordered(CONCURRENT_RECORDS)
+            .collect()
+            .await;
+
+        // Newer updates buffered during the failed flush take priority.
+        let mut first_error = None;
+        for (image_id, update, result) in results {
+            if let Err(e) = result {
+                let mut buffered = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+                buffered.entry(image_id).or_insert(update);
+                first_error.get_or_insert(e);
+            }
+        }
+
+        if let Some(e) = first_error {
+            return Err(e)
+        }
+
+        let image_ids = store.list_all(ACCESS_RECORDS_DIR).await?;
+        futures::stream::iter(image_ids)
+            .filter_map(|name| async move { name.parse::<Uuid>().ok() })
+            .map(|image_id| async move {
+                let record: Option<AccessRecord> = store.load(&record_key(image_id)).await?;
+                Ok::<_, anyhow::Error>(record.map(|record| (image_id, record)))
+            })
+            .buffer_unordered(CONCURRENT_RECORDS)
+            .try_filter_map(|record| async move { Ok(record) })
+            .try_collect()
+            .await
+    }
+}
+
+/// Merges the update into the image's stored record.
+async fn apply_update(store: &MetadataStore, image_id: Uuid, update: PendingAccess) -> anyhow::Result<()> {
+    let key = record_key(image_id);
+    let existing: Option<AccessRecord> = store.load(&key).await?;
+
+    let record = match (update, existing) {
+        (PendingAccess::Removed, _) => return store.remove(&key).await,
+        (PendingAccess::Uploaded(uploaded_at), _) => AccessRecord {
+            last_access: uploaded_at,
+            uploaded_at: Some(uploaded_at),
+            tiered_down: false,
+            archived: false,
+        },
+        // Another instance may have recorded a later access.
+        (PendingAccess::Touched(last_access), existing) => AccessRecord {
+            last_access: existing.map(|r| r.last_access.max(last_access)).unwrap_or(last_access),
+            uploaded_at: existing.and_then(|r| r.uploaded_at),
+            tiered_down: false,
+            archived: false,
+        },
+        (PendingAccess::TieredDown, Some(record)) => AccessRecord { tiered_down: true, ..record },
+        (PendingAccess::Archived, Some(record)) => AccessRecord { tiered_down: true, archived: true, ..record },
+        (PendingAccess::TieredDown | PendingAccess::Archived, None) => return Ok(()),
+    };
+
+    store.save(&key, &record).await
+}
+
+/// The key of the document holding the access record of the given image.
+fn record_key(image_id: Uuid) -> String {
+    format!("{}/{}", ACCESS_RECORDS_DIR, image_id)
+}
+
+/// The current unix timestamp rounded down to the last access resolution.
+fn coarse_now() -> i64 {
+    let now = chrono::Utc::now().timestamp();
+    now - now.rem_euclid(LAST_ACCESS_RESOLUTION)
+}
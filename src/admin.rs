@@ -0,0 +1,1166 @@
+use once_cell::sync::OnceCell;
+use poem::http::StatusCode;
+use poem::{handler, Body, Endpoint, EndpointExt, IntoResponse, Request, Response, Route};
+use poem_openapi::{ApiResponse, Object, OpenApi, OpenApiService};
+use poem_openapi::param::{Path, Query};
+use poem_openapi::payload::{Binary, Html, Json};
+use uuid::Uuid;
+
+use crate::changes::{ChangeEvent, ChangesSince};
+use crate::config::config;
+use crate::controller::{buckets, get_bucket_by_name, key_matches, BucketController};
+use crate::export::ExportPage;
+use crate::keys::ApiKeyInfo;
+use crate::lifecycle::LifecycleReport;
+use crate::allocator::AllocatorStats;
+use crate::profiling::Capture;
+use crate::quarantine::QuarantinedUpload;
+use crate::routes::Detail;
+
+static ADMIN_TOKEN: OnceCell<String> = OnceCell::new();
+
+/// The default number of images returned by the top accessed images endpoint.
+const DEFAULT_TOP_ACCESSED: usize = 10;
+
+/// The maximum number of images returned by the top accessed images endpoint.
+const MAX_TOP_ACCESSED: usize = 1000;
+
+/// The number of images shown per page of the bucket preview.
+const PREVIEW_PAGE_SIZE: usize = 48;
+
+/// The default number of images exported per page.
+const DEFAULT_EXPORT_LIMIT: usize = 1000;
+
+/// The maximum number of images exported per page.
+const MAX_EXPORT_LIMIT: usize = 10_000;
+
+/// The default number of changes returned per page.
+const DEFAULT_CHANGES_LIMIT: usize = 1000;
+
+/// The maximum number of changes returned per page.
+const MAX_CHANGES_LIMIT: usize = 10_000;
+
+/// The default duration of a CPU profile capture in seconds.
+const DEFAULT_PROFILE_DURATION: u64 = 10;
+
+/// The maximum duration of a CPU profile capture in seconds.
+const MAX_PROFILE_DURATION: u64 = 300;
+
+#[derive(Debug, Object)]
+pub struct BucketInfo {
+    /// The name of the bucket.
+    name: String,
+
+    /// The id of the bucket used within the storage backends.
+    bucket_id: u32,
+
+    /// The processing mode of the bucket.
+    mode: String,
+}
+
+#[derive(ApiResponse)]
+pub enum BucketsResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<BucketInfo>>),
+}
+
+#[derive(ApiResponse)]
+pub enum OriginalResponse {
+    #[oai(status = 200)]
+    Ok(
+        Binary<Vec<u8>>,
+        #[oai(header = "content-type")] String,
+    ),
+
+    /// The bucket, image or its original does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(Debug, Object)]
+pub struct CreateApiKey {
+    /// A name describing who the key is issued to.
+    name: Option<String>,
+
+    /// How long in seconds the key is valid for.
+    ///
+    /// If not given the key is valid until it's revoked.
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Object)]
+pub struct CreatedApiKey {
+    /// The key to give as the `Authorization: Bearer <key>` header.
+    ///
+    /// The key is not stored, so it can't be retrieved again.
+    key: String,
+
+    #[oai(flatten)]
+    info: ApiKeyInfo,
+}
+
+#[derive(ApiResponse)]
+pub enum ApiKeysResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<ApiKeyInfo>>),
+
+    /// The bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum CreateApiKeyResponse {
+    #[oai(status = 200)]
+    Ok(Json<CreatedApiKey>),
+
+    /// The bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum RevokeApiKeyResponse {
+    /// The key was revoked.
+    #[oai(status = 204)]
+    Revoked,
+
+    /// The bucket or key does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum QuarantineResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<QuarantinedUpload>>),
+
+    /// The quarantine is not enabled for the bucket.
+    #[oai(status = 400)]
+    NotEnabled(Json<Detail>),
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum QuarantinedPayloadResponse {
+    #[oai(status = 200)]
+    Ok(Binary<Vec<u8>>),
+
+    /// The quarantine is not enabled for the bucket.
+    #[oai(status = 400)]
+    NotEnabled(Json<Detail>),
+
+    /// The bucket or quarantined upload does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum RemoveQuarantinedResponse {
+    /// The upload was removed from the quarantine.
+    #[oai(status = 204)]
+    Removed,
+
+    /// The quarantine is not enabled for the bucket.
+    #[oai(status = 400)]
+    NotEnabled(Json<Detail>),
+
+    /// The bucket or quarantined upload does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(Debug, Object)]
+pub struct ImageAccessStats {
+    /// The id of the image.
+    image_id: Uuid,
+
+    /// The number of times the image has been fetched.
+    fetches: u64,
+}
+
+#[derive(ApiResponse)]
+pub enum AccessStatsResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<ImageAccessStats>>),
+
+    /// Access stats are not enabled for the bucket.
+    #[oai(status = 400)]
+    NotEnabled(Json<Detail>),
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum ImageAccessStatsResponse {
+    #[oai(status = 200)]
+    Ok(Json<ImageAccessStats>),
+
+    /// Access stats are not enabled for the bucket.
+    #[oai(status = 400)]
+    NotEnabled(Json<Detail>),
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(Debug, Object)]
+pub struct PresetSizing {
+    /// The name of the preset.
+    name: String,
+
+    /// The sizing id the preset's variants are stored under.
+    sizing_id: u32,
+
+    /// The width the preset resizes images to.
+    width: u32,
+
+    /// The height the preset resizes images to.
+    height: u32,
+}
+
+#[derive(Debug, Object)]
+pub struct CustomSizing {
+    /// The width of the custom size.
+    width: u32,
+
+    /// The height of the custom size.
+    height: u32,
+
+    /// The sizing id of the custom size.
+    sizing_id: u32,
+}
+
+#[derive(Debug, Object)]
+pub struct SizingRegistry {
+    /// The id of the bucket used within the storage backends.
+    bucket_id: u32,
+
+    /// The sizing id of the original image.
+    original_sizing_id: u32,
+
+    /// The bucket's presets and the sizing ids they map to.
+    presets: Vec<PresetSizing>,
+
+    /// The sizing ids of the requested custom sizes.
+    custom: Vec<CustomSizing>,
+}
+
+#[derive(ApiResponse)]
+pub enum PresetsResponse {
+    #[oai(status = 200)]
+    Ok(Json<SizingRegistry>),
+
+    /// A requested custom size is invalid.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(Debug, Object)]
+pub struct PreviewImage {
+    /// The id of the image.
+    image_id: Uuid,
+
+    /// When the image was uploaded, formatted as RFC 3339.
+    uploaded_at: String,
+
+    /// The size of the uploaded image in bytes.
+    size: u64,
+
+    /// The tags the image was uploaded with.
+    tags: Vec<String>,
+
+    /// The URL of a small variant of the image.
+    url: String,
+}
+
+#[derive(Debug, Object)]
+pub struct PreviewPage {
+    /// The page of images, starting from `0`.
+    page: usize,
+
+    /// The total number of indexed images.
+    total: usize,
+
+    /// The images of the page, most recently uploaded first.
+    images: Vec<PreviewImage>,
+}
+
+#[derive(ApiResponse)]
+pub enum PreviewResponse {
+    #[oai(status = 200)]
+    Ok(Json<PreviewPage>),
+
+    /// The page rendered as an HTML grid of thumbnails.
+    #[oai(status = 200)]
+    Html(Html<String>),
+
+    /// The bucket does not have an `index`.
+    #[oai(status = 400)]
+    NotIndexed(Json<Detail>),
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum ExportResponse {
+    /// The page of images as newline-delimited JSON, one image per line.
+    #[oai(status = 200)]
+    Ok(
+        Binary<Body>,
+        #[oai(header = "content-type")] String,
+        /// The cursor to fetch the next page with, omitted on the last page.
+        #[oai(header = "x-next-cursor")] Option<String>,
+    ),
+
+    /// The limit is invalid or the bucket does not have an `index`.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(Debug, Object)]
+pub struct BucketChanges {
+    /// The changes after the given cursor, oldest first.
+    events: Vec<ChangeEvent>,
+
+    /// The cursor to fetch the following changes with.
+    cursor: u64,
+
+    /// If more changes are available after `cursor`.
+    has_more: bool,
+}
+
+#[derive(ApiResponse)]
+pub enum ChangesResponse {
+    #[oai(status = 200)]
+    Ok(Json<BucketChanges>),
+
+    /// The limit is invalid or the bucket does not have a `change_log`.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+
+    /// The changes after the cursor are no longer available,
+    /// the mirror must re-sync from a full export.
+    #[oai(status = 410)]
+    Gone(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum LifecycleResponse {
+    #[oai(status = 200)]
+    Ok(Json<LifecycleReport>),
+
+    /// Lifecycle rules are not configured for the bucket.
+    #[oai(status = 400)]
+    NotEnabled(Json<Detail>),
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum CpuProfileResponse {
+    /// The sampled stacks rendered as a flamegraph SVG.
+    #[oai(status = 200)]
+    Ok(
+        Binary<Vec<u8>>,
+        #[oai(header = "content-type")] String,
+    ),
+
+    /// No stacks were sampled as the server was idle during the capture.
+    #[oai(status = 204)]
+    Idle,
+
+    /// Profiling is not enabled.
+    #[oai(status = 400)]
+    NotEnabled(Json<Detail>),
+
+    /// Another capture is already in progress.
+    #[oai(status = 409)]
+    InProgress(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum HeapStatsResponse {
+    #[oai(status = 200)]
+    Ok(Json<AllocatorStats>),
+
+    /// Profiling is not enabled.
+    #[oai(status = 400)]
+    NotEnabled(Json<Detail>),
+}
+
+pub struct AdminApi;
+
+#[OpenApi]
+impl AdminApi {
+    /// List Buckets
+    ///
+    /// List the configured buckets.
+    #[oai(path = "/buckets", method = "get")]
+    pub async fn list_buckets(&self) -> BucketsResponse {
+        let mut buckets: Vec<BucketInfo> = buckets()
+            .map(|bucket| BucketInfo {
+                name: bucket.name().to_string(),
+                bucket_id: bucket.bucket_id(),
+                mode: format!("{:?}", bucket.cfg().mode).to_lowercase(),
+            })
+            .collect();
+        buckets.sort_by(|a, b| a.name.cmp(&b.name));
+
+        BucketsResponse::Ok(Json(buckets))
+    }
+
+    /// Bucket Presets
+    ///
+    /// Get the mapping of the bucket's presets to the sizing ids their
+    /// variants are stored under, along with the ids of any requested custom sizes.
+    #[oai(path = "/buckets/:bucket/presets", method = "get")]
+    pub async fn bucket_presets(
+        &self,
+        /// The bucket to get the presets of.
+        bucket: Path<String>,
+
+        /// A comma separated list of custom sizes to compute the sizing ids of,
+        /// formatted as `WIDTHxHEIGHT`, e.g. `800x600,200x200`.
+        sizes: Query<Option<String>>,
+    ) -> PresetsResponse {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return PresetsResponse::NotFound(Json(detail))
+            },
+            Some(b) => b,
+        };
+
+        let mut presets: Vec<PresetSizing> = bucket.cfg().presets
+            .iter()
+            .map(|(name, cfg)| PresetSizing {
+                name: name.clone(),
+                sizing_id: crate::utils::crc_hash(name),
+                width: cfg.width,
+                height: cfg.height,
+            })
+            .collect();
+        presets.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut custom = vec![];
+        for size in sizes.0.iter().flat_map(|s| s.split(',')).map(str::trim).filter(|s| !s.is_empty()) {
+            let (width, height) = match parse_size(size) {
+                None => {
+                    let detail = Detail::new(format!("The custom size {:?} is invalid, expected `WIDTHxHEIGHT`.", size));
+                    return PresetsResponse::BadRequest(Json(detail))
+                },
+                Some(size) => size,
+            };
+
+            let sizing_id = match bucket.cfg().custom_sizing_id(width, height) {
+                Err(e) => return PresetsResponse::BadRequest(Json(Detail::new(e))),
+                Ok(sizing_id) => sizing_id,
+            };
+
+            custom.push(CustomSizing { width, height, sizing_id });
+        }
+
+        PresetsResponse::Ok(Json(SizingRegistry {
+            bucket_id: bucket.bucket_id(),
+            original_sizing_id: 0,
+            presets,
+            custom,
+        }))
+    }
+
+    /// Bucket Preview
+    ///
+    /// Get a page of the bucket's most recently uploaded images along with
+    /// the URL of a small variant of each, for quickly eyeballing a bucket.
+    /// Requires the bucket to have an `index`.
+    #[oai(path = "/buckets/:bucket/preview", method = "get")]
+    pub async fn bucket_preview(
+        &self,
+        /// The bucket to preview.
+        bucket: Path<String>,
+
+        /// The page of images to return, starting from `0`.
+        ///
+        /// Defaults to `0`.
+        page: Query<Option<usize>>,
+
+        /// Render the page as an HTML grid rather than JSON.
+        ///
+        /// Defaults to `false`.
+        html: Query<Option<bool>>,
+    ) -> poem::Result<PreviewResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(PreviewResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        let records = match bucket.indexed_images().await? {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not have an index.", bucket.name()));
+                return Ok(PreviewResponse::NotIndexed(Json(detail)))
+            },
+            Some(records) => records,
+        };
+
+        let mut records: Vec<_> = records.into_iter().collect();
+        records.sort_by(|(a_id, a), (b_id, b)| b.uploaded_at.cmp(&a.uploaded_at).then(a_id.cmp(b_id)));
+
+        let page = page.0.unwrap_or(0);
+        let preset = thumbnail_preset(bucket);
+        let images = records
+            .iter()
+            .skip(page.saturating_mul(PREVIEW_PAGE_SIZE))
+            .take(PREVIEW_PAGE_SIZE)
+            .map(|(image_id, record)| PreviewImage {
+                image_id: *image_id,
+                uploaded_at: chrono::NaiveDateTime::from_timestamp_opt(record.uploaded_at, 0)
+                    .map(|dt| chrono::DateTime::<chrono::Utc>::from_utc(dt, chrono::Utc).to_rfc3339())
+                    .unwrap_or_default(),
+                size: record.size,
+                tags: record.tags.clone(),
+                url: image_url(bucket.name(), *image_id, preset),
+            })
+            .collect();
+
+        let page = PreviewPage {
+            page,
+            total: records.len(),
+            images,
+        };
+
+        if html.0.unwrap_or(false) {
+            return Ok(PreviewResponse::Html(Html(render_preview(bucket.name(), &page))))
+        }
+
+        Ok(PreviewResponse::Ok(Json(page)))
+    }
+
+    /// Export Bucket
+    ///
+    /// Stream the metadata of the bucket's images as newline-delimited JSON for
+    /// ingestion into analytics warehouses, a page at a time in ascending order
+    /// of their id. Each line holds the image's id, upload time, checksum, size,
+    /// upload metadata and its stored variants. Requires the bucket to have an `index`.
+    ///
+    /// The `x-next-cursor` header of each page is given as the `cursor` to fetch
+    /// the next page, and is omitted on the last page.
+    #[oai(path = "/buckets/:bucket/export", method = "get")]
+    pub async fn export_bucket(
+        &self,
+        /// The bucket to export.
+        bucket: Path<String>,
+
+        /// Only export the images after this id.
+        cursor: Query<Option<Uuid>>,
+
+        /// The maximum number of images to export, between 1 and 10000. Defaults to 1000.
+        limit: Query<Option<usize>>,
+    ) -> poem::Result<ExportResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(ExportResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        let limit = limit.0.unwrap_or(DEFAULT_EXPORT_LIMIT);
+        if limit == 0 || limit > MAX_EXPORT_LIMIT {
+            let detail = Detail::new(format!("The limit must be between 1 and {}.", MAX_EXPORT_LIMIT));
+            return Ok(ExportResponse::BadRequest(Json(detail)))
+        }
+
+        let records = match bucket.indexed_images().await? {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not have an index.", bucket.name()));
+                return Ok(ExportResponse::BadRequest(Json(detail)))
+            },
+            Some(records) => records,
+        };
+
+        let page = ExportPage::new(records, cursor.0, limit);
+        let next_cursor = page.next_cursor.map(|cursor| cursor.to_string());
+        let body = Body::from_bytes_stream(crate::export::stream(bucket, page));
+
+        Ok(ExportResponse::Ok(Binary(body), "application/x-ndjson".to_string(), next_cursor))
+    }
+
+    /// Bucket Changes
+    ///
+    /// List the images created, replaced and deleted in the bucket after the
+    /// given cursor, oldest first, so external systems can mirror the bucket
+    /// incrementally. Requires the bucket to have a `change_log`.
+    ///
+    /// Without a `since` cursor no changes are returned, only the cursor of the
+    /// most recent change to resume from after a full export. The returned `cursor`
+    /// is given as `since` to fetch the following changes. If the changes after
+    /// the cursor have been dropped from the log `410 Gone` is returned.
+    #[oai(path = "/buckets/:bucket/changes", method = "get")]
+    pub async fn bucket_changes(
+        &self,
+        /// The bucket to list the changes of.
+        bucket: Path<String>,
+
+        /// Only list the changes after this cursor.
+        since: Query<Option<u64>>,
+
+        /// The maximum number of changes to list, between 1 and 10000. Defaults to 1000.
+        limit: Query<Option<usize>>,
+    ) -> poem::Result<ChangesResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(ChangesResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        let limit = limit.0.unwrap_or(DEFAULT_CHANGES_LIMIT);
+        if limit == 0 || limit > MAX_CHANGES_LIMIT {
+            let detail = Detail::new(format!("The limit must be between 1 and {}.", MAX_CHANGES_LIMIT));
+            return Ok(ChangesResponse::BadRequest(Json(detail)))
+        }
+
+        let not_enabled = || {
+            let detail = Detail::new(format!("The bucket {:?} does not have a change log.", bucket.name()));
+            ChangesResponse::BadRequest(Json(detail))
+        };
+
+        let since = match since.0 {
+            Some(since) => since,
+            None => {
+                let cursor = match bucket.latest_change().await? {
+                    None => return Ok(not_enabled()),
+                    Some(cursor) => cursor,
+                };
+
+                let changes = BucketChanges { events: vec![], cursor, has_more: false };
+                return Ok(ChangesResponse::Ok(Json(changes)))
+            },
+        };
+
+        // One extra change is fetched to tell if there are more.
+        let mut events = match bucket.changes_since(since, limit + 1).await? {
+            None => return Ok(not_enabled()),
+            Some(ChangesSince::Expired) => {
+                let detail = Detail::new(format!(
+                    "The changes after cursor {} are no longer available, re-sync from a full export.",
+                    since,
+                ));
+                return Ok(ChangesResponse::Gone(Json(detail)))
+            },
+            Some(ChangesSince::Events(events)) => events,
+        };
+
+        let has_more = events.len() > limit;
+        events.truncate(limit);
+        let cursor = events.last().map(|event| event.cursor).unwrap_or(since);
+
+        Ok(ChangesResponse::Ok(Json(BucketChanges { events, cursor, has_more })))
+    }
+
+    /// Top Accessed Images
+    ///
+    /// List the most fetched images of the bucket.
+    /// Requires `access_stats` to be enabled for the bucket.
+    #[oai(path = "/buckets/:bucket/stats", method = "get")]
+    pub async fn top_accessed(
+        &self,
+        /// The bucket to get the access stats of.
+        bucket: Path<String>,
+
+        /// The maximum number of images to return, up to 1000.
+        ///
+        /// Defaults to 10.
+        limit: Query<Option<usize>>,
+    ) -> poem::Result<AccessStatsResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(AccessStatsResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        let counts = match bucket.access_counts().await? {
+            None => return Ok(AccessStatsResponse::NotEnabled(Json(stats_not_enabled(bucket.name())))),
+            Some(counts) => counts,
+        };
+
+        let mut stats: Vec<ImageAccessStats> = counts
+            .into_iter()
+            .map(|(image_id, fetches)| ImageAccessStats { image_id, fetches })
+            .collect();
+        stats.sort_by(|a, b| b.fetches.cmp(&a.fetches).then(a.image_id.cmp(&b.image_id)));
+        stats.truncate(limit.0.unwrap_or(DEFAULT_TOP_ACCESSED).min(MAX_TOP_ACCESSED));
+
+        Ok(AccessStatsResponse::Ok(Json(stats)))
+    }
+
+    /// Image Access Stats
+    ///
+    /// Get the number of times the given image has been fetched.
+    /// Requires `access_stats` to be enabled for the bucket.
+    #[oai(path = "/buckets/:bucket/stats/:image_id", method = "get")]
+    pub async fn image_access_stats(
+        &self,
+        /// The bucket the image belongs to.
+        bucket: Path<String>,
+
+        /// The id of the image.
+        image_id: Path<Uuid>,
+    ) -> poem::Result<ImageAccessStatsResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(ImageAccessStatsResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        let counts = match bucket.access_counts().await? {
+            None => return Ok(ImageAccessStatsResponse::NotEnabled(Json(stats_not_enabled(bucket.name())))),
+            Some(counts) => counts,
+        };
+
+        let fetches = counts.get(&*image_id).copied().unwrap_or(0);
+        Ok(ImageAccessStatsResponse::Ok(Json(ImageAccessStats { image_id: *image_id, fetches })))
+    }
+
+    /// Fetch Original
+    ///
+    /// Get the image's original as stored, including for
+    /// buckets which don't serve originals publicly.
+    #[oai(path = "/buckets/:bucket/images/:image_id/original", method = "get")]
+    pub async fn fetch_original(
+        &self,
+        /// The bucket the image belongs to.
+        bucket: Path<String>,
+
+        /// The id of the image.
+        image_id: Path<Uuid>,
+    ) -> poem::Result<OriginalResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(OriginalResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        match bucket.original(*image_id).await? {
+            None => {
+                let detail = Detail::new(format!("The original of image {} does not exist.", *image_id));
+                Ok(OriginalResponse::NotFound(Json(detail)))
+            },
+            Some((data, kind)) => Ok(OriginalResponse::Ok(Binary(data.to_vec()), kind.as_content_type())),
+        }
+    }
+
+    /// List API Keys
+    ///
+    /// List the API keys created for the bucket via the admin API, configured
+    /// keys are not included and the keys themselves are never returned.
+    #[oai(path = "/buckets/:bucket/keys", method = "get")]
+    pub async fn list_api_keys(
+        &self,
+        /// The bucket to list the API keys of.
+        bucket: Path<String>,
+    ) -> poem::Result<ApiKeysResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(ApiKeysResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        Ok(ApiKeysResponse::Ok(Json(bucket.api_keys().await?)))
+    }
+
+    /// Create API Key
+    ///
+    /// Create an API key authorizing uploads and deletes on the bucket. Several keys
+    /// can be valid at once, so keys can be rotated by creating the new key before
+    /// revoking the old key.
+    #[oai(path = "/buckets/:bucket/keys", method = "post")]
+    pub async fn create_api_key(
+        &self,
+        /// The bucket the key authorizes requests to.
+        bucket: Path<String>,
+
+        payload: Json<CreateApiKey>,
+    ) -> poem::Result<CreateApiKeyResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(CreateApiKeyResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        let (info, key) = bucket.create_api_key(payload.0.name, payload.0.expires_in).await?;
+        Ok(CreateApiKeyResponse::Ok(Json(CreatedApiKey { key, info })))
+    }
+
+    /// Revoke API Key
+    ///
+    /// Revoke an API key created via the admin API, requests
+    /// using the key are rejected immediately.
+    #[oai(path = "/buckets/:bucket/keys/:key_id", method = "delete")]
+    pub async fn revoke_api_key(
+        &self,
+        /// The bucket the key belongs to.
+        bucket: Path<String>,
+
+        /// The id of the key to revoke.
+        key_id: Path<String>,
+    ) -> poem::Result<RevokeApiKeyResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(RevokeApiKeyResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        if bucket.revoke_api_key(&key_id).await? {
+            Ok(RevokeApiKeyResponse::Revoked)
+        } else {
+            let detail = Detail::new(format!("The API key {:?} does not exist.", &*key_id));
+            Ok(RevokeApiKeyResponse::NotFound(Json(detail)))
+        }
+    }
+
+    /// List Quarantined Uploads
+    ///
+    /// List the uploads of the bucket which failed to be decoded or encoded,
+    /// oldest first. Requires `quarantine` to be enabled for the bucket.
+    #[oai(path = "/buckets/:bucket/quarantine", method = "get")]
+    pub async fn list_quarantined(
+        &self,
+        /// The bucket to list the quarantined uploads of.
+        bucket: Path<String>,
+    ) -> poem::Result<QuarantineResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(QuarantineResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        match bucket.quarantined_uploads().await? {
+            None => Ok(QuarantineResponse::NotEnabled(Json(quarantine_not_enabled(bucket.name())))),
+            Some(uploads) => Ok(QuarantineResponse::Ok(Json(uploads))),
+        }
+    }
+
+    /// Fetch Quarantined Upload
+    ///
+    /// Get the payload of a quarantined upload exactly as it was uploaded.
+    #[oai(path = "/buckets/:bucket/quarantine/:upload_id", method = "get")]
+    pub async fn fetch_quarantined(
+        &self,
+        /// The bucket the upload was made to.
+        bucket: Path<String>,
+
+        /// The id of the quarantined upload.
+        upload_id: Path<Uuid>,
+    ) -> poem::Result<QuarantinedPayloadResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(QuarantinedPayloadResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        if bucket.cfg().quarantine.is_none() {
+            return Ok(QuarantinedPayloadResponse::NotEnabled(Json(quarantine_not_enabled(bucket.name()))))
+        }
+
+        match bucket.quarantined_payload(*upload_id).await? {
+            None => {
+                let detail = Detail::new(format!("The quarantined upload {} does not exist.", *upload_id));
+                Ok(QuarantinedPayloadResponse::NotFound(Json(detail)))
+            },
+            Some(data) => Ok(QuarantinedPayloadResponse::Ok(Binary(data.to_vec()))),
+        }
+    }
+
+    /// Remove Quarantined Upload
+    ///
+    /// Remove an upload from the quarantine once it's no longer needed.
+    #[oai(path = "/buckets/:bucket/quarantine/:upload_id", method = "delete")]
+    pub async fn remove_quarantined(
+        &self,
+        /// The bucket the upload was made to.
+        bucket: Path<String>,
+
+        /// The id of the quarantined upload.
+        upload_id: Path<Uuid>,
+    ) -> poem::Result<RemoveQuarantinedResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(RemoveQuarantinedResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        if bucket.cfg().quarantine.is_none() {
+            return Ok(RemoveQuarantinedResponse::NotEnabled(Json(quarantine_not_enabled(bucket.name()))))
+        }
+
+        if bucket.remove_quarantined(*upload_id).await? {
+            Ok(RemoveQuarantinedResponse::Removed)
+        } else {
+            let detail = Detail::new(format!("The quarantined upload {} does not exist.", *upload_id));
+            Ok(RemoveQuarantinedResponse::NotFound(Json(detail)))
+        }
+    }
+
+    /// Run Lifecycle Rules
+    ///
+    /// Evaluate the bucket's lifecycle rules now, returning the affected images.
+    #[oai(path = "/buckets/:bucket/lifecycle", method = "post")]
+    pub async fn run_lifecycle(
+        &self,
+        /// The bucket to apply the lifecycle rules of.
+        bucket: Path<String>,
+
+        /// Only report what the rules would do rather than applying them.
+        ///
+        /// Defaults to the bucket's `dry_run` setting.
+        dry_run: Query<Option<bool>>,
+    ) -> poem::Result<LifecycleResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail::new(format!("The bucket {:?} does not exist.", &*bucket));
+                return Ok(LifecycleResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        let dry_run = match bucket.cfg().lifecycle {
+            None => {
+                let detail = Detail::new(format!("Lifecycle rules are not configured for the bucket {:?}.", bucket.name()));
+                return Ok(LifecycleResponse::NotEnabled(Json(detail)))
+            },
+            Some(ref cfg) => dry_run.0.unwrap_or(cfg.dry_run),
+        };
+
+        let report = crate::lifecycle::run(bucket, dry_run).await?;
+        Ok(LifecycleResponse::Ok(Json(report)))
+    }
+
+    /// Capture CPU Profile
+    ///
+    /// Samples the stacks of every thread for the given number of `seconds`
+    /// (defaults to 10, at most 300), capturing where the CPU time is spent
+    /// including within the decoders, encoders and resizer.
+    ///
+    /// The profile is returned as a flamegraph SVG. Requires `enable_profiling`.
+    #[oai(path = "/profile/cpu", method = "post")]
+    pub async fn cpu_profile(&self, seconds: Query<Option<u64>>) -> poem::Result<CpuProfileResponse> {
+        if !config().enable_profiling {
+            return Ok(CpuProfileResponse::NotEnabled(Json(profiling_not_enabled())))
+        }
+
+        let capture = match Capture::start()? {
+            None => return Ok(CpuProfileResponse::InProgress(Json(Detail::new("A profile is already being captured.")))),
+            Some(capture) => capture,
+        };
+
+        let seconds = seconds.0
+            .unwrap_or(DEFAULT_PROFILE_DURATION)
+            .clamp(1, MAX_PROFILE_DURATION);
+        tokio::time::sleep(std::time::Duration::from_secs(seconds)).await;
+
+        match capture.finish()? {
+            None => Ok(CpuProfileResponse::Idle),
+            Some(svg) => Ok(CpuProfileResponse::Ok(Binary(svg), "image/svg+xml".to_string())),
+        }
+    }
+
+    /// Heap Statistics
+    ///
+    /// The memory statistics of the global allocator. Requires `enable_profiling`.
+    #[oai(path = "/profile/heap", method = "get")]
+    pub async fn heap_stats(&self) -> HeapStatsResponse {
+        if !config().enable_profiling {
+            return HeapStatsResponse::NotEnabled(Json(profiling_not_enabled()))
+        }
+
+        HeapStatsResponse::Ok(Json(crate::allocator::stats()))
+    }
+}
+
+fn profiling_not_enabled() -> Detail {
+    Detail::new("Profiling is not enabled, it can be enabled with `enable_profiling`.")
+}
+
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.split_once('x')?;
+    let width = width.parse().ok().filter(|w| *w > 0)?;
+    let height = height.parse().ok().filter(|h| *h > 0)?;
+    Some((width, height))
+}
+
+/// The smallest preset of the bucket, if it has any.
+fn thumbnail_preset(bucket: &BucketController) -> Option<&str> {
+    bucket.cfg().presets
+        .iter()
+        .min_by_key(|(name, cfg)| (cfg.width as u64 * cfg.height as u64, name.as_str()))
+        .map(|(name, _)| name.as_str())
+}
+
+fn image_url(bucket: &str, image_id: Uuid, preset: Option<&str>) -> String {
+    let url = format!(
+        "/v1{}/{}/{}",
+        config().base_serving_path.as_deref().unwrap_or(""),
+        bucket,
+        image_id,
+    );
+
+    match preset {
+        None => url,
+        Some(preset) => format!("{}?size={}", url, preset),
+    }
+}
+
+fn render_preview(bucket: &str, page: &PreviewPage) -> String {
+    let bucket = escape_html(bucket);
+    let tiles: String = page.images
+        .iter()
+        .map(|image| format!(
+            r#"<figure><a href="{url}"><img src="{url}" loading="lazy"></a><figcaption>{id}<br>{uploaded_at}</figcaption></figure>"#,
+            url = escape_html(&image.url),
+            id = image.image_id,
+            uploaded_at = escape_html(&image.uploaded_at),
+        ))
+        .collect();
+
+    let mut nav = String::new();
+    if page.page > 0 {
+        nav.push_str(&format!(r#"<a href="?html=true&page={}">Previous</a> "#, page.page - 1));
+    }
+    if (page.page + 1) * PREVIEW_PAGE_SIZE < page.total {
+        nav.push_str(&format!(r#"<a href="?html=true&page={}">Next</a>"#, page.page + 1));
+    }
+
+    format!(
+        r#"<!DOCTYPE html><html><head><meta charset="utf-8"><title>{bucket} preview</title><style>
+body {{ font-family: sans-serif; }}
+main {{ display: grid; grid-template-columns: repeat(auto-fill, minmax(160px, 1fr)); gap: 8px; }}
+figure {{ margin: 0; }}
+img {{ width: 100%; height: 160px; object-fit: cover; background: #eee; }}
+figcaption {{ font-size: 10px; overflow-wrap: anywhere; }}
+</style></head><body><h1>{bucket}</h1><p>Page {page} of {total} images.</p><main>{tiles}</main><nav>{nav}</nav></body></html>"#,
+        bucket = bucket,
+        page = page.page,
+        total = page.total,
+        tiles = tiles,
+        nav = nav,
+    )
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn quarantine_not_enabled(bucket: &str) -> Detail {
+    Detail::new(format!("The quarantine is not enabled for the bucket {:?}.", bucket))
+}
+
+fn stats_not_enabled(bucket: &str) -> Detail {
+    Detail::new(format!("Access stats are not enabled for the bucket {:?}.", bucket))
+}
+
+/// Sets the token every request to the admin API must carry
+/// as `Authorization: Bearer <token>`.
+pub fn init_token(token: String) {
+    let _ = ADMIN_TOKEN.set(token);
+}
+
+/// If an admin token has been configured.
+pub fn has_token() -> bool {
+    ADMIN_TOKEN.get().is_some()
+}
+
+/// Adds the admin API and metrics endpoint to the given routes.
+///
+/// The admin API requires the admin token if one is configured.
+pub fn mount(route: Route) -> Route {
+    let api_service = OpenApiService::new(
+        AdminApi,
+        "Lust Admin API",
+        env!("CARGO_PKG_VERSION"),
+    );
+
+    mount_metrics(route).nest("/admin", api_service.around(require_token))
+}
+
+/// Adds only the metrics endpoint to the given routes.
+pub fn mount_metrics(route: Route) -> Route {
+    route.at("/metrics", metrics)
+}
+
+/// Rejects requests to the admin API without the admin token if one is configured.
+async fn require_token<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
+    let expected = match ADMIN_TOKEN.get() {
+        None => return next.call(req).await.map(IntoResponse::into_response),
+        Some(token) => token,
+    };
+
+    let token = req.headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match token {
+        Some(token) if key_matches(expected, token) => {
+            next.call(req).await.map(IntoResponse::into_response)
+        },
+        _ => Ok(StatusCode::UNAUTHORIZED.into_response()),
+    }
+}
+
+/// Renders the metrics in the Prometheus text format.
+#[handler]
+async fn metrics() -> poem::Result<String> {
+    Ok(crate::metrics::render()?)
+}
@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+
+use once_cell::sync::OnceCell;
+use poem::{Endpoint, IntoResponse, Request, Response};
+use tokio::sync::{oneshot, Semaphore, SemaphorePermit};
+
+use crate::auth::Scope;
+use crate::config::PermitWeights;
+
+static MEMORY_BUDGET: OnceCell<MemoryBudget> = OnceCell::new();
+static LOW_PRIORITY_LIMIT: OnceCell<Semaphore> = OnceCell::new();
+
+/// The header internal traffic sets to run at a lower priority.
+const PRIORITY_HEADER: &str = "x-lust-priority";
+
+tokio::task_local! {
+    static PRIORITY: Priority;
+}
+
+/// The number of bytes each pixel is assumed to take once decoded.
+///
+/// Images are generally converted to RGBA8 at some point in the pipeline.
+const BYTES_PER_PIXEL: usize = 4;
+
+pub fn init_memory_budget(max_memory: usize) {
+    let _ = MEMORY_BUDGET.set(MemoryBudget::new(max_memory));
+}
+
+pub fn memory_budget<'a>() -> Option<&'a MemoryBudget> {
+    MEMORY_BUDGET.get()
+}
+
+/// Estimates the amount of memory required to process the given image.
+///
+/// This only reads the image header so is cheap enough to run before
+/// any work is admitted, if the dimensions cannot be read the encoded
+/// size is used instead.
+pub fn estimate_processing_memory(data: &[u8]) -> usize {
+    let dimensions = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+
+    match dimensions {
+        Some((width, height)) => {
+            (width as usize * height as usize * BYTES_PER_PIXEL) + data.len()
+        },
+        None => data.len(),
+    }
+}
+
+/// A budget of memory shared by all images being decoded/encoded.
+///
+/// Work is queued until enough of the budget is free rather than
+/// allowing concurrent large images to exhaust the system's memory.
+pub struct MemoryBudget {
+    limiter: Semaphore,
+    capacity_kb: u32,
+}
+
+impl MemoryBudget {
+    /// Creates a new budget with the given capacity in MB.
+    pub fn new(max_memory: usize) -> Self {
+        let capacity_kb = (max_memory * 1024).min(u32::MAX as usize) as u32;
+
+        Self {
+            limiter: Semaphore::new(capacity_kb as usize),
+            capacity_kb,
+        }
+    }
+
+    /// Reserves the given amount of memory in bytes, waiting until
+    /// enough of the budget is available.
+    ///
+    /// Reservations larger than the whole budget are capped to the budget
+    /// so they are processed on their own rather than rejected.
+    pub async fn reserve(&self, size: usize) -> anyhow::Result<SemaphorePermit<'_>> {
+        let kb = (size / 1024).clamp(1, self.capacity_kb as usize) as u32;
+        Ok(self.limiter.acquire_many(kb).await?)
+    }
+}
+
+pub fn init_low_priority_limit(max_concurrency: usize) {
+    let _ = LOW_PRIORITY_LIMIT.set(Semaphore::new(max_concurrency));
+}
+
+/// The priority operations are scheduled with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Priority {
+    /// User facing traffic.
+    Normal,
+
+    /// Background work such as batch re-encoding, limited by
+    /// `max_low_priority_concurrency` so it can't degrade user facing traffic.
+    Low,
+}
+
+/// The priority of the operation running in the current task.
+pub fn current_priority() -> Priority {
+    PRIORITY.try_with(|priority| *priority).unwrap_or(Priority::Normal)
+}
+
+/// Runs the future with the given priority, e.g. when moving
+/// an operation to a background task.
+pub async fn with_priority<F: Future>(priority: Priority, fut: F) -> F::Output {
+    PRIORITY.scope(priority, fut).await
+}
+
+/// Waits for a low priority slot if the current task runs at a low priority.
+pub async fn acquire_priority_slot() -> anyhow::Result<Option<SemaphorePermit<'static>>> {
+    match (current_priority(), LOW_PRIORITY_LIMIT.get()) {
+        (Priority::Low, Some(limit)) => Ok(Some(limit.acquire().await?)),
+        _ => Ok(None),
+    }
+}
+
+/// Runs requests to the image API carrying `X-Lust-Priority: low` at a low priority.
+///
+/// The header is only honoured for requests authorized by one of the bucket's
+/// API keys or a JWT with any scope on the bucket, otherwise it's ignored.
+pub async fn prioritize<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
+    let low_priority = req.headers()
+        .get(PRIORITY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().eq_ignore_ascii_case("low"))
+        .unwrap_or(false);
+
+    if !low_priority || !is_internal(&req).await {
+        return next.call(req).await.map(IntoResponse::into_response)
+    }
+
+    with_priority(Priority::Low, next.call(req))
+        .await
+        .map(IntoResponse::into_response)
+}
+
+async fn is_internal(req: &Request) -> bool {
+    let bucket = req.uri()
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .next()
+        .and_then(crate::controller::get_bucket_by_name);
+
+    let token = req.headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    let (bucket, token) = match (bucket, token) {
+        (Some(bucket), Some(token)) => (bucket, token),
+        _ => return false,
+    };
+
+    if crate::auth::is_jwt(token) {
+        return [Scope::Read, Scope::Write, Scope::Delete]
+            .into_iter()
+            .any(|scope| crate::auth::grants(token, bucket.name(), scope))
+    }
+
+    bucket.is_api_key(token).await.unwrap_or(false)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Operation {
+    Upload,
+    Fetch,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Upload => "upload",
+            Self::Fetch => "fetch",
+        }
+    }
+}
+
+/// The concurrency permits held by an operation, released once dropped.
+pub struct ConcurrencyPermit {
+    limit: Arc<Limit>,
+    weight: u32,
+}
+
+impl ConcurrencyPermit {
+    /// The number of permits held.
+    #[inline]
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.limit.release(self.weight);
+    }
+}
+
+/// An operation waiting to be admitted by a [`Limit`].
+struct Waiter {
+    weight: u32,
+    admit: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct LimitState {
+    available: u32,
+    normal: VecDeque<Waiter>,
+    low: VecDeque<Waiter>,
+}
+
+impl LimitState {
+    /// Admits the queued operations which fit in the free permits.
+    ///
+    /// Low priority operations are only admitted once no normal
+    /// priority operation is waiting.
+    fn admit_waiters(&mut self) {
+        loop {
+            // Waiters which gave up are skipped rather than blocking the queue.
+            for queue in [&mut self.normal, &mut self.low] {
+                while queue.front().map(|w| w.admit.is_closed()).unwrap_or(false) {
+                    queue.pop_front();
+                }
+            }
+
+            let queue = if self.normal.is_empty() { &mut self.low } else { &mut self.normal };
+            match queue.front() {
+                Some(waiter) if waiter.weight <= self.available => {},
+                _ => return,
+            }
+
+            let waiter = queue.pop_front().unwrap();
+            if waiter.admit.send(()).is_ok() {
+                self.available -= waiter.weight;
+            }
+        }
+    }
+
+    fn queue(&mut self, priority: Priority) -> &mut VecDeque<Waiter> {
+        match priority {
+            Priority::Normal => &mut self.normal,
+            Priority::Low => &mut self.low,
+        }
+    }
+}
+
+/// Waits for a queued operation to be admitted, handing the
+/// permits back if it gives up after being admitted.
+struct Admission<'a> {
+    limit: &'a Limit,
+    weight: u32,
+    admit: Option<oneshot::Receiver<()>>,
+}
+
+impl<'a> Admission<'a> {
+    async fn wait(&mut self) -> anyhow::Result<()> {
+        if let Some(admit) = self.admit.as_mut() {
+            admit.await.map_err(|_| anyhow!("The concurrency limit was closed."))?;
+            self.admit = None;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Admission<'a> {
+    fn drop(&mut self) {
+        let mut admit = match self.admit.take() {
+            None => return,
+            Some(admit) => admit,
+        };
+
+        admit.close();
+        if admit.try_recv().is_ok() {
+            self.limit.release(self.weight);
+        } else {
+            self.limit.state.lock().unwrap().admit_waiters();
+        }
+    }
+}
+
+/// A semaphore which knows its own capacity, admitting normal
+/// priority operations ahead of any low priority ones.
+struct Limit {
+    state: Mutex<LimitState>,
+    capacity: u32,
+}
+
+impl Limit {
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.min(u32::MAX as usize) as u32;
+        let state = LimitState {
+            available: capacity,
+            ..Default::default()
+        };
+
+        Self {
+            state: Mutex::new(state),
+            capacity,
+        }
+    }
+
+    async fn acquire(self: &Arc<Self>, weight: u32, priority: Priority) -> anyhow::Result<ConcurrencyPermit> {
+        // A weight larger than the limit itself would never be satisfied.
+        let weight = weight.clamp(1, self.capacity.max(1));
+
+        let admit = {
+            let mut state = self.state.lock().unwrap();
+            let (tx, rx) = oneshot::channel();
+            state.queue(priority).push_back(Waiter { weight, admit: tx });
+            state.admit_waiters();
+            rx
+        };
+
+        let mut admission = Admission { limit: self, weight, admit: Some(admit) };
+        admission.wait().await?;
+
+        Ok(ConcurrencyPermit { limit: self.clone(), weight })
+    }
+
+    fn release(&self, weight: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.available += weight;
+        state.admit_waiters();
+    }
+}
+
+/// Limits the number of concurrent uploads and fetches.
+///
+/// Uploads and fetches can be given independent limits so a burst of
+/// expensive uploads cannot starve cheap fetches, otherwise they share
+/// a single limit.
+pub struct ConcurrencyLimiter {
+    uploads: Option<Arc<Limit>>,
+    fetches: Option<Arc<Limit>>,
+    weights: PermitWeights,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(
+        shared: Option<usize>,
+        uploads: Option<usize>,
+        fetches: Option<usize>,
+        weights: PermitWeights,
+    ) -> Self {
+        let shared = shared.map(Limit::new).map(Arc::new);
+
+        Self {
+            uploads: uploads.map(Limit::new).map(Arc::new).or_else(|| shared.clone()),
+            fetches: fetches.map(Limit::new).map(Arc::new).or(shared),
+            weights,
+        }
+    }
+
+    #[inline]
+    pub fn is_limited(&self, op: Operation) -> bool {
+        self.limit(op).is_some()
+    }
+
+    /// Acquires the permits for the given operation if it is limited.
+    ///
+    /// Uploads consume an additional permit per `upload_size_step` of their size in bytes.
+    /// Low priority operations wait until no normal priority operation is queued.
+    pub async fn acquire(
+        &self,
+        op: Operation,
+        size: usize,
+        priority: Priority,
+    ) -> anyhow::Result<Option<ConcurrencyPermit>> {
+        let weight = match op {
+            Operation::Upload => self.weights.upload.saturating_add(self.size_weight(size)),
+            Operation::Fetch => self.weights.fetch,
+        };
+
+        match self.limit(op) {
+            Some(limit) => Ok(Some(limit.acquire(weight, priority).await?)),
+            None => Ok(None),
+        }
+    }
+
+    fn size_weight(&self, size: usize) -> u32 {
+        match self.weights.upload_size_step {
+            None => 0,
+            Some(step) => (size / (step * 1024)).min(u32::MAX as usize) as u32,
+        }
+    }
+
+    #[inline]
+    fn limit(&self, op: Operation) -> Option<&Arc<Limit>> {
+        match op {
+            Operation::Upload => self.uploads.as_ref(),
+            Operation::Fetch => self.fetches.as_ref(),
+        }
+    }
+}
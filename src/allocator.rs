@@ -0,0 +1,145 @@
+use poem_openapi::Object;
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// The name of the global allocator in use.
+pub const fn name() -> &'static str {
+    if cfg!(feature = "jemalloc") {
+        "jemalloc"
+    } else if cfg!(feature = "mimalloc") {
+        "mimalloc"
+    } else {
+        "system"
+    }
+}
+
+#[derive(Debug, Default, Object)]
+pub struct AllocatorStats {
+    /// The global allocator in use.
+    allocator: String,
+
+    /// The physically resident memory of the process in bytes.
+    resident: Option<u64>,
+
+    /// The memory in pages the allocator has handed out to allocations in bytes.
+    active: Option<u64>,
+
+    /// The memory currently allocated by the process in bytes.
+    allocated: Option<u64>,
+
+    /// The fraction of the active memory which is not allocated,
+    /// the memory lost to fragmentation.
+    fragmentation: Option<f64>,
+}
+
+impl AllocatorStats {
+    #[inline]
+    pub fn resident(&self) -> Option<u64> {
+        self.resident
+    }
+
+    #[inline]
+    pub fn active(&self) -> Option<u64> {
+        self.active
+    }
+
+    #[inline]
+    pub fn allocated(&self) -> Option<u64> {
+        self.allocated
+    }
+
+    #[inline]
+    pub fn fragmentation(&self) -> Option<f64> {
+        self.fragmentation
+    }
+}
+
+/// The current memory statistics of the global allocator.
+///
+/// Statistics the allocator doesn't track are left unset.
+pub fn stats() -> AllocatorStats {
+    let mut stats = allocator_stats();
+    stats.allocator = name().to_string();
+
+    if let (Some(active), Some(allocated)) = (stats.active, stats.allocated) {
+        if active > 0 {
+            stats.fragmentation = Some(active.saturating_sub(allocated) as f64 / active as f64);
+        }
+    }
+
+    stats
+}
+
+#[cfg(feature = "jemalloc")]
+fn allocator_stats() -> AllocatorStats {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // The statistics are cached until the epoch is advanced.
+    if let Err(e) = epoch::advance() {
+        warn!("Failed to refresh the jemalloc statistics: {}", e);
+    }
+
+    AllocatorStats {
+        resident: stats::resident::read().ok().map(|v| v as u64),
+        active: stats::active::read().ok().map(|v| v as u64),
+        allocated: stats::allocated::read().ok().map(|v| v as u64),
+        ..Default::default()
+    }
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn allocator_stats() -> AllocatorStats {
+    let mut elapsed_ms = 0;
+    let mut user_time_ms = 0;
+    let mut system_time_ms = 0;
+    let mut current_rss = 0;
+    let mut peak_rss = 0;
+    let mut current_commit = 0;
+    let mut peak_commit = 0;
+    let mut page_faults = 0;
+
+    // SAFETY: Every pointer given is valid for the duration of the call.
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed_ms,
+            &mut user_time_ms,
+            &mut system_time_ms,
+            &mut current_rss,
+            &mut peak_rss,
+            &mut current_commit,
+            &mut peak_commit,
+            &mut page_faults,
+        );
+    }
+
+    // mimalloc only tracks the allocated memory in debug builds.
+    AllocatorStats {
+        resident: Some(current_rss as u64),
+        active: Some(current_commit as u64),
+        ..Default::default()
+    }
+}
+
+#[cfg(not(any(feature = "mimalloc", feature = "jemalloc")))]
+fn allocator_stats() -> AllocatorStats {
+    AllocatorStats {
+        resident: process_resident(),
+        ..Default::default()
+    }
+}
+
+/// The resident memory of the process as reported by procfs.
+#[cfg(not(any(feature = "mimalloc", feature = "jemalloc")))]
+fn process_resident() -> Option<u64> {
+    const PAGE_SIZE: u64 = 4096;
+
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * PAGE_SIZE)
+}
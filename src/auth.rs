@@ -0,0 +1,251 @@
+//! JWT authorization of the image API, allowing each service of a
+//! multi-tenant deployment to be given credentials scoped to its buckets.
+//!
+//! Tokens are given as the `Authorization: Bearer <token>` header and grant
+//! scopes per bucket via their `scopes` claim, `*` granting scopes on every
+//! bucket, e.g. `{"exp": 1700000000, "scopes": {"user-profiles": ["read", "write"]}}`.
+
+use std::collections::HashMap;
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use once_cell::sync::OnceCell;
+use poem::http::{Method, StatusCode};
+use poem::{Endpoint, IntoResponse, Request, Response};
+use serde::Deserialize;
+
+use crate::config::{JwtAlgorithm, JwtConfig};
+use crate::controller::get_bucket_by_name;
+
+static VERIFIER: OnceCell<Verifier> = OnceCell::new();
+
+/// The bucket name granting scopes on every bucket.
+const WILDCARD_BUCKET: &str = "*";
+
+/// The operations a token can be scoped to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Fetching and listing images, and signing fetch URLs.
+    Read,
+
+    /// Uploading and replacing images.
+    Write,
+
+    /// Deleting and purging images.
+    Delete,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    #[serde(default)]
+    scopes: HashMap<String, Vec<Scope>>,
+}
+
+impl Claims {
+    fn grants(&self, bucket: &str, scope: Scope) -> bool {
+        [bucket, WILDCARD_BUCKET]
+            .iter()
+            .filter_map(|bucket| self.scopes.get(*bucket))
+            .any(|scopes| scopes.contains(&scope))
+    }
+}
+
+struct VerificationKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Verifies tokens against the configured keys.
+struct Verifier {
+    keys: Vec<VerificationKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    public_read: bool,
+}
+
+impl Verifier {
+    fn new(cfg: &JwtConfig) -> anyhow::Result<Self> {
+        let keys = cfg.keys
+            .iter()
+            .map(|key| {
+                let (algorithm, decoding_key) = match key.algorithm {
+                    JwtAlgorithm::Hs256 => (Algorithm::HS256, DecodingKey::from_secret(key.key.as_bytes())),
+                    JwtAlgorithm::Rs256 => (Algorithm::RS256, DecodingKey::from_rsa_pem(key.key.as_bytes())?),
+                };
+
+                Ok(VerificationKey {
+                    kid: key.kid.clone(),
+                    algorithm,
+                    key: decoding_key,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            keys,
+            issuer: cfg.issuer.clone(),
+            audience: cfg.audience.clone(),
+            public_read: cfg.public_read,
+        })
+    }
+
+    /// Decodes the token's claims if it's signed by one of the keys.
+    fn verify(&self, token: &str) -> Option<Claims> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+
+        self.keys
+            .iter()
+            .filter(|key| key.algorithm == header.alg)
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .find_map(|key| {
+                let mut validation = Validation::new(key.algorithm);
+                if let Some(ref issuer) = self.issuer {
+                    validation.set_issuer(&[issuer]);
+                }
+                if let Some(ref audience) = self.audience {
+                    validation.set_audience(&[audience]);
+                }
+
+                jsonwebtoken::decode::<Claims>(token, &key.key, &validation)
+                    .ok()
+                    .map(|data| data.claims)
+            })
+    }
+}
+
+/// Loads the configured JWT keys, failing if any of them are invalid.
+pub fn setup() -> anyhow::Result<()> {
+    if let Some(ref cfg) = crate::config::config().jwt {
+        let _ = VERIFIER.set(Verifier::new(cfg)?);
+    }
+
+    Ok(())
+}
+
+/// If JWT authorization is configured.
+pub fn is_enabled() -> bool {
+    VERIFIER.get().is_some()
+}
+
+/// If the string has the three `.` separated segments of a JWT,
+/// as opposed to being an API key.
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+/// Checks the token is valid and grants the scope on the bucket.
+pub fn grants(token: &str, bucket: &str, scope: Scope) -> bool {
+    VERIFIER.get()
+        .and_then(|verifier| verifier.verify(token))
+        .map(|claims| claims.grants(bucket, scope))
+        .unwrap_or(false)
+}
+
+/// The scope a request to the image API requires on its bucket.
+struct RequiredScope<'a> {
+    bucket: &'a str,
+    scope: Scope,
+
+    /// If the endpoint authorizes requests by API key itself.
+    checks_api_keys: bool,
+}
+
+fn required_scope(req: &Request) -> Option<RequiredScope<'_>> {
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    let bucket = segments.first().copied().filter(|bucket| !bucket.is_empty())?;
+
+    let (scope, checks_api_keys) = match (req.method(), &segments[1..]) {
+        (&Method::POST, [] | [""] | ["batch"] | ["import"]) | (&Method::PUT, [_]) => (Scope::Write, true),
+        (&Method::POST, ["sign"] | [_, "copy"]) => (Scope::Read, true),
+        (&Method::DELETE, [_]) | (&Method::POST, ["purge"] | [_, "restore" | "move"]) => (Scope::Delete, true),
+        (&Method::GET, ["uploads", _]) => (Scope::Write, true),
+        (&Method::GET, ["purge", _]) => (Scope::Delete, true),
+        (&Method::GET, [] | [""] | [_, "metadata"]) => (Scope::Read, true),
+        (&Method::GET | &Method::HEAD, _) => (Scope::Read, false),
+        _ => (Scope::Write, false),
+    };
+
+    Some(RequiredScope { bucket, scope, checks_api_keys })
+}
+
+fn rejection(status: StatusCode, detail: &str) -> Response {
+    let body = serde_json::json!({ "detail": detail });
+    Response::builder()
+        .status(status)
+        .content_type("application/json; charset=utf-8")
+        .body(body.to_string())
+}
+
+/// Rejects requests to the image API which are not authorized for the
+/// bucket and operation if JWT authorization is configured.
+///
+/// Requests without a JWT are only let through if they're reads and
+/// `public_read` is enabled, or to be authorized by an API key.
+pub async fn enforce_scopes<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
+    let verifier = match VERIFIER.get() {
+        None => return next.call(req).await.map(IntoResponse::into_response),
+        Some(verifier) => verifier,
+    };
+
+    let required = match required_scope(&req) {
+        // Unknown buckets are left for the API to respond to.
+        Some(required) if get_bucket_by_name(required.bucket).is_some() => required,
+        _ => return next.call(req).await.map(IntoResponse::into_response),
+    };
+
+    match check(verifier, &req, required).await? {
+        Some(rejection) => Ok(rejection),
+        None => next.call(req).await.map(IntoResponse::into_response),
+    }
+}
+
+/// Checks the request is authorized to read from the bucket if JWT
+/// authorization is configured, returning the rejection if not.
+///
+/// This is for routes outside of the image API such as thumbor URLs.
+pub async fn authorize_read(req: &Request, bucket: &str) -> poem::Result<Option<Response>> {
+    match VERIFIER.get() {
+        None => Ok(None),
+        Some(verifier) => check(verifier, req, RequiredScope {
+            bucket,
+            scope: Scope::Read,
+            checks_api_keys: false,
+        }).await,
+    }
+}
+
+async fn check(verifier: &Verifier, req: &Request, required: RequiredScope<'_>) -> poem::Result<Option<Response>> {
+    let token = req.headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| is_jwt(token));
+
+    match token.map(|token| verifier.verify(token)) {
+        Some(None) => return Ok(Some(rejection(StatusCode::UNAUTHORIZED, "The token is invalid or has expired."))),
+        Some(Some(claims)) => if !claims.grants(required.bucket, required.scope) {
+            return Ok(Some(rejection(
+                StatusCode::FORBIDDEN,
+                &format!("The token does not grant the {:?} scope on the bucket {:?}.", required.scope, required.bucket),
+            )))
+        },
+        None => {
+            let public = required.scope == Scope::Read && verifier.public_read;
+            let by_api_key = required.checks_api_keys && has_api_keys(required.bucket).await?;
+            if !(public || by_api_key) {
+                return Ok(Some(rejection(StatusCode::UNAUTHORIZED, "The request requires a token.")))
+            }
+        },
+    }
+
+    Ok(None)
+}
+
+async fn has_api_keys(bucket: &str) -> poem::Result<bool> {
+    match get_bucket_by_name(bucket) {
+        None => Ok(false),
+        Some(bucket) => bucket.has_api_keys().await.map_err(crate::routes::processing_error),
+    }
+}
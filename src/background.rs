@@ -0,0 +1,59 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+
+static TASKS: Lazy<TaskTracker> = Lazy::new(TaskTracker::default);
+
+/// Tracks detached background work, e.g. persisting generated variants,
+/// so it can be waited on during shutdown rather than killed mid-write.
+#[derive(Default)]
+struct TaskTracker {
+    active: AtomicUsize,
+    idle: Notify,
+}
+
+/// Decrements the active task count once the task completes or panics.
+struct ActiveGuard;
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        if TASKS.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            TASKS.idle.notify_waiters();
+        }
+    }
+}
+
+/// Spawns a tracked background task.
+pub fn spawn<F>(fut: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    TASKS.active.fetch_add(1, Ordering::AcqRel);
+
+    tokio::spawn(async move {
+        let _guard = ActiveGuard;
+        fut.await;
+    });
+}
+
+/// The number of background tasks still running.
+pub fn active_tasks() -> usize {
+    TASKS.active.load(Ordering::Acquire)
+}
+
+/// Waits until all tracked background tasks have completed.
+pub async fn wait_until_idle() {
+    loop {
+        // Registered before checking the count so a task completing
+        // in-between is not missed.
+        let notified = TASKS.idle.notified();
+
+        if active_tasks() == 0 {
+            return
+        }
+
+        notified.await;
+    }
+}
@@ -0,0 +1,263 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use clap::Args;
+use futures::StreamExt;
+use poem::http::Method;
+use poem::{Endpoint, Request, Route};
+use poem_openapi::OpenApiService;
+use uuid::Uuid;
+
+use crate::config::ImageKind;
+use crate::controller::{get_bucket_by_name, BucketController, UploadOptions, UploadOutcome};
+
+#[derive(Debug, Args)]
+pub struct BenchConfig {
+    #[clap(long)]
+    /// The file path to the config the buckets are benchmarked with.
+    ///
+    /// Images are written to the configured storage backend, the `memory`
+    /// backend can be used to benchmark processing alone.
+    pub config: PathBuf,
+
+    #[clap(long)]
+    /// The file path to the image uploaded and fetched.
+    pub image: PathBuf,
+
+    #[clap(long, default_value = "16")]
+    /// The number of requests in flight at once.
+    pub concurrency: usize,
+
+    #[clap(long, default_value = "500")]
+    /// The number of uploads made to each bucket, each uploaded
+    /// image is then fetched once.
+    pub requests: usize,
+
+    #[clap(long)]
+    /// Only benchmark the given bucket rather than every configured bucket.
+    pub bucket: Option<String>,
+
+    #[clap(long)]
+    /// Send requests through the HTTP layer rather than
+    /// calling the buckets directly.
+    pub http: bool,
+}
+
+/// The latencies of a single benchmarked operation.
+struct Report {
+    elapsed: Duration,
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+impl Report {
+    fn percentile(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO
+        }
+
+        let index = ((self.latencies.len() - 1) as f64 * percentile).round() as usize;
+        self.latencies[index]
+    }
+
+    fn print(&self, name: &str, bucket: &BucketController, operation: &str) {
+        let throughput = self.latencies.len() as f64 / self.elapsed.as_secs_f64();
+
+        println!(
+            "{:<24} {:<10} {:<8} {:>8.1} req/s  p50 {:>10.2?}  p99 {:>10.2?}  errors {}",
+            name,
+            format!("{:?}", bucket.cfg().mode).to_lowercase(),
+            operation,
+            throughput,
+            self.percentile(0.5),
+            self.percentile(0.99),
+            self.errors,
+        );
+    }
+}
+
+/// Runs the jobs with the given concurrency, timing each of them.
+///
+/// Jobs returning an error are counted but excluded from the latencies.
+async fn measure<T, F, Fut>(count: usize, concurrency: usize, job: F) -> (Report, Vec<T>)
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let start = Instant::now();
+    let results: Vec<_> = futures::stream::iter(0..count)
+        .map(|i| {
+            let fut = job(i);
+            async move {
+                let start = Instant::now();
+                let result = fut.await;
+                (start.elapsed(), result)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    let elapsed = start.elapsed();
+
+    let mut latencies = vec![];
+    let mut outputs = vec![];
+    let mut errors = 0;
+    for (latency, result) in results {
+        match result {
+            Ok(output) => {
+                latencies.push(latency);
+                outputs.push(output);
+            },
+            Err(e) => {
+                debug!("Benchmark request failed: {}", e);
+                errors += 1;
+            },
+        }
+    }
+    latencies.sort();
+
+    (Report { elapsed, latencies, errors }, outputs)
+}
+
+/// Benchmarks the upload and fetch latencies and throughput of each
+/// configured bucket, and therefore each processing mode in use.
+pub async fn run(args: BenchConfig) -> anyhow::Result<()> {
+    crate::config::init(&args.config).await?;
+    crate::init_global_state()?;
+    crate::setup_buckets().await?;
+
+    let image = tokio::fs::read(&args.image).await?;
+    let kind = image::guess_format(&image)
+        .ok()
+        .and_then(ImageKind::from_guessed_format)
+        .ok_or_else(|| anyhow!("The benchmark image is not a supported format."))?;
+
+    let mut buckets: Vec<&String> = crate::config::config()
+        .buckets
+        .keys()
+        .filter(|name| args.bucket.as_ref().map(|bucket| bucket == *name).unwrap_or(true))
+        .collect();
+    buckets.sort();
+
+    if buckets.is_empty() {
+        return Err(anyhow!("There are no buckets to benchmark."))
+    }
+
+    let app = Route::new().nest(
+        "/v1",
+        OpenApiService::new(crate::routes::LustApi, "Lust API", env!("CARGO_PKG_VERSION")),
+    );
+
+    println!(
+        "Benchmarking {} requests per bucket with a concurrency of {} {}",
+        args.requests,
+        args.concurrency,
+        if args.http { "via the HTTP layer" } else { "via the buckets directly" },
+    );
+
+    for name in buckets {
+        let bucket = get_bucket_by_name(name)
+            .ok_or_else(|| anyhow!("Bucket {} was not setup.", name))?;
+
+        let (report, uploaded) = measure(args.requests, args.concurrency, |_| {
+            let data = image.clone();
+            let app = &app;
+            async move {
+                if args.http {
+                    http_upload(app, name, data).await
+                } else {
+                    direct_upload(bucket, kind, data).await
+                }
+            }
+        }).await;
+        report.print(name, bucket, "upload");
+
+        let uploaded: Vec<Uuid> = uploaded.into_iter().flatten().collect();
+        let (report, _) = measure(uploaded.len(), args.concurrency, |i| {
+            let image_id = uploaded[i];
+            let app = &app;
+            async move {
+                if args.http {
+                    http_fetch(app, name, image_id).await
+                } else {
+                    direct_fetch(bucket, image_id).await
+                }
+            }
+        }).await;
+        report.print(name, bucket, "fetch");
+
+        crate::background::wait_until_idle().await;
+        for image_id in uploaded {
+            if let Err(e) = bucket.delete(image_id).await {
+                warn!("Failed to remove benchmark image {}: {}", image_id, e);
+            }
+        }
+    }
+
+    crate::metadata::flush_all().await;
+    crate::background::wait_until_idle().await;
+
+    Ok(())
+}
+
+/// Uploads the image, returning its id if it was processed within
+/// the bucket's `async_upload_threshold`.
+async fn direct_upload(
+    bucket: &BucketController,
+    kind: ImageKind,
+    data: Vec<u8>,
+) -> anyhow::Result<Option<Uuid>> {
+    match bucket.upload(kind, data, UploadOptions::default()).await? {
+        UploadOutcome::Complete(info) => Ok(Some(info.image_id())),
+        UploadOutcome::Pending(_) => Ok(None),
+    }
+}
+
+async fn direct_fetch(bucket: &BucketController, image_id: Uuid) -> anyhow::Result<()> {
+    let kind = bucket.cfg().serving_format(None);
+    bucket.fetch(image_id, kind, None, None, false)
+        .await?
+        .ok_or_else(|| anyhow!("Image {} was not found.", image_id))?;
+
+    Ok(())
+}
+
+async fn http_upload(app: &Route, bucket: &str, data: Vec<u8>) -> anyhow::Result<Option<Uuid>> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri_str(format!("/v1/{}", bucket))
+        .header("content-type", "application/octet-stream")
+        .header("content-length", data.len())
+        .body(data);
+
+    let resp = app.get_response(req).await;
+    if !resp.status().is_success() {
+        return Err(anyhow!("Upload failed with status {}", resp.status()))
+    }
+
+    let body = resp.into_body().into_bytes().await?;
+    let body: serde_json::Value = serde_json::from_slice(&body)?;
+    let image_id = body
+        .get("image_id")
+        .and_then(|v| v.as_str())
+        .map(Uuid::parse_str)
+        .transpose()?;
+
+    Ok(image_id)
+}
+
+async fn http_fetch(app: &Route, bucket: &str, image_id: Uuid) -> anyhow::Result<()> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri_str(format!("/v1/{}/{}", bucket, image_id))
+        .finish();
+
+    let resp = app.get_response(req).await;
+    if !resp.status().is_success() {
+        return Err(anyhow!("Fetch failed with status {}", resp.status()))
+    }
+
+    resp.into_body().into_bytes().await?;
+    Ok(())
+}
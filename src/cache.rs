@@ -1,4 +1,4 @@
-use std::ops::Deref;
+use std::time::{Duration, Instant};
 use anyhow::anyhow;
 use bytes::Bytes;
 use once_cell::sync::OnceCell;
@@ -13,6 +13,9 @@ pub fn new_cache(cfg: CacheConfig) -> anyhow::Result<Option<Cache>> {
         return Ok(None)
     }
 
+    let ttl = cfg.ttl.map(Duration::from_secs);
+    let grace = Duration::from_secs(cfg.stale_if_error.unwrap_or(0));
+
     let mut cache = moka::sync::CacheBuilder::default();
     if let Some(max_items) = cfg.max_images {
         cache = cache.max_capacity(max_items as u64)
@@ -20,11 +23,20 @@ pub fn new_cache(cfg: CacheConfig) -> anyhow::Result<Option<Cache>> {
 
     if let Some(max_memory) = cfg.max_capacity {
         cache = cache
-            .weigher(|k: &String, v: &Bytes| (k.len() + v.len()) as u32)
+            .weigher(|k: &String, v: &CachedImage| (k.len() + v.data.len()) as u32)
             .max_capacity((max_memory * 1024 * 1024) as u64);
     }
 
-    Ok(Some(cache.build().into()))
+    // Expired images are kept for the grace period so they can
+    // still be served if the storage backend fails.
+    if let Some(ttl) = ttl {
+        cache = cache.time_to_live(ttl + grace);
+    }
+
+    Ok(Some(Cache {
+        inner: cache.build(),
+        ttl,
+    }))
 }
 
 pub fn init_cache(cfg: CacheConfig) -> anyhow::Result<()> {
@@ -38,22 +50,41 @@ pub fn global_cache<'a>() -> Option<&'a Cache> {
     GLOBAL_CACHE.get()
 }
 
+#[derive(Clone)]
+struct CachedImage {
+    data: Bytes,
+    inserted_at: Instant,
+}
+
 pub struct Cache {
-    inner: moka::sync::Cache<String, Bytes>,
+    inner: moka::sync::Cache<String, CachedImage>,
+    ttl: Option<Duration>,
 }
 
-impl Deref for Cache {
-    type Target = moka::sync::Cache<String, Bytes>;
+impl Cache {
+    /// Gets the cached image if it hasn't expired.
+    pub fn get(&self, key: &String) -> Option<Bytes> {
+        let entry = self.inner.get(key)?;
+        match self.ttl {
+            Some(ttl) if entry.inserted_at.elapsed() >= ttl => None,
+            _ => Some(entry.data),
+        }
+    }
+
+    /// Gets the cached image even if it has expired, so long as it's still
+    /// within the `stale_if_error` grace period.
+    pub fn get_stale(&self, key: &String) -> Option<Bytes> {
+        self.inner.get(key).map(|entry| entry.data)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    pub fn insert(&self, key: String, data: Bytes) {
+        self.inner.insert(key, CachedImage {
+            data,
+            inserted_at: Instant::now(),
+        })
     }
-}
 
-impl From<moka::sync::Cache<String, Bytes>> for Cache {
-    fn from(v: moka::sync::Cache<String, Bytes>) -> Self {
-        Self {
-            inner: v
-        }
+    pub fn invalidate(&self, key: &String) {
+        self.inner.invalidate(key)
     }
-}
\ No newline at end of file
+}
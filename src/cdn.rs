@@ -0,0 +1,136 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Method, Request};
+use hyper_tls::HttpsConnector;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use uuid::Uuid;
+
+use crate::config::{CdnConfig, CdnProvider};
+
+/// The default base URL of the Fastly API.
+const FASTLY_API_URL: &str = "https://api.fastly.com";
+
+/// How the CDN should treat its cached copies of the image.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PurgeMode {
+    /// The copies are marked stale and revalidated with lust.
+    Soft,
+
+    /// The copies are dropped.
+    Hard,
+}
+
+impl PurgeMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Soft => "soft",
+            Self::Hard => "hard",
+        }
+    }
+}
+
+/// The surrogate key every response of the image is tagged with,
+/// the CDN purges every variant of the image by it.
+pub fn surrogate_key(bucket: &str, image_id: Uuid) -> String {
+    format!("{}/{}", bucket, image_id)
+}
+
+/// Sends the purges of a bucket's replaced and deleted images to its CDN.
+#[derive(Clone)]
+pub struct CdnPurger {
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    bucket: Arc<str>,
+    cfg: Arc<CdnConfig>,
+}
+
+impl CdnPurger {
+    pub fn new(bucket: &str, cfg: &CdnConfig) -> Self {
+        Self {
+            client: hyper::Client::builder().build(HttpsConnector::new()),
+            bucket: bucket.into(),
+            cfg: Arc::new(cfg.clone()),
+        }
+    }
+
+    /// Purges the cached copies of the image in the background.
+    ///
+    /// Soft purges are sent as hard purges if the bucket has disabled
+    /// `soft_purge`. Failed purges are only logged, the CDN still
+    /// expires its copies once their `cache-control` allows.
+    pub fn purge(&self, image_id: Uuid, etag: Option<u32>, mode: PurgeMode) {
+        let mode = if self.cfg.soft_purge { mode } else { PurgeMode::Hard };
+        let purger = self.clone();
+
+        crate::background::spawn(async move {
+            let timeout = Duration::from_secs(purger.cfg.timeout);
+            let result = match tokio::time::timeout(timeout, purger.send(image_id, etag, mode)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("Timed out after {:?}", timeout)),
+            };
+
+            let outcome = match result {
+                Ok(()) => "purged",
+                Err(e) => {
+                    warn!("Failed to {} purge image {} from the CDN: {}", mode.as_str(), image_id, e);
+                    "failed"
+                },
+            };
+
+            crate::metrics::CDN_PURGES
+                .with_label_values(&[&purger.bucket, mode.as_str(), outcome])
+                .inc();
+        });
+    }
+
+    async fn send(&self, image_id: Uuid, etag: Option<u32>, mode: PurgeMode) -> anyhow::Result<()> {
+        let key = surrogate_key(&self.bucket, image_id);
+        let req = match self.cfg.provider {
+            CdnProvider::Fastly => {
+                let url = format!(
+                    "{}/service/{}/purge/{}",
+                    self.cfg.url.as_deref().unwrap_or(FASTLY_API_URL).trim_end_matches('/'),
+                    self.cfg.service_id.as_deref().unwrap_or_default(),
+                    utf8_percent_encode(&key, NON_ALPHANUMERIC),
+                );
+
+                let mut req = Request::builder()
+                    .method(Method::POST)
+                    .uri(url)
+                    .header("fastly-key", self.cfg.api_token.as_deref().unwrap_or_default());
+                if mode == PurgeMode::Soft {
+                    req = req.header("fastly-soft-purge", "1");
+                }
+
+                req.body(Body::empty())?
+            },
+            CdnProvider::Webhook => {
+                let body = serde_json::json!({
+                    "bucket": &*self.bucket,
+                    "image_id": image_id,
+                    "surrogate_key": key,
+                    "etag": etag.map(crate::etags::format_etag),
+                    "soft": mode == PurgeMode::Soft,
+                });
+
+                let mut req = Request::builder()
+                    .method(Method::POST)
+                    .uri(self.cfg.url.as_deref().unwrap_or_default())
+                    .header("content-type", "application/json");
+                if let Some(ref token) = self.cfg.api_token {
+                    req = req.header("authorization", format!("Bearer {}", token));
+                }
+
+                req.body(Body::from(serde_json::to_vec(&body)?))?
+            },
+        };
+
+        let resp = self.client.request(req).await?;
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("The CDN responded with {}", resp.status()))
+        }
+
+        Ok(())
+    }
+}
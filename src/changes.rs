@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::ChangeLogConfig;
+use crate::metadata::MetadataStore;
+
+/// The metadata document the change log is stored in.
+const CHANGES_KEY: &str = "changes";
+
+/// How long in seconds the instance writing the change log keeps its
+/// claim after its last flush, no other instance can write it until then.
+const WRITER_LEASE: i64 = 5 * 60;
+
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[oai(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    /// The image was uploaded, copied into the bucket or restored.
+    Created,
+
+    /// The content of the image was replaced.
+    Replaced,
+
+    /// The image was deleted.
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct ChangeEvent {
+    /// The cursor of the event, increasing with every change to the bucket.
+    pub cursor: u64,
+
+    /// What happened to the image.
+    pub kind: ChangeKind,
+
+    /// The id of the changed image.
+    pub image_id: Uuid,
+
+    /// The unix timestamp the change happened.
+    pub changed_at: i64,
+}
+
+/// The events after a cursor.
+pub enum ChangesSince {
+    /// The events after the cursor, oldest first.
+    Events(Vec<ChangeEvent>),
+
+    /// Events after the cursor have been dropped from the log, or the
+    /// cursor is ahead of the log which has since been reset.
+    Expired,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LogState {
+    /// The cursor of the most recent event.
+    latest: u64,
+
+    events: VecDeque<ChangeEvent>,
+
+    #[serde(default)]
+    /// The id of the instance writing the log.
+    writer: Option<String>,
+
+    #[serde(default)]
+    /// The unix timestamp the log was last written.
+    written_at: i64,
+}
+
+/// A change which has not been appended to the stored log yet.
+struct PendingChange {
+    kind: ChangeKind,
+    image_id: Uuid,
+    changed_at: i64,
+}
+
+/// An append-only log of the images created, replaced and deleted in a
+/// bucket, letting external systems mirror the bucket incrementally.
+///
+/// New events are buffered in memory and flushed alongside the bucket's other
+/// metadata, they're only given a cursor once appended to the stored log so
+/// the log must only be written by a single instance. The first instance to
+/// flush claims the log, other instances fail to flush and keep their events
+/// buffered until the writer hasn't flushed for `WRITER_LEASE`, so writes should
+/// all go through one instance, e.g. a primary replicas forward writes to.
+///
+/// Only the most recent `max_events` events are kept.
+pub struct ChangeLog {
+    max_events: usize,
+    pending: Mutex<Vec<PendingChange>>,
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+impl ChangeLog {
+    pub fn new(cfg: &ChangeLogConfig) -> Self {
+        Self {
+            max_events: cfg.max_events,
+            pending: Mutex::default(),
+            flush_lock: tokio::sync::Mutex::default(),
+        }
+    }
+
+    /// Buffers the change to be appended to the log.
+    pub fn record(&self, kind: ChangeKind, image_id: Uuid) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.push(PendingChange {
+            kind,
+            image_id,
+            changed_at: chrono::Utc::now().timestamp(),
+        });
+    }
+
+    /// The cursor of the most recent event, `0` if nothing has changed yet.
+    pub async fn latest(&self, store: &MetadataStore) -> anyhow::Result<u64> {
+        Ok(self.read(store).await?.latest)
+    }
+
+    /// At most `limit` events after the cursor, oldest first.
+    pub async fn since(&self, store: &MetadataStore, cursor: u64, limit: usize) -> anyhow::Result<ChangesSince> {
+        let state = self.read(store).await?;
+
+        // The event directly after the cursor must still be in the log,
+        // otherwise changes would be silently skipped.
+        let oldest = state.events
+            .front()
+            .map(|event| event.cursor)
+            .unwrap_or(state.latest + 1);
+        if cursor > state.latest || (cursor < state.latest && cursor + 1 < oldest) {
+            return Ok(ChangesSince::Expired)
+        }
+
+        let events = state.events
+            .into_iter()
+            .filter(|event| event.cursor > cursor)
+            .take(limit)
+            .collect();
+
+        Ok(ChangesSince::Events(events))
+    }
+
+    /// Appends the buffered events to the stored log.
+    pub async fn flush(&self, store: &MetadataStore) -> anyhow::Result<()> {
+        let _guard = self.flush_lock.lock().await;
+        self.flush_locked(store).await.map(|_| ())
+    }
+
+    /// Flushes any buffered events and returns the stored log, events
+    /// buffered by an instance not writing the log are left out.
+    async fn read(&self, store: &MetadataStore) -> anyhow::Result<LogState> {
+        let _guard = self.flush_lock.lock().await;
+        match self.flush_locked(store).await {
+            Ok(state) => Ok(state),
+            Err(e) => {
+                warn!("Failed to flush the change log before reading it: {}", e);
+                store.load(CHANGES_KEY).await
+            },
+        }
+    }
+
+    async fn flush_locked(&self, store: &MetadataStore) -> anyhow::Result<LogState> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+
+        let state: LogState = match store.load(CHANGES_KEY).await {
+            Ok(state) => state,
+            Err(e) => {
+                self.restore(pending);
+                return Err(e)
+            },
+        };
+        if pending.is_empty() {
+            return Ok(state)
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let instance_id = crate::metadata::instance_id();
+        if let Some(writer) = state.writer.as_deref() {
+            if writer != instance_id && now - state.written_at < WRITER_LEASE {
+                self.restore(pending);
+                return Err(anyhow!(
+                    "The change log is being written by instance {:?}, changes must all be made through one instance.",
+                    writer,
+                ))
+            }
+        }
+
+        let mut appended = state.latest;
+        let mut events = state.events.clone();
+        for change in pending.iter() {
+            appended += 1;
+            events.push_back(ChangeEvent {
+                cursor: appended,
+                kind: change.kind,
+                image_id: change.image_id,
+                changed_at: change.changed_at,
+            });
+        }
+        while events.len() > self.max_events {
+            events.pop_front();
+        }
+
+        let updated = LogState {
+            latest: appended,
+            events,
+            writer: Some(instance_id.to_string()),
+            written_at: now,
+        };
+        if let Err(e) = store.save(CHANGES_KEY, &updated).await {
+            self.restore(pending);
+            return Err(e)
+        }
+
+        Ok(updated)
+    }
+
+    /// Puts back events which failed to flush ahead of any buffered since.
+    fn restore(&self, mut events: Vec<PendingChange>) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        events.append(&mut pending);
+
+        // Events beyond what the log keeps would be dropped once appended anyway.
+        let excess = events.len().saturating_sub(self.max_events);
+        events.drain(..excess);
+        *pending = events;
+    }
+}
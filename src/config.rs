@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use anyhow::{anyhow, Result};
 use image::ImageFormat;
 use image::imageops::FilterType;
@@ -8,7 +8,7 @@ use serde::Deserialize;
 use poem_openapi::Enum;
 use crate::pipelines::ProcessingMode;
 
-use crate::storage::backends::BackendConfigs;
+use crate::storage::backends::{BackendConfigs, FreeSpaceWatermark, S3Timeouts};
 
 static CONFIG: OnceCell<RuntimeConfig> = OnceCell::new();
 
@@ -24,6 +24,15 @@ pub fn init_test(data: &str) -> Result<()> {
     Ok(())
 }
 
+/// Validates and sets the config from one built programmatically.
+///
+/// The config can only be set once per process.
+#[cfg(any(test, feature = "testing"))]
+pub fn init_from(cfg: RuntimeConfig) -> Result<()> {
+    validate(&cfg)?;
+    CONFIG.set(cfg).map_err(|_| anyhow!("The config has already been initialised."))
+}
+
 pub async fn init(config_file: &Path) -> Result<()> {
     let file = tokio::fs::read(config_file).await?;
 
@@ -45,8 +54,265 @@ pub async fn init(config_file: &Path) -> Result<()> {
 }
 
 
+/// The response headers set by lust itself, these can't be
+/// set by a bucket's `response_headers`.
+const RESERVED_RESPONSE_HEADERS: &[&str] = &[
+    "content-type",
+    "content-length",
+    "content-encoding",
+    "transfer-encoding",
+];
+
+/// The sizing id of a custom resize given the sizing ids of the bucket's presets.
+///
+/// Errors if the id collides with a preset or the original image, see
+/// [`BucketConfig::custom_sizing_id`].
+pub fn resolve_custom_sizing_id(
+    mut preset_ids: impl Iterator<Item = u32>,
+    width: u32,
+    height: u32,
+) -> Result<u32> {
+    let sizing_id = crate::utils::crc_hash((width, height));
+
+    if sizing_id == 0 || preset_ids.any(|preset_id| preset_id == sizing_id) {
+        return Err(anyhow!(
+            "The custom size {}x{} collides with the sizing id of an existing preset.",
+            width, height,
+        ))
+    }
+
+    Ok(sizing_id)
+}
+
 fn validate(cfg: &RuntimeConfig) -> Result<()> {
+    if cfg.permit_weights.upload == 0 || cfg.permit_weights.fetch == 0 {
+        return Err(anyhow!("Permit weights must be at least 1."))
+    }
+
+    if cfg.max_low_priority_concurrency == Some(0) {
+        return Err(anyhow!("The max low priority concurrency must be at least 1."))
+    }
+
+    if cfg.max_processing_memory == Some(0) {
+        return Err(anyhow!("The max processing memory must be at least 1MB."))
+    }
+
+    if cfg.decode_limits.map(|limits| limits.has_zero()).unwrap_or(false) {
+        return Err(anyhow!("The decode limits must be greater than 0."))
+    }
+
+    if let Some(pool) = cfg.processing_pool {
+        if pool.threads == 0 {
+            return Err(anyhow!("The processing pool must have at least 1 thread."))
+        }
+
+        if !(1..=19).contains(&pool.niceness) {
+            return Err(anyhow!("The processing pool niceness must be between 1 and 19."))
+        }
+    }
+
+    if cfg.permit_weights.upload_size_step == Some(0) {
+        return Err(anyhow!("The upload size step must be at least 1KB."))
+    }
+
+    if cfg.circuit_breaker.failure_threshold == 0 {
+        return Err(anyhow!("The circuit breaker failure threshold must be at least 1."))
+    }
+
+    if cfg.api_keys.iter().any(|key| key.is_empty()) {
+        return Err(anyhow!("API keys must not be empty."))
+    }
+
+    if let Some(ref jwt) = cfg.jwt {
+        if jwt.keys.is_empty() {
+            return Err(anyhow!("JWT authorization requires at least one key."))
+        }
+
+        if jwt.keys.iter().any(|key| key.key.is_empty()) {
+            return Err(anyhow!("JWT keys must not be empty."))
+        }
+    }
+
+    if cfg.storage_retry.base_delay > cfg.storage_retry.max_delay {
+        return Err(anyhow!("The storage retry base delay must not exceed the max delay."))
+    }
+
+    if let BackendConfigs::Scylla { ref session, .. } = cfg.backend {
+        if session.connections_per_shard == Some(0) {
+            return Err(anyhow!("The Scylla connections per shard must be at least 1."))
+        }
+
+        if session.request_timeout == Some(0) {
+            return Err(anyhow!("The Scylla request timeout must be at least 1ms."))
+        }
+    }
+
+    if let BackendConfigs::FileSystem { free_space_watermark: Some(watermark), .. } = cfg.backend {
+        let FreeSpaceWatermark { min_free_space, check_interval } = watermark;
+        if min_free_space == 0 {
+            return Err(anyhow!("The filesystem free space watermark must be at least 1MB."))
+        }
+
+        if check_interval == 0 {
+            return Err(anyhow!("The filesystem free space check interval must be at least 1 second."))
+        }
+    }
+
+    if let BackendConfigs::BlobStorage { ref timeouts, max_in_flight, .. } = cfg.backend {
+        if max_in_flight == Some(0) {
+            return Err(anyhow!("The blob storage max in flight requests must be at least 1."))
+        }
+
+        let S3Timeouts { read, write, delete, list } = timeouts;
+        if [read, write, delete, list].contains(&&Some(0)) {
+            return Err(anyhow!("The blob storage timeouts must be at least 1ms."))
+        }
+    }
+
+    if let Some(ref replica) = cfg.replica {
+        let primary = replica.primary_url
+            .parse::<poem::http::Uri>()
+            .map_err(|e| anyhow!("The replica primary URL is invalid: {}", e))?;
+
+        if !matches!(primary.scheme_str(), Some("http") | Some("https")) || primary.host().is_none() {
+            return Err(anyhow!("The replica primary URL must be an absolute http or https URL."))
+        }
+
+        if cfg.replication.is_some() {
+            return Err(anyhow!("A replica can't replicate changes, replication must be configured on the primary."))
+        }
+    }
+
+    if let Some(ref remote_uploads) = cfg.remote_uploads {
+        if remote_uploads.allowed_hosts.is_empty() {
+            return Err(anyhow!("Remote uploads must allow at least one host."))
+        }
+
+        if remote_uploads.allowed_hosts.iter().any(|host| host.trim_start_matches("*.").is_empty()) {
+            return Err(anyhow!("Remote upload allowed hosts must not be empty."))
+        }
+
+        if remote_uploads.timeout == 0 {
+            return Err(anyhow!("The remote upload timeout must be at least 1 second."))
+        }
+    }
+
+    if let Some(ref import) = cfg.import {
+        if cfg.replica.is_some() {
+            return Err(anyhow!("A replica can't import objects, imports must be configured on the primary."))
+        }
+
+        if import.concurrency == 0 {
+            return Err(anyhow!("The import concurrency must be at least 1."))
+        }
+
+        if import.wait_time > 20 {
+            return Err(anyhow!("The import wait time must not exceed 20 seconds."))
+        }
+
+        let queue_url = import.queue_url
+            .parse::<poem::http::Uri>()
+            .map_err(|e| anyhow!("The import queue URL is invalid: {}", e))?;
+
+        if !matches!(queue_url.scheme_str(), Some("http") | Some("https")) || queue_url.host().is_none() {
+            return Err(anyhow!("The import queue URL must be an absolute http or https URL."))
+        }
+
+        for source in import.sources.iter() {
+            if !cfg.buckets.contains_key(&source.bucket) {
+                return Err(anyhow!(
+                    "The import of s3://{}/{} targets bucket {} which does not exist.",
+                    source.s3_bucket, source.prefix, source.bucket,
+                ))
+            }
+        }
+    }
+
+    if let Some(ref watch) = cfg.watch {
+        if cfg.replica.is_some() {
+            return Err(anyhow!("A replica can't watch a directory, watching must be configured on the primary."))
+        }
+
+        if watch.concurrency == 0 {
+            return Err(anyhow!("The watch concurrency must be at least 1."))
+        }
+
+        if watch.processed_directory() == watch.directory || watch.failed_directory() == watch.directory {
+            return Err(anyhow!("The processed and failed directories must differ from the watched directory."))
+        }
+
+        if !cfg.buckets.contains_key(&watch.bucket) {
+            return Err(anyhow!(
+                "The watch of {} targets bucket {} which does not exist.",
+                watch.directory.display(), watch.bucket,
+            ))
+        }
+    }
+
+    if let Some(ref thumbor) = cfg.thumbor {
+        if !thumbor.base_path.is_empty() && (!thumbor.base_path.starts_with('/') || thumbor.base_path.ends_with('/')) {
+            return Err(anyhow!("The thumbor base path must start with '/' and not end with '/'."))
+        }
+
+        if thumbor.security_key.is_none() && !thumbor.allow_unsafe {
+            return Err(anyhow!("The thumbor URLs must either be signed with a security key or allow unsafe URLs."))
+        }
+
+        if let Some(ref bucket) = thumbor.bucket {
+            if !cfg.buckets.contains_key(bucket) {
+                return Err(anyhow!("The thumbor bucket {} does not exist.", bucket))
+            }
+        }
+    }
+
+    if let Some(ref replication) = cfg.replication {
+        if replication.concurrency == 0 {
+            return Err(anyhow!("The replication concurrency must be at least 1."))
+        }
+
+        if replication.max_pending < replication.concurrency {
+            return Err(anyhow!("The replication max pending changes must be at least the concurrency."))
+        }
+    }
+
+    let mut bucket_ids: HashMap<u32, &String> = HashMap::new();
+    for name in cfg.buckets.keys() {
+        // Id `0` is reserved for validating the storage backend.
+        if crate::utils::crc_hash(name) == 0 {
+            return Err(anyhow!("Bucket {} is invalid: The bucket's id collides with a reserved id, the bucket must be renamed.", name))
+        }
+
+        if let Some(other) = bucket_ids.insert(crate::utils::crc_hash(name), name) {
+            return Err(anyhow!(
+                "Buckets {} and {} have colliding ids, one of the buckets must be renamed.",
+                other, name,
+            ))
+        }
+    }
+
     for (name, cfg) in cfg.buckets.iter() {
+        if cfg.api_keys.iter().any(|key| key.is_empty()) {
+            return Err(anyhow!("Bucket {} is invalid: API keys must not be empty.", name))
+        }
+
+        let mut sizing_ids: HashMap<u32, &String> = HashMap::new();
+        for preset in cfg.presets.keys() {
+            let sizing_id = crate::utils::crc_hash(preset);
+            if sizing_id == 0 {
+                return Err(anyhow!(
+                    "Bucket {} is invalid: The preset {} collides with the `original` sizing id, the preset must be renamed.",
+                    name, preset,
+                ))
+            }
+
+            if let Some(other) = sizing_ids.insert(sizing_id, preset) {
+                return Err(anyhow!(
+                    "Bucket {} is invalid: The presets {} and {} have colliding sizing ids, one of the presets must be renamed.",
+                    name, other, preset,
+                ))
+            }
+        }
+
         if !cfg.formats.png
             && !cfg.formats.jpeg
             && !cfg.formats.gif
@@ -67,9 +333,238 @@ fn validate(cfg: &RuntimeConfig) -> Result<()> {
             }
         }
 
+        if let Some(cap) = cfg.max_resolution {
+            if cap.width == 0 || cap.height == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The max resolution must be at least 1px.", name))
+            }
+        }
+
+        if cfg.decode_limits.map(|limits| limits.has_zero()).unwrap_or(false) {
+            return Err(anyhow!("Bucket {} is invalid: The decode limits must be greater than 0.", name))
+        }
+
+        if let Some(limits) = cfg.animation_limits {
+            if limits.max_frames.is_none() && limits.max_duration.is_none() && limits.max_decoded_size.is_none() {
+                return Err(anyhow!("Bucket {} is invalid: The animation limits must set at least one limit.", name))
+            }
+
+            if limits.max_frames == Some(0) || limits.max_duration == Some(0) || limits.max_decoded_size == Some(0) {
+                return Err(anyhow!("Bucket {} is invalid: The animation limits must be greater than 0.", name))
+            }
+        }
+
+        if !cfg.jit.persist_variants && cfg.mode != ProcessingMode::Jit {
+            return Err(anyhow!("Bucket {} is invalid: `persist_variants` only applies to jit buckets.", name))
+        }
+
+        for (preset, resizing) in cfg.presets.iter() {
+            if let Some(default_format) = resizing.default_serving_format {
+                if !cfg.formats.is_enabled(default_format) {
+                    return Err(anyhow!(
+                        "Bucket {} is invalid: The default serving format of preset {} is not an enabled encoding format.",
+                        name, preset,
+                    ))
+                }
+            }
+        }
+
+        for (preset, resizing) in cfg.presets.iter() {
+            if resizing.max_size.jpeg == Some(0) || resizing.max_size.webp == Some(0) {
+                return Err(anyhow!("Bucket {} is invalid: The max size of preset {} must be at least 1KB.", name, preset))
+            }
+        }
+
         if cfg.presets.keys().any(|v| v == "original") {
             return Err(anyhow!("Bucket {} is invalid: The `original` preset name is reserved.", name))
         }
+
+        if let Some(ref formats) = cfg.original.formats {
+            if formats.is_empty() || formats.iter().any(|kind| !cfg.formats.is_enabled(*kind)) {
+                return Err(anyhow!("Bucket {} is invalid: The original formats must be a non-empty set of enabled formats.", name))
+            }
+        }
+
+        if !cfg.original.store && cfg.mode != ProcessingMode::Aot {
+            return Err(anyhow!("Bucket {} is invalid: Only aot buckets can disable storing the original.", name))
+        }
+
+        if let Some(ref alpha) = cfg.alpha {
+            if let Err(e) = crate::utils::parse_colour(&alpha.background) {
+                return Err(anyhow!("Bucket {} is invalid: The alpha background is invalid: {}", name, e))
+            }
+        }
+
+        if let Some(ref missing) = cfg.missing_image {
+            if missing.path.is_some() == missing.colour.is_some() {
+                return Err(anyhow!("Bucket {} is invalid: The missing image must have *either* a path or a colour.", name))
+            }
+
+            if missing.width == 0 || missing.height == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The missing image dimensions must be at least 1px.", name))
+            }
+        }
+
+        if let Some(ref limit) = cfg.egress_limit {
+            if limit.max_monthly_egress == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The max monthly egress must be at least 1MB.", name))
+            }
+        }
+
+        if let Some(stats) = cfg.access_stats {
+            if stats.flush_interval == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The access stats flush interval must be at least 1 second.", name))
+            }
+        }
+
+        if let Some(index) = cfg.index {
+            if index.flush_interval == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The index flush interval must be at least 1 second.", name))
+            }
+
+            if index.duplicates.map(|v| v.max_distance > 64).unwrap_or(false) {
+                return Err(anyhow!("Bucket {} is invalid: The duplicates max distance must be at most 64.", name))
+            }
+        }
+
+        if let Some(ref lifecycle) = cfg.lifecycle {
+            if lifecycle.check_interval == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The lifecycle check interval must be at least 1 second.", name))
+            }
+
+            for rule in lifecycle.rules.iter() {
+                if rule.not_accessed_for.is_none() && rule.older_than.is_none() {
+                    return Err(anyhow!("Bucket {} is invalid: Lifecycle rules must set `not_accessed_for` and/or `older_than`.", name))
+                }
+
+                if rule.not_accessed_for == Some(0) || rule.older_than == Some(0) {
+                    return Err(anyhow!("Bucket {} is invalid: Lifecycle rules must apply to images at least 1 day old.", name))
+                }
+            }
+
+            if lifecycle.max_count == Some(0) {
+                return Err(anyhow!("Bucket {} is invalid: The lifecycle max count must be at least 1.", name))
+            }
+
+            let drops_variants = lifecycle.rules
+                .iter()
+                .any(|r| r.action == LifecycleAction::DropVariants);
+            if drops_variants && cfg.mode == ProcessingMode::Realtime {
+                return Err(anyhow!("Bucket {} is invalid: Realtime buckets only store the original so cannot drop variants.", name))
+            }
+        }
+
+        if let Some(ref soft_delete) = cfg.soft_delete {
+            if soft_delete.reap_interval == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The soft delete reap interval must be at least 1 second.", name))
+            }
+        }
+
+        if let Some(ref quarantine) = cfg.quarantine {
+            if quarantine.max_size == 0 || quarantine.ttl == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The quarantine max size and ttl must be greater than 0.", name))
+            }
+        }
+
+        if let Some(ref cdn) = cfg.cdn {
+            if let Some(ref url) = cdn.url {
+                let uri = url
+                    .parse::<poem::http::Uri>()
+                    .map_err(|e| anyhow!("Bucket {} is invalid: The CDN URL is invalid: {}", name, e))?;
+
+                if !matches!(uri.scheme_str(), Some("http") | Some("https")) || uri.host().is_none() {
+                    return Err(anyhow!("Bucket {} is invalid: The CDN URL must be an absolute http or https URL.", name))
+                }
+            }
+
+            match cdn.provider {
+                CdnProvider::Fastly if cdn.service_id.is_none() || cdn.api_token.is_none() => {
+                    return Err(anyhow!("Bucket {} is invalid: Fastly purges require a service_id and api_token.", name))
+                },
+                CdnProvider::Webhook if cdn.url.is_none() => {
+                    return Err(anyhow!("Bucket {} is invalid: Webhook purges require a url.", name))
+                },
+                _ => {},
+            }
+
+            if cdn.timeout == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The CDN timeout must be greater than 0.", name))
+            }
+        }
+
+        if let Some(changes) = cfg.change_log {
+            if changes.flush_interval == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The change log flush interval must be at least 1 second.", name))
+            }
+
+            if changes.max_events == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The change log max events must be greater than 0.", name))
+            }
+        }
+
+        if let Some(lookups) = cfg.lookup_cache {
+            if lookups.ttl == 0 || lookups.max_entries == 0 {
+                return Err(anyhow!("Bucket {} is invalid: The lookup cache ttl and max entries must be greater than 0.", name))
+            }
+        }
+
+        if let Some(compression) = cfg.original_compression {
+            if !(1..=22).contains(&compression.level) {
+                return Err(anyhow!("Bucket {} is invalid: The original compression level must be between 1 and 22.", name))
+            }
+        }
+
+        if let Some(ref precompression) = cfg.precompression {
+            if !(1..=22).contains(&precompression.level) {
+                return Err(anyhow!("Bucket {} is invalid: The precompression level must be between 1 and 22.", name))
+            }
+
+            if precompression.formats.is_empty() {
+                return Err(anyhow!("Bucket {} is invalid: Precompression must apply to at least one format.", name))
+            }
+        }
+
+        if let IdScheme::Snowflake { worker_id } = cfg.id_scheme {
+            if worker_id > crate::ids::MAX_WORKER_ID {
+                return Err(anyhow!("Bucket {} is invalid: The snowflake worker id must be at most {}.", name, crate::ids::MAX_WORKER_ID))
+            }
+        }
+
+        if let Some(pattern) = cfg.custom_ids.as_ref().and_then(|v| v.slug_pattern.as_deref()) {
+            if let Err(e) = regex::Regex::new(pattern) {
+                return Err(anyhow!("Bucket {} is invalid: The custom id slug pattern is invalid: {}", name, e))
+            }
+        }
+
+        if let Some(ref transforms) = cfg.path_transforms {
+            if transforms.signing_key.as_deref() == Some("") {
+                return Err(anyhow!("Bucket {} is invalid: The path transforms signing key must not be empty.", name))
+            }
+
+            for transform in transforms.allowed.iter() {
+                crate::transforms::PathTransform::parse(transform)
+                    .and_then(|t| t.validate(cfg))
+                    .map_err(|e| anyhow!("Bucket {} is invalid: The allowed path transform {:?} is invalid: {}", name, transform, e))?;
+            }
+        }
+
+        if let Some(ref signing) = cfg.url_signing {
+            if signing.key.is_empty() {
+                return Err(anyhow!("Bucket {} is invalid: The URL signing key must not be empty.", name))
+            }
+        }
+
+        for (header, value) in cfg.response_headers.iter() {
+            let header_name = poem::http::header::HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| anyhow!("Bucket {} is invalid: {:?} is not a valid header name.", name, header))?;
+
+            if RESERVED_RESPONSE_HEADERS.contains(&header_name.as_str()) {
+                return Err(anyhow!("Bucket {} is invalid: The {} response header is set by lust and cannot be overridden.", name, header))
+            }
+
+            if poem::http::HeaderValue::from_str(value).is_err() {
+                return Err(anyhow!("Bucket {} is invalid: The value of the {} response header is not a valid header value.", name, header))
+            }
+        }
     }
 
     Ok(())
@@ -108,6 +603,130 @@ pub struct RuntimeConfig {
     ///
     /// This takes precedence over bucket level limits.
     pub max_concurrency: Option<usize>,
+
+    /// The global max concurrency for uploads and deletes.
+    ///
+    /// Overrides `max_concurrency` for uploads so they can be limited
+    /// independently of fetches.
+    pub max_upload_concurrency: Option<usize>,
+
+    /// The global max concurrency for fetches.
+    ///
+    /// Overrides `max_concurrency` for fetches so they can be limited
+    /// independently of uploads.
+    pub max_fetch_concurrency: Option<usize>,
+
+    #[serde(default)]
+    /// The number of concurrency permits each operation consumes.
+    ///
+    /// This allows more expensive operations to take up a larger share
+    /// of the concurrency limits.
+    pub permit_weights: PermitWeights,
+
+    /// The approximate amount of memory in MB images being processed
+    /// can use at once.
+    ///
+    /// Work exceeding the budget is queued until enough memory is freed.
+    /// If `None` no budget is applied.
+    pub max_processing_memory: Option<usize>,
+
+    /// The limits enforced by the image decoders on every image decoded.
+    ///
+    /// Images exceeding them are rejected by the decoder before their
+    /// pixels are allocated, buckets can set stricter limits of their own.
+    /// If `None` only the decoders' default allocation limit of 512MB applies.
+    pub decode_limits: Option<DecodeLimits>,
+
+    /// The maximum number of low priority operations, requested with an
+    /// authorized `X-Lust-Priority: low` header, ran at once across all buckets.
+    ///
+    /// Low priority operations also consume the regular concurrency permits,
+    /// so this caps the share of the limits background work can take.
+    /// If `None` low priority operations are not limited separately.
+    pub max_low_priority_concurrency: Option<usize>,
+
+    /// Runs image processing on a fixed number of threads at a below
+    /// normal OS priority, so processing never starves the threads serving
+    /// cheap cached traffic on small machines.
+    ///
+    /// If `None` processing runs at the normal priority on one
+    /// thread per CPU.
+    pub processing_pool: Option<ProcessingPoolConfig>,
+
+    #[serde(default)]
+    /// The addresses or CIDR ranges of trusted reverse proxies.
+    ///
+    /// The `Forwarded` and `X-Forwarded-For` headers are only honoured
+    /// for requests coming from these addresses when resolving the client IP.
+    pub trusted_proxies: Vec<crate::proxy::IpRange>,
+
+    #[serde(default)]
+    /// The API keys authorizing uploads and deletes on every bucket,
+    /// given as the `Authorization: Bearer <key>` header.
+    ///
+    /// If no keys are configured globally or for a bucket, uploads
+    /// and deletes on the bucket don't need to be authorized.
+    pub api_keys: Vec<String>,
+
+    /// Authorizes requests to the image API with JWTs granting
+    /// scopes per bucket.
+    ///
+    /// If `None` only API keys are checked.
+    pub jwt: Option<JwtConfig>,
+
+    #[serde(default)]
+    /// Enables the admin profiling endpoints, sampling CPU flamegraphs
+    /// and reporting the heap statistics.
+    ///
+    /// Defaults to `false`.
+    pub enable_profiling: bool,
+
+    #[serde(default)]
+    /// How operations the storage backend rejects due to throttling
+    /// or overload are retried.
+    pub storage_retry: StorageRetryConfig,
+
+    #[serde(default)]
+    /// When to stop calling a failing storage backend.
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// Mirrors every object written to the storage backend to a
+    /// secondary backend, e.g. in another region.
+    ///
+    /// If `None` nothing is replicated.
+    pub replication: Option<ReplicationConfig>,
+
+    /// Runs this instance as a read replica of a primary Lust instance.
+    ///
+    /// Replicas serve fetches from the storage backend and cache they share
+    /// with the primary, but forward uploads, deletes and purges to it.
+    ///
+    /// If `None` this instance serves every request itself.
+    pub replica: Option<ReplicaConfig>,
+
+    /// Imports objects dropped directly into watched S3 prefixes, as
+    /// announced by S3 event notifications delivered to an SQS queue.
+    ///
+    /// If `None` nothing is imported.
+    pub import: Option<ImportConfig>,
+
+    /// Imports files dropped into a watched directory, moving them
+    /// into a processed or failed directory once handled.
+    ///
+    /// If `None` no directory is watched.
+    pub watch: Option<WatchConfig>,
+
+    /// Allows images to be uploaded from a remote URL via `POST /:bucket/import`,
+    /// downloading them from the allowed hosts.
+    ///
+    /// If `None` uploads from remote URLs are rejected.
+    pub remote_uploads: Option<RemoteUploadConfig>,
+
+    /// Serves images via thumbor style URLs, e.g.
+    /// `/unsafe/fit-in/300x200/filters:format(webp)/user-profiles/:image_id`.
+    ///
+    /// If `None` only Lust's own URLs are served.
+    pub thumbor: Option<ThumborConfig>,
 }
 
 impl RuntimeConfig {
@@ -121,10 +740,313 @@ impl RuntimeConfig {
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
-pub struct CacheConfig {
-    /// The maximum amount of images to cache.
+pub struct ProcessingPoolConfig {
+    /// The number of threads images are encoded and resized on.
+    pub threads: usize,
+
+    #[serde(default = "default_processing_niceness")]
+    /// The niceness of the processing threads from 1 (highest) to 19 (lowest).
     ///
-    /// If set to `None` then this will fall back to capacity
+    /// This is only applied on Linux. Defaults to `10`.
+    pub niceness: i32,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct PermitWeights {
+    #[serde(default = "default_permit_weight")]
+    /// The permits consumed by each upload or delete.
+    ///
+    /// Defaults to `1`.
+    pub upload: u32,
+
+    #[serde(default = "default_permit_weight")]
+    /// The permits consumed by each fetch.
+    ///
+    /// Defaults to `1`.
+    pub fetch: u32,
+
+    /// The size in KB of upload data consuming one additional permit,
+    /// so a single huge upload can't monopolize the pipeline.
+    ///
+    /// If `None` every upload consumes the same number of permits.
+    pub upload_size_step: Option<usize>,
+}
+
+impl Default for PermitWeights {
+    fn default() -> Self {
+        Self {
+            upload: default_permit_weight(),
+            fetch: default_permit_weight(),
+            upload_size_step: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct StorageRetryConfig {
+    #[serde(default = "default_storage_max_retries")]
+    /// The number of times a throttled operation is retried before
+    /// the request is failed with a `503` status.
+    ///
+    /// Defaults to `3`.
+    pub max_retries: u32,
+
+    #[serde(default = "default_storage_base_delay")]
+    /// The delay in milliseconds before the first retry, doubling
+    /// with each subsequent retry.
+    ///
+    /// Each delay is randomly jittered between `0` and the delay.
+    ///
+    /// Defaults to `50`.
+    pub base_delay: u64,
+
+    #[serde(default = "default_storage_max_delay")]
+    /// The maximum delay in milliseconds between retries.
+    ///
+    /// Defaults to `2000`.
+    pub max_delay: u64,
+}
+
+impl Default for StorageRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: default_storage_max_retries(),
+            base_delay: default_storage_base_delay(),
+            max_delay: default_storage_max_delay(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplicationConfig {
+    /// The storage backend objects are mirrored to.
+    ///
+    /// Pointing this at the backend of a secondary Lust deployment
+    /// lets that deployment serve the replicated images.
+    pub target: BackendConfigs,
+
+    #[serde(default = "default_replication_concurrency")]
+    /// The number of objects replicated at once.
+    ///
+    /// Changes to the same image are always replicated in order.
+    ///
+    /// Defaults to `4`.
+    pub concurrency: usize,
+
+    #[serde(default = "default_replication_max_pending")]
+    /// The maximum number of changes waiting to be replicated.
+    ///
+    /// Changes made while the queue is full are dropped and must be
+    /// replicated by the `catch-up` command instead.
+    ///
+    /// Defaults to `100000`.
+    pub max_pending: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplicaConfig {
+    /// The base URL of the primary instance, e.g. `http://lust-primary:8000`.
+    ///
+    /// Forwarded requests keep their path, so the primary must serve
+    /// the API with the same `base_serving_path`.
+    pub primary_url: String,
+
+    #[serde(default = "default_replica_timeout")]
+    /// The time in seconds to wait for the primary to respond to
+    /// a forwarded request.
+    ///
+    /// Defaults to `60`.
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoteUploadConfig {
+    /// The hosts images can be downloaded from, e.g. `cms.example.com`.
+    ///
+    /// A leading `*.` allows every subdomain of the host, e.g. `*.example.com`.
+    pub allowed_hosts: Vec<String>,
+
+    #[serde(default = "default_remote_upload_timeout")]
+    /// The time in seconds to wait for the image to be downloaded.
+    ///
+    /// Defaults to `30`.
+    pub timeout: u64,
+}
+
+impl RemoteUploadConfig {
+    /// If images can be downloaded from the host.
+    pub fn is_allowed(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            match allowed.strip_prefix("*.") {
+                Some(domain) => host.strip_suffix(domain).map(|sub| sub.ends_with('.')).unwrap_or(false),
+                None => host == allowed,
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportConfig {
+    /// The URL of the SQS queue the S3 event notifications are delivered to,
+    /// either directly or through an SNS topic.
+    pub queue_url: String,
+
+    /// The region of the queue and the S3 buckets.
+    pub region: String,
+
+    /// The endpoint of the S3 service the objects are read from.
+    pub endpoint: String,
+
+    /// The watched prefixes and the buckets their objects are imported into.
+    pub sources: Vec<ImportSource>,
+
+    #[serde(default = "default_import_concurrency")]
+    /// The number of objects imported at once.
+    ///
+    /// Defaults to `4`.
+    pub concurrency: usize,
+
+    #[serde(default = "default_import_wait_time")]
+    /// The time in seconds to wait for notifications when polling the queue.
+    ///
+    /// Defaults to `20`, the maximum SQS allows.
+    pub wait_time: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportSource {
+    /// The name of the S3 bucket the objects are dropped into.
+    pub s3_bucket: String,
+
+    #[serde(default)]
+    /// The key prefix of the watched objects, e.g. `partner-a/`.
+    ///
+    /// Defaults to every object in the S3 bucket.
+    pub prefix: String,
+
+    /// The bucket the objects are imported into.
+    pub bucket: String,
+
+    #[serde(default)]
+    /// The tags to index the imported images under.
+    pub tags: Vec<String>,
+
+    #[serde(default)]
+    /// Deletes the object from the watched prefix once imported.
+    ///
+    /// Defaults to `false`.
+    pub delete_imported: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WatchConfig {
+    /// The directory files are dropped into.
+    pub directory: PathBuf,
+
+    /// The bucket the files are imported into.
+    pub bucket: String,
+
+    /// The directory imported files are moved into.
+    ///
+    /// Defaults to `processed` within the watched directory.
+    pub processed_directory: Option<PathBuf>,
+
+    /// The directory files which failed to import are moved into,
+    /// alongside a `.error` file describing the failure.
+    ///
+    /// Defaults to `failed` within the watched directory.
+    pub failed_directory: Option<PathBuf>,
+
+    #[serde(default)]
+    /// The tags to index the imported images under.
+    pub tags: Vec<String>,
+
+    #[serde(default = "default_watch_settle_time")]
+    /// The time in seconds a file must go unmodified for
+    /// before it's considered complete and imported.
+    ///
+    /// Defaults to `2`.
+    pub settle_time: u64,
+
+    #[serde(default = "default_watch_concurrency")]
+    /// The number of files imported at once.
+    ///
+    /// Defaults to `2`.
+    pub concurrency: usize,
+}
+
+impl WatchConfig {
+    pub fn processed_directory(&self) -> PathBuf {
+        self.processed_directory
+            .clone()
+            .unwrap_or_else(|| self.directory.join("processed"))
+    }
+
+    pub fn failed_directory(&self) -> PathBuf {
+        self.failed_directory
+            .clone()
+            .unwrap_or_else(|| self.directory.join("failed"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThumborConfig {
+    #[serde(default)]
+    /// The path the thumbor URLs are served under.
+    ///
+    /// Defaults to the root, matching the URLs of the thumbor deployment.
+    pub base_path: String,
+
+    /// The thumbor security key signed URLs are verified with.
+    pub security_key: Option<String>,
+
+    #[serde(default)]
+    /// Serves `/unsafe/` URLs which aren't signed.
+    ///
+    /// Defaults to `false`.
+    pub allow_unsafe: bool,
+
+    /// The bucket images are served from when the image path is only
+    /// the image id rather than `{bucket}/{image_id}`.
+    pub bucket: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(default = "default_breaker_threshold")]
+    /// The number of consecutive storage operations which can fail, once any
+    /// retries are exhausted, before the circuit breaker opens.
+    ///
+    /// While open, operations fail immediately without calling the backend
+    /// and only images held in the cache are served.
+    ///
+    /// Defaults to `10`.
+    pub failure_threshold: u32,
+
+    #[serde(default = "default_breaker_cooldown")]
+    /// The time in seconds the circuit breaker stays open before a single
+    /// operation is let through to probe if the backend has recovered.
+    ///
+    /// Defaults to `10`.
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: default_breaker_threshold(),
+            cooldown: default_breaker_cooldown(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct CacheConfig {
+    /// The maximum amount of images to cache.
+    ///
+    /// If set to `None` then this will fall back to capacity
     /// based caching.
     ///
     /// If both entries are `None` then the item is not cached.
@@ -137,6 +1059,19 @@ pub struct CacheConfig {
     ///
     /// If both entries are `None` then the item is not cached.
     pub max_capacity: Option<u32>,
+
+    /// The time in seconds images are cached for before they
+    /// must be fetched from the storage backend again.
+    ///
+    /// If `None` images are cached until they're evicted.
+    pub ttl: Option<u64>,
+
+    /// The time in seconds after an image expires that it can still be
+    /// served from the cache if fetching it from the storage backend fails.
+    ///
+    /// Stale images are served with a `Warning: 110` header.
+    /// If `None` expired images are never served.
+    pub stale_if_error: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -150,123 +1085,919 @@ pub struct BucketConfig {
     /// The given image format optimisation config.
     pub formats: ImageFormats,
 
-    /// The default format to serve images as.
+    /// The default format to serve images as.
+    ///
+    /// Defaults to the first enabled encoding format.
+    pub default_serving_format: Option<ImageKind>,
+
+    /// The default resizing preset to serve images as.
+    ///
+    /// Defaults to the original image size.
+    pub default_serving_preset: Option<String>,
+
+    #[serde(default)]
+    /// A set of resizing presets, this allows resizing dimensions to be accessed
+    /// via a name. E.g. "small", "medium", "large", etc...
+    pub presets: HashMap<String, ResizingConfig>,
+
+    /// A local cache config.
+    ///
+    /// If `None` this will use the global handler.
+    pub cache: Option<CacheConfig>,
+
+    /// A cache of processed pipeline outputs keyed by the checksum
+    /// of the source image and the operations applied to it.
+    ///
+    /// This lets duplicate images, even when uploaded under different
+    /// ids, reuse previous encodes rather than being processed again.
+    ///
+    /// If `None` no results are cached.
+    pub encoder_cache: Option<CacheConfig>,
+
+    /// The max upload size allowed for this bucket in KB.
+    pub max_upload_size: Option<u32>,
+
+    /// The max resolution originals are stored at.
+    ///
+    /// Uploads exceeding it are downscaled to fit before being processed,
+    /// keeping the storage and later processing costs of camera-original
+    /// uploads bounded.
+    ///
+    /// If `None` originals are stored at their uploaded resolution.
+    pub max_resolution: Option<ResolutionCap>,
+
+    #[serde(default)]
+    /// Convert uploads encoded as CMYK or tagged with a color profile
+    /// other than sRGB, e.g. AdobeRGB or Display P3, to sRGB on ingest.
+    ///
+    /// Browsers differ in how they render such images, converting them
+    /// once keeps every variant looking consistent wherever it's served.
+    ///
+    /// Defaults to `false`.
+    pub convert_to_srgb: bool,
+
+    #[serde(default = "default_true")]
+    /// Strip the EXIF, GPS and XMP metadata of uploads from the stored
+    /// and served images.
+    ///
+    /// If disabled the source image's EXIF data and ICC profile are kept.
+    ///
+    /// Defaults to `true`.
+    pub strip_metadata: bool,
+
+    #[serde(default)]
+    /// The metadata kept when `strip_metadata` is enabled.
+    pub preserve_metadata: PreserveMetadata,
+
+    /// How transparent images are encoded in formats without
+    /// transparency, i.e. JPEG.
+    ///
+    /// If `None` transparent pixels are flattened onto white.
+    pub alpha: Option<AlphaConfig>,
+
+    /// The limits enforced by the image decoders for this bucket.
+    ///
+    /// These apply alongside the global `decode_limits`, the stricter
+    /// of the two limits being used.
+    pub decode_limits: Option<DecodeLimits>,
+
+    /// The limits applied to animated uploads.
+    ///
+    /// Decoding every frame of a long animation can pin a processing
+    /// worker for minutes, these bound the work a single upload can cause.
+    ///
+    /// If `None` animations are accepted regardless of their length.
+    pub animation_limits: Option<AnimationLimits>,
+
+    /// The per-bucket max concurrency.
+    pub max_concurrency: Option<usize>,
+
+    /// The per-bucket max concurrency for uploads and deletes.
+    ///
+    /// Overrides `max_concurrency` for uploads.
+    pub max_upload_concurrency: Option<usize>,
+
+    /// The per-bucket max concurrency for fetches.
+    ///
+    /// Overrides `max_concurrency` for fetches.
+    pub max_fetch_concurrency: Option<usize>,
+
+    /// The maximum number of variants of a single image persisted
+    /// to the storage backend concurrently.
+    ///
+    /// If `None` all variants are stored at once.
+    pub max_store_concurrency: Option<usize>,
+
+    /// Compress original images before they're persisted.
+    ///
+    /// Originals are wrapped in a zstd frame and are transparently
+    /// decompressed when fetched. This is useful for buckets keeping
+    /// large, losslessly encoded masters.
+    ///
+    /// If `None` originals are stored as is.
+    pub original_compression: Option<CompressionConfig>,
+
+    /// Store pre-compressed copies of large images.
+    ///
+    /// Variants and originals not covered by `original_compression` are
+    /// wrapped in a zstd frame and served as is with a `content-encoding: zstd`
+    /// header to clients advertising support via `accept-encoding`, other
+    /// clients are served the decompressed image.
+    ///
+    /// If `None` variants are stored as is.
+    pub precompression: Option<PrecompressionConfig>,
+
+    #[serde(default = "default_idempotency_key_ttl")]
+    /// How long in seconds an upload's `idempotency-key` is remembered for.
+    ///
+    /// Retried uploads with the same key within this window return the
+    /// original upload info rather than re-processing the image. If `0`
+    /// keys are not remembered and every retry re-processes the image.
+    ///
+    /// Defaults to `86400` (24 hours).
+    pub idempotency_key_ttl: u64,
+
+    #[serde(default)]
+    /// How the ids of uploaded images are generated.
+    ///
+    /// Defaults to `uuid`, random UUIDv4s.
+    pub id_scheme: IdScheme,
+
+    /// Allows uploads to choose their own image id via the `image_id`
+    /// query parameter or `x-image-id` header.
+    ///
+    /// If `None` uploads are always assigned a generated id.
+    pub custom_ids: Option<CustomIdsConfig>,
+
+    /// The time in milliseconds an upload can take before it's moved
+    /// to the background and a `202` status is returned.
+    ///
+    /// The upload status can then be polled via the returned status URL.
+    /// This is mostly useful for `aot` buckets processing large images.
+    ///
+    /// If `None` uploads always complete before responding.
+    pub async_upload_threshold: Option<u64>,
+
+    /// The time in milliseconds an image can be processed for before
+    /// the request is failed with a `504` status.
+    ///
+    /// If `None` processing is never timed out.
+    pub processing_timeout: Option<u64>,
+
+    #[serde(default)]
+    /// Options for the `original` pseudo-preset, the image
+    /// at its uploaded size.
+    pub original: OriginalConfig,
+
+    #[serde(default = "default_true")]
+    /// Allow the original to be fetched via the image API.
+    ///
+    /// If disabled fetches of the original are rejected with a `403` status
+    /// and only presets can be fetched, presets are always re-encoded rather
+    /// than served from the original as is. Originals can still be retrieved
+    /// via the admin API.
+    ///
+    /// Defaults to `true`.
+    pub serve_original: bool,
+
+    #[serde(default)]
+    /// Options specific to the `jit` processing mode.
+    pub jit: JitConfig,
+
+    #[serde(default)]
+    /// Rules controlling which variants are generated based
+    /// on the source image.
+    ///
+    /// These are evaluated by the `aot` and `jit` pipelines.
+    pub processing_rules: ProcessingRules,
+
+    /// A placeholder image served when a requested image does not exist.
+    ///
+    /// If `None` a JSON error is returned instead.
+    pub missing_image: Option<MissingImageConfig>,
+
+    /// The monthly egress limit of the bucket.
+    ///
+    /// If `None` no limit is enforced.
+    pub egress_limit: Option<EgressLimitConfig>,
+
+    /// Per-image fetch counting.
+    ///
+    /// If `None` fetches are not counted.
+    pub access_stats: Option<AccessStatsConfig>,
+
+    /// Rules removing images which are no longer being fetched.
+    ///
+    /// If `None` images are kept indefinitely.
+    pub lifecycle: Option<LifecycleConfig>,
+
+    /// Moves deleted images to the trash rather than removing them, they
+    /// can be restored until the retention period has passed.
+    ///
+    /// If `None` deletes are permanent.
+    pub soft_delete: Option<SoftDeleteConfig>,
+
+    /// Keeps uploads which fail to be decoded or encoded so they can be
+    /// retrieved via the admin API to reproduce the failure.
+    ///
+    /// If `None` failed uploads are discarded.
+    pub quarantine: Option<QuarantineConfig>,
+
+    /// Caches the results of image metadata and existence lookups for a
+    /// short time so high frequency polling doesn't hit the storage backend.
+    ///
+    /// If `None` every lookup is served by the storage backend.
+    pub lookup_cache: Option<LookupCacheConfig>,
+
+    /// Purges the cached copies of replaced and deleted images from the
+    /// CDN in front of lust.
+    ///
+    /// If `None` the CDN is left to expire its cached copies.
+    pub cdn: Option<CdnConfig>,
+
+    /// An index of the bucket's images used to find and purge them.
+    ///
+    /// If `None` images are not indexed.
+    pub index: Option<IndexConfig>,
+
+    /// Records the images created, replaced and deleted in the bucket so
+    /// external systems can mirror it incrementally via the admin API.
+    ///
+    /// The log is written by a single instance, instances sharing the
+    /// storage backend should all forward their writes to it.
+    ///
+    /// If `None` changes are not recorded.
+    pub change_log: Option<ChangeLogConfig>,
+
+    #[serde(default)]
+    /// Static headers attached to every fetch response of the bucket,
+    /// e.g. `X-Content-Type-Options` or `Content-Security-Policy`.
+    pub response_headers: HashMap<String, String>,
+
+    /// Allows transformations to be given in the URL path rather than
+    /// the query, e.g. `/:image_id/300x300,webp`, for CDNs which normalise
+    /// or strip the query string when caching.
+    ///
+    /// If `None` transformations can only be given in the query.
+    pub path_transforms: Option<PathTransformsConfig>,
+
+    /// Requires fetches to be signed with the bucket's key, allowing
+    /// private buckets to be exposed without a proxy in front of them.
+    ///
+    /// If `None` fetches don't need to be signed.
+    pub url_signing: Option<UrlSigningConfig>,
+
+    #[serde(default)]
+    /// The API keys authorizing uploads and deletes on the bucket
+    /// in addition to the global `api_keys`.
+    pub api_keys: Vec<String>,
+}
+
+impl BucketConfig {
+    #[inline]
+    pub fn sizing_preset_ids(&self) -> Vec<u32> {
+        let mut presets: Vec<u32> =
+            self.presets.keys().map(crate::utils::crc_hash).collect();
+        if self.default_serving_preset.is_none() {
+            presets.push(0)
+        }
+        presets
+    }
+
+    /// The format images of the given preset are served as when no
+    /// format is requested.
+    ///
+    /// The preset's `default_serving_format` takes precedence over the bucket's.
+    pub fn serving_format(&self, preset: Option<&str>) -> ImageKind {
+        preset
+            .or(self.default_serving_preset.as_deref())
+            .and_then(|preset| self.presets.get(preset))
+            .and_then(|resizing| resizing.default_serving_format)
+            .or(self.default_serving_format)
+            .unwrap_or_else(|| self.formats.first_enabled_format())
+    }
+
+    /// If requests for the given preset are served the original.
+    pub fn is_original(&self, preset: Option<&str>) -> bool {
+        match preset.or(self.default_serving_preset.as_deref()) {
+            None => true,
+            Some(preset) => preset == "original",
+        }
+    }
+
+    /// The sizing id of a custom resize.
+    ///
+    /// Errors if the id collides with a preset or the original image, as
+    /// the variants would otherwise be indistinguishable.
+    pub fn custom_sizing_id(&self, width: u32, height: u32) -> Result<u32> {
+        resolve_custom_sizing_id(self.presets.keys().map(crate::utils::crc_hash), width, height)
+    }
+
+    /// How often in seconds the bucket's buffered metadata is flushed
+    /// to the storage backend.
+    ///
+    /// Returns `None` if the bucket doesn't buffer any metadata.
+    pub fn metadata_flush_interval(&self) -> Option<u64> {
+        let lifecycle = self.lifecycle
+            .as_ref()
+            .map(|_| default_metadata_flush_interval());
+
+        [
+            self.access_stats.map(|v| v.flush_interval),
+            self.index.map(|v| v.flush_interval),
+            self.change_log.map(|v| v.flush_interval),
+            lifecycle,
+        ]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Enum, Eq, PartialEq, Hash, Deserialize, strum::AsRefStr)]
+#[oai(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ImageKind {
+    /// The PNG encoding format.
+    Png,
+
+    /// The JPEG encoding format.
+    Jpeg,
+
+    /// The WebP encoding format.
+    Webp,
+
+    /// The GIF encoding format.
+    Gif,
+}
+
+#[allow(clippy::from_over_into)]
+impl Into<image::ImageFormat> for ImageKind {
+    fn into(self) -> ImageFormat {
+        match self {
+            Self::Png => image::ImageFormat::Png,
+            Self::Jpeg => image::ImageFormat::Jpeg,
+            Self::Gif => image::ImageFormat::Gif,
+            Self::Webp => image::ImageFormat::WebP,
+        }
+    }
+}
+
+impl ImageKind {
+    pub fn from_content_type(kind: &str) -> Option<Self> {
+        match kind {
+            "image/png" => Some(Self::Png),
+            "image/jpeg" => Some(Self::Jpeg),
+            "image/gif" => Some(Self::Gif),
+            "image/webp" => Some(Self::Webp),
+            "png" => Some(Self::Png),
+            "jpeg" => Some(Self::Jpeg),
+            "gif" => Some(Self::Gif),
+            "webp" => Some(Self::Webp),
+            _ => None
+        }
+    }
+
+    pub fn from_file_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "png" => Some(Self::Png),
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "gif" => Some(Self::Gif),
+            "webp" => Some(Self::Webp),
+            _ => None
+        }
+    }
+
+    pub fn from_guessed_format(fmt: image::ImageFormat) -> Option<Self> {
+        match fmt {
+            image::ImageFormat::Png => Some(Self::Png),
+            image::ImageFormat::Jpeg => Some(Self::Jpeg),
+            image::ImageFormat::Gif => Some(Self::Gif),
+            image::ImageFormat::WebP => Some(Self::Webp),
+            _ => None
+        }
+    }
+
+    pub fn as_content_type(&self) -> String {
+        format!("image/{}", self.as_file_extension())
+    }
+
+    pub fn as_file_extension(&self) -> &'static str {
+        match self {
+            ImageKind::Png => "png",
+            ImageKind::Jpeg => "jpeg",
+            ImageKind::Webp => "webp",
+            ImageKind::Gif => "gif",
+        }
+    }
+
+    pub fn variants() -> &'static [Self] {
+        &[
+            Self::Png,
+            Self::Jpeg,
+            Self::Gif,
+            Self::Webp,
+        ]
+    }
+}
+
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PathTransformsConfig {
+    #[serde(default)]
+    /// The transformations which can be requested without a signature,
+    /// e.g. `300x300,webp` or `thumbnail,png`.
+    pub allowed: Vec<String>,
+
+    /// The key other transformations must be signed with, the signature
+    /// is given as a trailing `sig=` token of the transformation.
+    ///
+    /// If `None` only the allowed transformations can be requested.
+    pub signing_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct JwtConfig {
+    /// The keys tokens can be signed with.
+    pub keys: Vec<JwtKeyConfig>,
+
+    /// The `iss` claim tokens must have, if set.
+    pub issuer: Option<String>,
+
+    /// The `aud` claim tokens must have, if set.
+    pub audience: Option<String>,
+
+    #[serde(default = "default_true")]
+    /// Allows fetches without a token, only requiring tokens to change images.
+    ///
+    /// Defaults to `true`.
+    pub public_read: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct JwtKeyConfig {
+    /// The algorithm tokens are signed with using this key.
+    pub algorithm: JwtAlgorithm,
+
+    /// The shared secret for `HS256`, or the PEM encoded public key for `RS256`.
+    pub key: String,
+
+    /// The `kid` header of tokens signed with this key.
+    ///
+    /// If `None` the key is tried for any token using its algorithm.
+    pub kid: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub enum JwtAlgorithm {
+    #[serde(rename = "HS256")]
+    Hs256,
+    #[serde(rename = "RS256")]
+    Rs256,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdScheme {
+    /// Random UUIDv4s.
+    #[default]
+    Uuid,
+
+    /// ULIDs, a millisecond timestamp followed by 80 random bits.
+    Ulid,
+
+    /// Snowflakes, a millisecond timestamp, worker id and sequence number,
+    /// as the first 64 bits of the id with the remaining bits unset.
+    Snowflake {
+        #[serde(default)]
+        /// The id of this instance, between 0 and 1023.
+        ///
+        /// Every instance uploading to the bucket must use a different id.
+        worker_id: u16,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CustomIdsConfig {
+    /// A regex custom ids which are not UUIDs must match, e.g. `^[a-z0-9-]{1,64}$`.
+    ///
+    /// Matching slugs are stored under a UUID derived from the bucket and
+    /// slug, which is returned as the image id. The pattern should be anchored
+    /// as it is otherwise allowed to match only part of the slug.
+    ///
+    /// If `None` only UUIDs are accepted.
+    pub slug_pattern: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UrlSigningConfig {
+    /// The key fetch URLs are signed with, the signature is given
+    /// as the `sig` query parameter along with an `expires` timestamp.
+    pub key: String,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default = "default_compression_level")]
+    /// The zstd compression level from 1 to 22 inclusive.
+    ///
+    /// Defaults to `3`.
+    pub level: i32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PrecompressionConfig {
+    #[serde(default = "default_compression_level")]
+    /// The zstd compression level from 1 to 22 inclusive.
+    ///
+    /// Defaults to `3`.
+    pub level: i32,
+
+    #[serde(default = "default_precompression_min_size")]
+    /// The minimum size in bytes of a variant before it is compressed.
+    ///
+    /// Defaults to `65536` (64KB).
+    pub min_size: usize,
+
+    #[serde(default = "default_precompression_formats")]
+    /// The formats which are compressed.
+    ///
+    /// Only losslessly encoded formats benefit meaningfully from compression.
+    ///
+    /// Defaults to `[png]`.
+    pub formats: Vec<ImageKind>,
+}
+
+impl PrecompressionConfig {
+    /// The compression level to store the variant with, if it should be compressed.
+    pub fn level_for(&self, kind: ImageKind, len: usize) -> Option<i32> {
+        if len >= self.min_size && self.formats.contains(&kind) {
+            Some(self.level)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct JitConfig {
+    #[serde(default = "default_true")]
+    /// Write variants generated on fetch back to the storage backend.
+    ///
+    /// If disabled variants are only held in the cache and are regenerated
+    /// from the original once evicted, this avoids storage writes where they're
+    /// expensive and the cache hit rate is high.
+    ///
+    /// Defaults to `true`.
+    pub persist_variants: bool,
+
+    #[serde(default)]
+    /// Generate the other enabled formats of a variant alongside the
+    /// requested format when generating it on fetch.
+    ///
+    /// The decoded and resized image is reused so later requests for the
+    /// other formats don't repeat the work.
+    ///
+    /// Defaults to `false`.
+    pub encode_siblings: bool,
+}
+
+impl Default for JitConfig {
+    fn default() -> Self {
+        Self {
+            persist_variants: true,
+            encode_siblings: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OriginalConfig {
+    /// The formats the original can be served in.
+    ///
+    /// Requests negotiating any other format are served the first of
+    /// these, requests for any other format explicitly are rejected.
+    ///
+    /// If `None` the original can be served in any enabled format.
+    pub formats: Option<Vec<ImageKind>>,
+
+    #[serde(default = "default_true")]
+    /// Store the original alongside the presets.
+    ///
+    /// Only `aot` buckets can disable this, as they never need
+    /// the original to generate their presets later on. Missing
+    /// presets can't be regenerated without it.
+    ///
+    /// Defaults to `true`.
+    pub store: bool,
+}
+
+impl Default for OriginalConfig {
+    fn default() -> Self {
+        Self {
+            formats: None,
+            store: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ProcessingRules {
+    #[serde(default)]
+    /// Skip generating resizing presets which would upscale the source image.
+    ///
+    /// The source image is served at its original size instead.
+    ///
+    /// Defaults to `false`.
+    pub skip_upscaling: bool,
+
+    #[serde(default)]
+    /// The minimum size in KB a source image must be for a given
+    /// format to be generated.
+    ///
+    /// Smaller images are served as is instead. The original image
+    /// store format is always generated for the original sizing.
+    pub min_source_size: HashMap<ImageKind, u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MissingImageConfig {
+    /// The path to a static image file to serve.
+    pub path: Option<String>,
+
+    /// The hex colour of a generated solid placeholder, e.g. `#e5e5e5`.
+    pub colour: Option<String>,
+
+    #[serde(default = "default_placeholder_size")]
+    /// The width of the generated placeholder.
+    ///
+    /// Defaults to `64`.
+    pub width: u32,
+
+    #[serde(default = "default_placeholder_size")]
+    /// The height of the generated placeholder.
+    ///
+    /// Defaults to `64`.
+    pub height: u32,
+
+    #[serde(default)]
+    /// The status code the placeholder is returned with.
+    ///
+    /// Defaults to `not_found` (404).
+    pub status: MissingImageStatus,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingImageStatus {
+    /// Return the placeholder with a `404 Not Found` status.
+    #[default]
+    NotFound,
+
+    /// Return the placeholder with a `410 Gone` status.
+    Gone,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EgressLimitConfig {
+    /// The maximum amount of image data in MB that can be served
+    /// by the bucket within a calendar month (UTC).
+    pub max_monthly_egress: u64,
+
+    /// A URL to redirect fetches to once the limit is exceeded,
+    /// e.g. a placeholder image.
+    ///
+    /// If `None` fetches are rejected with a `429` status.
+    pub exceeded_redirect: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct AccessStatsConfig {
+    #[serde(default = "default_metadata_flush_interval")]
+    /// How often in seconds buffered fetch counts are flushed
+    /// to the storage backend.
+    ///
+    /// Defaults to `60`.
+    pub flush_interval: u64,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct IndexConfig {
+    #[serde(default = "default_index_flush_interval")]
+    /// How often in seconds buffered changes to the index are
+    /// flushed to the storage backend.
+    ///
+    /// Defaults to `10`.
+    pub flush_interval: u64,
+
+    /// Compares the perceptual hash of uploads against the indexed
+    /// images, returning near-identical images as possible duplicates.
+    ///
+    /// If `None` uploads are not compared.
+    pub duplicates: Option<DuplicatesConfig>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct DuplicatesConfig {
+    #[serde(default = "default_duplicates_max_distance")]
+    /// The maximum number of the 64 perceptual hash bits which can differ
+    /// for an indexed image to be considered a possible duplicate.
+    ///
+    /// Defaults to `5`.
+    pub max_distance: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SoftDeleteConfig {
+    #[serde(default = "default_trash_retention")]
+    /// How long in seconds deleted images are kept in the trash.
+    ///
+    /// Defaults to `604800` (7 days).
+    pub retention: u64,
+
+    #[serde(default = "default_lifecycle_check_interval")]
+    /// How often in seconds images past the retention period are purged.
+    ///
+    /// Defaults to `3600` (1 hour).
+    pub reap_interval: u64,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CdnProvider {
+    /// Purges by surrogate key via the Fastly API.
+    Fastly,
+
+    /// Sends each purge as a JSON `POST` to the given `url`, for
+    /// CDNs without native support.
+    Webhook,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CdnConfig {
+    /// The CDN the purges are sent to.
+    pub provider: CdnProvider,
+
+    /// The webhook purges are sent to or the base URL of the Fastly API.
+    ///
+    /// Defaults to `https://api.fastly.com` for Fastly, required for webhooks.
+    pub url: Option<String>,
+
+    /// The id of the Fastly service serving the bucket.
+    pub service_id: Option<String>,
+
+    /// The Fastly API token, or the bearer token sent to the webhook.
+    pub api_token: Option<String>,
+
+    #[serde(default = "default_true")]
+    /// Mark replaced images as stale so the CDN revalidates them with
+    /// lust rather than dropping its copies entirely.
+    ///
+    /// This avoids every edge fetching the new image at once when a
+    /// popular image is replaced. Deleted images are always hard purged.
+    ///
+    /// Defaults to `true`.
+    pub soft_purge: bool,
+
+    #[serde(default = "default_cdn_timeout")]
+    /// The time in seconds to wait for the CDN to accept a purge.
+    ///
+    /// Defaults to `10`.
+    pub timeout: u64,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct ChangeLogConfig {
+    #[serde(default = "default_index_flush_interval")]
+    /// How often in seconds buffered changes are flushed
+    /// to the storage backend.
+    ///
+    /// Defaults to `10`.
+    pub flush_interval: u64,
+
+    #[serde(default = "default_change_log_max_events")]
+    /// The number of most recent changes kept, mirrors which fall
+    /// further behind must re-sync from a full export.
+    ///
+    /// Defaults to `100000`.
+    pub max_events: usize,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct LookupCacheConfig {
+    #[serde(default = "default_lookup_cache_ttl")]
+    /// The time in seconds lookups are cached for.
     ///
-    /// Defaults to the first enabled encoding format.
-    pub default_serving_format: Option<ImageKind>,
+    /// Defaults to `5`.
+    pub ttl: u64,
 
-    /// The default resizing preset to serve images as.
+    #[serde(default = "default_lookup_cache_max_entries")]
+    /// The maximum number of lookups cached of each kind.
     ///
-    /// Defaults to the original image size.
-    pub default_serving_preset: Option<String>,
+    /// Defaults to `10000`.
+    pub max_entries: u64,
+}
 
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct PreserveMetadata {
     #[serde(default)]
-    /// A set of resizing presets, this allows resizing dimensions to be accessed
-    /// via a name. E.g. "small", "medium", "large", etc...
-    pub presets: HashMap<String, ResizingConfig>,
-
-    /// A local cache config.
+    /// Keep the artist and copyright EXIF tags.
     ///
-    /// If `None` this will use the global handler.
-    pub cache: Option<CacheConfig>,
-
-    /// The max upload size allowed for this bucket in KB.
-    pub max_upload_size: Option<u32>,
+    /// Defaults to `false`.
+    pub copyright: bool,
 
-    /// The per-bucket max concurrency.
-    pub max_concurrency: Option<usize>,
+    #[serde(default = "default_true")]
+    /// Keep embedded wide gamut ICC color profiles, e.g. Display P3,
+    /// re-attaching them to every JPEG, PNG and WebP output.
+    ///
+    /// Images re-encoded without their profile are rendered as sRGB
+    /// and shift color. Buckets with `convert_to_srgb` enabled have
+    /// no profiles left to keep.
+    ///
+    /// Defaults to `true`.
+    pub icc: bool,
 }
 
-impl BucketConfig {
-    #[inline]
-    pub fn sizing_preset_ids(&self) -> Vec<u32> {
-        let mut presets: Vec<u32> =
-            self.presets.keys().map(crate::utils::crc_hash).collect();
-        match self.default_serving_preset {
-            None => presets.push(0),
-            _ => ()
+impl Default for PreserveMetadata {
+    fn default() -> Self {
+        Self {
+            copyright: false,
+            icc: true,
         }
-        presets
     }
 }
 
-#[derive(Copy, Clone, Debug, Enum, Eq, PartialEq, Deserialize, strum::AsRefStr)]
-#[oai(rename_all = "lowercase")]
-#[serde(rename_all = "lowercase")]
-pub enum ImageKind {
-    /// The PNG encoding format.
-    Png,
+#[derive(Clone, Debug, Deserialize)]
+pub struct QuarantineConfig {
+    #[serde(default = "default_quarantine_max_size")]
+    /// The max total size in MB of the quarantined uploads, the
+    /// oldest uploads are evicted once exceeded.
+    ///
+    /// Defaults to `100`.
+    pub max_size: u64,
 
-    /// The JPEG encoding format.
-    Jpeg,
+    #[serde(default = "default_trash_retention")]
+    /// How long in seconds uploads are kept in quarantine.
+    ///
+    /// Defaults to `604800` (7 days).
+    pub ttl: u64,
+}
 
-    /// The WebP encoding format.
-    Webp,
+#[derive(Clone, Debug, Deserialize)]
+pub struct LifecycleConfig {
+    #[serde(default = "default_lifecycle_check_interval")]
+    /// How often in seconds the lifecycle rules are evaluated.
+    ///
+    /// Defaults to `3600` (1 hour).
+    pub check_interval: u64,
 
-    /// The GIF encoding format.
-    Gif,
-}
+    #[serde(default)]
+    /// Only report what the rules would do rather than applying them.
+    ///
+    /// Defaults to `false`.
+    pub dry_run: bool,
 
-#[allow(clippy::from_over_into)]
-impl Into<image::ImageFormat> for ImageKind {
-    fn into(self) -> ImageFormat {
-        match self {
-            Self::Png => image::ImageFormat::Png,
-            Self::Jpeg => image::ImageFormat::Jpeg,
-            Self::Gif => image::ImageFormat::Gif,
-            Self::Webp => image::ImageFormat::WebP,
-        }
-    }
+    /// The maximum number of tracked images to keep.
+    ///
+    /// The least recently accessed images are deleted once exceeded.
+    /// If `None` no limit is applied.
+    pub max_count: Option<usize>,
+
+    #[serde(default)]
+    /// The rules to apply to the bucket's images.
+    pub rules: Vec<LifecycleRule>,
 }
 
-impl ImageKind {
-    pub fn from_content_type(kind: &str) -> Option<Self> {
-        match kind {
-            "image/png" => Some(Self::Png),
-            "image/jpeg" => Some(Self::Jpeg),
-            "image/gif" => Some(Self::Gif),
-            "image/webp" => Some(Self::Webp),
-            "png" => Some(Self::Png),
-            "jpeg" => Some(Self::Jpeg),
-            "gif" => Some(Self::Gif),
-            "webp" => Some(Self::Webp),
-            _ => None
-        }
-    }
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct LifecycleRule {
+    /// The number of days an image must not have been fetched,
+    /// or uploaded, within for the rule to apply.
+    pub not_accessed_for: Option<u32>,
 
-    pub fn from_guessed_format(fmt: image::ImageFormat) -> Option<Self> {
-        match fmt {
-            image::ImageFormat::Png => Some(Self::Png),
-            image::ImageFormat::Jpeg => Some(Self::Jpeg),
-            image::ImageFormat::Gif => Some(Self::Gif),
-            image::ImageFormat::WebP => Some(Self::Webp),
-            _ => None
-        }
-    }
+    /// The number of days since an image was uploaded for the rule to apply.
+    pub older_than: Option<u32>,
 
-    pub fn as_content_type(&self) -> String {
-        format!("image/{}", self.as_file_extension())
-    }
+    /// The action to take on matching images.
+    pub action: LifecycleAction,
+}
 
-    pub fn as_file_extension(&self) -> &'static str {
-        match self {
-            ImageKind::Png => "png",
-            ImageKind::Jpeg => "jpeg",
-            ImageKind::Webp => "webp",
-            ImageKind::Gif => "gif",
-        }
-    }
+/// The actions lifecycle rules can take, in order of precedence.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleAction {
+    /// Remove the generated variants of the image, keeping only the original.
+    ///
+    /// Variants are regenerated from the original if the image is fetched again.
+    DropVariants,
 
-    pub fn variants() -> &'static [Self] {
-        &[
-            Self::Png,
-            Self::Jpeg,
-            Self::Gif,
-            Self::Webp,
-        ]
-    }
-}
+    /// Remove the generated variants of the image and compress the original.
+    ///
+    /// The original is transparently decompressed if the image is fetched again.
+    Archive,
 
+    /// Delete the image and all of its variants.
+    Delete,
+}
 
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct ImageFormats {
@@ -322,6 +2053,15 @@ impl ImageFormats {
         }
     }
 
+    pub fn set_enabled(&mut self, kind: ImageKind, enabled: bool) {
+        match kind {
+            ImageKind::Png => self.png = enabled,
+            ImageKind::Jpeg => self.jpeg = enabled,
+            ImageKind::Webp => self.webp = enabled,
+            ImageKind::Gif => self.gif = enabled,
+        }
+    }
+
     pub fn first_enabled_format(&self) -> ImageKind {
         if self.png {
             return ImageKind::Png
@@ -363,10 +2103,130 @@ pub struct WebpConfig {
     pub threading: bool,
 }
 
+impl WebpConfig {
+    /// Builds the libwebp encoder config for this bucket.
+    pub fn as_encoder_config(&self) -> webp::WebPConfig {
+        webp::config(
+            self.quality.is_none(),
+            self.quality.unwrap_or(50f32),
+            self.method.unwrap_or(4) as i32,
+            self.threading,
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct ResolutionCap {
+    /// The max width of a stored original.
+    pub width: u32,
+
+    /// The max height of a stored original.
+    pub height: u32,
+
+    #[serde(default)]
+    /// The resizing filter algorithm to downscale with.
+    ///
+    /// Defaults to nearest neighbour.
+    pub filter: ResizingFilter,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+pub struct DecodeLimits {
+    /// The max width of an image in pixels.
+    pub max_width: Option<u32>,
+
+    /// The max height of an image in pixels.
+    pub max_height: Option<u32>,
+
+    /// The max memory in MB the decoder can allocate for a single image.
+    pub max_alloc: Option<u64>,
+}
+
+impl DecodeLimits {
+    fn has_zero(&self) -> bool {
+        self.max_width == Some(0) || self.max_height == Some(0) || self.max_alloc == Some(0)
+    }
+
+    /// Combines the limits with the other limits, keeping the stricter of each.
+    pub fn merge(self, other: DecodeLimits) -> DecodeLimits {
+        fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+
+        DecodeLimits {
+            max_width: min(self.max_width, other.max_width),
+            max_height: min(self.max_height, other.max_height),
+            max_alloc: min(self.max_alloc, other.max_alloc),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Deserialize)]
+pub struct AnimationLimits {
+    /// The max number of frames an animation can have.
+    pub max_frames: Option<u32>,
+
+    /// The max total duration of an animation in milliseconds.
+    pub max_duration: Option<u64>,
+
+    /// The max total size of all the decoded frames in KB.
+    pub max_decoded_size: Option<u64>,
+
+    #[serde(default)]
+    /// What to do with animations exceeding any of the limits.
+    ///
+    /// Defaults to `reject`.
+    pub on_exceeded: AnimationLimitAction,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AlphaConfig {
+    #[serde(default)]
+    /// What to do with transparent images.
+    ///
+    /// Defaults to `flatten`.
+    pub policy: AlphaPolicy,
+
+    #[serde(default = "default_alpha_background")]
+    /// The hex colour transparent pixels are flattened onto, e.g. `#ffffff`.
+    ///
+    /// Defaults to `#ffffff`.
+    pub background: String,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlphaPolicy {
+    /// The image is blended onto the background colour.
+    #[default]
+    Flatten,
+
+    /// The image is encoded as a PNG instead.
+    Fallback,
+
+    /// The image is rejected with a `415` status.
+    Reject,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnimationLimitAction {
+    /// The upload is rejected.
+    #[default]
+    Reject,
+
+    /// The animation is cut down to the frames within the limits.
+    Truncate,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ResizingFilter {
     /// Nearest Neighbor
+    #[default]
     Nearest,
 
     /// Linear Filter
@@ -395,12 +2255,6 @@ impl Into<image::imageops::FilterType> for ResizingFilter {
     }
 }
 
-impl Default for ResizingFilter {
-    fn default() -> Self {
-        Self::Nearest
-    }
-}
-
 #[derive(Copy, Clone, Debug, Default, Deserialize)]
 pub struct ResizingConfig {
     /// The width to resize the image to.
@@ -414,13 +2268,174 @@ pub struct ResizingConfig {
     ///
     /// Defaults to nearest neighbour.
     pub filter: ResizingFilter,
+
+    /// The default format to serve images of this preset as.
+    ///
+    /// Defaults to the bucket's `default_serving_format`.
+    pub default_serving_format: Option<ImageKind>,
+
+    #[serde(default)]
+    /// The max size variants of this preset are encoded at per format.
+    ///
+    /// Defaults to no limit.
+    pub max_size: SizeBudget,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+pub struct SizeBudget {
+    /// The max size of JPEG variants in KB.
+    pub jpeg: Option<u32>,
+
+    /// The max size of WebP variants in KB.
+    ///
+    /// Variants are encoded lossily when a limit is set.
+    pub webp: Option<u32>,
+}
+
+impl SizeBudget {
+    /// The max size in bytes of variants of the given format.
+    ///
+    /// Only lossy formats can be encoded to a size budget.
+    pub fn max_bytes(&self, kind: ImageKind) -> Option<usize> {
+        let max_size = match kind {
+            ImageKind::Jpeg => self.jpeg,
+            ImageKind::Webp => self.webp,
+            ImageKind::Png | ImageKind::Gif => None,
+        };
+
+        max_size.map(|v| v as usize * 1024)
+    }
+}
+
+const fn default_cdn_timeout() -> u64 {
+    10
+}
+
+const fn default_lookup_cache_ttl() -> u64 {
+    5
+}
+
+const fn default_lookup_cache_max_entries() -> u64 {
+    10_000
+}
+
+const fn default_change_log_max_events() -> usize {
+    100_000
 }
 
 const fn default_true() -> bool {
     true
 }
 
+fn default_alpha_background() -> String {
+    "#ffffff".to_string()
+}
+
 const fn default_original_format() -> ImageKind {
     ImageKind::Png
 }
 
+const fn default_precompression_min_size() -> usize {
+    64 << 10
+}
+
+fn default_precompression_formats() -> Vec<ImageKind> {
+    vec![ImageKind::Png]
+}
+
+const fn default_compression_level() -> i32 {
+    3
+}
+
+const fn default_permit_weight() -> u32 {
+    1
+}
+
+const fn default_storage_max_retries() -> u32 {
+    3
+}
+
+const fn default_storage_base_delay() -> u64 {
+    50
+}
+
+const fn default_storage_max_delay() -> u64 {
+    2000
+}
+
+const fn default_breaker_threshold() -> u32 {
+    10
+}
+
+const fn default_breaker_cooldown() -> u64 {
+    10
+}
+
+const fn default_replication_concurrency() -> usize {
+    4
+}
+
+const fn default_replication_max_pending() -> usize {
+    100_000
+}
+
+const fn default_replica_timeout() -> u64 {
+    60
+}
+
+const fn default_remote_upload_timeout() -> u64 {
+    30
+}
+
+const fn default_import_concurrency() -> usize {
+    4
+}
+
+const fn default_import_wait_time() -> u64 {
+    20
+}
+
+const fn default_watch_settle_time() -> u64 {
+    2
+}
+
+const fn default_watch_concurrency() -> usize {
+    2
+}
+
+const fn default_placeholder_size() -> u32 {
+    64
+}
+
+const fn default_idempotency_key_ttl() -> u64 {
+    60 * 60 * 24
+}
+
+const fn default_metadata_flush_interval() -> u64 {
+    60
+}
+
+const fn default_index_flush_interval() -> u64 {
+    10
+}
+
+const fn default_duplicates_max_distance() -> u32 {
+    5
+}
+
+const fn default_processing_niceness() -> i32 {
+    10
+}
+
+const fn default_trash_retention() -> u64 {
+    60 * 60 * 24 * 7
+}
+
+const fn default_quarantine_max_size() -> u64 {
+    100
+}
+
+const fn default_lifecycle_check_interval() -> u64 {
+    60 * 60
+}
+
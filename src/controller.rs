@@ -1,19 +1,65 @@
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
 use bytes::Bytes;
+use futures::StreamExt;
 use once_cell::sync::OnceCell;
 use uuid::Uuid;
-use poem_openapi::Object;
-use tokio::sync::{Semaphore, SemaphorePermit};
+use poem_openapi::{Enum, Object};
+use tokio::sync::SemaphorePermit;
+use crate::access::{AccessRecord, AccessStats, LastAccess};
+use crate::admission::{memory_budget, ConcurrencyLimiter, ConcurrencyPermit, Operation, Priority};
 use crate::cache::{Cache, global_cache};
+use crate::cdn::{CdnPurger, PurgeMode};
+use crate::changes::{ChangeKind, ChangeLog, ChangesSince};
 
-use crate::config::{BucketConfig, ImageKind};
+use crate::config::{BucketConfig, DuplicatesConfig, ImageKind};
+use crate::egress::EgressTracker;
+use crate::etags::Precondition;
+use crate::ids::IdGenerator;
+use crate::index::{ImageIndex, ImageRecord};
+use crate::keys::{ApiKeyInfo, ManagedKeys};
+use crate::lookups::LookupCache;
+use crate::metadata::MetadataStore;
+use crate::placeholder::Placeholder;
+use crate::purge::PurgeJobInfo;
 use crate::pipelines::{PipelineController, ProcessingMode, StoreEntry};
+use crate::processor::{ProcessingError, ProcessingPanic};
+use crate::processor::animation::AnimationLimitExceeded;
+use crate::storage::{InsufficientStorage, StorageThrottled, StorageUnavailable};
 use crate::storage::template::StorageBackend;
+use crate::tombstones::Tombstones;
+use crate::trash::Trash;
+use crate::quarantine::{Quarantine, QuarantinedUpload};
 
 static BUCKETS: OnceCell<hashbrown::HashMap<u32, BucketController>> = OnceCell::new();
 
+/// The maximum number of idempotency keys remembered per bucket.
+const MAX_IDEMPOTENCY_KEYS: u64 = 10_000;
+
+/// The maximum number of background upload jobs tracked per bucket.
+const MAX_UPLOAD_JOBS: u64 = 10_000;
+
+/// The maximum number of purge jobs tracked per bucket.
+const MAX_PURGE_JOBS: u64 = 1_000;
+
+/// How long the progress of a purge job can be polled for.
+const PURGE_JOB_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// The zstd compression level archived originals are stored with.
+const ARCHIVE_COMPRESSION_LEVEL: i32 = 19;
+
+/// How long the status of a background upload job can be polled for.
+const UPLOAD_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of possible duplicates returned for an upload.
+const MAX_POSSIBLE_DUPLICATES: usize = 10;
+
+/// The number of locks replaces and deletes are striped across per bucket.
+const WRITE_LOCK_STRIPES: usize = 64;
+
 pub fn init_buckets(buckets: hashbrown::HashMap<u32, BucketController>) {
     let _ = BUCKETS.set(buckets);
 }
@@ -22,246 +68,2028 @@ pub fn get_bucket_by_id(bucket_id: u32) -> Option<&'static BucketController> {
     BUCKETS.get_or_init(hashbrown::HashMap::new).get(&bucket_id)
 }
 
+pub fn buckets() -> impl Iterator<Item = &'static BucketController> {
+    BUCKETS.get_or_init(hashbrown::HashMap::new).values()
+}
+
 pub fn get_bucket_by_name(bucket: impl Hash) -> Option<&'static BucketController> {
     let bucket_id = crate::utils::crc_hash(bucket);
     get_bucket_by_id(bucket_id)
 }
 
-async fn get_optional_permit<'a>(
-    global: &'a Option<Arc<Semaphore>>,
-    local: &'a Option<Semaphore>,
-) -> anyhow::Result<Option<SemaphorePermit<'a>>> {
-    if let Some(limiter) = global {
-        return Ok(Some(limiter.acquire().await?))
+/// The permits held by an operation, released once dropped.
+struct Permits {
+    _priority: Option<SemaphorePermit<'static>>,
+    _concurrency: Option<ConcurrencyPermit>,
+    _in_use: Option<PermitsInUse>,
+}
+
+/// Reports the concurrency permits held by an operation until dropped.
+struct PermitsInUse {
+    gauge: prometheus::IntGauge,
+    weight: i64,
+}
+
+impl Drop for PermitsInUse {
+    fn drop(&mut self) {
+        self.gauge.sub(self.weight);
+    }
+}
+
+/// Counts operations dropped while waiting for their permits.
+struct Queued<'a> {
+    bucket: &'a str,
+    op: Operation,
+    admitted: bool,
+}
+
+impl<'a> Drop for Queued<'a> {
+    fn drop(&mut self) {
+        if !self.admitted {
+            crate::metrics::CONCURRENCY_REJECTIONS
+                .with_label_values(&[self.bucket, self.op.as_str(), "abandoned"])
+                .inc();
+        }
+    }
+}
+
+/// Acquires the permits for the operation, `size` being the
+/// number of bytes uploaded if any.
+///
+/// Low priority operations first wait for a low priority slot so they
+/// only ever hold a limited share of the concurrency permits.
+async fn get_optional_permit<'a>(
+    bucket: &'a str,
+    global: &'a ConcurrencyLimiter,
+    local: &'a ConcurrencyLimiter,
+    op: Operation,
+    size: usize,
+) -> anyhow::Result<Permits> {
+    let priority = crate::admission::current_priority();
+    acquire_permits(bucket, global, local, op, size, priority).await
+}
+
+/// Acquires the permits for generating a variant which wasn't stored.
+///
+/// Processing is queued behind any normal priority operation so cheap
+/// fetches of stored variants are never held up by expensive encodes.
+async fn get_processing_permit<'a>(
+    bucket: &'a str,
+    global: &'a ConcurrencyLimiter,
+    local: &'a ConcurrencyLimiter,
+) -> anyhow::Result<Permits> {
+    acquire_permits(bucket, global, local, Operation::Fetch, 0, Priority::Low).await
+}
+
+async fn acquire_permits<'a>(
+    bucket: &'a str,
+    global: &'a ConcurrencyLimiter,
+    local: &'a ConcurrencyLimiter,
+    op: Operation,
+    size: usize,
+    priority: Priority,
+) -> anyhow::Result<Permits> {
+    let start = Instant::now();
+    let mut queued = Queued { bucket, op, admitted: false };
+
+    let acquired = async {
+        let slot = crate::admission::acquire_priority_slot().await?;
+
+        let concurrency = if global.is_limited(op) {
+            global.acquire(op, size, priority).await?
+        } else {
+            local.acquire(op, size, priority).await?
+        };
+
+        Ok::<_, anyhow::Error>((slot, concurrency))
+    }.await;
+
+    queued.admitted = true;
+    let (slot, concurrency) = match acquired {
+        Ok(permits) => permits,
+        Err(e) => {
+            crate::metrics::CONCURRENCY_REJECTIONS
+                .with_label_values(&[bucket, op.as_str(), "error"])
+                .inc();
+            return Err(e)
+        },
+    };
+
+    crate::metrics::CONCURRENCY_QUEUE_WAIT
+        .with_label_values(&[bucket, op.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
+    let in_use = concurrency.as_ref().map(|permit| {
+        let gauge = crate::metrics::CONCURRENCY_PERMITS_IN_USE
+            .with_label_values(&[bucket, op.as_str()]);
+        let weight = permit.weight() as i64;
+        gauge.add(weight);
+
+        PermitsInUse { gauge, weight }
+    });
+
+    Ok(Permits {
+        _priority: slot,
+        _concurrency: concurrency,
+        _in_use: in_use,
+    })
+}
+
+/// If the upload failed due to the image rather than the server, such
+/// failures are worth keeping in the bucket's quarantine.
+fn is_quarantinable(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<ProcessingError>() {
+        Some(ProcessingError::Timeout { .. } | ProcessingError::LimitsExceeded { .. }) => false,
+        Some(_) => true,
+        None => e.is::<ProcessingPanic>(),
+    }
+}
+
+/// Recreates an error shared between concurrent callers, keeping the
+/// errors which are responded to with a specific status.
+fn unshare_error(e: &anyhow::Error) -> anyhow::Error {
+    fn typed<T: std::error::Error + Clone + Send + Sync + 'static>(e: &anyhow::Error) -> Option<anyhow::Error> {
+        e.downcast_ref::<T>().cloned().map(anyhow::Error::new)
+    }
+
+    typed::<ProcessingError>(e)
+        .or_else(|| typed::<ProcessingPanic>(e))
+        .or_else(|| typed::<AnimationLimitExceeded>(e))
+        .or_else(|| typed::<StorageThrottled>(e))
+        .or_else(|| typed::<StorageUnavailable>(e))
+        .or_else(|| typed::<InsufficientStorage>(e))
+        .unwrap_or_else(|| anyhow!("{:#}", e))
+}
+
+async fn reserve_processing_memory(
+    data: &[u8],
+) -> anyhow::Result<Option<SemaphorePermit<'static>>> {
+    if let Some(budget) = memory_budget() {
+        let estimate = crate::admission::estimate_processing_memory(data);
+        return Ok(Some(budget.reserve(estimate).await?))
+    }
+
+    Ok(None)
+}
+
+
+#[derive(Object, Debug, Clone)]
+pub struct ImageUploadInfo {
+    /// The computed image sizing id.
+    ///
+    /// This is useful for tracking files outside of lust as this is
+    /// generally used for filtering within the storage systems.
+    sizing_id: u32,
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct UploadInfo {
+    /// The generated ID for the file.
+    ///
+    /// This can be used to access the file for the given bucket.
+    image_id: Uuid,
+
+    /// The time spent processing the image in seconds.
+    processing_time: f32,
+
+    /// The time spent uploading the image to the persistent store.
+    io_time: f32,
+
+    /// The crc32 checksum of the uploaded image.
+    checksum: u32,
+
+    /// The information that is specific to the image.
+    images: Vec<ImageUploadInfo>,
+
+    /// The id of the bucket the image was stored in.
+    ///
+    /// This is useful for tracking files outside of lust as this is
+    /// generally used for filtering within the storage systems.
+    bucket_id: u32,
+
+    /// The indexed images which look near-identical to the uploaded image,
+    /// most similar first.
+    ///
+    /// This is always empty unless the bucket's index has `duplicates` enabled.
+    possible_duplicates: Vec<Uuid>,
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct VariantInfo {
+    /// The sizing id of the variant.
+    sizing_id: u32,
+
+    /// The encoding format of the variant.
+    kind: ImageKind,
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct DeleteInfo {
+    /// The variants of the image which existed and were removed.
+    removed: Vec<VariantInfo>,
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct ImageMetadata {
+    /// The id of the image.
+    image_id: Uuid,
+
+    /// The unix timestamp the image was uploaded.
+    uploaded_at: i64,
+
+    /// The crc32 checksum of the uploaded image.
+    checksum: u32,
+
+    /// The size of the uploaded image in bytes.
+    size: u64,
+
+    /// The tags the image was uploaded with.
+    tags: Vec<String>,
+
+    /// The filename the image was uploaded with, if known.
+    original_filename: Option<String>,
+
+    /// The identifier of who uploaded the image, if given.
+    uploader: Option<String>,
+}
+
+impl ImageMetadata {
+    pub fn new(image_id: Uuid, record: ImageRecord) -> Self {
+        Self {
+            image_id,
+            uploaded_at: record.uploaded_at,
+            checksum: record.checksum,
+            size: record.size,
+            tags: record.tags,
+            original_filename: record.original_filename,
+            uploader: record.uploader,
+        }
+    }
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct ListedImage {
+    /// The id of the image.
+    image_id: Uuid,
+
+    /// The combined size in bytes of the image's stored originals.
+    size: u64,
+
+    /// The unix timestamp the image was stored at.
+    created_at: i64,
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct ImageListing {
+    /// The images of the page in ascending order of their id.
+    images: Vec<ListedImage>,
+
+    /// The cursor to fetch the next page with, if there are more images.
+    next_cursor: Option<Uuid>,
+}
+
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq)]
+#[oai(rename_all = "lowercase")]
+pub enum UploadJobStatus {
+    /// The image is being processed.
+    Processing,
+
+    /// The processed images are being persisted.
+    Storing,
+
+    /// The upload has completed.
+    Complete,
+
+    /// The upload failed.
+    Failed,
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct UploadJobInfo {
+    /// The id of the upload job.
+    job_id: Uuid,
+
+    /// The current status of the upload.
+    status: UploadJobStatus,
+
+    /// The upload info once the upload has completed.
+    upload: Option<UploadInfo>,
+
+    /// The reason the upload failed if applicable.
+    error: Option<String>,
+}
+
+impl UploadInfo {
+    #[inline]
+    pub fn image_id(&self) -> Uuid {
+        self.image_id
+    }
+
+    #[inline]
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+}
+
+impl UploadJobInfo {
+    #[inline]
+    pub fn job_id(&self) -> Uuid {
+        self.job_id
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct UploadOptions {
+    /// A unique key identifying the upload.
+    ///
+    /// Retries with the same key resolve to the same image.
+    pub idempotency_key: Option<String>,
+
+    /// The tags to index the image under.
+    pub tags: Vec<String>,
+
+    /// The preset and format pairs to generate in the background once
+    /// the image is stored, rather than on the first fetch.
+    pub pregenerate: Vec<(String, ImageKind)>,
+
+    /// The filename the image was uploaded with.
+    pub original_filename: Option<String>,
+
+    /// The identifier of who uploaded the image.
+    pub uploader: Option<String>,
+
+    /// The id the image should be stored under rather than a generated one.
+    pub image_id: Option<Uuid>,
+}
+
+/// An upload's custom image id which is already used by another image.
+#[derive(Debug)]
+pub struct ImageIdConflict(pub Uuid);
+
+impl std::fmt::Display for ImageIdConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "An image already exists with the id {}", self.0)
+    }
+}
+
+impl std::error::Error for ImageIdConflict {}
+
+pub enum UploadOutcome {
+    /// The upload completed within the bucket's `async_upload_threshold`.
+    Complete(UploadInfo),
+
+    /// The upload is still being completed in the background.
+    Pending(UploadJobInfo),
+}
+
+pub enum ReplaceOutcome {
+    /// The image's content was replaced.
+    Replaced(UploadInfo),
+
+    /// The image does not exist.
+    NotFound,
+
+    /// The image did not meet the given precondition.
+    PreconditionFailed,
+}
+
+/// An image served by a fetch.
+pub struct FetchedImage {
+    pub data: Bytes,
+    pub kind: ImageKind,
+
+    /// If the image is an expired cached copy served as fetching
+    /// it from the storage backend failed.
+    pub stale: bool,
+}
+
+pub struct BucketController {
+    name: String,
+    bucket_id: u32,
+    cache: Option<Arc<Cache>>,
+    placeholder: Option<Placeholder>,
+    global_limiter: Arc<ConcurrencyLimiter>,
+    config: BucketConfig,
+    pipeline: PipelineController,
+    storage: Arc<dyn StorageBackend>,
+    limiter: ConcurrencyLimiter,
+    idempotent_uploads: moka::future::Cache<String, UploadInfo>,
+    egress: EgressTracker,
+    upload_jobs: moka::sync::Cache<Uuid, UploadJobInfo>,
+    metadata: MetadataStore,
+    access_stats: Option<AccessStats>,
+    last_access: Option<LastAccess>,
+    tombstones: Tombstones,
+    trash: Option<Trash>,
+    quarantine: Option<Quarantine>,
+    api_keys: ManagedKeys,
+    index: Option<ImageIndex>,
+    lookups: Option<LookupCache>,
+    cdn: Option<CdnPurger>,
+    changes: Option<ChangeLog>,
+    purge_jobs: moka::sync::Cache<Uuid, PurgeJobInfo>,
+    write_locks: Vec<tokio::sync::Mutex<()>>,
+    custom_id_pattern: Option<regex::Regex>,
+    ids: IdGenerator,
+}
+
+impl BucketController {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        bucket_id: u32,
+        cache: Option<Cache>,
+        placeholder: Option<Placeholder>,
+        global_limiter: Arc<ConcurrencyLimiter>,
+        config: BucketConfig,
+        pipeline: PipelineController,
+        storage: Arc<dyn StorageBackend>,
+    ) -> Self {
+        Self {
+            cdn: config.cdn.as_ref().map(|cfg| CdnPurger::new(&name, cfg)),
+            name,
+            bucket_id,
+            cache: cache.map(Arc::new),
+            placeholder,
+            global_limiter,
+            limiter: ConcurrencyLimiter::new(
+                config.max_concurrency,
+                config.max_upload_concurrency,
+                config.max_fetch_concurrency,
+                crate::config::config().permit_weights,
+            ),
+            idempotent_uploads: moka::future::Cache::builder()
+                .max_capacity(MAX_IDEMPOTENCY_KEYS)
+                .time_to_live(Duration::from_secs(config.idempotency_key_ttl))
+                .build(),
+            upload_jobs: moka::sync::Cache::builder()
+                .max_capacity(MAX_UPLOAD_JOBS)
+                .time_to_live(UPLOAD_JOB_TTL)
+                .build(),
+            egress: EgressTracker::new(
+                config.egress_limit
+                    .as_ref()
+                    .map(|v| v.max_monthly_egress * 1024 * 1024),
+            ),
+            access_stats: config.access_stats.map(|_| AccessStats::default()),
+            last_access: config.lifecycle.as_ref().map(|_| LastAccess::default()),
+            tombstones: Tombstones::default(),
+            trash: config.soft_delete.as_ref().map(|_| Trash::default()),
+            quarantine: config.quarantine.as_ref().map(Quarantine::new),
+            api_keys: ManagedKeys::default(),
+            index: config.index.map(|_| ImageIndex::default()),
+            lookups: config.lookup_cache.as_ref().map(LookupCache::new),
+            changes: config.change_log.as_ref().map(ChangeLog::new),
+            purge_jobs: moka::sync::Cache::builder()
+                .max_capacity(MAX_PURGE_JOBS)
+                .time_to_live(PURGE_JOB_TTL)
+                .build(),
+            write_locks: (0..WRITE_LOCK_STRIPES)
+                .map(|_| tokio::sync::Mutex::new(()))
+                .collect(),
+            ids: IdGenerator::new(config.id_scheme),
+            custom_id_pattern: config.custom_ids
+                .as_ref()
+                .and_then(|v| v.slug_pattern.as_deref())
+                .map(|pattern| regex::Regex::new(pattern).expect("The slug pattern is validated with the config")),
+            metadata: MetadataStore::new(bucket_id, storage.clone()),
+            config,
+            pipeline,
+            storage,
+        }
+    }
+    
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn cfg(&self) -> &BucketConfig {
+        &self.config
+    }
+
+    /// Resolves the custom id an upload should be stored under.
+    ///
+    /// UUIDs are used as is, slugs matching the bucket's `slug_pattern` are
+    /// mapped to a UUID derived from the bucket and slug.
+    pub fn resolve_custom_id(&self, id: &str) -> anyhow::Result<Uuid> {
+        if self.config.custom_ids.is_none() {
+            return Err(anyhow!("Custom image ids are not enabled for this bucket."))
+        }
+
+        if let Ok(image_id) = Uuid::parse_str(id) {
+            return Ok(image_id)
+        }
+
+        match self.custom_id_pattern {
+            None => Err(anyhow!("The custom image id must be a UUID.")),
+            Some(ref pattern) if !pattern.is_match(id) => {
+                Err(anyhow!("The custom image id does not match the bucket's slug pattern."))
+            },
+            Some(_) => {
+                let name = format!("{}/{}", self.bucket_id, id);
+                Ok(Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes()))
+            },
+        }
+    }
+
+    #[inline]
+    pub fn bucket_id(&self) -> u32 {
+        self.bucket_id
+    }
+
+    /// The storage backend of the bucket, letting tests inspect what
+    /// has been persisted without going through the API.
+    #[cfg(any(test, feature = "testing"))]
+    #[inline]
+    pub fn storage(&self) -> &Arc<dyn StorageBackend> {
+        &self.storage
+    }
+
+    #[inline]
+    pub fn placeholder(&self) -> Option<&Placeholder> {
+        self.placeholder.as_ref()
+    }
+
+    #[inline]
+    pub fn egress_exceeded(&self) -> bool {
+        self.egress.is_exceeded()
+    }
+
+    #[inline]
+    pub fn upload_job(&self, job_id: Uuid) -> Option<UploadJobInfo> {
+        self.upload_jobs.get(&job_id)
+    }
+
+    /// If any API keys are configured globally or for the bucket,
+    /// or have been created for the bucket via the admin API.
+    pub async fn has_api_keys(&self) -> anyhow::Result<bool> {
+        if !crate::config::config().api_keys.is_empty() || !self.config.api_keys.is_empty() {
+            return Ok(true)
+        }
+
+        self.api_keys.any(&self.metadata).await
+    }
+
+    /// If the key is one of the configured keys or a managed key which has not expired.
+    pub async fn is_api_key(&self, key: &str) -> anyhow::Result<bool> {
+        let configured = crate::config::config().api_keys
+            .iter()
+            .chain(self.config.api_keys.iter())
+            .fold(false, |matched, configured| key_matches(configured, key) | matched);
+
+        if configured {
+            return Ok(true)
+        }
+
+        self.api_keys.matches(&self.metadata, key).await
+    }
+
+    pub async fn api_keys(&self) -> anyhow::Result<Vec<ApiKeyInfo>> {
+        self.api_keys.list(&self.metadata).await
+    }
+
+    /// Creates a managed API key, returning the key which is only available now.
+    pub async fn create_api_key(
+        &self,
+        name: Option<String>,
+        expires_in: Option<u64>,
+    ) -> anyhow::Result<(ApiKeyInfo, String)> {
+        self.api_keys.create(&self.metadata, name, expires_in).await
+    }
+
+    /// Revokes the managed API key, returning `false` if it does not exist.
+    pub async fn revoke_api_key(&self, key_id: &str) -> anyhow::Result<bool> {
+        self.api_keys.revoke(&self.metadata, key_id).await
+    }
+
+    /// Persists any buffered metadata of the bucket.
+    pub async fn flush_metadata(&self) -> anyhow::Result<()> {
+        if let Some(ref stats) = self.access_stats {
+            stats.flush(&self.metadata).await?;
+        }
+
+        if let Some(ref last_access) = self.last_access {
+            last_access.flush(&self.metadata).await?;
+        }
+
+        if let Some(ref index) = self.index {
+            index.flush(&self.metadata).await?;
+        }
+
+        if let Some(ref changes) = self.changes {
+            changes.flush(&self.metadata).await?;
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_indexed(&self) -> bool {
+        self.index.is_some()
+    }
+
+    /// The index record of the image, served from the lookup cache if enabled.
+    ///
+    /// Returns `None` if the bucket is not indexed or the image is not indexed.
+    pub async fn image_record(&self, image_id: Uuid) -> anyhow::Result<Option<ImageRecord>> {
+        let index = match self.index {
+            None => return Ok(None),
+            Some(ref index) => index,
+        };
+
+        if let Some(ref lookups) = self.lookups {
+            let cached = lookups.record(image_id);
+            self.record_lookup("metadata", cached.is_some());
+            if let Some(record) = cached {
+                return Ok(record)
+            }
+        }
+
+        let record = index.record(&self.metadata, image_id).await?;
+        if let Some(ref lookups) = self.lookups {
+            lookups.insert_record(image_id, record.clone());
+        }
+
+        Ok(record)
+    }
+
+    /// The indexed images of the bucket.
+    ///
+    /// Returns `None` if the bucket is not indexed.
+    pub async fn indexed_images(&self) -> anyhow::Result<Option<HashMap<Uuid, ImageRecord>>> {
+        match self.index {
+            None => Ok(None),
+            Some(ref index) => index.records(&self.metadata).await.map(Some),
+        }
+    }
+
+    /// The stored variants of the image.
+    ///
+    /// Returns `None` if the image has been deleted or is in the trash.
+    pub async fn live_variants(&self, image_id: Uuid) -> anyhow::Result<Option<Vec<(u32, ImageKind)>>> {
+        if self.tombstones.contains(&self.metadata, image_id).await? {
+            return Ok(None)
+        }
+
+        let variants = self.storage.list_variants(self.bucket_id, image_id).await?;
+        Ok(Some(variants).filter(|variants| !variants.is_empty()))
+    }
+
+    /// The changes to the bucket after the cursor, oldest first.
+    ///
+    /// Returns `None` if the bucket has no `change_log`.
+    pub async fn changes_since(&self, cursor: u64, limit: usize) -> anyhow::Result<Option<ChangesSince>> {
+        match self.changes {
+            None => Ok(None),
+            Some(ref changes) => changes.since(&self.metadata, cursor, limit).await.map(Some),
+        }
+    }
+
+    /// The cursor of the bucket's most recent change.
+    ///
+    /// Returns `None` if the bucket has no `change_log`.
+    pub async fn latest_change(&self) -> anyhow::Result<Option<u64>> {
+        match self.changes {
+            None => Ok(None),
+            Some(ref changes) => changes.latest(&self.metadata).await.map(Some),
+        }
+    }
+
+    #[inline]
+    pub fn purge_job(&self, job_id: Uuid) -> Option<PurgeJobInfo> {
+        self.purge_jobs.get(&job_id)
+    }
+
+    #[inline]
+    pub fn update_purge_job(&self, job: PurgeJobInfo) {
+        self.purge_jobs.insert(job.job_id(), job);
+    }
+
+    /// The stored original of the image, bypassing the bucket's `serve_original`.
+    ///
+    /// Returns `None` if the image or its original does not exist.
+    pub async fn original(&self, image_id: Uuid) -> anyhow::Result<Option<(Bytes, ImageKind)>> {
+        if self.tombstones.contains(&self.metadata, image_id).await? {
+            return Ok(None)
+        }
+
+        self.fetch_original(image_id).await
+    }
+
+    /// The fetch counts of the bucket's images.
+    ///
+    /// Returns `None` if access stats are not enabled for the bucket.
+    pub async fn access_counts(&self) -> anyhow::Result<Option<HashMap<Uuid, u64>>> {
+        match self.access_stats {
+            None => Ok(None),
+            Some(ref stats) => stats.counts(&self.metadata).await.map(Some),
+        }
+    }
+
+    /// The access records of the bucket's images.
+    ///
+    /// Returns `None` if the bucket has no lifecycle rules.
+    pub async fn access_records(&self) -> anyhow::Result<Option<HashMap<Uuid, AccessRecord>>> {
+        match self.last_access {
+            None => Ok(None),
+            Some(ref last_access) => last_access.records(&self.metadata).await.map(Some),
+        }
+    }
+
+    pub async fn upload(
+        &self,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+    ) -> anyhow::Result<UploadOutcome> {
+        let threshold = match self.config.async_upload_threshold {
+            None => {
+                return self.tracked_upload(kind, data, options, None)
+                    .await
+                    .map(UploadOutcome::Complete)
+            },
+            Some(threshold) => Duration::from_millis(threshold),
+        };
+
+        // The upload is ran as a tracked background task so it can outlive
+        // the request if it exceeds the threshold.
+        let job_id = Uuid::new_v4();
+        self.set_job_status(job_id, UploadJobStatus::Processing);
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let bucket_id = self.bucket_id;
+        let priority = crate::admission::current_priority();
+        crate::background::spawn(crate::admission::with_priority(priority, async move {
+            let bucket = match get_bucket_by_id(bucket_id) {
+                Some(bucket) => bucket,
+                None => return,
+            };
+
+            let result = bucket.tracked_upload(kind, data, options, Some(job_id)).await;
+            bucket.finish_job(job_id, &result);
+            let _ = tx.send(result);
+        }));
+
+        match tokio::time::timeout(threshold, rx).await {
+            Ok(Ok(result)) => {
+                self.upload_jobs.invalidate(&job_id);
+                result.map(UploadOutcome::Complete)
+            },
+            Ok(Err(_)) => Err(anyhow!("Upload job {} was cancelled.", job_id)),
+            Err(_) => {
+                let job = self.upload_job(job_id)
+                    .ok_or_else(|| anyhow!("Upload job {} is no longer tracked.", job_id))?;
+                Ok(UploadOutcome::Pending(job))
+            },
+        }
+    }
+
+    async fn tracked_upload(
+        &self,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        job_id: Option<Uuid>,
+    ) -> anyhow::Result<UploadInfo> {
+        let start = Instant::now();
+        let result = self.idempotent_upload(kind, data, options, job_id).await;
+        self.record_request("upload", start, result.as_ref().map(|_| true));
+
+        result
+    }
+
+    async fn idempotent_upload(
+        &self,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        job_id: Option<Uuid>,
+    ) -> anyhow::Result<UploadInfo> {
+        if let Some(image_id) = options.image_id {
+            return self.upload_custom(image_id, kind, data, options, job_id).await
+        }
+
+        let key = match options.idempotency_key.clone() {
+            None => return self.upload_as(self.ids.generate(), kind, data, options, job_id).await,
+            Some(key) => key,
+        };
+
+        // Retries with the same key always resolve to the same image id, even
+        // once the original attempt is no longer remembered, so at worst the
+        // image is re-processed and overwritten rather than duplicated.
+        let name = format!("{}:{}", self.bucket_id, key);
+        let image_id = Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes());
+
+        if self.config.idempotency_key_ttl == 0 {
+            return self.upload_retry(image_id, kind, data, options, job_id).await
+        }
+
+        self.idempotent_uploads
+            .try_get_with(key, self.upload_retry(image_id, kind, data, options, job_id))
+            .await
+            .map_err(|e| unshare_error(&e))
+    }
+
+    /// Uploads an idempotent upload under its derived id.
+    ///
+    /// Once the key is forgotten a retry re-processes the upload under the
+    /// id of the image the original attempt stored, so a failed retry only
+    /// rolls back the image if it did not already exist.
+    async fn upload_retry(
+        &self,
+        image_id: Uuid,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        job_id: Option<Uuid>,
+    ) -> anyhow::Result<UploadInfo> {
+        let _guard = self.write_lock(image_id).lock().await;
+
+        let exists = !self.storage.list_variants(self.bucket_id, image_id).await?.is_empty()
+            && !self.tombstones.contains(&self.metadata, image_id).await?;
+
+        self.upload_with(image_id, kind, data, options, job_id, !exists).await
+    }
+
+    /// Uploads the image under the id chosen by the client, failing
+    /// with an [`ImageIdConflict`] if the id is already in use.
+    ///
+    /// A retry with the same idempotency key returns the original upload
+    /// info rather than conflicting with the image it created.
+    async fn upload_custom(
+        &self,
+        image_id: Uuid,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        job_id: Option<Uuid>,
+    ) -> anyhow::Result<UploadInfo> {
+        let key = options.idempotency_key.clone();
+        if let Some(info) = key.as_ref().and_then(|key| self.idempotent_uploads.get(key)) {
+            return Ok(info)
+        }
+
+        let _guard = self.write_lock(image_id).lock().await;
+
+        // A concurrent retry may have stored the image while this one
+        // waited for the lock, so this is re-checked before conflicting.
+        if let Some(info) = key.as_ref().and_then(|key| self.idempotent_uploads.get(key)) {
+            return Ok(info)
+        }
+
+        let exists = !self.storage.list_variants(self.bucket_id, image_id).await?.is_empty()
+            && !self.tombstones.contains(&self.metadata, image_id).await?;
+        if exists {
+            return Err(ImageIdConflict(image_id).into())
+        }
+
+        let info = self.upload_as(image_id, kind, data, options, job_id).await?;
+        if let Some(key) = key {
+            self.idempotent_uploads.insert(key, info.clone()).await;
+        }
+
+        Ok(info)
+    }
+
+    async fn upload_as(
+        &self,
+        image_id: Uuid,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        job_id: Option<Uuid>,
+    ) -> anyhow::Result<UploadInfo> {
+        self.upload_with(image_id, kind, data, options, job_id, true).await
+    }
+
+    async fn upload_with(
+        &self,
+        image_id: Uuid,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        job_id: Option<Uuid>,
+        rollback: bool,
+    ) -> anyhow::Result<UploadInfo> {
+        let (info, _) = self.store_upload(image_id, kind, data, options, job_id, rollback).await?;
+        self.set_etag(image_id, info.checksum).await;
+        self.record_change(ChangeKind::Created, image_id);
+
+        Ok(info)
+    }
+
+    /// Stores the checksum of the image's current content as its `etag`.
+    async fn set_etag(&self, image_id: Uuid, checksum: u32) {
+        // A missing checksum only causes conditional writes to be rejected.
+        if let Err(e) = crate::etags::set(&self.metadata, image_id, checksum).await {
+            warn!("Failed to store the checksum of image {}: {}", image_id, e);
+        }
+    }
+
+    /// Appends the change to the bucket's `change_log` if enabled.
+    fn record_change(&self, kind: ChangeKind, image_id: Uuid) {
+        if let Some(ref changes) = self.changes {
+            changes.record(kind, image_id);
+        }
+    }
+
+    /// Processes and stores the image under the given id, returning
+    /// the upload info and the variants which were stored.
+    ///
+    /// The caller stores the image's `etag` once the upload is visible.
+    ///
+    /// If `rollback` is set every variant of the image is removed
+    /// if it could not be stored completely.
+    async fn store_upload(
+        &self,
+        image_id: Uuid,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        job_id: Option<Uuid>,
+        rollback: bool,
+    ) -> anyhow::Result<(UploadInfo, Vec<(u32, ImageKind)>)> {
+        let mut options = options;
+        let pregenerate = std::mem::take(&mut options.pregenerate);
+
+        debug!("Uploading processed image with kind: {:?} and is {} bytes in size.", kind, data.len());
+
+        // Refused before any processing so the backend never runs out
+        // of space part way through storing the variants.
+        self.storage.check_capacity().await?;
+        let permit = get_optional_permit(&self.name, &self.global_limiter, &self.limiter, Operation::Upload, data.len()).await?;
+        let reservation = reserve_processing_memory(&data).await?;
+
+        let processing_start = Instant::now();
+        let checksum = crc32fast::hash(&data);
+        let size = data.len() as u64;
+        let pipeline = self.pipeline.clone();
+        let detect_duplicates = self.duplicates_config().is_some();
+        let limits = crate::processor::decode_limits(self.config.decode_limits);
+        let quarantined = self.quarantine.as_ref().map(|_| Bytes::copy_from_slice(&data));
+        let result = self.run_pipeline("upload", (permit, reservation), move || {
+            // The hash is taken before the pipeline consumes the upload.
+            let phash = if detect_duplicates {
+                crate::processor::decode(&data, kind, &limits)
+                    .map(|img| crate::processor::phash::perceptual_hash(&img))
+                    .ok()
+            } else {
+                None
+            };
+
+            pipeline.on_upload(kind, data).map(|result| (result, phash))
+        }).await;
+        let ((result, phash), _permits) = match (result, quarantined) {
+            (Err(e), Some(data)) if is_quarantinable(&e) => {
+                self.quarantine_upload(data, Some(kind), &e).await;
+                return Err(e)
+            },
+            (result, _) => result?,
+        };
+        let processing_time = processing_start.elapsed();
+
+        if let Some(job_id) = job_id {
+            self.set_job_status(job_id, UploadJobStatus::Storing);
+        }
+
+        // Idempotent uploads can re-use the id of a deleted image.
+        self.tombstones.remove(&self.metadata, image_id).await?;
+
+        let stored: Vec<(u32, ImageKind)> = result.result.to_store
+            .iter()
+            .map(|entry| (entry.sizing_id, entry.kind))
+            .collect();
+
+        let io_start = Instant::now();
+        let image_upload_info = match self.concurrent_upload(image_id, result.result.to_store).await {
+            Ok(info) => info,
+            Err(e) => {
+                if rollback {
+                    self.rollback_upload(image_id).await;
+                }
+                return Err(e)
+            },
+        };
+        let io_time = io_start.elapsed();
+
+        if let Some(ref last_access) = self.last_access {
+            last_access.uploaded(image_id);
+        }
+
+        let possible_duplicates = match phash {
+            None => vec![],
+            Some(phash) => self.possible_duplicates(image_id, phash).await,
+        };
+
+        if let Some(ref index) = self.index {
+            index.insert(image_id, ImageRecord {
+                uploaded_at: chrono::Utc::now().timestamp(),
+                checksum,
+                size,
+                tags: options.tags,
+                original_filename: options.original_filename,
+                uploader: options.uploader,
+                phash,
+            });
+        }
+        self.invalidate_lookups(image_id);
+
+        let info = UploadInfo {
+            checksum,
+            image_id,
+            bucket_id: self.bucket_id,
+            images: image_upload_info,
+            processing_time: processing_time.as_secs_f32(),
+            io_time: io_time.as_secs_f32(),
+            possible_duplicates,
+        };
+
+        if !pregenerate.is_empty() {
+            self.pregenerate(image_id, pregenerate);
+        }
+
+        Ok((info, stored))
+    }
+
+    fn duplicates_config(&self) -> Option<DuplicatesConfig> {
+        self.config.index.and_then(|index| index.duplicates)
+    }
+
+    /// The indexed images whose perceptual hash is within the bucket's
+    /// `max_distance` of the given hash, most similar first.
+    ///
+    /// Duplicates are only a hint, so the upload isn't failed if
+    /// the index can't be read.
+    async fn possible_duplicates(&self, image_id: Uuid, phash: u64) -> Vec<Uuid> {
+        let max_distance = match self.duplicates_config() {
+            None => return vec![],
+            Some(cfg) => cfg.max_distance,
+        };
+
+        let records = match self.indexed_images().await {
+            Ok(Some(records)) => records,
+            Ok(None) => return vec![],
+            Err(e) => {
+                warn!("Failed to read the index to find duplicates of image {}: {}", image_id, e);
+                return vec![]
+            },
+        };
+
+        let mut duplicates: Vec<(u32, Uuid)> = records
+            .into_iter()
+            .filter(|(id, _)| *id != image_id)
+            .filter_map(|(id, record)| {
+                let distance = crate::processor::phash::distance(phash, record.phash?);
+                (distance <= max_distance).then_some((distance, id))
+            })
+            .collect();
+        duplicates.sort_unstable();
+
+        duplicates
+            .into_iter()
+            .take(MAX_POSSIBLE_DUPLICATES)
+            .map(|(_, id)| id)
+            .collect()
+    }
+
+    /// Generates the given variants of the image in the background.
+    ///
+    /// AOT buckets already generate every variant at upload time and
+    /// realtime buckets never store variants, so this is a no-op for both.
+    fn pregenerate(&self, image_id: Uuid, variants: Vec<(String, ImageKind)>) {
+        if self.config.mode != ProcessingMode::Jit {
+            return
+        }
+
+        let bucket_id = self.bucket_id;
+        crate::background::spawn(async move {
+            let bucket = match get_bucket_by_id(bucket_id) {
+                Some(bucket) => bucket,
+                None => return,
+            };
+
+            for (preset, kind) in variants {
+                if let Err(e) = bucket.fetch_variant(image_id, kind, Some(preset.clone()), None, false).await {
+                    error!("Failed to pregenerate the {} {:?} variant of image {}: {}", preset, kind, image_id, e);
+                }
+            }
+        });
+    }
+
+    /// Replaces the content of an existing image.
+    ///
+    /// If a precondition is given the image is only replaced if it is met.
+    pub async fn replace(
+        &self,
+        image_id: Uuid,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        precondition: Option<Precondition>,
+    ) -> anyhow::Result<ReplaceOutcome> {
+        let start = Instant::now();
+        let result = self.replace_image(image_id, kind, data, options, precondition).await;
+        self.record_request(
+            "replace",
+            start,
+            result.as_ref().map(|v| matches!(v, ReplaceOutcome::Replaced(_))),
+        );
+
+        result
+    }
+
+    async fn replace_image(
+        &self,
+        image_id: Uuid,
+        kind: ImageKind,
+        data: Vec<u8>,
+        options: UploadOptions,
+        precondition: Option<Precondition>,
+    ) -> anyhow::Result<ReplaceOutcome> {
+        let _guard = self.write_lock(image_id).lock().await;
+
+        let existing = self.storage.list_variants(self.bucket_id, image_id).await?;
+        if existing.is_empty() || self.tombstones.contains(&self.metadata, image_id).await? {
+            return Ok(ReplaceOutcome::NotFound)
+        }
+
+        if let Some(precondition) = precondition {
+            let checksum = crate::etags::get(&self.metadata, image_id).await?;
+            if !precondition.matches(true, checksum) {
+                return Ok(ReplaceOutcome::PreconditionFailed)
+            }
+        }
+
+        let (info, stored) = self.store_upload(image_id, kind, data, options, None, false).await?;
+
+        // Variants generated from the previous content which were not overwritten
+        // are removed so they're regenerated from the new original.
+        for (sizing_id, kind) in existing.iter().copied().filter(|v| !stored.contains(v)) {
+            self.storage.delete_variant(self.bucket_id, image_id, kind, sizing_id).await?;
+        }
+        self.invalidate_cache(image_id, existing);
+
+        // The etag is only bumped once none of the previous variants can be
+        // served, so the CDN never revalidates stale content under the new etag.
+        self.set_etag(image_id, info.checksum).await;
+        if let Some(ref cdn) = self.cdn {
+            cdn.purge(image_id, Some(info.checksum), PurgeMode::Soft);
+        }
+        self.record_change(ChangeKind::Replaced, image_id);
+
+        Ok(ReplaceOutcome::Replaced(info))
+    }
+
+    /// Fetches the image, generating the variant if required.
+    ///
+    /// If `accept_compressed` is set, pre-compressed variants are
+    /// returned as is within their zstd frame.
+    pub async fn fetch(
+        &self,
+        image_id: Uuid,
+        desired_kind: ImageKind,
+        size_preset: Option<String>,
+        custom_sizing: Option<(u32, u32)>,
+        accept_compressed: bool,
+    ) -> anyhow::Result<Option<FetchedImage>> {
+        let start = Instant::now();
+        let result = self.fetch_variant(
+            image_id,
+            desired_kind,
+            size_preset,
+            custom_sizing,
+            accept_compressed,
+        ).await;
+        self.record_request("fetch", start, result.as_ref().map(|v| v.is_some()));
+
+        if let Ok(Some(ref entry)) = result {
+            let served = entry.data.len() as u64;
+            self.egress.record(served);
+            if let Some(ref stats) = self.access_stats {
+                stats.record(image_id);
+            }
+            if let Some(ref last_access) = self.last_access {
+                last_access.touch(image_id);
+            }
+            crate::metrics::SERVED_BYTES
+                .with_label_values(&[&self.name])
+                .inc_by(served);
+        }
+
+        result
+    }
+
+    async fn fetch_variant(
+        &self,
+        image_id: Uuid,
+        desired_kind: ImageKind,
+        size_preset: Option<String>,
+        custom_sizing: Option<(u32, u32)>,
+        accept_compressed: bool,
+    ) -> anyhow::Result<Option<FetchedImage>> {
+        debug!(
+            "Fetching image with image_id: {}, desired_kind: {:?}, preset: {:?}, custom_sizing: {:?}.",
+            image_id, desired_kind, &size_preset, &custom_sizing,
+        );
+
+        // While the storage backend's circuit breaker is open cached copies are
+        // still served, tombstones are cached briefly so this only matters
+        // if the image's tombstone hasn't been looked up recently.
+        match self.tombstones.contains(&self.metadata, image_id).await {
+            Ok(true) => return Ok(None),
+            Ok(false) => {},
+            Err(e) if e.is::<StorageUnavailable>() => {},
+            Err(e) => return Err(e),
+        }
+
+        // Clients polling for an image to exist are answered without
+        // hitting the storage backend until the lookup expires.
+        if let Some(ref lookups) = self.lookups {
+            let missing = lookups.is_missing(image_id);
+            self.record_lookup("existence", missing);
+            if missing {
+                return Ok(None)
+            }
+        }
+
+        let sizing = size_preset
+            .map(Some)
+            .unwrap_or_else(|| self.config.default_serving_preset.clone());
+
+        let sizing_id = if let Some(sizing_preset) = sizing {
+          if sizing_preset == "original" {
+              0
+          } else {
+              crate::utils::crc_hash(sizing_preset)
+          }
+        } else {
+            0
+        };
+
+        // In real time situations
+        let fetch_kind = if self.config.mode == ProcessingMode::Realtime {
+            self.config.formats.original_image_store_format
+        } else {
+            desired_kind
+        };
+
+        // Cache hits are served without acquiring a permit so they are
+        // never queued behind expensive uncached work.
+        //
+        // Only stored variants are served compressed, realtime buckets
+        // always process the original.
+        let accept_compressed = accept_compressed && self.config.mode != ProcessingMode::Realtime;
+        if self.config.mode != ProcessingMode::Realtime {
+            let maybe_cached = self.cache_backend().and_then(|cache| {
+                let compressed = if accept_compressed {
+                    cache.get(&self.compressed_cache_key(sizing_id, image_id, fetch_kind))
+                } else {
+                    None
+                };
+
+                compressed.or_else(|| cache.get(&self.cache_key(sizing_id, image_id, fetch_kind)))
+            });
+
+            if let Some(data) = maybe_cached {
+                self.record_cache_lookup(true);
+                return Ok(Some(FetchedImage { data, kind: fetch_kind, stale: false }))
+            }
+        }
+
+        let result = self.fetch_uncached(
+            image_id,
+            desired_kind,
+            fetch_kind,
+            sizing_id,
+            custom_sizing,
+            accept_compressed,
+        ).await;
+
+        let e = match result {
+            Ok(entry) => {
+                return Ok(entry.map(|entry| FetchedImage { data: entry.data, kind: entry.kind, stale: false }))
+            },
+            Err(e) => e,
+        };
+
+        // Expired copies are only kept by the cache during the `stale_if_error`
+        // grace period, realtime buckets only cache the original so are excluded.
+        let maybe_stale = self.cache_backend()
+            .filter(|_| self.config.mode != ProcessingMode::Realtime)
+            .and_then(|cache| {
+                let compressed = if accept_compressed {
+                    cache.get_stale(&self.compressed_cache_key(sizing_id, image_id, fetch_kind))
+                } else {
+                    None
+                };
+
+                compressed.or_else(|| cache.get_stale(&self.cache_key(sizing_id, image_id, fetch_kind)))
+            });
+
+        match maybe_stale {
+            None => Err(e),
+            Some(data) => {
+                warn!("Failed to fetch image {}, serving a stale cached copy: {}", image_id, e);
+                Ok(Some(FetchedImage { data, kind: fetch_kind, stale: true }))
+            },
+        }
+    }
+
+    /// Fetches the variant from the storage backend, generating
+    /// it from the original if the bucket's mode requires.
+    async fn fetch_uncached(
+        &self,
+        image_id: Uuid,
+        desired_kind: ImageKind,
+        fetch_kind: ImageKind,
+        sizing_id: u32,
+        custom_sizing: Option<(u32, u32)>,
+        accept_compressed: bool,
+    ) -> anyhow::Result<Option<StoreEntry>> {
+        let permit = get_optional_permit(&self.name, &self.global_limiter, &self.limiter, Operation::Fetch, 0).await?;
+
+        let maybe_existing = self.caching_fetch(
+            image_id,
+            fetch_kind,
+            if self.config.mode == ProcessingMode::Realtime { 0 } else { sizing_id },
+            accept_compressed,
+        ).await?;
+
+        let (data, retrieved_kind) = match maybe_existing {
+            // Existing variants are served as is to avoid spawning additional
+            // threads, only realtime buckets need to process the original.
+            Some(computed) if self.config.mode != ProcessingMode::Realtime => {
+                return Ok(Some(StoreEntry { data: computed, kind: fetch_kind, sizing_id }))
+            },
+            Some(computed) => (computed, fetch_kind),
+            // If we're in JIT mode we want to re-encode the image and store it.
+            //
+            // AOT buckets should already have every variant, but a partially failed
+            // upload or a preset added after the upload can leave holes, so we
+            // backfill them from the original and persist them like JIT does.
+            None if self.config.mode != ProcessingMode::Realtime => {
+                match self.fetch_original(image_id).await? {
+                    None => {
+                        self.mark_missing(image_id);
+                        return Ok(None)
+                    },
+                    Some((original, kind)) => {
+                        if self.config.mode == ProcessingMode::Aot {
+                            warn!(
+                                "Image {} is missing the {:?} variant for sizing id {}, regenerating from original.",
+                                image_id, desired_kind, sizing_id,
+                            );
+                        }
+
+                        (original, kind)
+                    },
+                }
+            },
+            None => {
+                self.mark_missing(image_id);
+                return Ok(None)
+            },
+        };
+
+        // The stored copy has been read, generating the variant is
+        // queued again at a lower priority.
+        drop(permit);
+        let permit = get_processing_permit(&self.name, &self.global_limiter, &self.limiter).await?;
+
+        let reservation = reserve_processing_memory(&data).await?;
+        let pipeline = self.pipeline.clone();
+        let (result, _permits) = self.run_pipeline("fetch", (permit, reservation), move || {
+            pipeline.on_fetch(desired_kind, retrieved_kind, data, sizing_id, custom_sizing)
+        }).await?;
+
+        // Generated variants are persisted in the background so the response
+        // isn't held up by the storage backend, these are tracked so they
+        // can complete during a graceful shutdown.
+        //
+        // Buckets not persisting variants only keep them in the cache.
+        let to_store = result.result.to_store;
+
+        // Presets skipped by the processing rules are served from the original
+        // without storing anything, so they're cached to avoid re-processing
+        // the original on every fetch.
+        if let (true, Some(response), Some(cache)) = (to_store.is_empty(), &result.result.response, self.cache_backend()) {
+            if response.kind == fetch_kind && self.config.mode != ProcessingMode::Realtime {
+                cache.insert(self.cache_key(sizing_id, image_id, fetch_kind), response.data.clone());
+            }
+        }
+
+        if !to_store.is_empty() && !self.config.jit.persist_variants {
+            if let Some(cache) = self.cache_backend() {
+                for entry in to_store {
+                    cache.insert(self.cache_key(entry.sizing_id, image_id, entry.kind), entry.data);
+                }
+            }
+        } else if !to_store.is_empty() {
+            let bucket_id = self.bucket_id;
+            crate::background::spawn(async move {
+                let bucket = match get_bucket_by_id(bucket_id) {
+                    Some(bucket) => bucket,
+                    None => return,
+                };
+
+                // The image may have been deleted while the variant was being generated.
+                match bucket.tombstones.contains(&bucket.metadata, image_id).await {
+                    Ok(false) => {},
+                    Ok(true) => return,
+                    Err(e) => {
+                        error!("Failed to check the tombstone of image {}: {}", image_id, e);
+                        return
+                    },
+                }
+
+                if let Err(e) = bucket.concurrent_upload(image_id, to_store).await {
+                    error!("Failed to persist generated variants of image {}: {}", image_id, e);
+                }
+            });
+        }
+
+        Ok(result.result.response)
+    }
+
+    pub async fn delete(&self, image_id: Uuid) -> anyhow::Result<DeleteInfo> {
+        let start = Instant::now();
+        let result = {
+            let _guard = self.write_lock(image_id).lock().await;
+            self.delete_image(image_id).await
+        };
+        self.record_request("delete", start, result.as_ref().map(|_| true));
+
+        result
+    }
+
+    /// Deletes the image if it meets the given precondition.
+    ///
+    /// Returns `None` if the precondition was not met.
+    pub async fn delete_if(
+        &self,
+        image_id: Uuid,
+        precondition: &Precondition,
+    ) -> anyhow::Result<Option<DeleteInfo>> {
+        let start = Instant::now();
+        let result = self.conditional_delete(image_id, precondition).await;
+        self.record_request("delete", start, result.as_ref().map(|v| v.is_some()));
+
+        result
+    }
+
+    async fn conditional_delete(
+        &self,
+        image_id: Uuid,
+        precondition: &Precondition,
+    ) -> anyhow::Result<Option<DeleteInfo>> {
+        let _guard = self.write_lock(image_id).lock().await;
+
+        let exists = !self.storage.list_variants(self.bucket_id, image_id).await?.is_empty()
+            && !self.tombstones.contains(&self.metadata, image_id).await?;
+        let checksum = crate::etags::get(&self.metadata, image_id).await?;
+        if !precondition.matches(exists, checksum) {
+            return Ok(None)
+        }
+
+        self.delete_image(image_id).await.map(Some)
+    }
+
+    /// Restores a soft deleted image from the trash.
+    ///
+    /// Returns `false` if the image is not in the trash.
+    pub async fn restore(&self, image_id: Uuid) -> anyhow::Result<bool> {
+        let trash = match self.trash {
+            None => return Ok(false),
+            Some(ref trash) => trash,
+        };
+
+        let start = Instant::now();
+        let result = async {
+            let _guard = self.write_lock(image_id).lock().await;
+            if !trash.contains(&self.metadata, image_id).await? {
+                return Ok(false)
+            }
+
+            // The trash entry is only dropped once the image is visible again, so
+            // a failed restore can be retried and is never purged half restored.
+            self.tombstones.remove(&self.metadata, image_id).await?;
+            trash.remove(&self.metadata, image_id).await?;
+            self.invalidate_lookups(image_id);
+            self.record_change(ChangeKind::Created, image_id);
+            Ok(true)
+        }.await;
+        self.record_request("restore", start, result.as_ref().map(|v| *v));
+
+        result
+    }
+
+    /// Copies the image into the destination bucket, running its stored
+    /// original through the destination's pipeline.
+    ///
+    /// The copy keeps the image's id if `keep_id` is set, otherwise an id is
+    /// generated by the destination. The indexed metadata of the image is
+    /// carried over if both buckets have an `index`.
+    ///
+    /// Returns `None` if the image or its original does not exist.
+    pub async fn copy_to(
+        &self,
+        image_id: Uuid,
+        destination: &BucketController,
+        keep_id: bool,
+    ) -> anyhow::Result<Option<UploadInfo>> {
+        let (data, kind) = match self.original(image_id).await? {
+            None => return Ok(None),
+            Some(original) => original,
+        };
+
+        let mut options = UploadOptions {
+            image_id: keep_id.then_some(image_id),
+            ..Default::default()
+        };
+        if let Some(mut records) = self.indexed_images().await? {
+            if let Some(record) = records.remove(&image_id) {
+                options.tags = record.tags;
+                options.original_filename = record.original_filename;
+                options.uploader = record.uploader;
+            }
+        }
+
+        destination.tracked_upload(kind, data.to_vec(), options, None)
+            .await
+            .map(Some)
+    }
+
+    /// Copies the image into the destination bucket as with [`Self::copy_to`],
+    /// deleting it from this bucket once the copy is stored.
+    pub async fn move_to(
+        &self,
+        image_id: Uuid,
+        destination: &BucketController,
+        keep_id: bool,
+    ) -> anyhow::Result<Option<UploadInfo>> {
+        let info = match self.copy_to(image_id, destination, keep_id).await? {
+            None => return Ok(None),
+            Some(info) => info,
+        };
+
+        self.delete(image_id).await?;
+        Ok(Some(info))
+    }
+
+    /// Keeps the payload of a failed upload if the bucket has a `quarantine`.
+    ///
+    /// Failures to quarantine the upload are only logged so the
+    /// upload's own error is what's returned to the client.
+    pub async fn quarantine_upload(&self, data: Bytes, format: Option<ImageKind>, error: &(dyn std::fmt::Display + Sync)) {
+        let quarantine = match self.quarantine {
+            None => return,
+            Some(ref quarantine) => quarantine,
+        };
+
+        let format = format.map(|kind| kind.as_file_extension().to_string());
+        match quarantine.insert(&self.metadata, data, format, error.to_string()).await {
+            Ok(Some(upload_id)) => info!("Quarantined failed upload {} of bucket {}: {}", upload_id, self.name, error),
+            Ok(None) => debug!("Failed upload of bucket {} is too large to quarantine.", self.name),
+            Err(e) => error!("Failed to quarantine a failed upload of bucket {}: {}", self.name, e),
+        }
+    }
+
+    /// The uploads in the bucket's quarantine.
+    ///
+    /// Returns `None` if the bucket has no `quarantine`.
+    pub async fn quarantined_uploads(&self) -> anyhow::Result<Option<Vec<QuarantinedUpload>>> {
+        match self.quarantine {
+            None => Ok(None),
+            Some(ref quarantine) => quarantine.list(&self.metadata).await.map(Some),
+        }
+    }
+
+    /// The payload of the quarantined upload, `None` if it does not exist.
+    pub async fn quarantined_payload(&self, upload_id: Uuid) -> anyhow::Result<Option<Bytes>> {
+        match self.quarantine {
+            None => Ok(None),
+            Some(ref quarantine) => quarantine.payload(&self.metadata, upload_id).await,
+        }
+    }
+
+    /// Removes the upload from the quarantine, returning if it existed.
+    pub async fn remove_quarantined(&self, upload_id: Uuid) -> anyhow::Result<bool> {
+        match self.quarantine {
+            None => Ok(false),
+            Some(ref quarantine) => quarantine.remove(&self.metadata, upload_id).await,
+        }
+    }
+
+    /// Permanently removes the trashed images which have been in
+    /// the trash for longer than the bucket's retention period.
+    pub async fn reap_trash(&self) -> anyhow::Result<Vec<Uuid>> {
+        let (trash, retention) = match (&self.trash, &self.config.soft_delete) {
+            (Some(trash), Some(cfg)) => (trash, cfg.retention),
+            _ => return Ok(vec![]),
+        };
+
+        let before = chrono::Utc::now().timestamp() - retention as i64;
+        let mut purged = vec![];
+        for image_id in trash.expired(&self.metadata, before).await? {
+            let _guard = self.write_lock(image_id).lock().await;
+            if !trash.contains(&self.metadata, image_id).await? {
+                continue
+            }
+
+            // Images re-uploaded under the same id since are kept.
+            if self.tombstones.contains(&self.metadata, image_id).await? {
+                self.purge_image(image_id).await?;
+                purged.push(image_id);
+            }
+            trash.remove(&self.metadata, image_id).await?;
+        }
+
+        Ok(purged)
     }
 
-    if let Some(limiter) = local {
-        return Ok(Some(limiter.acquire().await?))
+    async fn delete_image(&self, image_id: Uuid) -> anyhow::Result<DeleteInfo> {
+        let info = match self.trash {
+            None => self.purge_image(image_id).await?,
+            Some(ref trash) => self.trash_image(trash, image_id).await?,
+        };
+
+        if !info.removed.is_empty() {
+            if let Some(ref cdn) = self.cdn {
+                cdn.purge(image_id, None, PurgeMode::Hard);
+            }
+            self.record_change(ChangeKind::Deleted, image_id);
+        }
+
+        Ok(info)
     }
 
-    Ok(None)
-}
+    /// Moves the image to the trash, hiding it behind a tombstone
+    /// while its variants are kept so it can be restored.
+    async fn trash_image(&self, trash: &Trash, image_id: Uuid) -> anyhow::Result<DeleteInfo> {
+        debug!("Moving image {} to the trash", image_id);
 
+        let existing = self.storage.list_variants(self.bucket_id, image_id).await?;
+        if existing.is_empty() || trash.contains(&self.metadata, image_id).await? {
+            return Ok(DeleteInfo { removed: vec![] })
+        }
 
-#[derive(Object, Debug)]
-pub struct ImageUploadInfo {
-    /// The computed image sizing id.
-    ///
-    /// This is useful for tracking files outside of lust as this is
-    /// generally used for filtering within the storage systems.
-    sizing_id: u32,
-}
+        // The variants of a permanent delete which previously failed part way
+        // through are not restorable, so the delete is completed instead.
+        if self.tombstones.contains(&self.metadata, image_id).await? {
+            return self.purge_image(image_id).await
+        }
 
-#[derive(Object, Debug)]
-pub struct UploadInfo {
-    /// The generated ID for the file.
+        trash.insert(&self.metadata, image_id).await?;
+        self.tombstones.insert(&self.metadata, image_id).await?;
+        self.invalidate_cache(image_id, existing.clone());
+
+        Ok(DeleteInfo {
+            removed: existing
+                .into_iter()
+                .map(|(sizing_id, kind)| VariantInfo { sizing_id, kind })
+                .collect(),
+        })
+    }
+
+    /// Removes every variant of the image which exists in the storage backend.
     ///
-    /// This can be used to access the file for the given bucket.
-    image_id: Uuid,
+    /// The backend is checked again once the variants are removed and cached
+    /// copies are only invalidated for the variants confirmed to be gone.
+    async fn purge_image(&self, image_id: Uuid) -> anyhow::Result<DeleteInfo> {
+        debug!("Removing image {}", image_id);
 
-    /// The time spent processing the image in seconds.
-    processing_time: f32,
+        let _permit = get_optional_permit(&self.name, &self.global_limiter, &self.limiter, Operation::Upload, 0).await?;
+        let existing = self.storage.list_variants(self.bucket_id, image_id).await?;
 
-    /// The time spent uploading the image to the persistent store.
-    io_time: f32,
+        // The tombstone is written before any variants are removed so a
+        // partially deleted image is never regenerated from what remains.
+        if !existing.is_empty() {
+            self.tombstones.insert(&self.metadata, image_id).await?;
+        }
 
-    /// The crc32 checksum of the uploaded image.
-    checksum: u32,
+        for (sizing_id, kind) in existing.iter().copied() {
+            self.storage.delete_variant(self.bucket_id, image_id, kind, sizing_id).await?;
+        }
 
-    /// The information that is specific to the image.
-    images: Vec<ImageUploadInfo>,
+        let remaining = self.storage.list_variants(self.bucket_id, image_id).await?;
+        let removed: Vec<(u32, ImageKind)> = existing
+            .into_iter()
+            .filter(|variant| !remaining.contains(variant))
+            .collect();
+        self.invalidate_cache(image_id, removed.clone());
 
-    /// The id of the bucket the image was stored in.
-    ///
-    /// This is useful for tracking files outside of lust as this is
-    /// generally used for filtering within the storage systems.
-    bucket_id: u32,
-}
+        if !remaining.is_empty() {
+            return Err(anyhow!(
+                "Failed to remove {} variants of image {}: {:?}",
+                remaining.len(), image_id, remaining,
+            ))
+        }
 
-pub struct BucketController {
-    bucket_id: u32,
-    cache: Option<Arc<Cache>>,
-    global_limiter: Option<Arc<Semaphore>>,
-    config: BucketConfig,
-    pipeline: PipelineController,
-    storage: Arc<dyn StorageBackend>,
-    limiter: Option<Semaphore>,
-}
+        if let Some(ref last_access) = self.last_access {
+            last_access.remove(image_id);
+        }
 
-impl BucketController {
-    pub fn new(
-        bucket_id: u32,
-        cache: Option<Cache>,
-        global_limiter: Option<Arc<Semaphore>>,
-        config: BucketConfig,
-        pipeline: PipelineController,
-        storage: Arc<dyn StorageBackend>,
-    ) -> Self {
-        Self {
-            bucket_id,
-            cache: cache.map(Arc::new),
-            global_limiter,
-            limiter: config.max_concurrency.map(Semaphore::new),
-            config,
-            pipeline,
-            storage,
+        if let Some(ref index) = self.index {
+            index.remove(image_id);
         }
+
+        crate::etags::remove(&self.metadata, image_id).await?;
+
+        Ok(DeleteInfo {
+            removed: removed
+                .into_iter()
+                .map(|(sizing_id, kind)| VariantInfo { sizing_id, kind })
+                .collect(),
+        })
     }
-    
-    #[inline]
-    pub fn cfg(&self) -> &BucketConfig {
-        &self.config
+
+    /// Removes every stored variant of the image except the original.
+    ///
+    /// The variants are regenerated from the original if the image is fetched again.
+    pub async fn drop_variants(&self, image_id: Uuid) -> anyhow::Result<()> {
+        debug!("Dropping the generated variants of image {}", image_id);
+
+        let _permit = get_optional_permit(&self.name, &self.global_limiter, &self.limiter, Operation::Upload, 0).await?;
+        if self.remove_generated_variants(image_id).await?.is_none() {
+            return Ok(())
+        }
+
+        if let Some(ref last_access) = self.last_access {
+            last_access.mark_tiered_down(image_id);
+        }
+
+        Ok(())
     }
 
-    pub async fn upload(&self, kind: ImageKind, data: Vec<u8>) -> anyhow::Result<UploadInfo> {
-        debug!("Uploading processed image with kind: {:?} and is {} bytes in size.", kind, data.len());
+    /// Removes every stored variant of the image except the original
+    /// and re-stores the original compressed with zstd.
+    ///
+    /// The original is transparently decompressed if the image is fetched again.
+    pub async fn archive(&self, image_id: Uuid) -> anyhow::Result<()> {
+        debug!("Archiving image {}", image_id);
 
-        let _permit = get_optional_permit(&self.global_limiter, &self.limiter).await?;
+        let _permit = get_optional_permit(&self.name, &self.global_limiter, &self.limiter, Operation::Upload, 0).await?;
+        let (original, kind) = match self.remove_generated_variants(image_id).await? {
+            None => return Ok(()),
+            Some(original) => original,
+        };
 
-        let processing_start = Instant::now();
-        let checksum = crc32fast::hash(&data);
-        let pipeline = self.pipeline.clone();
-        let result = tokio::task::spawn_blocking(move || {
-            pipeline.on_upload(kind, data)
+        let compressed = tokio::task::spawn_blocking(move || {
+            crate::processor::compression::compress(&original, ARCHIVE_COMPRESSION_LEVEL)
         }).await??;
-        let processing_time = processing_start.elapsed();
+        self.storage.store(self.bucket_id, image_id, kind, 0, compressed).await?;
 
-        let image_id = Uuid::new_v4();
-        let io_start = Instant::now();
-        let image_upload_info = self.concurrent_upload(image_id, result.result.to_store).await?;
-        let io_time = io_start.elapsed();
+        if let Some(ref last_access) = self.last_access {
+            last_access.mark_archived(image_id);
+        }
 
-        Ok(UploadInfo {
-            checksum,
-            image_id,
-            bucket_id: self.bucket_id,
-            images: image_upload_info,
-            processing_time: processing_time.as_secs_f32(),
-            io_time: io_time.as_secs_f32(),
-        })
+        Ok(())
     }
 
-    pub async fn fetch(
-        &self,
-        image_id: Uuid,
-        desired_kind: ImageKind,
-        size_preset: Option<String>,
-        custom_sizing: Option<(u32, u32)>,
-    ) -> anyhow::Result<Option<StoreEntry>> {
-        debug!(
-            "Fetching image with image_id: {}, desired_kind: {:?}, preset: {:?}, custom_sizing: {:?}.",
-            image_id, desired_kind, &size_preset, &custom_sizing,
-        );
+    /// Lists up to `limit` of the bucket's images after the given cursor.
+    ///
+    /// Trashed and deleted images are skipped, so the backend is listed
+    /// until the page is full or there are no more images.
+    pub async fn list(&self, cursor: Option<Uuid>, limit: usize) -> anyhow::Result<ImageListing> {
+        // One extra image is collected to tell if there's another page.
+        let mut images = Vec::with_capacity(limit + 1);
+        let mut after = cursor;
+        loop {
+            let wanted = limit + 1 - images.len();
+            let stored = self.storage.list_ids(self.bucket_id, after, wanted).await?;
+            let exhausted = stored.len() < wanted;
+            after = stored.last().map(|image| image.image_id).or(after);
 
-        let _permit = get_optional_permit(&self.global_limiter, &self.limiter).await?;
+            for image in stored {
+                let trashed = match self.trash {
+                    None => false,
+                    Some(ref trash) => trash.contains(&self.metadata, image.image_id).await?,
+                };
+                if trashed || self.tombstones.contains(&self.metadata, image.image_id).await? {
+                    continue
+                }
 
-        let sizing = size_preset
-            .map(Some)
-            .unwrap_or_else(|| self.config.default_serving_preset.clone());
+                images.push(ListedImage {
+                    image_id: image.image_id,
+                    size: image.size,
+                    created_at: image.stored_at,
+                });
+            }
 
-        let sizing_id = if let Some(sizing_preset) = sizing {
-          if sizing_preset == "original" {
-              0
-          } else {
-              crate::utils::crc_hash(sizing_preset)
-          }
+            if exhausted || images.len() > limit {
+                break
+            }
+        }
+
+        let next_cursor = if images.len() > limit {
+            images.truncate(limit);
+            images.last().map(|image| image.image_id)
         } else {
-            0
+            None
         };
 
-        // In real time situations
-        let fetch_kind = if self.config.mode == ProcessingMode::Realtime {
-            self.config.formats.original_image_store_format
-        } else {
-            desired_kind
+        Ok(ImageListing { images, next_cursor })
+    }
+}
+
+impl BucketController {
+    /// Records the outcome of a request against the bucket's metrics.
+    fn record_request(&self, operation: &str, start: Instant, found: Result<bool, &anyhow::Error>) {
+        let status = match found {
+            Ok(true) => "ok",
+            Ok(false) => "not_found",
+            Err(_) => "error",
         };
 
-        let maybe_existing = self.caching_fetch(
-            image_id,
-            fetch_kind,
-            if self.config.mode == ProcessingMode::Realtime { 0 } else { sizing_id },
-        ).await?;
+        crate::metrics::REQUESTS
+            .with_label_values(&[&self.name, operation, status])
+            .inc();
+        crate::metrics::REQUEST_DURATION
+            .with_label_values(&[&self.name, operation])
+            .observe(start.elapsed().as_secs_f64());
+    }
 
-        let (data, retrieved_kind) = match maybe_existing {
-            // If we're in JIT mode we want to re-encode the image and store it.
-            None => if self.config.mode == ProcessingMode::Jit {
-                let base_kind = self.config.formats.original_image_store_format;
-                let value = self.caching_fetch(
-                    image_id,
-                    base_kind,
-                    0,
-                ).await?;
+    fn set_job_status(&self, job_id: Uuid, status: UploadJobStatus) {
+        self.upload_jobs.insert(job_id, UploadJobInfo {
+            job_id,
+            status,
+            upload: None,
+            error: None,
+        });
+    }
 
-                match value {
-                    None => return Ok(None),
-                    Some(original) => (original, base_kind)
-                }
-            } else {
-                return Ok(None)
+    fn finish_job(&self, job_id: Uuid, result: &anyhow::Result<UploadInfo>) {
+        let job = match result {
+            Ok(info) => UploadJobInfo {
+                job_id,
+                status: UploadJobStatus::Complete,
+                upload: Some(info.clone()),
+                error: None,
+            },
+            Err(e) => UploadJobInfo {
+                job_id,
+                status: UploadJobStatus::Failed,
+                upload: None,
+                error: Some(e.to_string()),
             },
-            Some(computed) => (computed, fetch_kind),
         };
 
-        // Small optimisation here when in AOT mode to avoid
-        // spawning additional threads.
-        if self.config.mode == ProcessingMode::Aot {
-            return Ok(Some(StoreEntry { data, kind: retrieved_kind, sizing_id }))
-        }
+        self.upload_jobs.insert(job_id, job);
+    }
 
-        let pipeline = self.pipeline.clone();
-        let result = tokio::task::spawn_blocking(move || {
-            pipeline.on_fetch(desired_kind, retrieved_kind, data, sizing_id, custom_sizing)
-        }).await??;
+    fn record_cache_lookup(&self, hit: bool) {
+        crate::metrics::CACHE_REQUESTS
+            .with_label_values(&[&self.name, if hit { "hit" } else { "miss" }])
+            .inc();
+    }
+
+    fn record_lookup(&self, lookup: &str, hit: bool) {
+        crate::metrics::LOOKUP_CACHE_REQUESTS
+            .with_label_values(&[&self.name, lookup, if hit { "hit" } else { "miss" }])
+            .inc();
+    }
 
-        self.concurrent_upload(image_id, result.result.to_store).await?;
+    #[inline]
+    fn cache_key(&self, sizing_id: u32, image_id: Uuid, kind: ImageKind) -> String {
+         format!(
+            "{bucket}:{sizing}:{image}:{kind}",
+            bucket = self.bucket_id,
+            sizing = sizing_id,
+            image = image_id,
+            kind = kind.as_file_extension(),
+        )
+    }
 
-        Ok(result.result.response)
+    #[inline]
+    fn cache_backend(&self) -> Option<&Cache> {
+        self.cache
+            .as_ref()
+            .map(|v| Some(v.as_ref()))
+            .unwrap_or_else(global_cache)
     }
 
-    pub async fn delete(&self, image_id: Uuid) -> anyhow::Result<()> {
-        debug!("Removing image {}", image_id);
+    /// The lock replaces and deletes of the image are serialized by.
+    #[inline]
+    fn write_lock(&self, image_id: Uuid) -> &tokio::sync::Mutex<()> {
+        &self.write_locks[(image_id.as_u128() % WRITE_LOCK_STRIPES as u128) as usize]
+    }
+
+    /// The cache key of the variant while still within its zstd frame.
+    fn compressed_cache_key(&self, sizing_id: u32, image_id: Uuid, kind: ImageKind) -> String {
+        format!("{}:zst", self.cache_key(sizing_id, image_id, kind))
+    }
 
-        let _permit = get_optional_permit(&self.global_limiter, &self.limiter).await?;
-        let purged_entities = self.storage.delete(self.bucket_id, image_id).await?;
+    /// Invalidates the cached copies of every preset of the image, used when
+    /// the image is changed by another instance sharing the storage backend.
+    ///
+    /// Cached copies of custom sizes are left to expire.
+    pub(crate) fn invalidate_image(&self, image_id: Uuid) {
+        let entries = self.config.presets
+            .keys()
+            .map(crate::utils::crc_hash)
+            .chain(std::iter::once(0))
+            .flat_map(|sizing_id| {
+                ImageKind::variants()
+                    .iter()
+                    .map(move |kind| (sizing_id, *kind))
+            })
+            .collect();
+
+        self.invalidate_cache(image_id, entries);
+    }
 
-        if let Some(ref cache) = self.cache {
-            for (sizing_id, kind) in purged_entities {
+    fn invalidate_cache(&self, image_id: Uuid, entries: Vec<(u32, ImageKind)>) {
+        if let Some(cache) = self.cache_backend() {
+            for (sizing_id, kind) in entries {
                 let cache_key = self.cache_key(sizing_id, image_id, kind);
                 cache.invalidate(&cache_key);
+                cache.invalidate(&self.compressed_cache_key(sizing_id, image_id, kind));
+            }
+
+            // Skipped presets are only ever cached so are never listed by the backend.
+            if self.config.processing_rules.skip_upscaling {
+                for preset in self.config.presets.keys() {
+                    let sizing_id = crate::utils::crc_hash(preset);
+                    for kind in [ImageKind::Png, ImageKind::Jpeg, ImageKind::Webp, ImageKind::Gif] {
+                        cache.invalidate(&self.cache_key(sizing_id, image_id, kind));
+                    }
+                }
             }
         }
 
-        Ok(())
+        self.invalidate_lookups(image_id);
     }
-}
 
-impl BucketController {
     #[inline]
-    fn cache_key(&self, sizing_id: u32, image_id: Uuid, kind: ImageKind) -> String {
-         format!(
-            "{bucket}:{sizing}:{image}:{kind}",
-            bucket = self.bucket_id,
-            sizing = sizing_id,
-            image = image_id,
-            kind = kind.as_file_extension(),
-        )
+    fn invalidate_lookups(&self, image_id: Uuid) {
+        if let Some(ref lookups) = self.lookups {
+            lookups.invalidate(image_id);
+        }
+    }
+
+    /// Removes any variants of a partially stored upload so the image
+    /// is never left half uploaded and served inconsistently.
+    async fn rollback_upload(&self, image_id: Uuid) {
+        warn!("Failed to store all variants of image {}, rolling back upload.", image_id);
+
+        match self.storage.delete(self.bucket_id, image_id).await {
+            Ok(purged_entities) => self.invalidate_cache(image_id, purged_entities),
+            Err(e) => error!("Failed to roll back the upload of image {}: {}", image_id, e),
+        }
+    }
+
+    /// Removes every stored variant of the image except the original,
+    /// returning the original if the image exists.
+    async fn remove_generated_variants(
+        &self,
+        image_id: Uuid,
+    ) -> anyhow::Result<Option<(Bytes, ImageKind)>> {
+        let (original, original_kind) = match self.fetch_original(image_id).await? {
+            None => return Ok(None),
+            Some(original) => original,
+        };
+
+        // Cached copies of variants which were never stored may
+        // still exist, so every preset is invalidated.
+        let existing = self.storage.list_variants(self.bucket_id, image_id).await?;
+        for (sizing_id, kind) in existing {
+            if sizing_id == 0 && kind == original_kind {
+                continue
+            }
+
+            self.storage.delete_variant(self.bucket_id, image_id, kind, sizing_id).await?;
+        }
+        self.invalidate_image(image_id);
+
+        Ok(Some((original, original_kind)))
     }
 
+    /// Fetches the variant from the cache or the storage backend.
+    ///
+    /// Compressed variants are decompressed unless `keep_compressed` is set.
     async fn caching_fetch(
         &self,
         image_id: Uuid,
         fetch_kind: ImageKind,
         sizing_id: u32,
+        keep_compressed: bool,
     ) -> anyhow::Result<Option<Bytes>> {
-        let maybe_cache_backend = self.cache
-            .as_ref()
-            .map(|v| Some(v.as_ref()))
-            .unwrap_or_else(global_cache);
+        let maybe_cache_backend = self.cache_backend();
 
         let cache_key = self.cache_key(sizing_id, image_id, fetch_kind);
+        let compressed_cache_key = self.compressed_cache_key(sizing_id, image_id, fetch_kind);
 
         if let Some(cache) = maybe_cache_backend {
-            if let Some(buffer) = cache.get(&cache_key) {
+            let maybe_buffer = if keep_compressed {
+                cache.get(&compressed_cache_key).or_else(|| cache.get(&cache_key))
+            } else {
+                cache.get(&cache_key)
+            };
+            self.record_cache_lookup(maybe_buffer.is_some());
+
+            if let Some(buffer) = maybe_buffer {
                 return Ok(Some(buffer))
             }
         }
@@ -273,6 +2101,24 @@ impl BucketController {
             sizing_id
         ).await?;
 
+        let maybe_existing = match maybe_existing {
+            Some(buffer) if keep_compressed && crate::processor::compression::is_compressed(&buffer) => {
+                if let Some(cache) = maybe_cache_backend {
+                    cache.insert(compressed_cache_key, buffer.clone());
+                }
+
+                return Ok(Some(buffer))
+            },
+            Some(buffer) if crate::processor::compression::is_compressed(&buffer) => {
+                let buffer = tokio::task::spawn_blocking(move || {
+                    crate::processor::compression::maybe_decompress(buffer)
+                }).await??;
+
+                Some(buffer)
+            },
+            other => other,
+        };
+
         if let Some(cache) = maybe_cache_backend {
             if let Some(ref buffer) = maybe_existing {
                 cache.insert(cache_key, buffer.clone());
@@ -282,6 +2128,73 @@ impl BucketController {
         Ok(maybe_existing)
     }
 
+    /// Fetches the stored original of the image.
+    ///
+    /// AOT buckets store the original in every enabled format so each is
+    /// tried, preferring the configured original store format.
+    /// Caches the image as not existing, this must only be called once
+    /// the image's original is known to be missing rather than a variant.
+    fn mark_missing(&self, image_id: Uuid) {
+        if let Some(ref lookups) = self.lookups {
+            lookups.insert_missing(image_id);
+        }
+    }
+
+    async fn fetch_original(
+        &self,
+        image_id: Uuid,
+    ) -> anyhow::Result<Option<(Bytes, ImageKind)>> {
+        let base_kind = self.config.formats.original_image_store_format;
+        if self.config.mode != ProcessingMode::Aot {
+            let value = self.caching_fetch(image_id, base_kind, 0, false).await?;
+            return Ok(value.map(|v| (v, base_kind)))
+        }
+
+        let candidates = std::iter::once(base_kind)
+            .chain(ImageKind::variants().iter().copied().filter(|k| *k != base_kind))
+            .filter(|k| self.config.formats.is_enabled(*k));
+
+        for kind in candidates {
+            if let Some(original) = self.caching_fetch(image_id, kind, 0, false).await? {
+                return Ok(Some((original, kind)))
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Runs the pipeline job on the blocking pool, failing with a
+    /// `ProcessingError::Timeout` if it exceeds the bucket's `processing_timeout`.
+    ///
+    /// Timed out jobs are left to finish in the background as blocking
+    /// tasks cannot be cancelled, their result is discarded.
+    ///
+    /// The permits are held by the job and handed back once it completes,
+    /// so a timed out job keeps its share of the limits until it finishes.
+    async fn run_pipeline<T: Send + 'static, P: Send + 'static>(
+        &self,
+        stage: &'static str,
+        permits: P,
+        job: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<(T, P)> {
+        let handle = crate::processor::pool::run(move || (job(), permits));
+        let timeout = match self.config.processing_timeout {
+            None => {
+                let (result, permits) = handle.await?;
+                return Ok((result?, permits))
+            },
+            Some(timeout) => Duration::from_millis(timeout),
+        };
+
+        match tokio::time::timeout(timeout, handle).await {
+            Ok(result) => {
+                let (result, permits) = result?;
+                Ok((result?, permits))
+            },
+            Err(_) => Err(ProcessingError::Timeout { stage }.into()),
+        }
+    }
+
     async fn concurrent_upload(
         &self,
         image_id: Uuid,
@@ -299,14 +2212,33 @@ impl BucketController {
                 image_id,
                 store_entry.kind,
             );
+            let original_compression = if store_entry.sizing_id == 0 {
+                self.config.original_compression.map(|c| c.level)
+            } else {
+                None
+            };
+            let compression_level = original_compression.or_else(|| {
+                self.config.precompression
+                    .as_ref()
+                    .and_then(|c| c.level_for(store_entry.kind, store_entry.data.len()))
+            });
+
+            let t = async move {
+                let data = if let Some(level) = compression_level {
+                    let data = store_entry.data.clone();
+                    tokio::task::spawn_blocking(move || {
+                        crate::processor::compression::compress(&data, level)
+                    }).await??
+                } else {
+                    store_entry.data.clone()
+                };
 
-            let t = tokio::spawn(async move {
                 storage.store(
                     bucket_id,
                     image_id,
                     store_entry.kind,
                     store_entry.sizing_id,
-                    store_entry.data.clone(),
+                    data,
                 ).await?;
 
                 if let Some(ref cache) = cache {
@@ -314,16 +2246,35 @@ impl BucketController {
                 }
 
                 Ok::<_, anyhow::Error>(())
-            });
+            };
 
             tasks.push(t);
         }
 
-        for task in tasks {
-            task.await??;
+        let fan_out = self.config
+            .max_store_concurrency
+            .unwrap_or(tasks.len())
+            .max(1);
+
+        // Every task is awaited before bailing so no writes are left in
+        // flight when the caller decides to roll back.
+        let results: Vec<_> = futures::stream::iter(tasks)
+            .buffer_unordered(fan_out)
+            .collect()
+            .await;
+        self.invalidate_lookups(image_id);
+
+        for result in results {
+            result?;
         }
 
         Ok(image_upload_info)
     }
 }
 
+/// Compares the configured key and given key in constant time,
+/// only leaking whether their lengths are equal.
+pub(crate) fn key_matches(configured: &str, key: &str) -> bool {
+    configured.len() == key.len()
+        && configured.bytes().zip(key.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
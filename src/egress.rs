@@ -0,0 +1,66 @@
+use std::sync::Mutex;
+
+use chrono::{Datelike, Utc};
+
+/// Tracks the amount of image data served by a bucket
+/// within the current calendar month.
+///
+/// Usage is held in memory so is reset when the server restarts.
+pub struct EgressTracker {
+    limit: Option<u64>,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Default)]
+struct Usage {
+    period: u32,
+    served: u64,
+}
+
+impl EgressTracker {
+    /// Creates a new tracker with an optional limit in bytes.
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            usage: Mutex::new(Usage {
+                period: current_period(),
+                served: 0,
+            }),
+        }
+    }
+
+    /// Records the given number of bytes as served.
+    pub fn record(&self, bytes: u64) {
+        let period = current_period();
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.period != period {
+            *usage = Usage { period, served: 0 };
+        }
+
+        usage.served = usage.served.saturating_add(bytes);
+    }
+
+    /// The number of bytes served within the current month.
+    pub fn served(&self) -> u64 {
+        let usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.period != current_period() {
+            return 0
+        }
+
+        usage.served
+    }
+
+    /// If the bucket has served at least its limit for the current month.
+    pub fn is_exceeded(&self) -> bool {
+        match self.limit {
+            None => false,
+            Some(limit) => self.served() >= limit,
+        }
+    }
+}
+
+/// A unique identifier for the current calendar month (UTC).
+fn current_period() -> u32 {
+    let now = Utc::now();
+    now.year() as u32 * 12 + now.month0()
+}
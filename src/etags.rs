@@ -0,0 +1,70 @@
+use uuid::Uuid;
+
+use crate::metadata::MetadataStore;
+
+/// The metadata document the checksum of the image is stored in.
+///
+/// Each image has its own document so conditional writes never
+/// need to load the checksums of the entire bucket.
+fn etag_key(image_id: Uuid) -> String {
+    format!("etags/{}", image_id)
+}
+
+/// The checksum of the image's current content.
+///
+/// Returns `None` for images uploaded before checksums were tracked.
+pub async fn get(store: &MetadataStore, image_id: Uuid) -> anyhow::Result<Option<u32>> {
+    store.load(&etag_key(image_id)).await
+}
+
+pub async fn set(store: &MetadataStore, image_id: Uuid, checksum: u32) -> anyhow::Result<()> {
+    store.save(&etag_key(image_id), &checksum).await
+}
+
+pub async fn remove(store: &MetadataStore, image_id: Uuid) -> anyhow::Result<()> {
+    store.remove(&etag_key(image_id)).await
+}
+
+/// Formats the checksum as a strong `etag` header value.
+pub fn format_etag(checksum: u32) -> String {
+    format!("\"{:08x}\"", checksum)
+}
+
+/// The condition a replace or delete requires the image to meet, as
+/// given by the `if-match` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precondition {
+    /// The image must exist.
+    Exists,
+
+    /// The image's checksum must be one of the given values.
+    OneOf(Vec<u32>),
+}
+
+impl Precondition {
+    pub fn parse(if_match: &str) -> Self {
+        if if_match.trim() == "*" {
+            return Self::Exists
+        }
+
+        // Weak tags never match as `if-match` requires a strong comparison.
+        let checksums = if_match
+            .split(',')
+            .map(str::trim)
+            .filter_map(|tag| tag.strip_prefix('"')?.strip_suffix('"'))
+            .filter_map(|tag| u32::from_str_radix(tag, 16).ok())
+            .collect();
+
+        Self::OneOf(checksums)
+    }
+
+    /// If an image with the given state meets the condition.
+    pub fn matches(&self, exists: bool, checksum: Option<u32>) -> bool {
+        match self {
+            Self::Exists => exists,
+            Self::OneOf(expected) => checksum
+                .map(|checksum| exists && expected.contains(&checksum))
+                .unwrap_or(false),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::controller::BucketController;
+use crate::index::ImageRecord;
+
+#[derive(Serialize)]
+struct ExportedVariant {
+    /// The sizing id the variant is stored under.
+    sizing_id: u32,
+
+    /// The preset the variant was resized to, `original` for the
+    /// original image or `custom` for a custom size.
+    preset: String,
+
+    /// The format the variant is encoded in.
+    format: &'static str,
+}
+
+/// A line of the bucket export.
+#[derive(Serialize)]
+struct ExportedImage {
+    image_id: Uuid,
+
+    /// When the image was uploaded, formatted as RFC 3339.
+    uploaded_at: String,
+
+    /// The crc32 checksum of the uploaded image.
+    checksum: u32,
+
+    /// The size of the uploaded image in bytes.
+    size: u64,
+
+    tags: Vec<String>,
+    original_filename: Option<String>,
+    uploader: Option<String>,
+
+    /// The stored variants of the image.
+    variants: Vec<ExportedVariant>,
+}
+
+/// A page of the bucket's indexed images in ascending order of their id.
+pub struct ExportPage {
+    pub images: Vec<(Uuid, ImageRecord)>,
+
+    /// The cursor to fetch the next page with, if there are more images.
+    pub next_cursor: Option<Uuid>,
+}
+
+impl ExportPage {
+    /// Takes the page of at most `limit` images after the `cursor`.
+    pub fn new(records: HashMap<Uuid, ImageRecord>, cursor: Option<Uuid>, limit: usize) -> Self {
+        let mut images: Vec<_> = records
+            .into_iter()
+            .filter(|(image_id, _)| cursor.map(|cursor| *image_id > cursor).unwrap_or(true))
+            .collect();
+        images.sort_by_key(|(image_id, _)| *image_id);
+
+        let next_cursor = if images.len() > limit {
+            images.truncate(limit);
+            images.last().map(|(image_id, _)| *image_id)
+        } else {
+            None
+        };
+
+        Self { images, next_cursor }
+    }
+}
+
+/// Streams the page as newline-delimited JSON, one image per line.
+///
+/// The variants of each image are listed from the storage backend as
+/// the line is written, images deleted since the page was taken are skipped.
+pub fn stream(
+    bucket: &'static BucketController,
+    page: ExportPage,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send {
+    let presets: HashMap<u32, String> = bucket.cfg()
+        .presets
+        .keys()
+        .map(|name| (crate::utils::crc_hash(name), name.clone()))
+        .collect();
+
+    futures::stream::iter(page.images)
+        .then(move |(image_id, record)| {
+            let presets = presets.clone();
+            async move {
+                let variants = match bucket.live_variants(image_id).await {
+                    Ok(None) => return Ok(None),
+                    Ok(Some(variants)) => variants,
+                    Err(e) => {
+                        error!("Failed to list the variants of image {} for the export: {}", image_id, e);
+                        return Err(std::io::Error::other(e.to_string()))
+                    },
+                };
+
+                let variants = variants
+                    .into_iter()
+                    .map(|(sizing_id, kind)| ExportedVariant {
+                        sizing_id,
+                        preset: match sizing_id {
+                            0 => "original".to_string(),
+                            _ => presets
+                                .get(&sizing_id)
+                                .cloned()
+                                .unwrap_or_else(|| "custom".to_string()),
+                        },
+                        format: kind.as_file_extension(),
+                    })
+                    .collect();
+
+                let image = ExportedImage {
+                    image_id,
+                    uploaded_at: chrono::NaiveDateTime::from_timestamp_opt(record.uploaded_at, 0)
+                        .map(|dt| chrono::DateTime::<chrono::Utc>::from_utc(dt, chrono::Utc).to_rfc3339())
+                        .unwrap_or_default(),
+                    checksum: record.checksum,
+                    size: record.size,
+                    tags: record.tags,
+                    original_filename: record.original_filename,
+                    uploader: record.uploader,
+                    variants,
+                };
+
+                let mut line = serde_json::to_vec(&image)?;
+                line.push(b'\n');
+                Ok(Some(Bytes::from(line)))
+            }
+        })
+        .filter_map(|line| async move { line.transpose() })
+}
@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::config::IdScheme;
+
+/// The epoch snowflake timestamps count from, 2020-01-01T00:00:00Z.
+const SNOWFLAKE_EPOCH: u64 = 1_577_836_800_000;
+const WORKER_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// The largest worker id which fits in a snowflake.
+pub const MAX_WORKER_ID: u16 = (1 << WORKER_BITS) - 1;
+
+/// Generates the ids of a bucket's new images.
+///
+/// Every scheme produces a UUID, the time-sortable schemes put the
+/// timestamp in the most significant bits so the ids order by creation.
+pub struct IdGenerator {
+    scheme: IdScheme,
+
+    /// The timestamp of the last snowflake and its sequence number.
+    snowflake: Mutex<(u64, u64)>,
+}
+
+impl IdGenerator {
+    pub fn new(scheme: IdScheme) -> Self {
+        Self {
+            scheme,
+            snowflake: Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn generate(&self) -> Uuid {
+        match self.scheme {
+            IdScheme::Uuid => Uuid::new_v4(),
+            IdScheme::Ulid => {
+                let random = rand::thread_rng().gen::<u128>() & ((1 << 80) - 1);
+                Uuid::from_u128(((unix_millis() as u128) << 80) | random)
+            },
+            IdScheme::Snowflake { worker_id } => {
+                Uuid::from_u128((self.next_snowflake(worker_id) as u128) << 64)
+            },
+        }
+    }
+
+    /// A 41 bit millisecond timestamp, 10 bit worker id and 12 bit sequence.
+    ///
+    /// Timestamps never go backwards, if the clock does or the sequence of the
+    /// current millisecond is exhausted the last timestamp is carried forward.
+    fn next_snowflake(&self, worker_id: u16) -> u64 {
+        let mut state = self.snowflake.lock().unwrap();
+        let (last, sequence) = *state;
+
+        let now = unix_millis().saturating_sub(SNOWFLAKE_EPOCH);
+        let next = if now > last {
+            (now, 0)
+        } else if sequence < MAX_SEQUENCE {
+            (last, sequence + 1)
+        } else {
+            (last + 1, 0)
+        };
+        *state = next;
+
+        let (timestamp, sequence) = next;
+        (timestamp << (WORKER_BITS + SEQUENCE_BITS)) | ((worker_id as u64) << SEQUENCE_BITS) | sequence
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or_default()
+}
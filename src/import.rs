@@ -0,0 +1,403 @@
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use futures::StreamExt;
+use percent_encoding::percent_decode_str;
+use rusoto_core::credential::{AutoRefreshingProvider, ChainProvider, ProvideAwsCredentials};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{DispatchSignedRequest, HttpClient, Region, RusotoError};
+use rusoto_s3::{DeleteObjectRequest, GetObjectError, GetObjectRequest, S3Client, S3};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::AsyncReadExt;
+
+use crate::config::{ImageKind, ImportConfig, ImportSource};
+use crate::controller::{get_bucket_by_name, BucketController, UploadOptions, UploadOutcome};
+
+/// The maximum number of messages SQS returns from a single receive.
+const MAX_RECEIVED_MESSAGES: usize = 10;
+
+/// The time to wait before polling the queue again after failing to.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A credential timeout.
+const CREDENTIAL_TIMEOUT: u64 = 5;
+
+/// An object announced by an `ObjectCreated` event notification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreatedObject {
+    pub s3_bucket: String,
+    pub key: String,
+    pub size: u64,
+
+    /// Orders the events of the key, distinguishing
+    /// an object from any later object of the same key.
+    pub sequencer: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Notification {
+    #[serde(rename = "Records", default)]
+    records: Vec<EventRecord>,
+
+    /// The notification delivered through an SNS topic.
+    #[serde(rename = "Message")]
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventRecord {
+    event_name: String,
+    s3: EventEntity,
+}
+
+#[derive(Deserialize)]
+struct EventEntity {
+    bucket: EventBucket,
+    object: EventObject,
+}
+
+#[derive(Deserialize)]
+struct EventBucket {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct EventObject {
+    key: String,
+    #[serde(default)]
+    size: u64,
+    sequencer: Option<String>,
+}
+
+/// Parses the objects created according to an S3 event notification,
+/// delivered either directly or wrapped by an SNS topic.
+///
+/// Notifications of other events, like the test event sent when the
+/// notifications are configured, contain no objects.
+pub fn parse_notification(body: &str) -> anyhow::Result<Vec<CreatedObject>> {
+    let notification: Notification = serde_json::from_str(body)?;
+    if let Some(message) = notification.message {
+        return parse_notification(&message)
+    }
+
+    notification.records
+        .into_iter()
+        .filter(|record| record.event_name.starts_with("ObjectCreated:"))
+        .map(|record| {
+            // Keys are form encoded, so spaces are encoded as `+`.
+            let key = record.s3.object.key.replace('+', " ");
+            let key = percent_decode_str(&key)
+                .decode_utf8()
+                .with_context(|| format!("The object key {} is not valid UTF-8.", record.s3.object.key))?
+                .into_owned();
+
+            Ok(CreatedObject {
+                s3_bucket: record.s3.bucket.name,
+                key,
+                size: record.s3.object.size,
+                sequencer: record.s3.object.sequencer,
+            })
+        })
+        .collect()
+}
+
+/// The first source watching the object, if any.
+pub fn find_source<'a>(sources: &'a [ImportSource], object: &CreatedObject) -> Option<&'a ImportSource> {
+    sources
+        .iter()
+        .find(|source| source.s3_bucket == object.s3_bucket && object.key.starts_with(&source.prefix))
+}
+
+/// Uploads the object through the pipeline of the source's bucket.
+///
+/// Returns `None` if the object is skipped, as it's not an image or is
+/// larger than the bucket allows, otherwise the outcome of the upload.
+pub async fn ingest(
+    bucket: &BucketController,
+    source: &ImportSource,
+    object: &CreatedObject,
+    data: Vec<u8>,
+) -> anyhow::Result<Option<UploadOutcome>> {
+    if data.len() > crate::routes::upload_limit(bucket) {
+        warn!("Skipping import of s3://{}/{}, the object exceeds the upload limit of bucket {}.", object.s3_bucket, object.key, bucket.name());
+        return Ok(None)
+    }
+
+    let kind = match image::guess_format(&data).ok().and_then(ImageKind::from_guessed_format) {
+        None => {
+            warn!("Skipping import of s3://{}/{}, the object is not a supported image format.", object.s3_bucket, object.key);
+            return Ok(None)
+        },
+        Some(kind) => kind,
+    };
+
+    // Notifications are delivered at least once, redeliveries resolve to the same image.
+    let options = UploadOptions {
+        idempotency_key: Some(format!(
+            "s3://{}/{}@{}",
+            object.s3_bucket,
+            object.key,
+            object.sequencer.as_deref().unwrap_or_default(),
+        )),
+        tags: source.tags.clone(),
+        original_filename: object.key.rsplit('/').next().map(str::to_string),
+        ..Default::default()
+    };
+
+    let outcome = bucket.upload(kind, data, options).await?;
+    match outcome {
+        UploadOutcome::Complete(ref info) => info!(
+            "Imported s3://{}/{} into bucket {} as image {}.",
+            object.s3_bucket, object.key, bucket.name(), info.image_id(),
+        ),
+        UploadOutcome::Pending(ref job) => info!(
+            "Importing s3://{}/{} into bucket {} with upload job {}.",
+            object.s3_bucket, object.key, bucket.name(), job.job_id(),
+        ),
+    }
+
+    Ok(Some(outcome))
+}
+
+/// A message received from the queue.
+struct Message {
+    receipt_handle: String,
+    body: String,
+}
+
+/// Consumes the S3 event notifications delivered to the queue.
+struct ImportListener {
+    cfg: &'static ImportConfig,
+    http_client: HttpClient,
+    credentials: AutoRefreshingProvider<ChainProvider>,
+    queue_region: Region,
+    s3: S3Client,
+}
+
+impl ImportListener {
+    fn new(cfg: &'static ImportConfig) -> anyhow::Result<Self> {
+        let mut chain_provider = ChainProvider::new();
+        chain_provider.set_timeout(Duration::from_secs(CREDENTIAL_TIMEOUT));
+
+        let credentials = AutoRefreshingProvider::new(chain_provider)
+            .with_context(|| "Failed to fetch credentials for the import queue.")?;
+
+        let http_client = HttpClient::new()
+            .with_context(|| "Failed to create request dispatcher")?;
+
+        let s3 = S3Client::new_with(
+            HttpClient::new().with_context(|| "Failed to create request dispatcher")?,
+            credentials.clone(),
+            Region::Custom { name: cfg.region.clone(), endpoint: cfg.endpoint.clone() },
+        );
+
+        // The queue's actions are sent to the root of its host.
+        let queue_url = cfg.queue_url.parse::<poem::http::Uri>()?;
+        let queue_endpoint = format!(
+            "{}://{}",
+            queue_url.scheme_str().unwrap_or("https"),
+            queue_url.authority().ok_or_else(|| anyhow!("The import queue URL has no host."))?,
+        );
+
+        Ok(Self {
+            cfg,
+            http_client,
+            credentials,
+            queue_region: Region::Custom { name: cfg.region.clone(), endpoint: queue_endpoint },
+            s3,
+        })
+    }
+
+    /// Calls an action of the queue using the SQS JSON protocol.
+    async fn call_queue(&self, action: &str, payload: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+        let mut request = SignedRequest::new("POST", "sqs", &self.queue_region, "/");
+        request.set_content_type("application/x-amz-json-1.0".to_string());
+        request.add_header("x-amz-target", &format!("AmazonSQS.{}", action));
+        request.set_payload(Some(serde_json::to_vec(&payload)?));
+        request.sign(&self.credentials.credentials().await?);
+
+        // Receives are long polls so can take up to the wait time to respond.
+        let timeout = Duration::from_secs(self.cfg.wait_time + 10);
+        let mut response = self.http_client.dispatch(request, Some(timeout)).await?;
+        let response = response.buffer().await?;
+
+        if !response.status.is_success() {
+            return Err(anyhow!(
+                "The queue rejected the {} action with status {}: {}",
+                action, response.status, response.body_as_str(),
+            ))
+        }
+
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+
+    async fn receive(&self) -> anyhow::Result<Vec<Message>> {
+        let response = self.call_queue("ReceiveMessage", json!({
+            "QueueUrl": self.cfg.queue_url,
+            "MaxNumberOfMessages": MAX_RECEIVED_MESSAGES,
+            "WaitTimeSeconds": self.cfg.wait_time,
+        })).await?;
+
+        let messages = response
+            .get("Messages")
+            .and_then(|v| v.as_array())
+            .map(|messages| {
+                messages
+                    .iter()
+                    .filter_map(|message| Some(Message {
+                        receipt_handle: message.get("ReceiptHandle")?.as_str()?.to_string(),
+                        body: message.get("Body")?.as_str()?.to_string(),
+                    }))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(messages)
+    }
+
+    async fn acknowledge(&self, message: &Message) {
+        let result = self.call_queue("DeleteMessage", json!({
+            "QueueUrl": self.cfg.queue_url,
+            "ReceiptHandle": message.receipt_handle,
+        })).await;
+
+        if let Err(e) = result {
+            warn!("Failed to remove a handled notification from the import queue: {}", e);
+        }
+    }
+
+    /// Imports the objects of the notification, removing it from the queue
+    /// once every object is handled so failed imports are redelivered.
+    async fn handle(&self, message: Message) {
+        let objects = match parse_notification(&message.body) {
+            Ok(objects) => objects,
+            Err(e) => {
+                warn!("Discarding a notification which could not be parsed: {}", e);
+                self.acknowledge(&message).await;
+                return
+            },
+        };
+
+        let mut handled = true;
+        for object in objects {
+            match self.import(&object).await {
+                Ok(imported) => {
+                    let outcome = if imported { "imported" } else { "skipped" };
+                    crate::metrics::IMPORTED_OBJECTS.with_label_values(&[outcome]).inc();
+                },
+                Err(e) => {
+                    crate::metrics::IMPORTED_OBJECTS.with_label_values(&["failed"]).inc();
+                    error!("Failed to import s3://{}/{}: {}", object.s3_bucket, object.key, e);
+                    handled = false;
+                },
+            }
+        }
+
+        if handled {
+            self.acknowledge(&message).await;
+        }
+    }
+
+    /// Imports the object if it's watched, returning if it was imported.
+    async fn import(&self, object: &CreatedObject) -> anyhow::Result<bool> {
+        let source = match find_source(&self.cfg.sources, object) {
+            None => {
+                debug!("Ignoring s3://{}/{} which is not watched.", object.s3_bucket, object.key);
+                return Ok(false)
+            },
+            Some(source) => source,
+        };
+
+        let bucket = get_bucket_by_name(&source.bucket)
+            .ok_or_else(|| anyhow!("Bucket {} was not setup.", source.bucket))?;
+
+        // Avoids reading objects which would be rejected anyway.
+        if object.size as usize > crate::routes::upload_limit(bucket) {
+            warn!("Skipping import of s3://{}/{}, the object exceeds the upload limit of bucket {}.", object.s3_bucket, object.key, bucket.name());
+            return Ok(false)
+        }
+
+        let data = match self.read_object(object).await? {
+            None => {
+                debug!("Skipping import of s3://{}/{} which no longer exists.", object.s3_bucket, object.key);
+                return Ok(false)
+            },
+            Some(data) => data,
+        };
+
+        if ingest(bucket, source, object, data).await?.is_none() {
+            return Ok(false)
+        }
+
+        if source.delete_imported {
+            let request = DeleteObjectRequest {
+                bucket: object.s3_bucket.clone(),
+                key: object.key.clone(),
+                ..Default::default()
+            };
+
+            if let Err(e) = self.s3.delete_object(request).await {
+                warn!("Failed to delete imported object s3://{}/{}: {}", object.s3_bucket, object.key, e);
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn read_object(&self, object: &CreatedObject) -> anyhow::Result<Option<Vec<u8>>> {
+        let request = GetObjectRequest {
+            bucket: object.s3_bucket.clone(),
+            key: object.key.clone(),
+            ..Default::default()
+        };
+
+        let res = match self.s3.get_object(request).await {
+            Ok(res) => res,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(RusotoError::Unknown(ref res)) if res.status.as_u16() == 404 => return Ok(None),
+            Err(other) => return Err(other.into()),
+        };
+
+        let mut buffer = Vec::with_capacity(res.content_length.unwrap_or(0) as usize);
+        if let Some(body) = res.body {
+            body
+                .into_async_read()
+                .read_to_end(&mut buffer)
+                .await?;
+        }
+
+        Ok(Some(buffer))
+    }
+
+    async fn run(self) {
+        loop {
+            let messages = match self.receive().await {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("Failed to receive notifications from the import queue: {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue
+                },
+            };
+
+            futures::stream::iter(messages)
+                .for_each_concurrent(self.cfg.concurrency, |message| self.handle(message))
+                .await;
+        }
+    }
+}
+
+/// Starts consuming the import queue if configured.
+pub fn start() -> anyhow::Result<()> {
+    let cfg = match crate::config::config().import {
+        None => return Ok(()),
+        Some(ref cfg) => cfg,
+    };
+
+    let listener = ImportListener::new(cfg)?;
+    info!("Importing objects announced by the queue {}.", cfg.queue_url);
+    tokio::spawn(listener.run());
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::metadata::MetadataStore;
+
+/// The metadata directory each image's index record is stored in.
+const INDEX_DIR: &str = "index";
+
+/// The number of index records loaded or updated at once.
+const CONCURRENT_RECORDS: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageRecord {
+    /// The unix timestamp the image was uploaded.
+    pub uploaded_at: i64,
+
+    /// The crc32 checksum of the uploaded image.
+    pub checksum: u32,
+
+    /// The size of the uploaded image in bytes.
+    pub size: u64,
+
+    #[serde(default)]
+    /// The tags the image was uploaded with.
+    pub tags: Vec<String>,
+
+    #[serde(default)]
+    /// The filename the image was uploaded with, if known.
+    pub original_filename: Option<String>,
+
+    #[serde(default)]
+    /// The identifier of who uploaded the image, if given.
+    pub uploader: Option<String>,
+
+    #[serde(default)]
+    /// The perceptual hash of the image if duplicate detection was
+    /// enabled when it was uploaded.
+    pub phash: Option<u64>,
+}
+
+/// An index of the images stored in a bucket.
+///
+/// Changes are buffered in memory and periodically merged into
+/// the bucket's metadata store, reads always include buffered changes.
+///
+/// Each image's record is its own document so instances sharing the
+/// storage backend never overwrite each other's changes.
+#[derive(Default)]
+pub struct ImageIndex {
+    pending: Mutex<HashMap<Uuid, Option<ImageRecord>>>,
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+impl ImageIndex {
+    /// Adds or replaces the image in the index.
+    pub fn insert(&self, image_id: Uuid, record: ImageRecord) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.insert(image_id, Some(record));
+    }
+
+    /// Removes the image from the index.
+    pub fn remove(&self, image_id: Uuid) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.insert(image_id, None);
+    }
+
+    /// Merges the buffered changes into the metadata store.
+    pub async fn flush(&self, store: &MetadataStore) -> anyhow::Result<()> {
+        let _guard = self.flush_lock.lock().await;
+
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        let results: Vec<(Uuid, Option<ImageRecord>, anyhow::Result<()>)> = futures::stream::iter(pending)
+            .map(|(image_id, change)| async move {
+                let key = record_key(image_id);
+                let result = match change {
+                    Some(ref record) => store.save(&key, record).await,
+                    None => store.remove(&key).await,
+                };
+                (image_id, change, result)
+            })
+            .buffer_unordered(CONCURRENT_RECORDS)
+            .collect()
+            .await;
+
+        // Newer changes buffered during the failed flush take priority.
+        let mut first_error = None;
+        for (image_id, change, result) in results {
+            if let Err(e) = result {
+                let mut buffered = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+                buffered.entry(image_id).or_insert(change);
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// The record of the image, including changes not yet flushed.
+    pub async fn record(&self, store: &MetadataStore, image_id: Uuid) -> anyhow::Result<Option<ImageRecord>> {
+        let _guard = self.flush_lock.lock().await;
+
+        if let Some(change) = self.pending.lock().unwrap_or_else(|e| e.into_inner()).get(&image_id) {
+            return Ok(change.clone())
+        }
+
+        store.load(&record_key(image_id)).await
+    }
+
+    /// Every indexed image, including changes not yet flushed.
+    pub async fn records(&self, store: &MetadataStore) -> anyhow::Result<HashMap<Uuid, ImageRecord>> {
+        let _guard = self.flush_lock.lock().await;
+
+        let image_ids = store.list_all(INDEX_DIR).await?;
+        let mut records: HashMap<Uuid, ImageRecord> = futures::stream::iter(image_ids)
+            .filter_map(|name| async move { name.parse::<Uuid>().ok() })
+            .map(|image_id| async move {
+                let record: Option<ImageRecord> = store.load(&record_key(image_id)).await?;
+                Ok::<_, anyhow::Error>(record.map(|record| (image_id, record)))
+            })
+            .buffer_unordered(CONCURRENT_RECORDS)
+            .try_filter_map(|record| async move { Ok(record) })
+            .try_collect()
+            .await?;
+
+        let pending = self.pending.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for (image_id, change) in pending {
+            match change {
+                Some(record) => records.insert(image_id, record),
+                None => records.remove(&image_id),
+            };
+        }
+
+        Ok(records)
+    }
+}
+
+/// The key of the document holding the index record of the given image.
+fn record_key(image_id: Uuid) -> String {
+    format!("{}/{}", INDEX_DIR, image_id)
+}
@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use poem_openapi::Object;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::metadata::MetadataStore;
+
+/// The metadata directory each managed API key is stored in,
+/// named after the hash of the key.
+const API_KEYS_DIR: &str = "api_keys";
+
+/// How long a looked up key is trusted before it is re-read, this bounds
+/// how long a key created or revoked by another instance goes unnoticed.
+const KEY_LOOKUP_TTL: Duration = Duration::from_secs(5);
+
+/// The maximum number of looked up keys kept in memory.
+const MAX_CACHED_KEYS: u64 = 10_000;
+
+/// The prefix of generated keys, making them recognisable in logs and configs.
+const KEY_PREFIX: &str = "lust_";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct ApiKeyInfo {
+    /// The id the key is revoked by.
+    pub key_id: String,
+
+    /// A name describing who the key was issued to, if given.
+    pub name: Option<String>,
+
+    /// The unix timestamp the key was created.
+    pub created_at: i64,
+
+    /// The unix timestamp the key stops being valid, if it expires.
+    pub expires_at: Option<i64>,
+}
+
+impl ApiKeyInfo {
+    fn is_valid(&self, now: i64) -> bool {
+        self.expires_at.map(|expires_at| now < expires_at).unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredKey {
+    #[serde(flatten)]
+    info: ApiKeyInfo,
+
+    /// The hex encoded SHA-256 hash of the key, the key itself is never stored.
+    hash: String,
+}
+
+/// API keys created and revoked at runtime rather than configured,
+/// several can be valid at once so keys can be rotated without downtime.
+///
+/// Each key is stored under its own document so instances sharing the
+/// storage backend never overwrite each other's keys, lookups are cached
+/// briefly to keep them off the request path.
+pub struct ManagedKeys {
+    /// The looked up keys by their hash, `None` if there is no such key.
+    cached: moka::sync::Cache<String, Option<StoredKey>>,
+
+    /// If any keys exist.
+    any: moka::sync::Cache<(), bool>,
+}
+
+impl Default for ManagedKeys {
+    fn default() -> Self {
+        Self {
+            cached: moka::sync::Cache::builder()
+                .max_capacity(MAX_CACHED_KEYS)
+                .time_to_live(KEY_LOOKUP_TTL)
+                .build(),
+            any: moka::sync::Cache::builder()
+                .time_to_live(KEY_LOOKUP_TTL)
+                .build(),
+        }
+    }
+}
+
+#[inline]
+fn key_doc(hash: &str) -> String {
+    format!("{}/{}", API_KEYS_DIR, hash)
+}
+
+impl ManagedKeys {
+    /// If any keys exist, including expired keys.
+    pub async fn any(&self, store: &MetadataStore) -> anyhow::Result<bool> {
+        if let Some(any) = self.any.get(&()) {
+            return Ok(any)
+        }
+
+        let any = !store.list(API_KEYS_DIR, None, 1).await?.is_empty();
+        self.any.insert((), any);
+
+        Ok(any)
+    }
+
+    /// If the key is one of the keys which has not expired.
+    pub async fn matches(&self, store: &MetadataStore, key: &str) -> anyhow::Result<bool> {
+        let hash = hash_key(key);
+        let stored = match self.cached.get(&hash) {
+            Some(stored) => stored,
+            None => {
+                let stored: Option<StoredKey> = store.load(&key_doc(&hash)).await?;
+                self.cached.insert(hash, stored.clone());
+                stored
+            },
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        Ok(stored.map(|stored| stored.info.is_valid(now)).unwrap_or(false))
+    }
+
+    /// Every key, without the keys themselves.
+    pub async fn list(&self, store: &MetadataStore) -> anyhow::Result<Vec<ApiKeyInfo>> {
+        let mut keys: Vec<ApiKeyInfo> = self.stored(store)
+            .await?
+            .into_iter()
+            .map(|stored| stored.info)
+            .collect();
+        keys.sort_by_key(|info| info.created_at);
+        Ok(keys)
+    }
+
+    /// Creates a new key, returning the key which is only available now.
+    pub async fn create(
+        &self,
+        store: &MetadataStore,
+        name: Option<String>,
+        expires_in: Option<u64>,
+    ) -> anyhow::Result<(ApiKeyInfo, String)> {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let key = format!("{}{}", KEY_PREFIX, base64::encode_config(secret, base64::URL_SAFE_NO_PAD));
+
+        let created_at = chrono::Utc::now().timestamp();
+        let info = ApiKeyInfo {
+            key_id: uuid::Uuid::new_v4().to_simple().to_string(),
+            name,
+            created_at,
+            expires_at: expires_in
+                .map(|secs| created_at.saturating_add(i64::try_from(secs).unwrap_or(i64::MAX))),
+        };
+
+        let stored = StoredKey { info: info.clone(), hash: hash_key(&key) };
+        store.save(&key_doc(&stored.hash), &stored).await?;
+        self.cached.insert(stored.hash.clone(), Some(stored));
+        self.any.insert((), true);
+
+        Ok((info, key))
+    }
+
+    /// Revokes the key, returning `false` if it does not exist.
+    pub async fn revoke(&self, store: &MetadataStore, key_id: &str) -> anyhow::Result<bool> {
+        let stored = self.stored(store)
+            .await?
+            .into_iter()
+            .find(|stored| stored.info.key_id == key_id);
+        let stored = match stored {
+            None => return Ok(false),
+            Some(stored) => stored,
+        };
+
+        store.remove(&key_doc(&stored.hash)).await?;
+        self.cached.insert(stored.hash, None);
+        self.any.invalidate(&());
+
+        Ok(true)
+    }
+
+    /// Every stored key.
+    async fn stored(&self, store: &MetadataStore) -> anyhow::Result<Vec<StoredKey>> {
+        let mut keys = vec![];
+        for hash in store.list_all(API_KEYS_DIR).await? {
+            let stored: Option<StoredKey> = store.load(&key_doc(&hash)).await?;
+            keys.extend(stored);
+        }
+
+        Ok(keys)
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -0,0 +1,153 @@
+mod storage;
+mod pipelines;
+mod controller;
+mod utils;
+mod processor;
+
+#[cfg(test)]
+mod tests;
+mod allocator;
+mod cache;
+mod egress;
+mod placeholder;
+mod access;
+mod tombstones;
+mod keys;
+mod index;
+mod purge;
+mod etags;
+mod remote;
+mod ids;
+mod quarantine;
+mod lookups;
+mod cdn;
+mod export;
+mod changes;
+
+pub mod config;
+pub mod routes;
+pub mod background;
+pub mod metrics;
+pub mod proxy;
+pub mod server;
+pub mod admin;
+pub mod metadata;
+pub mod lifecycle;
+pub mod trash;
+pub mod profiling;
+pub mod bench;
+pub mod replication;
+pub mod replica;
+pub mod import;
+pub mod watch;
+pub mod transforms;
+pub mod signing;
+pub mod auth;
+pub mod admission;
+pub mod thumbor;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+use std::sync::Arc;
+use anyhow::anyhow;
+use crate::admission::ConcurrencyLimiter;
+use crate::controller::BucketController;
+use crate::storage::resilience::ResilientBackend;
+use crate::storage::template::StorageBackend;
+
+#[macro_use]
+extern crate tracing;
+
+/// Initialises the global cache and processing memory budget if configured.
+pub fn init_global_state() -> anyhow::Result<()> {
+    if let Some(config) = config::config().global_cache {
+        cache::init_cache(config)?;
+    }
+
+    if let Some(max_memory) = config::config().max_processing_memory {
+        admission::init_memory_budget(max_memory);
+    }
+
+    if let Some(max_concurrency) = config::config().max_low_priority_concurrency {
+        admission::init_low_priority_limit(max_concurrency);
+    }
+
+    if let Some(pool) = config::config().processing_pool {
+        processor::pool::init(pool)?;
+    }
+
+    replica::setup();
+    remote::setup();
+    auth::setup()?;
+
+    Ok(())
+}
+
+/// Connects to the configured storage backend and checks it's usable
+/// by performing a write, read and delete roundtrip.
+pub async fn validate_backend() -> anyhow::Result<()> {
+    let storage = config::config()
+        .backend
+        .connect()
+        .await?;
+
+    storage::validate_roundtrip(storage.as_ref()).await
+}
+
+/// Connects to the configured storage backend and creates the controllers
+/// of every configured bucket.
+pub async fn setup_buckets() -> anyhow::Result<()> {
+    let global_limiter = Arc::new(ConcurrencyLimiter::new(
+        config::config().max_concurrency,
+        config::config().max_upload_concurrency,
+        config::config().max_fetch_concurrency,
+        config::config().permit_weights,
+    ));
+
+    let storage: Arc<dyn StorageBackend> = config::config()
+        .backend
+        .connect()
+        .await?;
+    let storage: Arc<dyn StorageBackend> = Arc::new(ResilientBackend::new(
+        storage,
+        config::config().storage_retry,
+        config::config().circuit_breaker,
+    ));
+    let storage = replication::setup(storage).await?;
+
+    let buckets = config::config()
+        .buckets
+        .iter()
+        .map(|(bucket, cfg)| {
+            let bucket_id = crate::utils::crc_hash(bucket);
+            let pipeline = cfg.mode.build_pipeline(bucket, cfg)?;
+            let cache = cfg.cache
+                .map(cache::new_cache)
+                .transpose()?
+                .flatten();
+
+            let placeholder = cfg.missing_image
+                .as_ref()
+                .map(placeholder::load)
+                .transpose()
+                .map_err(|e| anyhow!("Bucket {} is invalid: {}", bucket, e))?;
+
+            let controller = BucketController::new(
+                bucket.clone(),
+                bucket_id,
+                cache,
+                placeholder,
+                global_limiter.clone(),
+                cfg.clone(),
+                pipeline,
+                storage.clone(),
+            );
+            Ok::<_, anyhow::Error>((bucket_id, controller))
+        })
+        .collect::<Result<hashbrown::HashMap<_, _>, anyhow::Error>>()?;
+
+    controller::init_buckets(buckets);
+
+    Ok(())
+}
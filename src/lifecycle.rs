@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use poem_openapi::Object;
+use uuid::Uuid;
+
+use crate::access::AccessRecord;
+use crate::config::{LifecycleAction, LifecycleConfig};
+use crate::controller::{buckets, BucketController};
+
+const SECONDS_PER_DAY: i64 = 60 * 60 * 24;
+
+/// A summary of a single evaluation of a bucket's lifecycle rules.
+#[derive(Debug, Default, Object)]
+pub struct LifecycleReport {
+    /// If the actions were only reported rather than applied.
+    pub dry_run: bool,
+
+    /// The images deleted.
+    pub deleted: Vec<Uuid>,
+
+    /// The images archived.
+    pub archived: Vec<Uuid>,
+
+    /// The images which had their generated variants dropped.
+    pub tiered_down: Vec<Uuid>,
+}
+
+/// Starts periodically evaluating the lifecycle rules of each bucket.
+pub fn start() {
+    for bucket in buckets() {
+        let (interval, dry_run) = match bucket.cfg().lifecycle {
+            None => continue,
+            Some(ref cfg) => (Duration::from_secs(cfg.check_interval), cfg.dry_run),
+        };
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+
+                match run(bucket, dry_run).await {
+                    Ok(report) => info!(
+                        "Lifecycle rules of bucket {} {}deleted {} images, archived {} images and dropped the variants of {} images.",
+                        bucket.name(),
+                        if report.dry_run { "(dry run) " } else { "" },
+                        report.deleted.len(),
+                        report.archived.len(),
+                        report.tiered_down.len(),
+                    ),
+                    Err(e) => error!("Failed to apply the lifecycle rules of bucket {}: {}", bucket.name(), e),
+                }
+            }
+        });
+    }
+}
+
+/// Evaluates the bucket's lifecycle rules against the access records
+/// of each image, applying the most destructive matching action.
+///
+/// If `dry_run` is set the actions are only reported.
+///
+/// Images uploaded before the rules were configured are not tracked
+/// until they're next fetched.
+pub async fn run(bucket: &BucketController, dry_run: bool) -> anyhow::Result<LifecycleReport> {
+    let mut report = LifecycleReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    let cfg = match bucket.cfg().lifecycle {
+        None => return Ok(report),
+        Some(ref cfg) => cfg,
+    };
+
+    let records = match bucket.access_records().await? {
+        None => return Ok(report),
+        Some(records) => records,
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    for (image_id, action) in plan(cfg, &records, now) {
+        match action {
+            LifecycleAction::Delete => {
+                if !dry_run {
+                    bucket.delete(image_id).await?;
+                }
+                report.deleted.push(image_id);
+            },
+            LifecycleAction::Archive => {
+                if !dry_run {
+                    bucket.archive(image_id).await?;
+                }
+                report.archived.push(image_id);
+            },
+            LifecycleAction::DropVariants => {
+                if !dry_run {
+                    bucket.drop_variants(image_id).await?;
+                }
+                report.tiered_down.push(image_id);
+            },
+        }
+    }
+
+    Ok(report)
+}
+
+/// Decides the action to take for each image, ordered by image id.
+fn plan(
+    cfg: &LifecycleConfig,
+    records: &HashMap<Uuid, AccessRecord>,
+    now: i64,
+) -> Vec<(Uuid, LifecycleAction)> {
+    let mut actions: HashMap<Uuid, LifecycleAction> = HashMap::new();
+    for (image_id, record) in records {
+        let idle_days = (now - record.last_access) / SECONDS_PER_DAY;
+        let age_days = record.uploaded_at.map(|v| (now - v) / SECONDS_PER_DAY);
+
+        let action = cfg.rules
+            .iter()
+            .filter(|rule| match rule.not_accessed_for {
+                None => true,
+                Some(days) => idle_days >= days as i64,
+            })
+            .filter(|rule| match (rule.older_than, age_days) {
+                (None, _) => true,
+                (Some(days), Some(age)) => age >= days as i64,
+                (Some(_), None) => false,
+            })
+            .map(|rule| rule.action)
+            .max();
+
+        let action = match action {
+            Some(LifecycleAction::DropVariants) if record.tiered_down => continue,
+            Some(LifecycleAction::Archive) if record.archived => continue,
+            Some(action) => action,
+            None => continue,
+        };
+
+        actions.insert(*image_id, action);
+    }
+
+    // The least recently accessed images are evicted to keep
+    // the bucket within its max count.
+    if let Some(max_count) = cfg.max_count {
+        let mut remaining: Vec<(&Uuid, &AccessRecord)> = records
+            .iter()
+            .filter(|(image_id, _)| actions.get(image_id) != Some(&LifecycleAction::Delete))
+            .collect();
+
+        if remaining.len() > max_count {
+            remaining.sort_by_key(|(image_id, record)| (record.last_access, **image_id));
+
+            let excess = remaining.len() - max_count;
+            for (image_id, _) in remaining.into_iter().take(excess) {
+                actions.insert(*image_id, LifecycleAction::Delete);
+            }
+        }
+    }
+
+    let mut actions: Vec<(Uuid, LifecycleAction)> = actions.into_iter().collect();
+    actions.sort_by_key(|(image_id, _)| *image_id);
+    actions
+}
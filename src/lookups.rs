@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::config::LookupCacheConfig;
+use crate::index::ImageRecord;
+
+/// Caches the results of small metadata lookups separately from the
+/// image cache so clients polling an image's metadata, or waiting for an
+/// image to exist, are served without hitting the storage backend.
+///
+/// Entries are only kept for a short time and are invalidated whenever
+/// the bucket writes or deletes the image.
+pub struct LookupCache {
+    /// The index record of each image, `None` if the image is not indexed.
+    records: moka::sync::Cache<Uuid, Option<ImageRecord>>,
+
+    /// The images which were found not to exist.
+    missing: moka::sync::Cache<Uuid, ()>,
+}
+
+impl LookupCache {
+    pub fn new(cfg: &LookupCacheConfig) -> Self {
+        let ttl = Duration::from_secs(cfg.ttl);
+
+        Self {
+            records: moka::sync::Cache::builder()
+                .max_capacity(cfg.max_entries)
+                .time_to_live(ttl)
+                .build(),
+            missing: moka::sync::Cache::builder()
+                .max_capacity(cfg.max_entries)
+                .time_to_live(ttl)
+                .build(),
+        }
+    }
+
+    /// The cached index record of the image.
+    ///
+    /// Returns `None` if the lookup is not cached.
+    pub fn record(&self, image_id: Uuid) -> Option<Option<ImageRecord>> {
+        self.records.get(&image_id)
+    }
+
+    pub fn insert_record(&self, image_id: Uuid, record: Option<ImageRecord>) {
+        self.records.insert(image_id, record);
+    }
+
+    /// If the image was recently found not to exist.
+    pub fn is_missing(&self, image_id: Uuid) -> bool {
+        self.missing.get(&image_id).is_some()
+    }
+
+    pub fn insert_missing(&self, image_id: Uuid) {
+        self.missing.insert(image_id, ());
+    }
+
+    /// Drops every cached lookup of the image.
+    pub fn invalidate(&self, image_id: Uuid) {
+        self.records.invalidate(&image_id);
+        self.missing.invalidate(&image_id);
+    }
+}
@@ -1,39 +1,25 @@
-mod config;
-mod storage;
-mod routes;
-mod pipelines;
-mod controller;
-mod utils;
-mod processor;
-
-#[cfg(test)]
-mod tests;
-mod cache;
-
 use std::path::PathBuf;
-use std::sync::Arc;
 use std::time::{Duration, Instant};
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use mimalloc::MiMalloc;
+use clap::{Parser, Subcommand};
+use futures::FutureExt;
 use poem::listener::TcpListener;
-use poem::{Endpoint, EndpointExt, IntoResponse, Request, Response, Route, Server};
+use poem::{Endpoint, EndpointExt, IntoEndpoint, IntoResponse, Request, Response, Route};
 use poem_openapi::OpenApiService;
-use tokio::sync::Semaphore;
 use tracing::Level;
-use crate::controller::BucketController;
-use crate::storage::template::StorageBackend;
-
-#[global_allocator]
-static GLOBAL: MiMalloc = MiMalloc;
-
+use lust::bench::BenchConfig;
+use lust::replication::CatchUpConfig;
+use lust::{admin, admission, auth, background, config, import, init_global_state, lifecycle, metadata, proxy, replica, replication, routes, server, setup_buckets, thumbor, trash, validate_backend, watch};
 #[macro_use]
 extern crate tracing;
 
 
 #[derive(Debug, Parser)]
-#[clap(author, version, about)]
+#[clap(author, version, about, subcommand_negates_reqs = true)]
 pub struct ServerConfig {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(short, long, env, default_value = "127.0.0.1")]
     /// The binding host address of the server.
     pub host: String,
@@ -50,11 +36,60 @@ pub struct ServerConfig {
     #[clap(long, env, default_value = "info")]
     pub log_level: Level,
 
+    #[clap(long, env, default_value = "30")]
+    /// The maximum time in seconds to wait for background work, like persisting
+    /// generated images, to complete when shutting down.
+    pub shutdown_timeout: u64,
+
+    #[clap(long, env)]
+    /// The binding host address of the admin and metrics API.
+    ///
+    /// Defaults to the `host` if unset.
+    pub admin_host: Option<String>,
+
+    #[clap(long, env)]
+    /// The port to serve the admin and metrics API on.
+    ///
+    /// If unset the admin and metrics API are served alongside the image API.
+    pub admin_port: Option<u16>,
+
     #[clap(long, env)]
+    /// The token requests to the admin API must carry as `Authorization: Bearer <token>`.
+    ///
+    /// The admin API is only served alongside the image API if set.
+    pub admin_token: Option<String>,
+
+    #[clap(long, env)]
+    /// The id distinguishing this instance from the other instances
+    /// sharing the storage backend, e.g. for sharding access counts.
+    ///
+    /// Defaults to the hostname if unset, it must be unique per instance.
+    pub instance_id: Option<String>,
+
+    #[clap(flatten)]
+    pub connection: server::ConnectionConfig,
+
+    #[clap(long)]
+    /// Performs a write, read and delete roundtrip against the configured
+    /// storage backend then exits, rather than starting the server.
+    pub validate_backend: bool,
+
+    #[clap(long, env, required = true)]
     /// The file path to a given config file.
     ///
     /// This can be either a JSON formatted config or YAML.
-    pub config_file: PathBuf,
+    pub config_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Benchmarks the upload and fetch latencies and throughput of the
+    /// configured buckets, rather than starting the server.
+    Bench(BenchConfig),
+
+    /// Replicates every indexed image to the replication target, catching it
+    /// up with changes lost to a restart or which failed to replicate.
+    CatchUp(CatchUpConfig),
 }
 
 
@@ -71,13 +106,39 @@ async fn main() -> Result<()> {
     }
     tracing_subscriber::fmt::init();
 
-    config::init(&args.config_file).await?;
+    match args.command {
+        Some(Command::Bench(bench)) => return lust::bench::run(bench).await,
+        Some(Command::CatchUp(catch_up)) => return replication::run_catch_up(catch_up).await,
+        None => {},
+    }
+
+    // Only optional when running a subcommand.
+    let config_file = args.config_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("The config file must be provided."))?;
+    config::init(config_file).await?;
 
-    if let Some(config) = config::config().global_cache {
-        cache::init_cache(config)?;
+    if args.validate_backend {
+        validate_backend().await?;
+        info!("The storage backend roundtrip completed successfully.");
+        return Ok(())
     }
 
+    if let Some(ref instance_id) = args.instance_id {
+        metadata::init_instance_id(instance_id);
+    }
+
+    init_global_state()?;
     setup_buckets().await?;
+    metadata::start_flushing();
+
+    // Lifecycle rules are applied and imports are made by the primary.
+    if !replica::is_replica() {
+        lifecycle::start();
+        trash::start();
+        import::start()?;
+        watch::start()?;
+    }
 
     let serving_path = if let Some(p) = config::config().base_serving_path.clone() {
         if !p.starts_with('/') {
@@ -101,10 +162,29 @@ async fn main() -> Result<()> {
     let spec = api_service.spec();
 
     let app = Route::new()
-        .nest(format!("/v1{}", serving_path), api_service)
+        .nest(format!("/v1{}", serving_path), api_service.into_endpoint().around(admission::prioritize).around(auth::enforce_scopes).around(replica::forward_writes))
         .nest("/ui", ui)
-        .at("/spec", poem::endpoint::make_sync(move |_| spec.clone()))
-        .around(log);
+        .at("/spec", poem::endpoint::make_sync(move |_| spec.clone()));
+    let app = thumbor::mount(app);
+
+    // The admin surface gets its own listener when configured so it
+    // can be restricted to an internal network.
+    let admin_bind = args.admin_port.map(|port| {
+        let host = args.admin_host.clone().unwrap_or_else(|| args.host.clone());
+        format!("{}:{}", host, port)
+    });
+    if let Some(token) = args.admin_token.clone() {
+        admin::init_token(token);
+    }
+
+    let app = match admin_bind {
+        Some(_) => app,
+        None if admin::has_token() => admin::mount(app),
+        None => {
+            warn!("The admin API is disabled, set an `--admin-token` or `--admin-port` to serve it.");
+            admin::mount_metrics(app)
+        },
+    };
 
     info!("Lust has started!");
     info!(
@@ -123,54 +203,61 @@ async fn main() -> Result<()> {
         &bind,
     );
 
-    Server::new(TcpListener::bind(&bind))
-        .run_with_graceful_shutdown(
-            app,
-            async move {
-                let _ = wait_for_signal().await;
-            },
-            Some(Duration::from_secs(2)),
-        )
-        .await?;
+    let shutdown = async move {
+        let _ = wait_for_signal().await;
+    }.shared();
 
-    Ok(())
-}
+    let public = server::serve(
+        TcpListener::bind(bind),
+        app.around(log),
+        &args.connection,
+        shutdown.clone(),
+        Duration::from_secs(2),
+    );
 
-async fn setup_buckets() -> anyhow::Result<()> {
-    let global_limiter = config::config()
-        .max_concurrency
-        .map(Semaphore::new)
-        .map(Arc::new);
-
-    let storage: Arc<dyn StorageBackend> = config::config()
-        .backend
-        .connect()
-        .await?;
-
-    let buckets = config::config()
-        .buckets
-        .iter()
-        .map(|(bucket, cfg)| {
-            let bucket_id = crate::utils::crc_hash(bucket);
-            let pipeline = cfg.mode.build_pipeline(cfg);
-            let cache = cfg.cache
-                .map(cache::new_cache)
-                .transpose()?
-                .flatten();
-
-            let controller = BucketController::new(
-                bucket_id,
-                cache,
-                global_limiter.clone(),
-                cfg.clone(),
-                pipeline,
-                storage.clone(),
+    match admin_bind {
+        None => public.await?,
+        Some(admin_bind) => {
+            info!("Admin API @ http://{}/admin", &admin_bind);
+
+            let admin = server::serve(
+                TcpListener::bind(admin_bind),
+                admin::mount(Route::new()).around(log),
+                &args.connection,
+                shutdown,
+                Duration::from_secs(2),
             );
-            Ok::<_, anyhow::Error>((bucket_id, controller))
-        })
-        .collect::<Result<hashbrown::HashMap<_, _>, anyhow::Error>>()?;
 
-    controller::init_buckets(buckets);
+            futures::try_join!(public, admin)?;
+        },
+    }
+
+    metadata::flush_all().await;
+
+    if background::active_tasks() > 0 || replication::pending() > 0 {
+        info!(
+            "Waiting for {} background tasks and {} changes to replicate before shutting down.",
+            background::active_tasks(),
+            replication::pending(),
+        );
+
+        // Background work can make further changes to replicate.
+        let drained = tokio::time::timeout(
+            Duration::from_secs(args.shutdown_timeout),
+            async {
+                background::wait_until_idle().await;
+                replication::wait_until_idle().await;
+            },
+        ).await;
+
+        if drained.is_err() {
+            warn!(
+                "Shutdown timeout reached with {} background tasks still running and {} changes not replicated.",
+                background::active_tasks(),
+                replication::pending(),
+            );
+        }
+    }
 
     Ok(())
 }
@@ -200,9 +287,16 @@ async fn wait_for_signal() -> Result<()> {
 }
 
 
-async fn log<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
+async fn log<E: Endpoint>(next: E, mut req: Request) -> poem::Result<Response> {
     let method = req.method().clone();
     let path = req.uri().clone();
+    let client = proxy::client_ip(&req);
+    if let Some(ip) = client {
+        req.extensions_mut().insert(proxy::ClientIp(ip));
+    }
+    let client = client
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
 
     let start = Instant::now();
     let res = next.call(req).await;
@@ -213,7 +307,8 @@ async fn log<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
             let resp = r.into_response();
 
             info!(
-                "{} -> {} {} [ {:?} ] - {:?}",
+                "{} {} -> {} {} [ {:?} ] - {:?}",
+                client,
                 method.as_str(),
                 resp.status().as_u16(),
                 resp.status().canonical_reason().unwrap_or(""),
@@ -232,7 +327,8 @@ async fn log<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
             }
 
             info!(
-                "{} -> {} {} [ {:?} ] - {:?}",
+                "{} {} -> {} {} [ {:?} ] - {:?}",
+                client,
                 method.as_str(),
                 resp.status().as_u16(),
                 resp.status().canonical_reason().unwrap_or(""),
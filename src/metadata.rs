@@ -0,0 +1,150 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::controller::buckets;
+use crate::storage::template::StorageBackend;
+
+/// The number of document names listed per request.
+const LIST_PAGE_SIZE: usize = 1000;
+
+static INSTANCE_ID: OnceCell<String> = OnceCell::new();
+
+/// Typed access to the metadata documents of a bucket.
+///
+/// Documents are stored as JSON alongside the images in the
+/// configured storage backend.
+#[derive(Clone)]
+pub struct MetadataStore {
+    bucket_id: u32,
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl MetadataStore {
+    pub fn new(bucket_id: u32, storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            bucket_id,
+            storage,
+        }
+    }
+
+    /// Loads the given document, returning the default value if
+    /// it has not been stored yet.
+    pub async fn load<T>(&self, key: &str) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned + Default,
+    {
+        match self.storage.fetch_metadata(self.bucket_id, key).await? {
+            None => Ok(T::default()),
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+        }
+    }
+
+    /// Stores the given document, replacing any existing version.
+    pub async fn save<T>(&self, key: &str, value: &T) -> anyhow::Result<()>
+    where
+        T: Serialize,
+    {
+        let data = serde_json::to_vec(value)?;
+        self.storage.store_metadata(self.bucket_id, key, data.into()).await
+    }
+
+    /// Loads the given raw document, returning `None` if it has not been stored.
+    pub async fn load_bytes(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        self.storage.fetch_metadata(self.bucket_id, key).await
+    }
+
+    /// Stores the given raw document, replacing any existing version.
+    pub async fn save_bytes(&self, key: &str, data: Bytes) -> anyhow::Result<()> {
+        self.storage.store_metadata(self.bucket_id, key, data).await
+    }
+
+    /// Removes the given document if it exists.
+    pub async fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.storage.delete_metadata(self.bucket_id, key).await
+    }
+
+    /// Lists up to `limit` names of the documents stored under `{dir}/`
+    /// in ascending order, starting after the `after` name if given.
+    pub async fn list(&self, dir: &str, after: Option<&str>, limit: usize) -> anyhow::Result<Vec<String>> {
+        self.storage.list_metadata(self.bucket_id, dir, after, limit).await
+    }
+
+    /// Lists the names of every document stored under `{dir}/`.
+    pub async fn list_all(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        let mut names: Vec<String> = vec![];
+        loop {
+            let page = self.list(dir, names.last().map(String::as_str), LIST_PAGE_SIZE).await?;
+            let done = page.len() < LIST_PAGE_SIZE;
+            names.extend(page);
+
+            if done {
+                return Ok(names)
+            }
+        }
+    }
+}
+
+/// Sets the id distinguishing the metadata written by this instance
+/// from that of the other instances sharing the storage backend.
+pub fn init_instance_id(instance_id: &str) {
+    let _ = INSTANCE_ID.set(sanitize_instance_id(instance_id));
+}
+
+/// The id of this instance, defaulting to its hostname.
+pub fn instance_id() -> &'static str {
+    INSTANCE_ID.get_or_init(|| {
+        let hostname = std::env::var("HOSTNAME")
+            .ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        sanitize_instance_id(&hostname)
+    })
+}
+
+/// Replaces any characters which aren't safe to use within a metadata key.
+fn sanitize_instance_id(instance_id: &str) -> String {
+    instance_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Starts periodically flushing the buffered metadata of each bucket.
+pub fn start_flushing() {
+    for bucket in buckets() {
+        let interval = match bucket.cfg().metadata_flush_interval() {
+            None => continue,
+            Some(interval) => Duration::from_secs(interval),
+        };
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = bucket.flush_metadata().await {
+                    error!("Failed to flush the metadata of bucket {}: {}", bucket.name(), e);
+                }
+            }
+        });
+    }
+}
+
+/// Flushes the buffered metadata of every bucket, e.g. before shutting down.
+pub async fn flush_all() {
+    for bucket in buckets() {
+        if let Err(e) = bucket.flush_metadata().await {
+            error!("Failed to flush the metadata of bucket {}: {}", bucket.name(), e);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder,
+    TextEncoder,
+    register_gauge,
+    register_gauge_vec,
+    register_histogram_vec,
+    register_int_counter,
+    register_int_counter_vec,
+    register_int_gauge,
+    register_int_gauge_vec,
+    Gauge,
+    GaugeVec,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    IntGaugeVec,
+};
+
+/// The number of requests handled per bucket.
+///
+/// Labelled by the bucket, the operation and the outcome of the request
+/// (`ok`, `not_found` or `error`).
+pub static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_requests_total",
+        "The number of requests handled per bucket.",
+        &["bucket", "operation", "status"],
+    )
+    .expect("register metric")
+});
+
+/// The time taken to handle requests per bucket.
+pub static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "lust_request_duration_seconds",
+        "The time taken to handle requests per bucket.",
+        &["bucket", "operation"],
+    )
+    .expect("register metric")
+});
+
+/// The number of image bytes served per bucket.
+pub static SERVED_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_served_bytes_total",
+        "The number of image bytes served per bucket.",
+        &["bucket"],
+    )
+    .expect("register metric")
+});
+
+/// The time spent running the processing pipelines per bucket.
+///
+/// Labelled by the bucket and the pipeline stage (`upload` or `fetch`).
+pub static PROCESSING_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "lust_processing_duration_seconds",
+        "The time spent running the processing pipelines per bucket.",
+        &["bucket", "stage"],
+    )
+    .expect("register metric")
+});
+
+/// The number of images encoded by the processing pipelines per bucket.
+///
+/// Labelled by the bucket, the source and target formats and the
+/// preset, `original` or `custom` sizing encoded.
+pub static CONVERSIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_conversions_total",
+        "The number of images encoded by the processing pipelines per bucket.",
+        &["bucket", "from", "to", "sizing"],
+    )
+    .expect("register metric")
+});
+
+/// The time spent encoding images per bucket, labelled as `lust_conversions_total`.
+pub static CONVERSION_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "lust_conversion_duration_seconds",
+        "The time spent encoding images per bucket.",
+        &["bucket", "from", "to", "sizing"],
+    )
+    .expect("register metric")
+});
+
+/// The number of cache lookups per bucket.
+///
+/// Labelled by the bucket and whether the lookup was a `hit` or `miss`.
+pub static CACHE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_cache_requests_total",
+        "The number of cache lookups per bucket.",
+        &["bucket", "result"],
+    )
+    .expect("register metric")
+});
+
+/// The number of lookup cache lookups per bucket.
+///
+/// Labelled by the bucket, the kind of lookup, either `metadata` or
+/// `existence`, and whether the lookup was a `hit` or `miss`.
+pub static LOOKUP_CACHE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_lookup_cache_requests_total",
+        "The number of lookup cache lookups per bucket.",
+        &["bucket", "lookup", "result"],
+    )
+    .expect("register metric")
+});
+
+/// The number of encoder cache lookups per bucket.
+///
+/// Labelled by the bucket, the processing stage and whether
+/// the lookup was a `hit` or `miss`.
+pub static ENCODER_CACHE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_encoder_cache_requests_total",
+        "The number of encoder cache lookups per bucket.",
+        &["bucket", "stage", "result"],
+    )
+    .expect("register metric")
+});
+
+/// The number of panics caught while processing images.
+///
+/// Labelled by the bucket, the processing stage and the image kind being
+/// processed at the time.
+pub static PROCESSING_PANICS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_processing_panics_total",
+        "The number of panics caught while processing images.",
+        &["bucket", "stage", "kind"],
+    )
+    .expect("register metric")
+});
+
+/// The number of storage operations throttled by the backend.
+///
+/// Labelled by the outcome, either `retried` or `failed` once the
+/// retries are exhausted.
+pub static STORAGE_THROTTLED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_storage_throttled_total",
+        "The number of storage operations throttled by the backend.",
+        &["outcome"],
+    )
+    .expect("register metric")
+});
+
+/// The time taken by storage backend operations, including retries.
+///
+/// Labelled by the bucket and the operation, e.g. `store`, `fetch` or `delete`.
+pub static STORAGE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "lust_storage_duration_seconds",
+        "The time taken by storage backend operations, including retries.",
+        &["bucket", "operation"],
+    )
+    .expect("register metric")
+});
+
+/// If the storage backend's circuit breaker is open, `1` while open.
+pub static STORAGE_BREAKER_OPEN: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "lust_storage_breaker_open",
+        "If the storage backend's circuit breaker is open.",
+    )
+    .expect("register metric")
+});
+
+/// The number of storage operations failed without calling
+/// the backend while the circuit breaker was open.
+pub static STORAGE_BREAKER_REJECTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "lust_storage_breaker_rejections_total",
+        "The number of storage operations rejected by the open circuit breaker.",
+    )
+    .expect("register metric")
+});
+
+/// The free space in bytes of the filesystem backend's directory.
+pub static STORAGE_FREE_SPACE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "lust_storage_free_space_bytes",
+        "The free space in bytes of the filesystem backend's directory.",
+    )
+    .expect("register metric")
+});
+
+/// If the filesystem backend is below its free space watermark, `1` while below.
+pub static STORAGE_LOW_SPACE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "lust_storage_low_space",
+        "If the filesystem backend is below its free space watermark.",
+    )
+    .expect("register metric")
+});
+
+/// The number of uploads refused as the storage backend is low on space.
+pub static STORAGE_LOW_SPACE_REJECTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "lust_storage_low_space_rejections_total",
+        "The number of uploads refused as the storage backend is low on space.",
+    )
+    .expect("register metric")
+});
+
+/// The number of concurrency permits held by operations per bucket.
+///
+/// Labelled by the bucket and the operation (`upload` or `fetch`), permits
+/// of the global limits are attributed to the bucket holding them.
+pub static CONCURRENCY_PERMITS_IN_USE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "lust_concurrency_permits_in_use",
+        "The number of concurrency permits held by operations per bucket.",
+        &["bucket", "operation"],
+    )
+    .expect("register metric")
+});
+
+/// The time operations spend waiting for their concurrency permits per bucket.
+pub static CONCURRENCY_QUEUE_WAIT: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "lust_concurrency_queue_wait_seconds",
+        "The time operations spend waiting for their concurrency permits per bucket.",
+        &["bucket", "operation"],
+    )
+    .expect("register metric")
+});
+
+/// The number of operations which never got their concurrency permits.
+///
+/// Labelled by the bucket, the operation and the reason, either `abandoned`
+/// if the request was dropped while queued, e.g. the client disconnected,
+/// or `error` if the permits could not be acquired.
+pub static CONCURRENCY_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_concurrency_rejections_total",
+        "The number of operations which never got their concurrency permits.",
+        &["bucket", "operation", "reason"],
+    )
+    .expect("register metric")
+});
+
+/// The number of changes replicated to the replication target.
+///
+/// Labelled by the outcome, either `replicated`, `failed` once the retries
+/// are exhausted, or `dropped` if the queue was full.
+pub static REPLICATION_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_replication_events_total",
+        "The number of changes replicated to the replication target.",
+        &["outcome"],
+    )
+    .expect("register metric")
+});
+
+/// The number of changes waiting to be replicated.
+pub static REPLICATION_PENDING: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "lust_replication_pending",
+        "The number of changes waiting to be replicated.",
+    )
+    .expect("register metric")
+});
+
+/// The time between the most recently replicated change
+/// being made and it being replicated.
+pub static REPLICATION_LAG: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "lust_replication_lag_seconds",
+        "The time between the most recently replicated change being made and it being replicated.",
+    )
+    .expect("register metric")
+});
+
+/// The number of purges sent to the CDN.
+///
+/// Labelled by the bucket, the purge mode, either `soft` or `hard`, and the
+/// outcome, either `purged` or `failed`.
+pub static CDN_PURGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_cdn_purges_total",
+        "The number of purges sent to the CDN.",
+        &["bucket", "mode", "outcome"],
+    )
+    .expect("register metric")
+});
+
+/// The number of requests a replica forwarded to the primary.
+///
+/// Labelled by the outcome, either `forwarded` or `failed` if the
+/// primary could not be reached.
+pub static FORWARDED_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_forwarded_requests_total",
+        "The number of requests forwarded to the primary instance.",
+        &["outcome"],
+    )
+    .expect("register metric")
+});
+
+/// The number of objects announced by S3 event notifications.
+///
+/// Labelled by the outcome, either `imported`, `skipped` if the object
+/// is not watched or not an image, or `failed` if it will be retried.
+pub static IMPORTED_OBJECTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_imported_objects_total",
+        "The number of objects announced by S3 event notifications.",
+        &["outcome"],
+    )
+    .expect("register metric")
+});
+
+/// The number of files dropped into the watched directory.
+///
+/// Labelled by the outcome, either `processed` or `failed`.
+pub static WATCHED_FILES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "lust_watched_files_total",
+        "The number of files dropped into the watched directory.",
+        &["outcome"],
+    )
+    .expect("register metric")
+});
+
+/// The memory statistics of the global allocator in bytes.
+///
+/// Labelled by the allocator and the statistic (`resident`, `active` or `allocated`),
+/// statistics the allocator doesn't track are not reported.
+pub static ALLOCATOR_MEMORY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "lust_allocator_memory_bytes",
+        "The memory statistics of the global allocator in bytes.",
+        &["allocator", "stat"],
+    )
+    .expect("register metric")
+});
+
+/// The fraction of the allocator's active memory lost to fragmentation.
+pub static ALLOCATOR_FRAGMENTATION: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "lust_allocator_fragmentation_ratio",
+        "The fraction of the allocator's active memory lost to fragmentation.",
+        &["allocator"],
+    )
+    .expect("register metric")
+});
+
+/// Refreshes the allocator gauges, these are sampled as they're rendered
+/// rather than tracked continuously.
+fn observe_allocator() {
+    let allocator = crate::allocator::name();
+    let stats = crate::allocator::stats();
+
+    let memory = [
+        ("resident", stats.resident()),
+        ("active", stats.active()),
+        ("allocated", stats.allocated()),
+    ];
+    for (stat, value) in memory {
+        if let Some(value) = value {
+            ALLOCATOR_MEMORY
+                .with_label_values(&[allocator, stat])
+                .set(value as i64);
+        }
+    }
+
+    if let Some(fragmentation) = stats.fragmentation() {
+        ALLOCATOR_FRAGMENTATION
+            .with_label_values(&[allocator])
+            .set(fragmentation);
+    }
+}
+
+/// Renders all registered metrics in the Prometheus text format.
+pub fn render() -> anyhow::Result<String> {
+    observe_allocator();
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -1,39 +1,65 @@
 use bytes::Bytes;
 use hashbrown::HashMap;
+use image::io::Limits;
 
 use crate::config::{BucketConfig, ImageFormats, ImageKind, ResizingConfig};
-use crate::pipelines::{Pipeline, PipelineResult, StoreEntry};
+use crate::pipelines::{Conversion, Pipeline, PipelineResult, StoreEntry};
+use crate::pipelines::rules::ProcessingRules;
 use crate::processor;
+use crate::processor::alpha::AlphaHandling;
 
 pub struct AheadOfTimePipeline {
     presets: HashMap<u32, ResizingConfig>,
     formats: ImageFormats,
+    alpha: AlphaHandling,
+    rules: ProcessingRules,
+    store_original: bool,
+    limits: Limits,
 }
 
 impl AheadOfTimePipeline {
-    pub fn new(cfg: &BucketConfig) -> Self {
-        Self {
+    pub fn new(cfg: &BucketConfig) -> anyhow::Result<Self> {
+        Ok(Self {
             presets: cfg.presets
                 .iter()
-                .map(|(key, cfg)| (crate::utils::crc_hash(key), cfg.clone()))
+                .map(|(key, cfg)| (crate::utils::crc_hash(key), *cfg))
                 .collect(),
             formats: cfg.formats,
-        }
+            alpha: AlphaHandling::new(cfg.alpha.as_ref())?,
+            rules: ProcessingRules::new(cfg),
+            store_original: cfg.original.store,
+            limits: processor::decode_limits(cfg.decode_limits),
+        })
     }
 }
 
 impl Pipeline for AheadOfTimePipeline {
     fn on_upload(&self, kind: ImageKind, data: Vec<u8>) -> anyhow::Result<PipelineResult> {
-        let resized = processor::resizer::resize_image_to_presets(&self.presets, kind, data.into())?;
+        let source_size = data.len();
+        let presets = self.rules.presets_for(&self.presets, kind, &data);
+        let resized = processor::resizer::resize_image_to_presets(&presets, kind, data.into(), &self.limits)?;
 
         let mut to_store = vec![];
+        let mut conversions = vec![];
         for to_encode in resized {
+            if to_encode.sizing_id == 0 && !self.store_original {
+                continue;
+            }
+
+            let formats = self.rules.formats_for(self.formats, to_encode.sizing_id, source_size);
+            if !ImageKind::variants().iter().any(|kind| formats.is_enabled(*kind)) {
+                continue;
+            }
+
             let encoded_images = processor::encoder::encode_following_config(
-                self.formats,
+                formats,
+                self.alpha,
+                self.presets.get(&to_encode.sizing_id).map(|cfg| cfg.max_size).unwrap_or_default(),
                 to_encode.img,
                 to_encode.sizing_id
             )?;
 
+            conversions.extend(encoded_images.iter().map(|v| Conversion::new(kind, v)));
             to_store.extend(
                 encoded_images
                 .into_iter()
@@ -47,24 +73,65 @@ impl Pipeline for AheadOfTimePipeline {
         Ok(PipelineResult {
             response: None,
             to_store,
+            conversions,
         })
     }
 
     fn on_fetch(
         &self,
-        _desired_kind: ImageKind,
+        desired_kind: ImageKind,
         data_kind: ImageKind,
         data: Bytes,
         sizing_id: u32,
         _custom_size: Option<(u32, u32)>,
     ) -> anyhow::Result<PipelineResult> {
+        // Existing variants are served directly by the controller, so we only
+        // get here when regenerating a missing variant from the original.
+        if !self.rules.should_encode(desired_kind, sizing_id, data.len()) {
+            return Ok(PipelineResult::unprocessed(data_kind, data))
+        }
+
+        let webp_config = self.formats.webp_config.as_encoder_config();
+
+        let img = processor::decode(&data, data_kind, &self.limits)?;
+        // Presets skipped by the processing rules are served at the original
+        // size and not stored, as they would only duplicate the original.
+        let (img, sizing_id, store) = match self.presets.get(&sizing_id) {
+            Some(cfg) if sizing_id != 0 => {
+                if self.rules.should_resize(cfg, (img.width(), img.height())) {
+                    (processor::resizer::resize(*cfg, &img)?, sizing_id, true)
+                } else {
+                    (img, 0, false)
+                }
+            },
+            _ => (img, 0, true),
+        };
+
+        let encoded = processor::encoder::encode_once(
+            webp_config,
+            self.alpha,
+            self.presets.get(&sizing_id).map(|cfg| cfg.max_size).unwrap_or_default(),
+            desired_kind,
+            img,
+            sizing_id,
+        )?;
+
         Ok(PipelineResult {
             response: Some(StoreEntry {
-                data,
-                sizing_id,
-                kind: data_kind,
+                kind: encoded.kind,
+                data: encoded.buff.clone(),
+                sizing_id: encoded.sizing_id,
             }),
-            to_store: vec![],
+            to_store: if store {
+                vec![StoreEntry {
+                    kind: encoded.kind,
+                    data: encoded.buff.clone(),
+                    sizing_id: encoded.sizing_id,
+                }]
+            } else {
+                vec![]
+            },
+            conversions: vec![Conversion::new(data_kind, &encoded)],
         })
     }
-}
\ No newline at end of file
+}
@@ -1,39 +1,47 @@
 use bytes::Bytes;
 use hashbrown::HashMap;
-use image::load_from_memory_with_format;
-use crate::config::{BucketConfig, ImageFormats, ImageKind, ResizingConfig};
-use crate::pipelines::{Pipeline, PipelineResult, StoreEntry};
+use image::io::Limits;
+use image::DynamicImage;
+use crate::config::{BucketConfig, ImageFormats, ImageKind, ResizingConfig, SizeBudget};
+use crate::pipelines::{Conversion, Pipeline, PipelineResult, StoreEntry};
+use crate::pipelines::rules::ProcessingRules;
 use crate::processor;
+use crate::processor::alpha::AlphaHandling;
 
 pub struct JustInTimePipeline {
     presets: HashMap<u32, ResizingConfig>,
     formats: ImageFormats,
+    alpha: AlphaHandling,
+    rules: ProcessingRules,
+    encode_siblings: bool,
+    limits: Limits,
 }
 
 impl JustInTimePipeline {
-    pub fn new(cfg: &BucketConfig) -> Self {
-        Self {
+    pub fn new(cfg: &BucketConfig) -> anyhow::Result<Self> {
+        Ok(Self {
             presets: cfg.presets
                 .iter()
-                .map(|(key, cfg)| (crate::utils::crc_hash(key), cfg.clone()))
+                .map(|(key, cfg)| (crate::utils::crc_hash(key), *cfg))
                 .collect(),
             formats: cfg.formats,
-        }
+            alpha: AlphaHandling::new(cfg.alpha.as_ref())?,
+            rules: ProcessingRules::new(cfg),
+            encode_siblings: cfg.jit.encode_siblings,
+            limits: processor::decode_limits(cfg.decode_limits),
+        })
     }
 }
 
 impl Pipeline for JustInTimePipeline {
     fn on_upload(&self, kind: ImageKind, data: Vec<u8>) -> anyhow::Result<PipelineResult> {
-        let webp_config = webp::config(
-            self.formats.webp_config.quality.is_none(),
-            self.formats.webp_config.quality.unwrap_or(50f32),
-            self.formats.webp_config.method.unwrap_or(4) as i32,
-            self.formats.webp_config.threading,
-        );
-
-        let img = load_from_memory_with_format(&data, kind.into())?;
+        let webp_config = self.formats.webp_config.as_encoder_config();
+
+        let img = processor::decode(&data, kind, &self.limits)?;
         let img = processor::encoder::encode_once(
             webp_config,
+            self.alpha,
+            SizeBudget::default(),
             self.formats.original_image_store_format,
             img,
             0,
@@ -41,6 +49,7 @@ impl Pipeline for JustInTimePipeline {
 
         Ok(PipelineResult {
             response: None,
+            conversions: vec![Conversion::new(kind, &img)],
             to_store: vec![StoreEntry { kind: img.kind, data: img.buff, sizing_id: img.sizing_id }],
         })
     }
@@ -53,26 +62,35 @@ impl Pipeline for JustInTimePipeline {
         sizing_id: u32,
         _custom_size: Option<(u32, u32)>,
     ) -> anyhow::Result<PipelineResult> {
-        let webp_config = webp::config(
-            self.formats.webp_config.quality.is_none(),
-            self.formats.webp_config.quality.unwrap_or(50f32),
-            self.formats.webp_config.method.unwrap_or(4) as i32,
-            self.formats.webp_config.threading,
-        );
-
-        let img = load_from_memory_with_format(&data, data_kind.into())?;
-        let (img, sizing_id) = if sizing_id != 0 {
-            if let Some(cfg) = self.presets.get(&sizing_id) {
-                (processor::resizer::resize(*cfg, &img), sizing_id)
-            } else {
-                (img, 0)
-            }
-        } else {
-            (img, 0)
+        if !self.rules.should_encode(desired_kind, sizing_id, data.len()) {
+            return Ok(PipelineResult::unprocessed(data_kind, data))
+        }
+
+        let webp_config = self.formats.webp_config.as_encoder_config();
+
+        let img = processor::decode(&data, data_kind, &self.limits)?;
+        // Presets skipped by the processing rules are served at the original
+        // size and not stored, as they would only duplicate the original.
+        let (img, sizing_id, store) = match self.presets.get(&sizing_id) {
+            Some(cfg) if sizing_id != 0 => {
+                if self.rules.should_resize(cfg, (img.width(), img.height())) {
+                    (processor::resizer::resize(*cfg, &img)?, sizing_id, true)
+                } else {
+                    (img, 0, false)
+                }
+            },
+            _ => (img, 0, true),
         };
 
+        let budget = self.presets.get(&sizing_id).map(|cfg| cfg.max_size).unwrap_or_default();
+        if store && self.encode_siblings {
+            return self.encode_with_siblings(desired_kind, data_kind, img, sizing_id, budget, data.len())
+        }
+
         let encoded = processor::encoder::encode_once(
             webp_config,
+            self.alpha,
+            budget,
             desired_kind,
             img,
             sizing_id,
@@ -84,11 +102,71 @@ impl Pipeline for JustInTimePipeline {
                 data: encoded.buff.clone(),
                 sizing_id: encoded.sizing_id,
             }),
-            to_store: vec![StoreEntry {
-                kind: encoded.kind,
-                data: encoded.buff.clone(),
-                sizing_id: encoded.sizing_id,
-            }]
+            to_store: if store {
+                vec![StoreEntry {
+                    kind: encoded.kind,
+                    data: encoded.buff.clone(),
+                    sizing_id: encoded.sizing_id,
+                }]
+            } else {
+                vec![]
+            },
+            conversions: vec![Conversion::new(data_kind, &encoded)],
         })
     }
-}
\ No newline at end of file
+}
+
+impl JustInTimePipeline {
+    /// Encodes every enabled format of the variant concurrently, responding
+    /// with the desired format and storing them all.
+    fn encode_with_siblings(
+        &self,
+        desired_kind: ImageKind,
+        data_kind: ImageKind,
+        img: DynamicImage,
+        sizing_id: u32,
+        budget: SizeBudget,
+        source_size: usize,
+    ) -> anyhow::Result<PipelineResult> {
+        // The original is never overwritten by a re-encoded copy of itself.
+        let mut formats = self.rules.formats_for(self.formats, sizing_id, source_size);
+        if sizing_id == 0 {
+            formats.set_enabled(self.formats.original_image_store_format, false);
+        }
+        formats.set_enabled(desired_kind, true);
+
+        let encoded = processor::encoder::encode_following_config(
+            formats,
+            self.alpha,
+            budget,
+            img,
+            sizing_id,
+        )?;
+
+        let conversions = encoded
+            .iter()
+            .map(|v| Conversion::new(data_kind, v))
+            .collect();
+        let to_store: Vec<StoreEntry> = encoded
+            .into_iter()
+            .map(|v| StoreEntry {
+                kind: v.kind,
+                data: v.buff,
+                sizing_id: v.sizing_id,
+            })
+            .collect();
+
+        // Transparent images requested as JPEG may have fallen back to PNG.
+        let response = to_store
+            .iter()
+            .find(|v| v.kind == desired_kind)
+            .or_else(|| to_store.iter().find(|v| v.kind == ImageKind::Png))
+            .cloned();
+
+        Ok(PipelineResult {
+            response,
+            to_store,
+            conversions,
+        })
+    }
+}
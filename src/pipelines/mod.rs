@@ -1,21 +1,30 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use anyhow::anyhow;
 use bytes::Bytes;
 use serde::Deserialize;
-use crate::config::{BucketConfig, ImageKind};
+use sha2::{Digest, Sha256};
+use image::io::Limits;
+use crate::config::{AnimationLimits, BucketConfig, CacheConfig, ImageKind, ResolutionCap, WebpConfig};
+use crate::processor;
+use crate::processor::encoder::EncodedImage;
+use crate::processor::metadata::{KeptMetadata, MetadataPolicy};
 
 pub mod realtime;
 pub mod aot;
 pub mod jit;
 mod register;
+mod rules;
 
 pub use register::{Pipeline, PipelineSelector};
 
-#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum ProcessingMode {
     /// Images will be optimised and resized when they're
     /// requested and then stored.
+    #[default]
     Jit,
 
     /// Images have all optimizations and resizing applied to them
@@ -27,23 +36,90 @@ pub enum ProcessingMode {
     Realtime,
 }
 
-impl Default for ProcessingMode {
-    fn default() -> Self {
-        Self::Jit
-    }
-}
-
 impl ProcessingMode {
-    pub fn build_pipeline(&self, cfg: &BucketConfig) -> PipelineController {
+    pub fn build_pipeline(&self, bucket: &str, cfg: &BucketConfig) -> anyhow::Result<PipelineController> {
         // Macro magic, ignore any type errors by the linter here.
         let selector = match self {
-            Self::Jit => PipelineSelector::from(jit::JustInTimePipeline::new(cfg)),
-            Self::Aot => PipelineSelector::from(aot::AheadOfTimePipeline::new(cfg)),
-            Self::Realtime => PipelineSelector::from(realtime::RealtimePipeline::new(cfg)),
+            Self::Jit => PipelineSelector::from(jit::JustInTimePipeline::new(cfg)?),
+            Self::Aot => PipelineSelector::from(aot::AheadOfTimePipeline::new(cfg)?),
+            Self::Realtime => PipelineSelector::from(realtime::RealtimePipeline::new(cfg)?),
         };
 
-        PipelineController {
+        let results = cfg.encoder_cache
+            .map(new_result_cache)
+            .transpose()?
+            .flatten();
+
+        Ok(PipelineController {
+            bucket: bucket.to_string(),
+            presets: Arc::new(
+                cfg.presets
+                    .keys()
+                    .map(|name| (crate::utils::crc_hash(name), name.clone()))
+                    .collect(),
+            ),
             inner: selector.into(),
+            results,
+            max_resolution: cfg.max_resolution,
+            convert_to_srgb: cfg.convert_to_srgb,
+            animation_limits: cfg.animation_limits,
+            webp_config: cfg.formats.webp_config,
+            limits: processor::decode_limits(cfg.decode_limits),
+            metadata: MetadataPolicy::new(cfg),
+        })
+    }
+}
+
+fn new_result_cache(cfg: CacheConfig) -> anyhow::Result<Option<ResultCache>> {
+    if cfg.max_capacity.is_some() && cfg.max_images.is_some() {
+        return Err(anyhow!("Encoder cache must be *either* based off of number of images or amount of memory, not both."))
+    } else if cfg.max_capacity.is_none() && cfg.max_images.is_none() {
+        return Ok(None)
+    }
+
+    let mut cache = moka::sync::CacheBuilder::default();
+    if let Some(max_items) = cfg.max_images {
+        cache = cache.max_capacity(max_items as u64)
+    }
+
+    if let Some(max_memory) = cfg.max_capacity {
+        cache = cache
+            .weigher(|_: &ResultKey, v: &PipelineResult| v.size() as u32)
+            .max_capacity((max_memory * 1024 * 1024) as u64);
+    }
+
+    Ok(Some(cache.build()))
+}
+
+type ResultCache = moka::sync::Cache<ResultKey, PipelineResult>;
+
+/// The operation a pipeline was ran with.
+#[derive(Copy, Clone, Hash, Eq, PartialEq)]
+enum Operation {
+    Upload {
+        kind: ImageKind,
+    },
+    Fetch {
+        desired_kind: ImageKind,
+        data_kind: ImageKind,
+        sizing_id: u32,
+        custom_size: Option<(u32, u32)>,
+    },
+}
+
+/// Identifies a pipeline output by the source image content
+/// rather than the image id.
+#[derive(Clone, Hash, Eq, PartialEq)]
+struct ResultKey {
+    checksum: [u8; 32],
+    operation: Operation,
+}
+
+impl ResultKey {
+    fn new(data: &[u8], operation: Operation) -> Self {
+        Self {
+            checksum: Sha256::digest(data).into(),
+            operation,
         }
     }
 }
@@ -56,15 +132,60 @@ pub struct ExecutionResult {
     pub execution_time: Duration,
 }
 
+#[derive(Clone)]
 pub struct PipelineResult {
     /// To be returned to the client in some form.
     pub response: Option<StoreEntry>,
 
     /// To be persisted to the given storage backend.
     pub to_store: Vec<StoreEntry>,
+
+    /// The format conversions ran to produce the result.
+    pub conversions: Vec<Conversion>,
+}
+
+impl PipelineResult {
+    /// Responds with the source image as is without storing anything.
+    pub fn unprocessed(kind: ImageKind, data: Bytes) -> Self {
+        Self {
+            response: Some(StoreEntry { data, kind, sizing_id: 0 }),
+            to_store: vec![],
+            conversions: vec![],
+        }
+    }
+
+    /// The approximate memory used by the result's image data.
+    fn size(&self) -> usize {
+        self.response
+            .iter()
+            .chain(self.to_store.iter())
+            .map(|entry| entry.data.len())
+            .sum()
+    }
+}
+
+/// An image encoded from one format to another by a pipeline.
+#[derive(Copy, Clone)]
+pub struct Conversion {
+    pub from: ImageKind,
+    pub to: ImageKind,
+    pub sizing_id: u32,
+    pub duration: Duration,
+}
+
+impl Conversion {
+    pub fn new(from: ImageKind, encoded: &EncodedImage) -> Self {
+        Self {
+            from,
+            to: encoded.kind,
+            sizing_id: encoded.sizing_id,
+            duration: encoded.encode_time,
+        }
+    }
 }
 
 /// The raw binary data of the image.
+#[derive(Clone)]
 pub struct StoreEntry {
     pub data: Bytes,
     pub kind: ImageKind,
@@ -73,7 +194,17 @@ pub struct StoreEntry {
 
 #[derive(Clone)]
 pub struct PipelineController {
+    bucket: String,
+    /// The preset names by their sizing id.
+    presets: Arc<HashMap<u32, String>>,
     inner: Arc<register::PipelineSelector>,
+    results: Option<ResultCache>,
+    max_resolution: Option<ResolutionCap>,
+    convert_to_srgb: bool,
+    animation_limits: Option<AnimationLimits>,
+    webp_config: WebpConfig,
+    limits: Limits,
+    metadata: MetadataPolicy,
 }
 
 impl PipelineController {
@@ -83,7 +214,54 @@ impl PipelineController {
         data: Vec<u8>,
     ) -> anyhow::Result<ExecutionResult> {
         let instant = Instant::now();
-        let result = self.inner.on_upload(kind, data)?;
+        let key = self.results
+            .as_ref()
+            .map(|_| ResultKey::new(&data, Operation::Upload { kind }));
+
+        let result = self.cached_or_run("upload", key, || {
+            processor::catch_panic("upload", kind, || {
+                let data = match self.animation_limits {
+                    Some(limits) => processor::animation::enforce_limits(limits, kind, data)?,
+                    None => data,
+                };
+                let kept = self.metadata.extract(kind, &data);
+
+                // Converted before capping the resolution as re-encoding
+                // CMYK images without converting them inverts their colors.
+                let data = if self.convert_to_srgb {
+                    processor::color::convert_to_srgb(
+                        self.webp_config.as_encoder_config(),
+                        kind,
+                        data,
+                        &self.limits,
+                    )?
+                } else {
+                    data
+                };
+
+                // A converted image's original profile no longer describes it.
+                let kept = if self.convert_to_srgb {
+                    KeptMetadata { icc: self.metadata.extract(kind, &data).icc, ..kept }
+                } else {
+                    kept
+                };
+
+                let data = match self.max_resolution {
+                    Some(cap) => processor::resizer::cap_resolution(
+                        self.webp_config.as_encoder_config(),
+                        cap,
+                        kind,
+                        data,
+                        &self.limits,
+                    )?,
+                    None => data,
+                };
+
+                let result = self.inner.on_upload(kind, data)?;
+                self.apply_metadata(result, &kept)
+            }).map(|result| self.record_conversions(result))
+        });
+        let result = self.observe("upload", instant, result)?;
         let execution_time = instant.elapsed();
 
         Ok(ExecutionResult { result, execution_time })
@@ -98,10 +276,118 @@ impl PipelineController {
         custom_size: Option<(u32, u32)>,
     ) -> anyhow::Result<ExecutionResult> {
         let instant = Instant::now();
-        let result = self.inner.on_fetch(desired_kind, data_kind, data, sizing_id, custom_size)?;
+        let key = self.results
+            .as_ref()
+            .map(|_| {
+                let operation = Operation::Fetch {
+                    desired_kind,
+                    data_kind,
+                    sizing_id,
+                    custom_size,
+                };
+                ResultKey::new(&data, operation)
+            });
+
+        let result = self.cached_or_run("fetch", key, || {
+            processor::catch_panic("fetch", data_kind, || {
+                let kept = self.metadata.extract(data_kind, &data);
+                let result = self.inner.on_fetch(desired_kind, data_kind, data, sizing_id, custom_size)?;
+                self.apply_metadata(result, &kept)
+            }).map(|result| self.record_conversions(result))
+        });
+        let result = self.observe("fetch", instant, result)?;
         let execution_time = instant.elapsed();
 
         Ok(ExecutionResult { result, execution_time })
     }
-}
 
+    /// Strips the metadata of the pipeline outputs, keeping only
+    /// the metadata of the source image allowed by the bucket.
+    fn apply_metadata(&self, mut result: PipelineResult, kept: &KeptMetadata) -> anyhow::Result<PipelineResult> {
+        for entry in result.response.iter_mut().chain(result.to_store.iter_mut()) {
+            entry.data = self.metadata.apply(entry.kind, entry.data.clone(), kept)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Records the conversions ran by the pipeline.
+    ///
+    /// Results served from the result cache ran no conversions
+    /// so are never recorded.
+    fn record_conversions(&self, result: PipelineResult) -> PipelineResult {
+        for conversion in result.conversions.iter() {
+            let sizing = match conversion.sizing_id {
+                0 => "original",
+                sizing_id => self.presets
+                    .get(&sizing_id)
+                    .map(String::as_str)
+                    .unwrap_or("custom"),
+            };
+
+            let labels = [
+                self.bucket.as_str(),
+                conversion.from.as_file_extension(),
+                conversion.to.as_file_extension(),
+                sizing,
+            ];
+            crate::metrics::CONVERSIONS.with_label_values(&labels).inc();
+            crate::metrics::CONVERSION_DURATION
+                .with_label_values(&labels)
+                .observe(conversion.duration.as_secs_f64());
+        }
+
+        result
+    }
+
+    /// Records the processing metrics of a pipeline execution.
+    fn observe(
+        &self,
+        stage: &str,
+        start: Instant,
+        result: anyhow::Result<PipelineResult>,
+    ) -> anyhow::Result<PipelineResult> {
+        crate::metrics::PROCESSING_DURATION
+            .with_label_values(&[&self.bucket, stage])
+            .observe(start.elapsed().as_secs_f64());
+
+        if let Err(ref e) = result {
+            if let Some(panic) = e.downcast_ref::<processor::ProcessingPanic>() {
+                crate::metrics::PROCESSING_PANICS
+                    .with_label_values(&[&self.bucket, panic.stage, panic.kind.as_file_extension()])
+                    .inc();
+            }
+        }
+
+        result
+    }
+
+    /// Returns a previous result for the same source content and operation
+    /// if one is cached, otherwise runs the pipeline and caches the output.
+    fn cached_or_run(
+        &self,
+        stage: &str,
+        key: Option<ResultKey>,
+        run: impl FnOnce() -> anyhow::Result<PipelineResult>,
+    ) -> anyhow::Result<PipelineResult> {
+        let (cache, key) = match (self.results.as_ref(), key) {
+            (Some(cache), Some(key)) => (cache, key),
+            _ => return run(),
+        };
+
+        let cached = cache.get(&key);
+        crate::metrics::ENCODER_CACHE_REQUESTS
+            .with_label_values(&[&self.bucket, stage, if cached.is_some() { "hit" } else { "miss" }])
+            .inc();
+
+        if let Some(result) = cached {
+            debug!("Reusing cached pipeline result for identical source image");
+            return Ok(result);
+        }
+
+        let result = run()?;
+        cache.insert(key, result.clone());
+
+        Ok(result)
+    }
+}
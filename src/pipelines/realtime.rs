@@ -1,41 +1,42 @@
 use bytes::Bytes;
 use hashbrown::HashMap;
-use image::load_from_memory_with_format;
-use crate::config::{BucketConfig, ImageFormats, ImageKind, ResizingConfig};
-use crate::pipelines::{Pipeline, PipelineResult, StoreEntry};
+use image::io::Limits;
+use crate::config::{BucketConfig, ImageFormats, ImageKind, ResizingConfig, SizeBudget};
+use crate::pipelines::{Conversion, Pipeline, PipelineResult, StoreEntry};
 use crate::processor;
+use crate::processor::alpha::AlphaHandling;
 
 pub struct RealtimePipeline {
     presets: HashMap<u32, ResizingConfig>,
     formats: ImageFormats,
+    alpha: AlphaHandling,
+    limits: Limits,
 }
 
 impl RealtimePipeline {
-    pub fn new(cfg: &BucketConfig) -> Self {
-        Self {
+    pub fn new(cfg: &BucketConfig) -> anyhow::Result<Self> {
+        Ok(Self {
             presets: cfg.presets
                 .iter()
                 .map(|(key, cfg)| (crate::utils::crc_hash(key), *cfg))
                 .collect(),
             formats: cfg.formats,
-        }
+            alpha: AlphaHandling::new(cfg.alpha.as_ref())?,
+            limits: processor::decode_limits(cfg.decode_limits),
+        })
     }
 }
 
 impl Pipeline for RealtimePipeline {
     fn on_upload(&self, kind: ImageKind, data: Vec<u8>) -> anyhow::Result<PipelineResult> {
-        let webp_config = webp::config(
-            self.formats.webp_config.quality.is_none(),
-            self.formats.webp_config.quality.unwrap_or(50f32),
-            self.formats.webp_config.method.unwrap_or(4) as i32,
-            self.formats.webp_config.threading,
-        );
+        let webp_config = self.formats.webp_config.as_encoder_config();
 
-        let img = load_from_memory_with_format(&data, kind.into())?;
-        let img = processor::encoder::encode_once(webp_config, self.formats.original_image_store_format, img, 0)?;
+        let img = processor::decode(&data, kind, &self.limits)?;
+        let img = processor::encoder::encode_once(webp_config, self.alpha, SizeBudget::default(), self.formats.original_image_store_format, img, 0)?;
 
         Ok(PipelineResult {
             response: None,
+            conversions: vec![Conversion::new(kind, &img)],
             to_store: vec![StoreEntry { kind: img.kind, data: img.buff, sizing_id: 0 }],
         })
     }
@@ -48,24 +49,25 @@ impl Pipeline for RealtimePipeline {
         sizing_id: u32,
         custom_size: Option<(u32, u32)>,
     ) -> anyhow::Result<PipelineResult> {
-        let webp_config = webp::config(
-            self.formats.webp_config.quality.is_none(),
-            self.formats.webp_config.quality.unwrap_or(50f32),
-            self.formats.webp_config.method.unwrap_or(4) as i32,
-            self.formats.webp_config.threading,
-        );
+        let webp_config = self.formats.webp_config.as_encoder_config();
 
-        let img = load_from_memory_with_format(&data, data_kind.into())?;
-        let (img, sizing_id) = if sizing_id != 0 {
+        let img = processor::decode(&data, data_kind, &self.limits)?;
+        let (img, sizing_id) = if sizing_id != 0 || custom_size.is_some() {
             let maybe_resize = match self.presets.get(&sizing_id) {
                 None => if let Some((width, height)) = custom_size {
+                    let custom_id = crate::config::resolve_custom_sizing_id(
+                        self.presets.keys().copied(),
+                        width,
+                        height,
+                    )?;
+
                     Some((
                         ResizingConfig {
                             width,
                             height,
-                            filter: Default::default()
+                            ..Default::default()
                         },
-                        crate::utils::crc_hash((width, height)),
+                        custom_id,
                     ))
                 } else {
                     None
@@ -74,7 +76,7 @@ impl Pipeline for RealtimePipeline {
             };
 
             if let Some((cfg, sizing_id)) = maybe_resize {
-                (processor::resizer::resize(cfg, &img), sizing_id)
+                (processor::resizer::resize(cfg, &img)?, sizing_id)
             } else {
                 (img, 0)
             }
@@ -84,12 +86,15 @@ impl Pipeline for RealtimePipeline {
 
         let encoded = processor::encoder::encode_once(
             webp_config,
+            self.alpha,
+            self.presets.get(&sizing_id).map(|cfg| cfg.max_size).unwrap_or_default(),
             desired_kind,
             img,
             sizing_id,
         )?;
 
         Ok(PipelineResult {
+            conversions: vec![Conversion::new(data_kind, &encoded)],
             response: Some(StoreEntry {
                 kind: encoded.kind,
                 data: encoded.buff,
@@ -0,0 +1,90 @@
+use std::io::Cursor;
+
+use hashbrown::HashMap;
+use image::io::Reader;
+
+use crate::config::{BucketConfig, ImageFormats, ImageKind, ResizingConfig};
+
+/// Decides which variants are worth generating for a given source image.
+pub struct ProcessingRules {
+    skip_upscaling: bool,
+    min_source_size: HashMap<ImageKind, usize>,
+    original_format: ImageKind,
+}
+
+impl ProcessingRules {
+    pub fn new(cfg: &BucketConfig) -> Self {
+        Self {
+            skip_upscaling: cfg.processing_rules.skip_upscaling,
+            // Sources too small for a format are served as is, which would
+            // serve the original of buckets which mustn't serve it.
+            min_source_size: cfg.processing_rules.min_source_size
+                .iter()
+                .filter(|_| cfg.serve_original)
+                .map(|(kind, size)| (*kind, (*size * 1024) as usize))
+                .collect(),
+            original_format: cfg.formats.original_image_store_format,
+        }
+    }
+
+    /// If the given preset should be generated for a source of the given dimensions.
+    ///
+    /// Presets are resized to fit within their bounds, so a source that
+    /// already fits would only ever be upscaled.
+    pub fn should_resize(&self, preset: &ResizingConfig, dimensions: (u32, u32)) -> bool {
+        let (width, height) = dimensions;
+        !self.skip_upscaling || width > preset.width || height > preset.height
+    }
+
+    /// If the given format should be generated for a source of the given size in bytes.
+    pub fn should_encode(&self, kind: ImageKind, sizing_id: u32, source_size: usize) -> bool {
+        if sizing_id == 0 && kind == self.original_format {
+            return true
+        }
+
+        match self.min_source_size.get(&kind) {
+            None => true,
+            Some(min_size) => source_size >= *min_size,
+        }
+    }
+
+    /// The enabled formats with any formats the source is too small for disabled.
+    pub fn formats_for(&self, mut formats: ImageFormats, sizing_id: u32, source_size: usize) -> ImageFormats {
+        for kind in ImageKind::variants() {
+            if !self.should_encode(*kind, sizing_id, source_size) {
+                formats.set_enabled(*kind, false);
+            }
+        }
+
+        formats
+    }
+
+    /// The presets which should be generated for the given source image.
+    pub fn presets_for(
+        &self,
+        presets: &HashMap<u32, ResizingConfig>,
+        kind: ImageKind,
+        data: &[u8],
+    ) -> HashMap<u32, ResizingConfig> {
+        if !self.skip_upscaling {
+            return presets.clone()
+        }
+
+        let dimensions = match source_dimensions(kind, data) {
+            None => return presets.clone(),
+            Some(dimensions) => dimensions,
+        };
+
+        presets
+            .iter()
+            .filter(|(_, preset)| self.should_resize(preset, dimensions))
+            .map(|(sizing_id, preset)| (*sizing_id, *preset))
+            .collect()
+    }
+}
+
+fn source_dimensions(kind: ImageKind, data: &[u8]) -> Option<(u32, u32)> {
+    Reader::with_format(Cursor::new(data), kind.into())
+        .into_dimensions()
+        .ok()
+}
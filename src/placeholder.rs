@@ -0,0 +1,44 @@
+use anyhow::anyhow;
+use bytes::Bytes;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+
+use crate::config::{ImageKind, MissingImageConfig, MissingImageStatus};
+
+/// An image served in place of images which don't exist.
+#[derive(Clone)]
+pub struct Placeholder {
+    pub data: Bytes,
+    pub kind: ImageKind,
+    pub status: MissingImageStatus,
+}
+
+/// Loads or generates the placeholder image for the given config.
+pub fn load(cfg: &MissingImageConfig) -> anyhow::Result<Placeholder> {
+    let (data, kind) = if let Some(ref path) = cfg.path {
+        let data = std::fs::read(path)
+            .map_err(|e| anyhow!("Failed to read missing image {:?}: {}", path, e))?;
+
+        let kind = image::guess_format(&data)
+            .ok()
+            .and_then(ImageKind::from_guessed_format)
+            .ok_or_else(|| anyhow!("Missing image {:?} is not a supported image format.", path))?;
+
+        (Bytes::from(data), kind)
+    } else if let Some(ref colour) = cfg.colour {
+        let pixel = crate::utils::parse_colour(colour)?;
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(cfg.width, cfg.height, pixel));
+
+        let mut buff = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut buff, ImageFormat::Png)?;
+
+        (Bytes::from(buff.into_inner()), ImageKind::Png)
+    } else {
+        return Err(anyhow!("The missing image must have *either* a path or a colour."))
+    };
+
+    Ok(Placeholder {
+        data,
+        kind,
+        status: cfg.status,
+    })
+}
@@ -0,0 +1,94 @@
+use image::{DynamicImage, Rgb, RgbImage};
+
+use super::ProcessingError;
+use crate::config::{AlphaConfig, AlphaPolicy, ImageKind};
+
+/// The bucket's alpha policy with its background colour parsed.
+#[derive(Copy, Clone, Debug)]
+pub struct AlphaHandling {
+    pub policy: AlphaPolicy,
+    pub background: Rgb<u8>,
+}
+
+impl AlphaHandling {
+    pub fn new(cfg: Option<&AlphaConfig>) -> anyhow::Result<Self> {
+        let cfg = match cfg {
+            None => return Ok(Self::default()),
+            Some(cfg) => cfg,
+        };
+
+        let [r, g, b, _] = crate::utils::parse_colour(&cfg.background)?.0;
+        Ok(Self {
+            policy: cfg.policy,
+            background: Rgb([r, g, b]),
+        })
+    }
+
+    /// Resolves the format the image is encoded as when `kind` is requested,
+    /// flattening the image if required.
+    ///
+    /// Returns `None` for the image if it can be encoded as is.
+    pub fn apply(
+        &self,
+        img: &DynamicImage,
+        kind: ImageKind,
+    ) -> anyhow::Result<(ImageKind, Option<DynamicImage>)> {
+        if kind != ImageKind::Jpeg || !has_transparency(img) {
+            return Ok((kind, None))
+        }
+
+        match self.policy {
+            AlphaPolicy::Flatten => Ok((kind, Some(flatten(img, self.background)))),
+            AlphaPolicy::Fallback => Ok((ImageKind::Png, None)),
+            AlphaPolicy::Reject => Err(ProcessingError::UnsupportedConversion {
+                to: kind,
+                msg: "The image has transparent pixels.".to_string(),
+            }.into()),
+        }
+    }
+}
+
+impl Default for AlphaHandling {
+    fn default() -> Self {
+        Self {
+            policy: AlphaPolicy::Flatten,
+            background: Rgb([u8::MAX; 3]),
+        }
+    }
+}
+
+/// If any pixel of the image is not fully opaque.
+pub fn has_transparency(img: &DynamicImage) -> bool {
+    if !img.color().has_alpha() {
+        return false
+    }
+
+    match img {
+        DynamicImage::ImageLumaA8(img) => img.pixels().any(|p| p.0[1] != u8::MAX),
+        DynamicImage::ImageRgba8(img) => img.pixels().any(|p| p.0[3] != u8::MAX),
+        DynamicImage::ImageLumaA16(img) => img.pixels().any(|p| p.0[1] != u16::MAX),
+        DynamicImage::ImageRgba16(img) => img.pixels().any(|p| p.0[3] != u16::MAX),
+        other => other.to_rgba8().pixels().any(|p| p.0[3] != u8::MAX),
+    }
+}
+
+/// Blends the image onto the solid background colour.
+fn flatten(img: &DynamicImage, background: Rgb<u8>) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    let flattened = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let alpha = a as u32;
+
+        let blend = |channel: u8, bg: u8| {
+            ((channel as u32 * alpha + bg as u32 * (255 - alpha) + 127) / 255) as u8
+        };
+
+        Rgb([
+            blend(r, background.0[0]),
+            blend(g, background.0[1]),
+            blend(b, background.0[2]),
+        ])
+    });
+
+    DynamicImage::ImageRgb8(flattened)
+}
@@ -0,0 +1,84 @@
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::{AnimationDecoder, Frame};
+
+use crate::config::{AnimationLimitAction, AnimationLimits, ImageKind};
+
+/// An animated upload exceeding the bucket's animation limits.
+#[derive(Debug, Clone)]
+pub struct AnimationLimitExceeded {
+    pub limit: &'static str,
+}
+
+impl Display for AnimationLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The animation exceeds the max {}", self.limit)
+    }
+}
+
+impl std::error::Error for AnimationLimitExceeded {}
+
+/// Checks the animation against the limits, rejecting or truncating it
+/// depending on the configured action.
+///
+/// Frames are decoded lazily so the work done is bounded by the limits
+/// rather than the length of the animation. Animations within the limits
+/// are returned untouched.
+pub fn enforce_limits(
+    limits: AnimationLimits,
+    kind: ImageKind,
+    data: Vec<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    // GIF is the only animated format decoded.
+    if kind != ImageKind::Gif {
+        return Ok(data)
+    }
+
+    let max_decoded_size = limits.max_decoded_size.map(|kb| kb * 1024);
+    let mut frames: Vec<Frame> = vec![];
+    let mut duration = 0;
+    let mut decoded_size = 0;
+    let mut exceeded = None;
+
+    for frame in GifDecoder::new(Cursor::new(&data))?.into_frames() {
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        duration += (numer / denom.max(1)) as u64;
+        decoded_size += frame.buffer().as_raw().len() as u64;
+
+        if limits.max_frames.map(|max| frames.len() as u32 >= max).unwrap_or(false) {
+            exceeded = Some("frame count");
+        } else if limits.max_duration.map(|max| duration > max).unwrap_or(false) {
+            exceeded = Some("duration");
+        } else if max_decoded_size.map(|max| decoded_size > max).unwrap_or(false) {
+            exceeded = Some("decoded size");
+        }
+
+        if exceeded.is_some() {
+            break
+        }
+
+        frames.push(frame);
+    }
+
+    let limit = match exceeded {
+        None => return Ok(data),
+        Some(limit) => limit,
+    };
+
+    // A single frame is always kept so the upload remains a valid image.
+    if limits.on_exceeded == AnimationLimitAction::Reject || frames.is_empty() {
+        return Err(AnimationLimitExceeded { limit }.into())
+    }
+
+    let mut buff = vec![];
+    {
+        let mut encoder = GifEncoder::new(&mut buff);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
+    }
+
+    Ok(buff)
+}
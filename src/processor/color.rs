@@ -0,0 +1,191 @@
+use bytes::Bytes;
+use image::{DynamicImage, ImageBuffer};
+use image::io::Limits;
+use img_parts::{DynImage, ImageICC};
+use lcms2::{ColorSpaceSignature, Flags, InfoType, Intent, Locale, PixelFormat, Profile, Transform};
+
+use super::ProcessingError;
+use crate::config::ImageKind;
+
+/// The APP14 marker Adobe encoders use to describe the JPEG's color transform.
+const ADOBE_MARKER: u8 = 0xEE;
+
+/// The Adobe color transform of YCCK encoded JPEGs.
+const ADOBE_TRANSFORM_YCCK: u8 = 2;
+
+/// Converts images tagged with a non-sRGB color profile or encoded as CMYK
+/// to sRGB, re-encoding them in their original format.
+///
+/// Images already in sRGB or without a profile are returned untouched
+/// without being decoded. GIFs are always returned untouched.
+pub fn convert_to_srgb(
+    webp_cfg: webp::WebPConfig,
+    kind: ImageKind,
+    data: Vec<u8>,
+    limits: &Limits,
+) -> anyhow::Result<Vec<u8>> {
+    if let ImageKind::Gif = kind {
+        return Ok(data)
+    }
+
+    let bytes = Bytes::copy_from_slice(&data);
+    let parsed = DynImage::from_bytes(bytes.clone())
+        .map_err(|e| ProcessingError::DecodeFailed { kind, msg: e.to_string() })?;
+
+    let profile = match parsed.as_ref().and_then(|img| img.icc_profile()) {
+        None => None,
+        Some(icc) => Some(Profile::new_icc(&icc)?),
+    };
+
+    let img = match kind {
+        ImageKind::Jpeg if is_cmyk_jpeg(&data, kind)? => {
+            cmyk_jpeg_to_srgb(&data, &bytes, profile.as_ref())?
+        },
+        _ => match profile {
+            Some(ref profile) if needs_conversion(profile) => {
+                let img = super::decode(&data, kind, limits)?;
+                rgb_to_srgb(profile, img)?
+            },
+            _ => return Ok(data),
+        },
+    };
+
+    let encoded = super::encoder::encode_to(webp_cfg, &img, kind)?;
+
+    Ok(encoded.to_vec())
+}
+
+/// If the ICC profile is an RGB profile other than sRGB, i.e. the image
+/// shifts color when rendered without it.
+pub fn is_wide_gamut(icc: &[u8]) -> bool {
+    Profile::new_icc(icc)
+        .map(|profile| needs_conversion(&profile))
+        .unwrap_or(false)
+}
+
+/// Only RGB profiles other than sRGB need converting, grayscale images
+/// look the same in every browser.
+fn needs_conversion(profile: &Profile) -> bool {
+    if profile.color_space() != ColorSpaceSignature::RgbData {
+        return false
+    }
+
+    let description = profile
+        .info(InfoType::Description, Locale::none())
+        .unwrap_or_default();
+
+    !description.contains("sRGB")
+}
+
+fn is_cmyk_jpeg(data: &[u8], kind: ImageKind) -> anyhow::Result<bool> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder
+        .read_info()
+        .map_err(|e| ProcessingError::DecodeFailed { kind, msg: e.to_string() })?;
+
+    let is_cmyk = decoder
+        .info()
+        .map(|info| info.pixel_format == jpeg_decoder::PixelFormat::CMYK32)
+        .unwrap_or(false);
+
+    Ok(is_cmyk)
+}
+
+/// Decodes the CMYK JPEG and converts it to sRGB using its embedded profile,
+/// falling back to a naive conversion if it has none.
+fn cmyk_jpeg_to_srgb(
+    data: &[u8],
+    bytes: &Bytes,
+    profile: Option<&Profile>,
+) -> anyhow::Result<DynamicImage> {
+    let kind = ImageKind::Jpeg;
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    let mut pixels = decoder
+        .decode()
+        .map_err(|e| ProcessingError::DecodeFailed { kind, msg: e.to_string() })?;
+
+    let info = decoder
+        .info()
+        .ok_or_else(|| ProcessingError::DecodeFailed { kind, msg: "Missing image info".to_string() })?;
+
+    // YCCK images come out of the decoder with their CMY channels
+    // inverted, unlike plain CMYK images, which is what gave CMYK
+    // uploads their inverted look.
+    if adobe_transform(bytes) == Some(ADOBE_TRANSFORM_YCCK) {
+        for pixel in pixels.chunks_exact_mut(4) {
+            for channel in &mut pixel[..3] {
+                *channel = 255 - *channel;
+            }
+        }
+    }
+
+    let mut rgb = vec![0; pixels.len() / 4 * 3];
+    match profile.filter(|p| p.color_space() == ColorSpaceSignature::CmykData) {
+        Some(profile) => {
+            let transform = Transform::<u8, u8>::new(
+                profile,
+                PixelFormat::CMYK_8,
+                &Profile::new_srgb(),
+                PixelFormat::RGB_8,
+                Intent::Perceptual,
+            )?;
+            transform.transform_pixels(&pixels, &mut rgb);
+        },
+        None => {
+            for (cmyk, rgb) in pixels.chunks_exact(4).zip(rgb.chunks_exact_mut(3)) {
+                let k = 255 - cmyk[3] as u32;
+                for (channel, ink) in rgb.iter_mut().zip(&cmyk[..3]) {
+                    *channel = ((255 - *ink as u32) * k / 255) as u8;
+                }
+            }
+        },
+    }
+
+    let img = ImageBuffer::from_raw(info.width as u32, info.height as u32, rgb)
+        .ok_or_else(|| ProcessingError::DecodeFailed { kind, msg: "Truncated image data".to_string() })?;
+
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+fn adobe_transform(bytes: &Bytes) -> Option<u8> {
+    let jpeg = img_parts::jpeg::Jpeg::from_bytes(bytes.clone()).ok()?;
+    let segment = jpeg.segments_by_marker(ADOBE_MARKER).next()?;
+    let contents = segment.contents();
+
+    if !contents.starts_with(b"Adobe") {
+        return None
+    }
+
+    contents.get(11).copied()
+}
+
+fn rgb_to_srgb(profile: &Profile, img: DynamicImage) -> anyhow::Result<DynamicImage> {
+    let srgb = Profile::new_srgb();
+
+    let img = if img.color().has_alpha() {
+        let mut img = img.into_rgba8();
+        let transform = Transform::<u8, u8>::new_flags(
+            profile,
+            PixelFormat::RGBA_8,
+            &srgb,
+            PixelFormat::RGBA_8,
+            Intent::Perceptual,
+            Flags::COPY_ALPHA,
+        )?;
+        transform.transform_in_place(&mut img);
+        DynamicImage::ImageRgba8(img)
+    } else {
+        let mut img = img.into_rgb8();
+        let transform = Transform::<u8, u8>::new(
+            profile,
+            PixelFormat::RGB_8,
+            &srgb,
+            PixelFormat::RGB_8,
+            Intent::Perceptual,
+        )?;
+        transform.transform_in_place(&mut img);
+        DynamicImage::ImageRgb8(img)
+    };
+
+    Ok(img)
+}
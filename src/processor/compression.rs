@@ -0,0 +1,28 @@
+use bytes::Bytes;
+
+/// The magic number every zstd frame begins with.
+///
+/// None of the supported image formats share this prefix so it can
+/// be safely used to detect compressed originals.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+#[inline]
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(&ZSTD_MAGIC)
+}
+
+pub fn compress(data: &[u8], level: i32) -> anyhow::Result<Bytes> {
+    let compressed = zstd::bulk::compress(data, level)?;
+    Ok(Bytes::from(compressed))
+}
+
+/// Decompresses the buffer if it is a zstd frame, otherwise the
+/// buffer is returned as is.
+pub fn maybe_decompress(data: Bytes) -> anyhow::Result<Bytes> {
+    if !is_compressed(&data) {
+        return Ok(data)
+    }
+
+    let decompressed = zstd::stream::decode_all(data.as_ref())?;
+    Ok(Bytes::from(decompressed))
+}
@@ -1,43 +1,69 @@
 use std::io::Cursor;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use bytes::Bytes;
 use image::{DynamicImage, ImageFormat};
-use crate::config::{ImageFormats, ImageKind};
+use image::codecs::jpeg::JpegEncoder;
+use crate::config::{ImageFormats, ImageKind, SizeBudget};
+use super::alpha::AlphaHandling;
+use super::ProcessingError;
+
+/// The quality JPEGs are encoded at by default.
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+/// The lowest quality searched when encoding to a size budget.
+const MIN_BUDGET_QUALITY: u8 = 10;
 
 
 pub struct EncodedImage {
     pub kind: ImageKind,
     pub buff: Bytes,
     pub sizing_id: u32,
+
+    /// The time spent encoding the image.
+    pub encode_time: Duration,
 }
 
 pub fn encode_following_config(
     cfg: ImageFormats,
+    alpha: AlphaHandling,
+    budget: SizeBudget,
     img: DynamicImage,
     sizing_id: u32,
 ) -> anyhow::Result<Vec<EncodedImage>> {
     let original_image = Arc::new(img);
 
-    let webp_config = webp::config(
-        cfg.webp_config.quality.is_none(),
-        cfg.webp_config.quality.unwrap_or(50f32),
-        cfg.webp_config.method.unwrap_or(4) as i32,
-        cfg.webp_config.threading,
-    );
+    let webp_config = cfg.webp_config.as_encoder_config();
 
     let (tx, rx) = crossbeam::channel::bounded(4);
 
+    let mut to_encode = vec![];
     for variant in ImageKind::variants() {
-        if cfg.is_enabled(*variant) {
-            let tx_local = tx.clone();
-            let local = original_image.clone();
-            rayon::spawn(move || {
-                let result = encode_to(webp_config, &local, (*variant).into());
-                tx_local
-                    .send(result.map(|v| EncodedImage { kind: *variant, buff: v, sizing_id }))
-                    .expect("Failed to respond to encoding request. Sender already closed.");
-            });
+        if !cfg.is_enabled(*variant) {
+            continue
         }
+
+        let (kind, flattened) = alpha.apply(&original_image, *variant)?;
+        // Formats falling back to one already being encoded are skipped.
+        if kind != *variant && cfg.is_enabled(kind) {
+            continue
+        }
+
+        let img = flattened.map(Arc::new).unwrap_or_else(|| original_image.clone());
+        to_encode.push((kind, img));
+    }
+
+    for (kind, local) in to_encode {
+        let tx_local = tx.clone();
+        rayon::spawn(move || {
+            let start = Instant::now();
+            let result = super::catch_panic("encode", kind, || {
+                encode_within_budget(webp_config, &local, kind, budget.max_bytes(kind))
+            });
+            let encode_time = start.elapsed();
+            // The receiver is only gone if the request was abandoned.
+            let _ = tx_local.send(result.map(|v| EncodedImage { kind, buff: v, sizing_id, encode_time }));
+        });
     }
 
     // Needed to prevent deadlock.
@@ -58,16 +84,25 @@ pub fn encode_following_config(
 
 pub fn encode_once(
     webp_cfg: webp::WebPConfig,
+    alpha: AlphaHandling,
+    budget: SizeBudget,
     to: ImageKind,
     img: DynamicImage,
     sizing_id: u32,
 ) -> anyhow::Result<EncodedImage> {
+    let (to, flattened) = alpha.apply(&img, to)?;
+    let img = flattened.unwrap_or(img);
+
     let (tx, rx) = crossbeam::channel::bounded(4);
 
     rayon::spawn(move || {
-        let result = encode_to(webp_cfg, &img, to.into());
-        tx.send(result.map(|v| EncodedImage { kind: to, buff: v, sizing_id }))
-            .expect("Failed to respond to encoding request. Sender already closed.");
+        let start = Instant::now();
+        let result = super::catch_panic("encode", to, || {
+            encode_within_budget(webp_cfg, &img, to, budget.max_bytes(to))
+        });
+        let encode_time = start.elapsed();
+        // The receiver is only gone if the request was abandoned.
+        let _ = tx.send(result.map(|v| EncodedImage { kind: to, buff: v, sizing_id, encode_time }));
     });
 
     rx.recv()?
@@ -75,15 +110,100 @@ pub fn encode_once(
 
 
 #[inline]
-pub fn encode_to(webp_cfg: webp::WebPConfig, img: &DynamicImage, format: ImageFormat) -> anyhow::Result<Bytes> {
-    if let ImageFormat::WebP = format {
+pub fn encode_to(webp_cfg: webp::WebPConfig, img: &DynamicImage, kind: ImageKind) -> anyhow::Result<Bytes> {
+    if let ImageKind::Webp = kind {
         let webp_image = webp::Encoder::from_image(webp_cfg, img);
-        let encoded = webp_image.encode();
-
-        return Ok(Bytes::from(encoded?.to_vec()))
+        return webp_image
+            .encode()
+            .map_err(|e| ProcessingError::EncodeFailed { kind, msg: e.to_string() }.into())
     }
 
     let mut buff = Cursor::new(Vec::new());
-    img.write_to(&mut buff, format)?;
+    let format: ImageFormat = kind.into();
+    img.write_to(&mut buff, format)
+        .map_err(|e| super::encode_error(kind, e))?;
     Ok(Bytes::from(buff.into_inner()))
-}
\ No newline at end of file
+}
+
+/// Encodes the image, searching for the highest quality within `max_bytes`
+/// if the default quality exceeds it.
+///
+/// If the image exceeds the budget even at the lowest quality searched
+/// that encoding is returned regardless.
+pub fn encode_within_budget(
+    webp_cfg: webp::WebPConfig,
+    img: &DynamicImage,
+    kind: ImageKind,
+    max_bytes: Option<usize>,
+) -> anyhow::Result<Bytes> {
+    let max_bytes = match max_bytes {
+        None => return encode_to(webp_cfg, img, kind),
+        Some(max_bytes) => max_bytes,
+    };
+
+    let default_quality = match kind {
+        ImageKind::Jpeg => DEFAULT_JPEG_QUALITY,
+        ImageKind::Webp if webp_cfg.lossless == 0 => webp_cfg.quality as u8,
+        // Lossless WebPs are searched from the highest lossy quality.
+        ImageKind::Webp => 100,
+        _ => return encode_to(webp_cfg, img, kind),
+    };
+
+    let encoded = encode_with_quality(webp_cfg, img, kind, default_quality)?;
+    if encoded.len() <= max_bytes || default_quality <= MIN_BUDGET_QUALITY {
+        return Ok(encoded)
+    }
+
+    let mut low = MIN_BUDGET_QUALITY;
+    let mut high = default_quality - 1;
+    let mut within_budget = None;
+    let mut smallest = encoded;
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let encoded = encode_with_quality(webp_cfg, img, kind, quality)?;
+
+        if encoded.len() <= max_bytes {
+            within_budget = Some(encoded);
+            low = quality + 1;
+        } else {
+            if encoded.len() < smallest.len() {
+                smallest = encoded;
+            }
+
+            if quality == MIN_BUDGET_QUALITY {
+                break
+            }
+            high = quality - 1;
+        }
+    }
+
+    match within_budget {
+        Some(encoded) => Ok(encoded),
+        None => {
+            warn!(
+                "Unable to encode {:?} image within {} bytes, smallest encoding is {} bytes.",
+                kind, max_bytes, smallest.len(),
+            );
+            Ok(smallest)
+        },
+    }
+}
+
+fn encode_with_quality(
+    mut webp_cfg: webp::WebPConfig,
+    img: &DynamicImage,
+    kind: ImageKind,
+    quality: u8,
+) -> anyhow::Result<Bytes> {
+    if let ImageKind::Jpeg = kind {
+        let mut buff = Vec::new();
+        JpegEncoder::new_with_quality(&mut buff, quality)
+            .encode_image(img)
+            .map_err(|e| super::encode_error(kind, e))?;
+        return Ok(Bytes::from(buff))
+    }
+
+    webp_cfg.lossless = 0;
+    webp_cfg.quality = quality as f32;
+    encode_to(webp_cfg, img, kind)
+}
@@ -0,0 +1,309 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use img_parts::jpeg::{markers, Jpeg};
+use img_parts::png::Png;
+use img_parts::riff::{RiffChunk, RiffContent};
+use img_parts::webp::{WebP, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8L, CHUNK_VP8X, CHUNK_XMP};
+use img_parts::{ImageEXIF, ImageICC};
+
+use super::ProcessingError;
+use crate::config::{BucketConfig, ImageKind};
+
+/// The EXIF tag naming the creator of the image.
+const TAG_ARTIST: u16 = 0x013B;
+
+/// The EXIF tag holding the image's copyright notice.
+const TAG_COPYRIGHT: u16 = 0x8298;
+
+/// The EXIF type of null terminated ASCII values.
+const TYPE_ASCII: u16 = 2;
+
+/// The PNG chunks carrying EXIF, ICC profiles or textual metadata.
+const PNG_METADATA_CHUNKS: [[u8; 4]; 6] = [*b"eXIf", *b"iCCP", *b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"];
+
+/// The VP8X flags of the chunks metadata is kept in.
+const VP8X_ICC_FLAG: u8 = 0x20;
+const VP8X_ALPHA_FLAG: u8 = 0x10;
+const VP8X_EXIF_FLAG: u8 = 0x08;
+const VP8X_XMP_FLAG: u8 = 0x04;
+
+/// The metadata carried over from an upload onto the images
+/// produced from it.
+#[derive(Clone, Default)]
+pub struct KeptMetadata {
+    /// The EXIF data without the `Exif\0\0` prefix.
+    pub exif: Option<Bytes>,
+
+    /// The embedded ICC color profile.
+    pub icc: Option<Bytes>,
+}
+
+/// Which metadata of an upload is kept in the stored and served images.
+#[derive(Copy, Clone)]
+pub struct MetadataPolicy {
+    strip: bool,
+    copyright: bool,
+    icc: bool,
+}
+
+impl MetadataPolicy {
+    pub fn new(cfg: &BucketConfig) -> Self {
+        Self {
+            strip: cfg.strip_metadata,
+            copyright: cfg.preserve_metadata.copyright,
+            icc: cfg.preserve_metadata.icc,
+        }
+    }
+
+    /// If any metadata is carried over from the source image.
+    fn keeps_any(&self) -> bool {
+        !self.strip || self.copyright || self.icc
+    }
+
+    /// Whether the ICC profile of the source image is carried over.
+    fn keeps_icc(&self) -> bool {
+        !self.strip || self.icc
+    }
+
+    /// Reads the metadata of the source image which should be kept.
+    ///
+    /// When stripping, only the artist and copyright EXIF tags survive
+    /// and everything else, including the GPS position, is dropped.
+    ///
+    /// Only wide gamut RGB profiles are kept, outputs are always encoded as
+    /// RGB so CMYK profiles no longer apply and sRGB is assumed without one.
+    pub fn extract(&self, kind: ImageKind, data: &[u8]) -> KeptMetadata {
+        if !self.keeps_any() {
+            return KeptMetadata::default()
+        }
+
+        let (exif, icc) = read_metadata(kind, Bytes::copy_from_slice(data));
+        let exif = match exif {
+            Some(exif) if self.strip => self.copyright
+                .then(|| copyright_exif(&exif))
+                .flatten(),
+            exif => exif,
+        };
+
+        KeptMetadata {
+            exif,
+            icc: icc.filter(|icc| self.keeps_icc() && super::color::is_wide_gamut(icc)),
+        }
+    }
+
+    /// Removes all metadata from the encoded image before attaching
+    /// the kept metadata of its source.
+    ///
+    /// GIFs carry no EXIF or ICC data and are returned untouched.
+    pub fn apply(&self, kind: ImageKind, data: Bytes, kept: &KeptMetadata) -> anyhow::Result<Bytes> {
+        if !self.strip && kept.exif.is_none() && kept.icc.is_none() {
+            return Ok(data)
+        }
+
+        let encode_failed = |e: img_parts::Error| ProcessingError::EncodeFailed { kind, msg: e.to_string() };
+        let mut out = Vec::with_capacity(data.len());
+        match kind {
+            ImageKind::Gif => return Ok(data),
+            ImageKind::Jpeg => {
+                let mut jpeg = Jpeg::from_bytes(data).map_err(encode_failed)?;
+                for marker in [markers::APP1, markers::APP2, markers::APP13, markers::COM] {
+                    jpeg.remove_segments_by_marker(marker);
+                }
+                jpeg.set_exif(kept.exif.clone());
+                jpeg.set_icc_profile(kept.icc.clone());
+                jpeg.encoder().write_to(&mut out)?;
+            },
+            ImageKind::Png => {
+                let mut png = Png::from_bytes(data).map_err(encode_failed)?;
+                for chunk in PNG_METADATA_CHUNKS {
+                    png.remove_chunks_by_type(chunk);
+                }
+                png.set_exif(kept.exif.clone());
+                png.set_icc_profile(kept.icc.clone());
+                png.encoder().write_to(&mut out)?;
+            },
+            ImageKind::Webp => {
+                let mut webp = WebP::from_bytes(data).map_err(encode_failed)?;
+                set_webp_metadata(&mut webp, kept);
+                webp.encoder().write_to(&mut out)?;
+            },
+        }
+
+        Ok(out.into())
+    }
+}
+
+fn read_metadata(kind: ImageKind, data: Bytes) -> (Option<Bytes>, Option<Bytes>) {
+    match kind {
+        ImageKind::Gif => (None, None),
+        ImageKind::Jpeg => Jpeg::from_bytes(data)
+            .map(|jpeg| (jpeg.exif(), jpeg.icc_profile()))
+            .unwrap_or_default(),
+        ImageKind::Png => Png::from_bytes(data)
+            .map(|png| (png.exif(), png.icc_profile()))
+            .unwrap_or_default(),
+        ImageKind::Webp => WebP::from_bytes(data)
+            .map(|webp| {
+                // Most encoders write the EXIF chunk without the `Exif\0\0`
+                // prefix img-parts expects.
+                let exif = webp.exif().or_else(|| {
+                    webp.chunk_by_id(CHUNK_EXIF)?
+                        .content()
+                        .data()
+                        .cloned()
+                });
+                (exif, webp.icc_profile())
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// Replaces the metadata chunks of the WebP image.
+///
+/// The VP8X header is written by hand as img-parts drops it whenever
+/// the image has no ICC or EXIF chunks, losing the alpha and animation
+/// flags of images which still need it.
+fn set_webp_metadata(webp: &mut WebP, kept: &KeptMetadata) {
+    for chunk in [CHUNK_EXIF, CHUNK_ICCP, CHUNK_XMP] {
+        webp.remove_chunks_by_id(chunk);
+    }
+
+    let vp8x = webp.chunks()
+        .iter()
+        .position(|chunk| chunk.id() == CHUNK_VP8X);
+
+    let vp8x = match vp8x {
+        Some(pos) => pos,
+        None if kept.exif.is_none() && kept.icc.is_none() => return,
+        None => match new_vp8x(webp) {
+            Some(chunk) => {
+                webp.chunks_mut().insert(0, chunk);
+                0
+            },
+            None => return,
+        },
+    };
+
+    if let Some(ref icc) = kept.icc {
+        let chunk = RiffChunk::new(CHUNK_ICCP, RiffContent::Data(icc.clone()));
+        webp.chunks_mut().insert(vp8x + 1, chunk);
+    }
+
+    if let Some(ref exif) = kept.exif {
+        let chunk = RiffChunk::new(CHUNK_EXIF, RiffContent::Data(exif.clone()));
+        webp.chunks_mut().push(chunk);
+    }
+
+    let chunk = &mut webp.chunks_mut()[vp8x];
+    let mut header = match chunk.content().data() {
+        Some(data) if data.len() >= 10 => BytesMut::from(&data[..]),
+        _ => return,
+    };
+
+    header[0] &= !(VP8X_ICC_FLAG | VP8X_EXIF_FLAG | VP8X_XMP_FLAG);
+    if kept.icc.is_some() {
+        header[0] |= VP8X_ICC_FLAG;
+    }
+    if kept.exif.is_some() {
+        header[0] |= VP8X_EXIF_FLAG;
+    }
+
+    *chunk = RiffChunk::new(CHUNK_VP8X, RiffContent::Data(header.freeze()));
+}
+
+/// Builds the VP8X header of a simple lossy or lossless WebP image.
+fn new_vp8x(webp: &WebP) -> Option<RiffChunk> {
+    let (width, height) = webp.dimensions()?;
+
+    // The alpha bit follows the 14 bit width and height of the VP8L header.
+    let has_alpha = webp.chunk_by_id(CHUNK_VP8L)
+        .and_then(|chunk| chunk.content().data())
+        .and_then(|data| data.get(4))
+        .map(|bits| bits & 0x10 != 0)
+        .unwrap_or(false);
+
+    let mut header = BytesMut::with_capacity(10);
+    header.put_u8(if has_alpha { VP8X_ALPHA_FLAG } else { 0 });
+    header.put_slice(&[0; 3]);
+    header.put_slice(&(width - 1).to_le_bytes()[..3]);
+    header.put_slice(&(height - 1).to_le_bytes()[..3]);
+
+    Some(RiffChunk::new(CHUNK_VP8X, RiffContent::Data(header.freeze())))
+}
+
+/// Rebuilds the EXIF data with only its artist and copyright tags.
+///
+/// Returns `None` if the image has neither.
+fn copyright_exif(exif: &[u8]) -> Option<Bytes> {
+    let tags: Vec<(u16, Vec<u8>)> = [TAG_ARTIST, TAG_COPYRIGHT]
+        .into_iter()
+        .filter_map(|tag| read_ascii_tag(exif, tag).map(|value| (tag, value)))
+        .collect();
+
+    if tags.is_empty() {
+        return None
+    }
+
+    // A little endian TIFF header followed by IFD0 and the tag values.
+    let ifd_len = 2 + tags.len() * 12 + 4;
+    let mut data_offset = 8 + ifd_len;
+    let mut out = BytesMut::new();
+    out.put_slice(b"II*\0");
+    out.put_u32_le(8);
+    out.put_u16_le(tags.len() as u16);
+
+    for (tag, value) in tags.iter() {
+        out.put_u16_le(*tag);
+        out.put_u16_le(TYPE_ASCII);
+        out.put_u32_le(value.len() as u32);
+        if value.len() <= 4 {
+            let mut inline = [0; 4];
+            inline[..value.len()].copy_from_slice(value);
+            out.put_slice(&inline);
+        } else {
+            out.put_u32_le(data_offset as u32);
+            data_offset += value.len();
+        }
+    }
+    out.put_u32_le(0);
+
+    for (_, value) in tags.iter().filter(|(_, value)| value.len() > 4) {
+        out.put_slice(value);
+    }
+
+    Some(out.freeze())
+}
+
+/// Reads an ASCII tag of IFD0 from the raw TIFF structured EXIF data.
+fn read_ascii_tag(exif: &[u8], tag: u16) -> Option<Vec<u8>> {
+    let little_endian = match exif.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+
+    let u16_at = |pos: usize| -> Option<u16> {
+        let bytes = exif.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    };
+    let u32_at = |pos: usize| -> Option<usize> {
+        let bytes = exif.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) } as usize)
+    };
+
+    let ifd = u32_at(4)?;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(tag))
+        .filter(|&entry| u16_at(entry + 2) == Some(TYPE_ASCII))
+        .and_then(|entry| {
+            let len = u32_at(entry + 4)?;
+            let start = if len <= 4 { entry + 8 } else { u32_at(entry + 8)? };
+            let value = exif.get(start..start.checked_add(len)?)?;
+
+            // Values are always written back null terminated.
+            let mut value = value.split(|&b| b == 0).next()?.to_vec();
+            value.push(0);
+            Some(value)
+        })
+}
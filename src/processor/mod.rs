@@ -1,2 +1,144 @@
+pub mod alpha;
+pub mod animation;
+pub mod color;
+pub mod compression;
 pub mod encoder;
-pub mod resizer;
\ No newline at end of file
+pub mod metadata;
+pub mod phash;
+pub mod pool;
+pub mod resizer;
+
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use image::{DynamicImage, ImageError};
+use image::io::{Limits, Reader};
+
+use crate::config::{DecodeLimits, ImageKind};
+
+/// A processing failure caused by the image or the requested operation
+/// rather than by the server itself.
+///
+/// These are surfaced to clients with a status describing the failure
+/// rather than as an internal server error.
+#[derive(Debug, Clone)]
+pub enum ProcessingError {
+    /// The image could not be decoded as its given format.
+    DecodeFailed { kind: ImageKind, msg: String },
+
+    /// The image exceeds the decode limits.
+    LimitsExceeded { kind: ImageKind, msg: String },
+
+    /// The image cannot be converted to the requested format.
+    UnsupportedConversion { to: ImageKind, msg: String },
+
+    /// The image could not be resized to the requested dimensions.
+    ResizeFailed { width: u32, height: u32 },
+
+    /// The image could not be encoded in the requested format.
+    EncodeFailed { kind: ImageKind, msg: String },
+
+    /// Processing did not complete within the bucket's `processing_timeout`.
+    Timeout { stage: &'static str },
+}
+
+impl Display for ProcessingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DecodeFailed { kind, msg } => write!(f, "Failed to decode the image as {:?}: {}", kind, msg),
+            Self::LimitsExceeded { kind, msg } => write!(f, "The {:?} image exceeds the decode limits: {}", kind, msg),
+            Self::UnsupportedConversion { to, msg } => write!(f, "The image cannot be converted to {:?}: {}", to, msg),
+            Self::ResizeFailed { width, height } => write!(f, "The image cannot be resized to {}x{}", width, height),
+            Self::EncodeFailed { kind, msg } => write!(f, "Failed to encode the image as {:?}: {}", kind, msg),
+            Self::Timeout { stage } => write!(f, "Processing timed out during the {} stage", stage),
+        }
+    }
+}
+
+impl std::error::Error for ProcessingError {}
+
+/// The decoder limits of a bucket, combining its own `decode_limits`
+/// with the global limits.
+pub fn decode_limits(bucket: Option<DecodeLimits>) -> Limits {
+    let cfg = match (crate::config::config().decode_limits, bucket) {
+        (None, None) => return Limits::default(),
+        (global, bucket) => global.unwrap_or_default().merge(bucket.unwrap_or_default()),
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = cfg.max_width;
+    limits.max_image_height = cfg.max_height;
+    if let Some(max_alloc) = cfg.max_alloc {
+        limits.max_alloc = Some(max_alloc * 1024 * 1024);
+    }
+
+    limits
+}
+
+/// Decodes the image, converting any failures into a `ProcessingError`.
+///
+/// The decoder enforces the limits itself so oversized images are
+/// rejected before their pixels are allocated.
+pub fn decode(data: &[u8], kind: ImageKind, limits: &Limits) -> anyhow::Result<DynamicImage> {
+    let mut reader = Reader::with_format(Cursor::new(data), kind.into());
+    reader.limits(limits.clone());
+
+    reader.decode().map_err(|e| match e {
+        ImageError::Limits(_) => ProcessingError::LimitsExceeded { kind, msg: e.to_string() },
+        _ => ProcessingError::DecodeFailed { kind, msg: e.to_string() },
+    }.into())
+}
+
+/// A panic caught while processing an image.
+#[derive(Debug, Clone)]
+pub struct ProcessingPanic {
+    pub stage: &'static str,
+    pub kind: ImageKind,
+    pub msg: String,
+}
+
+impl Display for ProcessingPanic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Processing panicked during the {} stage for a {:?} image: {}",
+            self.stage, self.kind, self.msg,
+        )
+    }
+}
+
+impl std::error::Error for ProcessingPanic {}
+
+/// Converts an encoder error into a `ProcessingError`.
+pub(crate) fn encode_error(kind: ImageKind, e: ImageError) -> anyhow::Error {
+    let msg = e.to_string();
+    match e {
+        ImageError::Unsupported(_) => ProcessingError::UnsupportedConversion { to: kind, msg },
+        _ => ProcessingError::EncodeFailed { kind, msg },
+    }.into()
+}
+
+/// Runs the given processing job, converting any panics into a `ProcessingPanic` error.
+///
+/// This isolates a bad input triggering a panic in the decoders/encoders
+/// to the request that submitted it rather than aborting the processing pool.
+pub fn catch_panic<T>(
+    stage: &'static str,
+    kind: ImageKind,
+    job: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    match catch_unwind(AssertUnwindSafe(job)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(|v| v.as_str()))
+                .unwrap_or("unknown cause")
+                .to_string();
+
+            Err(ProcessingPanic { stage, kind, msg }.into())
+        },
+    }
+}
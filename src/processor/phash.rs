@@ -0,0 +1,30 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+/// The width and height of the thumbnail the hash is computed from,
+/// one column wider than tall as each bit compares two neighbouring pixels.
+const HASH_WIDTH: u32 = 9;
+const HASH_HEIGHT: u32 = 8;
+
+/// The difference hash of the image, similar looking images have hashes
+/// differing in only a few bits regardless of their size or format.
+pub fn perceptual_hash(img: &DynamicImage) -> u64 {
+    let thumbnail = img
+        .resize_exact(HASH_WIDTH, HASH_HEIGHT, FilterType::Triangle)
+        .into_luma8();
+
+    let mut hash = 0;
+    for y in 0..HASH_HEIGHT {
+        for x in 0..HASH_WIDTH - 1 {
+            let brighter = thumbnail.get_pixel(x, y).0[0] < thumbnail.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+
+    hash
+}
+
+/// The number of bits differing between the two hashes.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
@@ -0,0 +1,80 @@
+use std::panic::AssertUnwindSafe;
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+
+use crate::config::ProcessingPoolConfig;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The queue of the threads processing pipelines run on if a processing pool
+/// is configured.
+///
+/// These are plain threads rather than a rayon pool, the work the pipelines spawn
+/// via rayon (including by the decoders) then goes to the global pool rather than
+/// being queued behind the pipeline waiting on it.
+static PIPELINE_POOL: OnceCell<crossbeam::channel::Sender<Job>> = OnceCell::new();
+
+/// Builds the low priority processing pools, this must be called
+/// before any image is processed.
+pub fn init(cfg: ProcessingPoolConfig) -> anyhow::Result<()> {
+    if cfg!(not(target_os = "linux")) {
+        warn!("Processing threads can only be ran at a lower priority on Linux, they run at the normal priority.");
+    }
+
+    let niceness = cfg.niceness;
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cfg.threads)
+        .thread_name(|i| format!("lust-encoder-{}", i))
+        .start_handler(move |_| lower_priority(niceness))
+        .build_global()
+        .map_err(|e| anyhow!("Failed to build the encoder pool: {}", e))?;
+
+    let (tx, rx) = crossbeam::channel::unbounded::<Job>();
+    for i in 0..cfg.threads {
+        let rx = rx.clone();
+        std::thread::Builder::new()
+            .name(format!("lust-pipeline-{}", i))
+            .spawn(move || {
+                lower_priority(niceness);
+                for job in rx {
+                    job();
+                }
+            })?;
+    }
+    let _ = PIPELINE_POOL.set(tx);
+
+    Ok(())
+}
+
+/// Runs the processing job on the low priority pipeline pool, or
+/// the runtime's blocking threads if no pool is configured.
+pub async fn run<T: Send + 'static>(job: impl FnOnce() -> T + Send + 'static) -> anyhow::Result<T> {
+    let pool = match PIPELINE_POOL.get() {
+        None => return Ok(tokio::task::spawn_blocking(job).await?),
+        Some(pool) => pool,
+    };
+
+    // A panic would otherwise kill the thread, it drops the
+    // sender instead so the job is failed like a blocking task.
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    pool.send(Box::new(move || {
+        if let Ok(result) = std::panic::catch_unwind(AssertUnwindSafe(job)) {
+            let _ = tx.send(result);
+        }
+    })).map_err(|_| anyhow!("The processing pool has shut down."))?;
+
+    rx.await.map_err(|_| anyhow!("The processing job panicked."))
+}
+
+/// Sets the niceness of the calling thread, on Linux each thread has
+/// its own priority which is set via its thread id.
+#[cfg(target_os = "linux")]
+fn lower_priority(niceness: i32) {
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, niceness) } != 0 {
+        warn!("Failed to lower the priority of processing thread {}: {}", tid, std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_priority(_niceness: i32) {}
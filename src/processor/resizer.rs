@@ -1,8 +1,11 @@
+use std::io::Cursor;
 use std::sync::Arc;
 use bytes::Bytes;
 use hashbrown::HashMap;
-use image::{DynamicImage, load_from_memory_with_format};
-use crate::config::{ImageKind, ResizingConfig};
+use image::DynamicImage;
+use image::io::{Limits, Reader};
+use crate::config::{ImageKind, ResizingConfig, ResolutionCap};
+use super::ProcessingError;
 
 pub struct ResizedImage {
     pub sizing_id: u32,
@@ -13,8 +16,9 @@ pub fn resize_image_to_presets(
     presets: &HashMap<u32, ResizingConfig>,
     kind: ImageKind,
     data: Bytes,
+    limits: &Limits,
 ) -> anyhow::Result<Vec<ResizedImage>> {
-    let original_image = Arc::new(load_from_memory_with_format(data.as_ref(), kind.into())?);
+    let original_image = Arc::new(super::decode(data.as_ref(), kind, limits)?);
 
     let (tx, rx) = crossbeam::channel::bounded(presets.len());
     for (sizing_id, cfg) in presets {
@@ -23,27 +27,60 @@ pub fn resize_image_to_presets(
         let local_tx = tx.clone();
         let local = original_image.clone();
         rayon::spawn(move || {
-            let img = resize(cfg, &local);
-            local_tx
-                .send(ResizedImage { sizing_id, img })
-                .expect("Failed to respond to encoding request. Sender already closed.");
+            let result = super::catch_panic("resize", kind, || resize(cfg, &local));
+            // The receiver is only gone if the request was abandoned.
+            let _ = local_tx.send(result.map(|img| ResizedImage { sizing_id, img }));
         });
     }
 
     // Needed to prevent deadlock.
     drop(tx);
 
+    // Every job is waited on before any error is returned.
+    let mut processed = vec![];
+    while let Ok(resized) = rx.recv() {
+        processed.push(resized);
+    }
+
     let mut finished = vec![ResizedImage {
        sizing_id: 0,
        img: original_image.as_ref().clone(),
     }];
-    while let Ok(encoded) = rx.recv() {
-        finished.push(encoded);
+    for resized in processed {
+        finished.push(resized?);
     }
 
     Ok(finished)
 }
 
-pub fn resize(cfg: ResizingConfig, img: &DynamicImage) -> DynamicImage {
-    img.resize(cfg.width, cfg.height, cfg.filter.into())
-}
\ No newline at end of file
+pub fn resize(cfg: ResizingConfig, img: &DynamicImage) -> anyhow::Result<DynamicImage> {
+    if cfg.width == 0 || cfg.height == 0 {
+        return Err(ProcessingError::ResizeFailed { width: cfg.width, height: cfg.height }.into())
+    }
+
+    Ok(img.resize(cfg.width, cfg.height, cfg.filter.into()))
+}
+/// Downscales the image to fit within the cap, keeping its aspect ratio.
+///
+/// The dimensions are read from the header first so images already within
+/// the cap are returned untouched without being decoded.
+pub fn cap_resolution(
+    webp_cfg: webp::WebPConfig,
+    cap: ResolutionCap,
+    kind: ImageKind,
+    data: Vec<u8>,
+    limits: &Limits,
+) -> anyhow::Result<Vec<u8>> {
+    let (width, height) = Reader::with_format(Cursor::new(&data), kind.into())
+        .into_dimensions()?;
+
+    if width <= cap.width && height <= cap.height {
+        return Ok(data)
+    }
+
+    let img = super::decode(&data, kind, limits)?;
+    let img = img.resize(cap.width, cap.height, cap.filter.into());
+    let encoded = super::encoder::encode_to(webp_cfg, &img, kind)?;
+
+    Ok(encoded.to_vec())
+}
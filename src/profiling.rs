@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+
+/// The frequency each thread's stack is sampled at in hertz.
+#[cfg(unix)]
+const SAMPLE_FREQUENCY: i32 = 99;
+
+/// If a capture is in progress, only a single profiler can run per process.
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+/// An in progress capture, sampling the stacks of every thread
+/// until it's finished or dropped.
+pub struct Capture {
+    #[cfg(unix)]
+    guard: Option<pprof::ProfilerGuard<'static>>,
+}
+
+impl Capture {
+    /// Starts sampling, returning `None` if a capture is already in progress.
+    pub fn start() -> Result<Option<Self>> {
+        if CAPTURING
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Ok(None)
+        }
+
+        match Self::sample() {
+            Ok(capture) => Ok(Some(capture)),
+            Err(e) => {
+                CAPTURING.store(false, Ordering::Release);
+                Err(e)
+            },
+        }
+    }
+
+    #[cfg(unix)]
+    fn sample() -> Result<Self> {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(SAMPLE_FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()?;
+
+        Ok(Self { guard: Some(guard) })
+    }
+
+    #[cfg(not(unix))]
+    fn sample() -> Result<Self> {
+        Err(anyhow::anyhow!("CPU profiling is only supported on unix."))
+    }
+
+    /// Ends the capture, rendering the sampled stacks as a flamegraph SVG.
+    ///
+    /// Returns `None` if no stacks were sampled, i.e. the process was idle.
+    #[cfg(unix)]
+    pub fn finish(mut self) -> Result<Option<Vec<u8>>> {
+        let guard = self.guard.take().expect("The capture is only finished once");
+        let report = guard.report().build()?;
+        drop(guard);
+
+        if report.data.is_empty() {
+            return Ok(None)
+        }
+
+        let mut svg = vec![];
+        report.flamegraph(&mut svg)?;
+        Ok(Some(svg))
+    }
+
+    #[cfg(not(unix))]
+    pub fn finish(self) -> Result<Option<Vec<u8>>> {
+        unreachable!("Captures cannot be started on this platform")
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        // The profiler must be stopped before another capture can start.
+        #[cfg(unix)]
+        drop(self.guard.take());
+        CAPTURING.store(false, Ordering::Release);
+    }
+}
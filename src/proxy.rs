@@ -0,0 +1,151 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use poem::http::HeaderMap;
+use poem::Request;
+use serde::Deserialize;
+
+/// The resolved IP address of the client that made the request.
+///
+/// This is inserted into the request extensions by the logging middleware.
+#[derive(Copy, Clone, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+impl Display for ClientIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A single IP address or a CIDR range, e.g. `10.0.0.0/8`.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                (u32::from(range) & mask) == (u32::from(ip) & mask)
+            },
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                (u128::from(range) & mask) == (u128::from(ip) & mask)
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            None => (s, None),
+            Some((addr, prefix)) => (addr, Some(prefix)),
+        };
+
+        let addr = IpAddr::from_str(addr.trim())
+            .map_err(|_| anyhow!("Invalid trusted proxy address {:?}.", s))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max_prefix,
+            Some(prefix) => prefix.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|v| *v <= max_prefix)
+                .ok_or_else(|| anyhow!("Invalid trusted proxy prefix length {:?}.", s))?,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Resolves the IP of the client which made the request.
+///
+/// Proxy headers are only followed while the hops are trusted proxies,
+/// the first untrusted address is the client. Returns `None` if the
+/// peer address is not an IP address.
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    let peer = req.remote_addr().as_socket_addr().map(SocketAddr::ip)?;
+    Some(resolve_client_ip(&crate::config::config().trusted_proxies, peer, req.headers()))
+}
+
+pub fn resolve_client_ip(trusted: &[IpRange], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|range| range.contains(ip));
+    if !is_trusted(peer) {
+        return peer
+    }
+
+    let mut client = peer;
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        client = hop;
+        if !is_trusted(hop) {
+            break
+        }
+    }
+
+    client
+}
+
+/// The chain of forwarded client addresses, preferring the
+/// standard `Forwarded` header over `X-Forwarded-For`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<IpAddr> {
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_forwarded_node(value))
+        })
+        .collect();
+
+    if !forwarded.is_empty() {
+        return forwarded
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| parse_forwarded_node(v.trim()))
+        .collect()
+}
+
+/// Parses a node from a forwarded header, e.g. `192.0.2.60`,
+/// `"192.0.2.60:4711"` or `"[2001:db8:cafe::17]:4711"`.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(ip)
+    }
+
+    if let Ok(addr) = SocketAddr::from_str(node) {
+        return Some(addr.ip())
+    }
+
+    node.strip_prefix('[')
+        .and_then(|v| v.split_once(']'))
+        .and_then(|(ip, _)| IpAddr::from_str(ip).ok())
+}
@@ -0,0 +1,184 @@
+use poem_openapi::{Enum, Object};
+use uuid::Uuid;
+
+use crate::controller::{get_bucket_by_id, BucketController};
+
+#[derive(Object, Debug, Clone)]
+pub struct PurgeFilter {
+    /// Only purge images uploaded before this time, formatted as RFC 3339.
+    ///
+    /// E.g. `2022-01-01T00:00:00Z`
+    uploaded_before: Option<String>,
+
+    /// Only purge images uploaded with this tag.
+    tag: Option<String>,
+
+    /// Only purge images whose id starts with this prefix.
+    prefix: Option<String>,
+
+    #[oai(default)]
+    /// Only report the matching images rather than deleting them.
+    dry_run: bool,
+}
+
+impl PurgeFilter {
+    #[inline]
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+}
+
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq)]
+#[oai(rename_all = "lowercase")]
+pub enum PurgeJobStatus {
+    /// The matching images are being deleted.
+    Running,
+
+    /// Every matching image has been processed.
+    Complete,
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct PurgeJobInfo {
+    /// The id of the purge job.
+    ///
+    /// This is `null` for dry runs.
+    job_id: Option<Uuid>,
+
+    /// The current status of the purge.
+    status: PurgeJobStatus,
+
+    /// If the matching images were only reported rather than deleted.
+    dry_run: bool,
+
+    /// The number of images matching the filter.
+    matched: usize,
+
+    /// The number of matching images deleted so far.
+    deleted: usize,
+
+    /// The number of matching images which failed to be deleted.
+    failed: usize,
+
+    /// The ids of the matching images.
+    ///
+    /// This is only populated for dry runs.
+    images: Vec<Uuid>,
+}
+
+impl PurgeJobInfo {
+    #[inline]
+    pub fn job_id(&self) -> Uuid {
+        self.job_id.unwrap_or_default()
+    }
+}
+
+/// Finds the indexed images matching the filter, ordered by image id.
+///
+/// Returns `None` if the bucket is not indexed.
+pub async fn matching(
+    bucket: &BucketController,
+    filter: &PurgeFilter,
+) -> anyhow::Result<Option<Vec<Uuid>>> {
+    let records = match bucket.indexed_images().await? {
+        None => return Ok(None),
+        Some(records) => records,
+    };
+
+    let uploaded_before = filter.uploaded_before
+        .as_deref()
+        .map(chrono::DateTime::parse_from_rfc3339)
+        .transpose()?
+        .map(|v| v.timestamp());
+
+    let mut matched: Vec<Uuid> = records
+        .into_iter()
+        .filter(|(_, record)| uploaded_before.map(|v| record.uploaded_at < v).unwrap_or(true))
+        .filter(|(_, record)| {
+            filter.tag
+                .as_ref()
+                .map(|tag| record.tags.contains(tag))
+                .unwrap_or(true)
+        })
+        .filter(|(image_id, _)| {
+            filter.prefix
+                .as_ref()
+                .map(|prefix| image_id.to_string().starts_with(prefix.as_str()))
+                .unwrap_or(true)
+        })
+        .map(|(image_id, _)| image_id)
+        .collect();
+    matched.sort();
+
+    Ok(Some(matched))
+}
+
+/// Validates the filter, returning the reason it's invalid if applicable.
+pub fn validate(filter: &PurgeFilter) -> Option<String> {
+    if filter.uploaded_before.is_none() && filter.tag.is_none() && filter.prefix.is_none() {
+        return Some("At least one of `uploaded_before`, `tag` or `prefix` must be set.".to_string())
+    }
+
+    if let Some(ref uploaded_before) = filter.uploaded_before {
+        if let Err(e) = chrono::DateTime::parse_from_rfc3339(uploaded_before) {
+            return Some(format!("The `uploaded_before` time is invalid: {}", e))
+        }
+    }
+
+    None
+}
+
+/// The report of a dry run for the given matching images.
+pub fn dry_run(images: Vec<Uuid>) -> PurgeJobInfo {
+    PurgeJobInfo {
+        job_id: None,
+        status: PurgeJobStatus::Complete,
+        dry_run: true,
+        matched: images.len(),
+        deleted: 0,
+        failed: 0,
+        images,
+    }
+}
+
+/// Starts deleting the given images in the background.
+///
+/// The progress can be polled via `BucketController::purge_job`.
+pub fn start(bucket: &BucketController, images: Vec<Uuid>) -> PurgeJobInfo {
+    let mut job = PurgeJobInfo {
+        job_id: Some(Uuid::new_v4()),
+        status: PurgeJobStatus::Running,
+        dry_run: false,
+        matched: images.len(),
+        deleted: 0,
+        failed: 0,
+        images: vec![],
+    };
+    bucket.update_purge_job(job.clone());
+
+    let bucket_id = bucket.bucket_id();
+    let info = job.clone();
+    crate::background::spawn(async move {
+        let bucket = match get_bucket_by_id(bucket_id) {
+            Some(bucket) => bucket,
+            None => return,
+        };
+
+        for image_id in images {
+            match bucket.delete(image_id).await {
+                Ok(_) => job.deleted += 1,
+                Err(e) => {
+                    error!("Failed to purge image {} from bucket {}: {}", image_id, bucket.name(), e);
+                    job.failed += 1;
+                },
+            }
+
+            bucket.update_purge_job(job.clone());
+        }
+
+        job.status = PurgeJobStatus::Complete;
+        bucket.update_purge_job(job);
+    });
+
+    info
+}
@@ -0,0 +1,164 @@
+use bytes::Bytes;
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OnceCell};
+use uuid::Uuid;
+
+use crate::config::QuarantineConfig;
+use crate::metadata::MetadataStore;
+
+/// The metadata document the quarantined uploads are listed in.
+const QUARANTINE_KEY: &str = "quarantine";
+
+/// The metadata document the payload of a quarantined upload is stored in.
+fn payload_key(upload_id: Uuid) -> String {
+    format!("quarantine/{}", upload_id)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct QuarantinedUpload {
+    /// The id the upload's payload is retrieved by.
+    pub upload_id: Uuid,
+
+    /// The error the upload failed with.
+    pub error: String,
+
+    /// The format the upload was given as or guessed to be, if known.
+    pub format: Option<String>,
+
+    /// The size of the payload in bytes.
+    pub size: u64,
+
+    /// The unix timestamp the upload was quarantined.
+    pub quarantined_at: i64,
+}
+
+/// Keeps the payloads of uploads which failed to be decoded or encoded
+/// so they can be retrieved by admins to reproduce the failure.
+///
+/// The oldest uploads are evicted once the payloads exceed the bucket's
+/// `max_size` and uploads are dropped once their `ttl` has passed.
+pub struct Quarantine {
+    max_size: u64,
+    ttl: i64,
+    entries: OnceCell<Mutex<Vec<QuarantinedUpload>>>,
+}
+
+impl Quarantine {
+    pub fn new(cfg: &QuarantineConfig) -> Self {
+        Self {
+            max_size: cfg.max_size * 1024 * 1024,
+            ttl: cfg.ttl as i64,
+            entries: OnceCell::new(),
+        }
+    }
+
+    async fn entries(&self, store: &MetadataStore) -> anyhow::Result<&Mutex<Vec<QuarantinedUpload>>> {
+        self.entries
+            .get_or_try_init(|| async {
+                let entries: Vec<QuarantinedUpload> = store.load(QUARANTINE_KEY).await?;
+                Ok::<_, anyhow::Error>(Mutex::new(entries))
+            })
+            .await
+    }
+
+    /// Quarantines the payload, returning its id or `None` if the
+    /// payload alone exceeds the quarantine's max size.
+    pub async fn insert(
+        &self,
+        store: &MetadataStore,
+        data: Bytes,
+        format: Option<String>,
+        error: String,
+    ) -> anyhow::Result<Option<Uuid>> {
+        let size = data.len() as u64;
+        if size > self.max_size {
+            return Ok(None)
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let upload = QuarantinedUpload {
+            upload_id: Uuid::new_v4(),
+            error,
+            format,
+            size,
+            quarantined_at: now,
+        };
+
+        let mut entries = self.entries(store).await?.lock().await;
+        store.save_bytes(&payload_key(upload.upload_id), data).await?;
+
+        // Entries are kept in the order they were quarantined.
+        let mut evicted = vec![];
+        entries.retain(|entry| {
+            let expired = entry.quarantined_at + self.ttl <= now;
+            if expired {
+                evicted.push(entry.upload_id);
+            }
+            !expired
+        });
+
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum::<u64>() + size;
+        while total > self.max_size && !entries.is_empty() {
+            let oldest = entries.remove(0);
+            total -= oldest.size;
+            evicted.push(oldest.upload_id);
+        }
+
+        let upload_id = upload.upload_id;
+        entries.push(upload);
+        store.save(QUARANTINE_KEY, &*entries).await?;
+
+        for upload_id in evicted {
+            if let Err(e) = store.remove(&payload_key(upload_id)).await {
+                warn!("Failed to remove the evicted quarantined upload {}: {}", upload_id, e);
+            }
+        }
+
+        Ok(Some(upload_id))
+    }
+
+    /// The quarantined uploads which have not expired, oldest first.
+    pub async fn list(&self, store: &MetadataStore) -> anyhow::Result<Vec<QuarantinedUpload>> {
+        let before = chrono::Utc::now().timestamp() - self.ttl;
+        let entries = self.entries(store).await?.lock().await;
+
+        Ok(entries
+            .iter()
+            .filter(|entry| entry.quarantined_at > before)
+            .cloned()
+            .collect())
+    }
+
+    /// The payload of the quarantined upload, `None` if it
+    /// does not exist or has expired.
+    pub async fn payload(&self, store: &MetadataStore, upload_id: Uuid) -> anyhow::Result<Option<Bytes>> {
+        let listed = self.list(store)
+            .await?
+            .iter()
+            .any(|entry| entry.upload_id == upload_id);
+        if !listed {
+            return Ok(None)
+        }
+
+        store.load_bytes(&payload_key(upload_id)).await
+    }
+
+    /// Removes the quarantined upload, returning if it existed.
+    pub async fn remove(&self, store: &MetadataStore, upload_id: Uuid) -> anyhow::Result<bool> {
+        let mut entries = self.entries(store).await?.lock().await;
+        let position = match entries.iter().position(|entry| entry.upload_id == upload_id) {
+            None => return Ok(false),
+            Some(position) => position,
+        };
+
+        let removed = entries.remove(position);
+        if let Err(e) = store.save(QUARANTINE_KEY, &*entries).await {
+            entries.insert(position, removed);
+            return Err(e)
+        }
+
+        store.remove(&payload_key(upload_id)).await?;
+        Ok(true)
+    }
+}
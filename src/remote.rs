@@ -0,0 +1,141 @@
+//! Downloads images from remote URLs so they can be uploaded
+//! without the client having to proxy the bytes itself.
+
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use futures::StreamExt;
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_LENGTH;
+use hyper::Uri;
+use hyper_tls::HttpsConnector;
+use once_cell::sync::OnceCell;
+
+use crate::config::RemoteUploadConfig;
+
+static DOWNLOADER: OnceCell<Downloader> = OnceCell::new();
+
+/// The reason an image could not be downloaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadRejection {
+    /// Uploads from remote URLs are not configured.
+    Disabled,
+
+    /// The URL is not an absolute http or https URL.
+    InvalidUrl,
+
+    /// The URL's host is not one of the allowed hosts.
+    HostNotAllowed(String),
+
+    /// The image exceeds the upload size limit.
+    TooBig,
+
+    /// The host responded with a non-success status, redirects are not followed.
+    BadStatus(u16),
+
+    /// The host could not be reached or the download failed part way.
+    Unreachable,
+
+    /// The image was not downloaded within the timeout.
+    TimedOut,
+}
+
+impl Display for DownloadRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "Uploads from remote URLs are not enabled."),
+            Self::InvalidUrl => write!(f, "The URL must be an absolute http or https URL."),
+            Self::HostNotAllowed(host) => write!(f, "Images can not be downloaded from the host {:?}.", host),
+            Self::TooBig => write!(f, "The image exceeds the maximum upload size."),
+            Self::BadStatus(status) => write!(f, "The remote host responded with the status {}.", status),
+            Self::Unreachable => write!(f, "The remote host could not be reached."),
+            Self::TimedOut => write!(f, "The image was not downloaded in time."),
+        }
+    }
+}
+
+struct Downloader {
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    cfg: &'static RemoteUploadConfig,
+}
+
+impl Downloader {
+    async fn download(&self, uri: Uri, limit: usize) -> Result<Vec<u8>, DownloadRejection> {
+        let resp = match self.client.get(uri).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!("Failed to download a remote upload: {}", e);
+                return Err(DownloadRejection::Unreachable)
+            },
+        };
+
+        if !resp.status().is_success() {
+            return Err(DownloadRejection::BadStatus(resp.status().as_u16()))
+        }
+
+        let content_length = resp.headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if content_length.map(|length| length > limit).unwrap_or(false) {
+            return Err(DownloadRejection::TooBig)
+        }
+
+        let mut data = Vec::with_capacity(content_length.unwrap_or_default());
+        let mut body = resp.into_body();
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("Failed to download a remote upload: {}", e);
+                    return Err(DownloadRejection::Unreachable)
+                },
+            };
+            data.extend_from_slice(&chunk);
+
+            if data.len() > limit {
+                return Err(DownloadRejection::TooBig)
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+/// Sets up the client downloading remote uploads if enabled.
+pub fn setup() {
+    if let Some(ref cfg) = crate::config::config().remote_uploads {
+        let _ = DOWNLOADER.set(Downloader {
+            client: hyper::Client::builder().build(HttpsConnector::new()),
+            cfg,
+        });
+    }
+}
+
+/// Downloads the image at the URL, rejecting it once it exceeds `limit` bytes.
+pub async fn download(url: &str, limit: usize) -> Result<Vec<u8>, DownloadRejection> {
+    let downloader = match DOWNLOADER.get() {
+        None => return Err(DownloadRejection::Disabled),
+        Some(downloader) => downloader,
+    };
+
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) if matches!(uri.scheme_str(), Some("http") | Some("https")) => uri,
+        _ => return Err(DownloadRejection::InvalidUrl),
+    };
+
+    let host = match uri.host() {
+        None => return Err(DownloadRejection::InvalidUrl),
+        Some(host) => host.to_string(),
+    };
+
+    if !downloader.cfg.is_allowed(&host) {
+        return Err(DownloadRejection::HostNotAllowed(host))
+    }
+
+    let timeout = Duration::from_secs(downloader.cfg.timeout);
+    match tokio::time::timeout(timeout, downloader.download(uri, limit)).await {
+        Ok(result) => result,
+        Err(_) => Err(DownloadRejection::TimedOut),
+    }
+}
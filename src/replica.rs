@@ -0,0 +1,168 @@
+use std::time::Duration;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderName, HOST};
+use hyper_tls::HttpsConnector;
+use once_cell::sync::OnceCell;
+use poem::http::{Method, StatusCode};
+use poem::{Endpoint, IntoResponse, Request, Response};
+use uuid::Uuid;
+
+use crate::config::ReplicaConfig;
+use crate::controller::get_bucket_by_name;
+use crate::proxy::ClientIp;
+
+static FORWARDER: OnceCell<Forwarder> = OnceCell::new();
+
+/// The headers which only apply to a single connection and
+/// must not be forwarded.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Forwards the requests a replica can't serve to the primary.
+struct Forwarder {
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    primary_url: String,
+    api_path: String,
+    timeout: Duration,
+}
+
+impl Forwarder {
+    fn new(cfg: &ReplicaConfig) -> Self {
+        Self {
+            client: hyper::Client::builder().build(HttpsConnector::new()),
+            primary_url: cfg.primary_url.trim_end_matches('/').to_string(),
+            api_path: format!(
+                "/v1{}",
+                crate::config::config().base_serving_path.as_deref().unwrap_or(""),
+            ),
+            timeout: Duration::from_secs(cfg.timeout),
+        }
+    }
+
+    async fn forward(&self, req: Request) -> poem::Result<Response> {
+        // The image API is nested so the request's path is relative to it.
+        let path = req.uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let uri = format!("{}{}{}", self.primary_url, self.api_path, path);
+        let client_ip = req.extensions().get::<ClientIp>().copied();
+
+        let mut req = hyper::Request::from(req);
+        *req.uri_mut() = uri.parse().map_err(poem::error::InternalServerError)?;
+
+        let headers = req.headers_mut();
+        headers.remove(HOST);
+        for name in HOP_BY_HOP_HEADERS {
+            headers.remove(*name);
+        }
+
+        // The primary only honours this if the replica is one of its trusted proxies.
+        if let Some(ClientIp(ip)) = client_ip {
+            let forwarded_for = HeaderName::from_static("x-forwarded-for");
+            let value = match headers.get(&forwarded_for).and_then(|v| v.to_str().ok()) {
+                Some(existing) => format!("{}, {}", existing, ip),
+                None => ip.to_string(),
+            };
+
+            if let Ok(value) = value.parse() {
+                headers.insert(forwarded_for, value);
+            }
+        }
+
+        let resp = match tokio::time::timeout(self.timeout, self.client.request(req)).await {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(e)) => {
+                crate::metrics::FORWARDED_REQUESTS.with_label_values(&["failed"]).inc();
+                warn!("Failed to forward request to the primary {}: {}", uri, e);
+                return Err(poem::Error::from_string(
+                    "The primary instance could not be reached.",
+                    StatusCode::BAD_GATEWAY,
+                ))
+            },
+            Err(_) => {
+                crate::metrics::FORWARDED_REQUESTS.with_label_values(&["failed"]).inc();
+                warn!("Timed out forwarding request to the primary {}.", uri);
+                return Err(poem::Error::from_string(
+                    "The primary instance did not respond in time.",
+                    StatusCode::GATEWAY_TIMEOUT,
+                ))
+            },
+        };
+
+        crate::metrics::FORWARDED_REQUESTS.with_label_values(&["forwarded"]).inc();
+
+        let mut resp = Response::from(resp);
+        for name in HOP_BY_HOP_HEADERS {
+            resp.headers_mut().remove(*name);
+        }
+
+        Ok(resp)
+    }
+}
+
+/// Sets up forwarding to the primary if running as a replica.
+pub fn setup() {
+    if let Some(ref cfg) = crate::config::config().replica {
+        info!("Running as a replica of {}.", cfg.primary_url);
+        let _ = FORWARDER.set(Forwarder::new(cfg));
+    }
+}
+
+/// If this instance is a replica of a primary instance.
+pub fn is_replica() -> bool {
+    FORWARDER.get().is_some()
+}
+
+/// Forwards the requests of the image API which change images,
+/// or check on jobs run by the primary, to the primary.
+///
+/// Every other request is served by this instance.
+pub async fn forward_writes<E: Endpoint>(next: E, req: Request) -> poem::Result<Response> {
+    let forwarder = match FORWARDER.get() {
+        Some(forwarder) if must_forward(&req) => forwarder,
+        _ => return next.call(req).await.map(IntoResponse::into_response),
+    };
+
+    let method = req.method().clone();
+    let changed_image = changed_image(req.uri().path())
+        .map(|(bucket, image_id)| (bucket.to_string(), image_id));
+
+    let resp = forwarder.forward(req).await?;
+
+    // The primary invalidates its own cache, a successful change must
+    // also invalidate any copies cached by this replica.
+    if resp.status().is_success() && (method == Method::PUT || method == Method::DELETE) {
+        if let Some((bucket, image_id)) = changed_image {
+            if let Some(bucket) = get_bucket_by_name(&bucket) {
+                bucket.invalidate_image(image_id);
+            }
+        }
+    }
+
+    Ok(resp)
+}
+
+/// Upload and purge jobs only exist on the primary which ran them.
+fn must_forward(req: &Request) -> bool {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return true
+    }
+
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    matches!(segments.as_slice(), [_, "uploads", _] | [_, "purge", _])
+}
+
+/// The bucket and image changed by a request to `/:bucket/:image_id`.
+fn changed_image(path: &str) -> Option<(&str, Uuid)> {
+    let (bucket, image_id) = path.trim_matches('/').split_once('/')?;
+    Some((bucket, Uuid::parse_str(image_id).ok()?))
+}
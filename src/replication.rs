@@ -0,0 +1,504 @@
+//! Mirrors the objects written to the storage backend to a secondary
+//! backend, e.g. in another region.
+//!
+//! Writes made through the storage backend are queued as changes which are
+//! replicated in the background by copying the current state of the object
+//! from the primary backend, so changes replicated out of order or more than
+//! once still converge on the primary's state.
+//!
+//! Changes are only queued in memory, any lost to a restart or a full queue
+//! are replicated by the `catch-up` command.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::Bytes;
+use clap::Args;
+use futures::StreamExt;
+use once_cell::sync::OnceCell;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+use uuid::Uuid;
+
+use crate::config::ImageKind;
+use crate::controller::{buckets, get_bucket_by_name, BucketController};
+use crate::metrics::{REPLICATION_EVENTS, REPLICATION_LAG, REPLICATION_PENDING};
+use crate::storage::resilience::ResilientBackend;
+use crate::storage::template::StoredImage;
+use crate::StorageBackend;
+
+/// The number of times replicating a change is attempted before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The delay before retrying a failed change, multiplied by the attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+static REPLICATOR: OnceCell<Replicator> = OnceCell::new();
+
+#[derive(Debug, Args)]
+pub struct CatchUpConfig {
+    #[clap(long)]
+    /// The file path to the config containing the replication target.
+    pub config: PathBuf,
+
+    #[clap(long)]
+    /// Only catch up the given bucket rather than every configured bucket.
+    pub bucket: Option<String>,
+}
+
+/// A change to an object in the primary backend.
+#[derive(Debug)]
+enum Change {
+    Variant {
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    },
+    Image {
+        bucket_id: u32,
+        image_id: Uuid,
+    },
+    Metadata {
+        bucket_id: u32,
+        key: String,
+    },
+}
+
+impl Change {
+    /// Changes to the same image or document are always sent to the
+    /// same worker so they're replicated in order.
+    fn shard(&self, shards: usize) -> usize {
+        let hash = match self {
+            Self::Variant { image_id, .. } | Self::Image { image_id, .. } => image_id.as_u128() as u64,
+            Self::Metadata { bucket_id, key } => crate::utils::crc_hash((bucket_id, key)) as u64,
+        };
+
+        (hash % shards as u64) as usize
+    }
+}
+
+struct QueuedChange {
+    change: Change,
+    queued_at: Instant,
+}
+
+/// The number of objects copied or removed to bring the target in line
+/// with the primary backend.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SyncReport {
+    pub copied: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+impl SyncReport {
+    fn merge(&mut self, other: SyncReport) {
+        self.copied += other.copied;
+        self.removed += other.removed;
+        self.unchanged += other.unchanged;
+    }
+}
+
+struct Replicator {
+    primary: Arc<dyn StorageBackend>,
+    target: Arc<dyn StorageBackend>,
+    concurrency: usize,
+    shards: Vec<mpsc::Sender<QueuedChange>>,
+    pending: AtomicUsize,
+    idle: Notify,
+}
+
+impl Replicator {
+    fn enqueue(&self, change: Change) {
+        let shard = &self.shards[change.shard(self.shards.len())];
+
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        REPLICATION_PENDING.inc();
+
+        let queued = QueuedChange {
+            change,
+            queued_at: Instant::now(),
+        };
+
+        if let Err(e) = shard.try_send(queued) {
+            let change = match e {
+                TrySendError::Full(queued) | TrySendError::Closed(queued) => queued.change,
+            };
+
+            warn!("Replication queue is full, dropping {:?}. Run `catch-up` to replicate it.", change);
+            REPLICATION_EVENTS.with_label_values(&["dropped"]).inc();
+            self.complete();
+        }
+    }
+
+    fn complete(&self) {
+        REPLICATION_PENDING.dec();
+        if self.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    async fn replicate(&self, change: &Change) -> anyhow::Result<SyncReport> {
+        match change {
+            Change::Variant { bucket_id, image_id, kind, sizing_id } => {
+                self.sync_variant(*bucket_id, *image_id, *kind, *sizing_id).await
+            },
+            Change::Image { bucket_id, image_id } => self.sync_image(*bucket_id, *image_id).await,
+            Change::Metadata { bucket_id, key } => self.sync_metadata(*bucket_id, key).await,
+        }
+    }
+
+    async fn sync_variant(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<SyncReport> {
+        let current = self.primary.fetch(bucket_id, image_id, kind, sizing_id).await?;
+        let replicated = self.target.fetch(bucket_id, image_id, kind, sizing_id).await?;
+
+        let mut report = SyncReport::default();
+        match (current, replicated) {
+            (Some(current), Some(replicated)) if current == replicated => report.unchanged += 1,
+            (Some(current), _) => {
+                self.target.store(bucket_id, image_id, kind, sizing_id, current).await?;
+                report.copied += 1;
+            },
+            (None, Some(_)) => {
+                self.target.delete_variant(bucket_id, image_id, kind, sizing_id).await?;
+                report.removed += 1;
+            },
+            (None, None) => report.unchanged += 1,
+        }
+
+        Ok(report)
+    }
+
+    /// Syncs every variant of the image which exists in either backend.
+    async fn sync_image(&self, bucket_id: u32, image_id: Uuid) -> anyhow::Result<SyncReport> {
+        let mut variants = self.primary.list_variants(bucket_id, image_id).await?;
+        variants.extend(self.target.list_variants(bucket_id, image_id).await?);
+        variants.sort_by_key(|(sizing_id, kind)| (*sizing_id, kind.as_file_extension()));
+        variants.dedup();
+
+        let mut report = SyncReport::default();
+        for (sizing_id, kind) in variants {
+            report.merge(self.sync_variant(bucket_id, image_id, kind, sizing_id).await?);
+        }
+
+        Ok(report)
+    }
+
+    async fn sync_metadata(&self, bucket_id: u32, key: &str) -> anyhow::Result<SyncReport> {
+        let current = self.primary.fetch_metadata(bucket_id, key).await?;
+        let replicated = self.target.fetch_metadata(bucket_id, key).await?;
+
+        let mut report = SyncReport::default();
+        match (current, replicated) {
+            (Some(current), Some(replicated)) if current == replicated => report.unchanged += 1,
+            (Some(current), _) => {
+                self.target.store_metadata(bucket_id, key, current).await?;
+                report.copied += 1;
+            },
+            (None, Some(_)) => {
+                self.target.delete_metadata(bucket_id, key).await?;
+                report.removed += 1;
+            },
+            (None, None) => report.unchanged += 1,
+        }
+
+        Ok(report)
+    }
+}
+
+/// Connects to the replication target if configured and starts replicating,
+/// returning the backend writes must be made through to be replicated.
+pub async fn setup(primary: Arc<dyn StorageBackend>) -> anyhow::Result<Arc<dyn StorageBackend>> {
+    let cfg = match crate::config::config().replication {
+        None => return Ok(primary),
+        Some(ref cfg) => cfg,
+    };
+
+    let target = cfg.target.connect().await?;
+    let target: Arc<dyn StorageBackend> = Arc::new(ResilientBackend::new(
+        target,
+        crate::config::config().storage_retry,
+        crate::config::config().circuit_breaker,
+    ));
+
+    let mut shards = vec![];
+    let mut receivers = vec![];
+    for _ in 0..cfg.concurrency {
+        let (tx, rx) = mpsc::channel(cfg.max_pending / cfg.concurrency);
+        shards.push(tx);
+        receivers.push(rx);
+    }
+
+    let replicator = Replicator {
+        primary: primary.clone(),
+        target,
+        concurrency: cfg.concurrency,
+        shards,
+        pending: AtomicUsize::new(0),
+        idle: Notify::new(),
+    };
+    REPLICATOR
+        .set(replicator)
+        .map_err(|_| anyhow!("Replication has already been setup."))?;
+
+    for rx in receivers {
+        tokio::spawn(run_worker(rx));
+    }
+
+    Ok(Arc::new(ReplicatingBackend { inner: primary }))
+}
+
+async fn run_worker(mut rx: mpsc::Receiver<QueuedChange>) {
+    let replicator = match REPLICATOR.get() {
+        None => return,
+        Some(replicator) => replicator,
+    };
+
+    while let Some(queued) = rx.recv().await {
+        let mut attempt = 1;
+        loop {
+            match replicator.replicate(&queued.change).await {
+                Ok(_) => {
+                    REPLICATION_EVENTS.with_label_values(&["replicated"]).inc();
+                    break
+                },
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    warn!("Failed to replicate {:?}, retrying: {}", queued.change, e);
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                },
+                Err(e) => {
+                    error!(
+                        "Failed to replicate {:?} after {} attempts, run `catch-up` to replicate it: {}",
+                        queued.change, attempt, e,
+                    );
+                    REPLICATION_EVENTS.with_label_values(&["failed"]).inc();
+                    break
+                },
+            }
+        }
+
+        REPLICATION_LAG.set(queued.queued_at.elapsed().as_secs_f64());
+        replicator.complete();
+    }
+}
+
+/// The number of changes waiting to be replicated.
+pub fn pending() -> usize {
+    REPLICATOR
+        .get()
+        .map(|replicator| replicator.pending.load(Ordering::Acquire))
+        .unwrap_or(0)
+}
+
+/// Waits until every queued change has been replicated.
+pub async fn wait_until_idle() {
+    let replicator = match REPLICATOR.get() {
+        None => return,
+        Some(replicator) => replicator,
+    };
+
+    loop {
+        // Registered before checking the count so a change completing
+        // in-between is not missed.
+        let notified = replicator.idle.notified();
+
+        if pending() == 0 {
+            return
+        }
+
+        notified.await;
+    }
+}
+
+/// Replicates every indexed image of the bucket, bringing the target in line
+/// with the primary backend after changes were lost or failed to replicate.
+///
+/// Only images in the bucket's index can be found, so images deleted while
+/// their changes couldn't be replicated remain in the target.
+pub async fn catch_up(bucket: &BucketController) -> anyhow::Result<SyncReport> {
+    let replicator = REPLICATOR
+        .get()
+        .ok_or_else(|| anyhow!("Replication is not configured."))?;
+
+    let images = bucket
+        .indexed_images()
+        .await?
+        .ok_or_else(|| anyhow!("Bucket {} has no index so its images can't be found.", bucket.name()))?;
+
+    let bucket_id = bucket.bucket_id();
+    let reports: Vec<anyhow::Result<SyncReport>> = futures::stream::iter(images.into_keys())
+        .map(|image_id| replicator.sync_image(bucket_id, image_id))
+        .buffer_unordered(replicator.concurrency)
+        .collect()
+        .await;
+
+    let mut report = SyncReport::default();
+    for result in reports {
+        report.merge(result?);
+    }
+
+    Ok(report)
+}
+
+/// Catches up the replication target of each configured bucket.
+pub async fn run_catch_up(args: CatchUpConfig) -> anyhow::Result<()> {
+    crate::config::init(&args.config).await?;
+    crate::init_global_state()?;
+    crate::setup_buckets().await?;
+
+    let targets: Vec<&BucketController> = match args.bucket {
+        Some(ref name) => vec![
+            get_bucket_by_name(name).ok_or_else(|| anyhow!("Bucket {} does not exist.", name))?,
+        ],
+        None => buckets().filter(|bucket| bucket.cfg().index.is_some()).collect(),
+    };
+
+    for bucket in targets {
+        let report = catch_up(bucket).await?;
+        println!(
+            "{:<24} copied {:>8}  removed {:>8}  unchanged {:>8}",
+            bucket.name(),
+            report.copied,
+            report.removed,
+            report.unchanged,
+        );
+    }
+
+    crate::metadata::flush_all().await;
+    wait_until_idle().await;
+
+    Ok(())
+}
+
+/// Wraps the primary backend, queuing every write to be replicated.
+struct ReplicatingBackend {
+    inner: Arc<dyn StorageBackend>,
+}
+
+impl ReplicatingBackend {
+    fn enqueue(&self, change: Change) {
+        if let Some(replicator) = REPLICATOR.get() {
+            replicator.enqueue(change);
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ReplicatingBackend {
+    async fn check_capacity(&self) -> anyhow::Result<()> {
+        self.inner.check_capacity().await
+    }
+
+    async fn store(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        self.inner.store(bucket_id, image_id, kind, sizing_id, data).await?;
+        self.enqueue(Change::Variant { bucket_id, image_id, kind, sizing_id });
+        Ok(())
+    }
+
+    async fn fetch(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<Option<Bytes>> {
+        self.inner.fetch(bucket_id, image_id, kind, sizing_id).await
+    }
+
+    async fn delete(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+    ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
+        // Even a failed delete may have removed some variants.
+        let result = self.inner.delete(bucket_id, image_id).await;
+        self.enqueue(Change::Image { bucket_id, image_id });
+        result
+    }
+
+    async fn list_variants(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+    ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
+        self.inner.list_variants(bucket_id, image_id).await
+    }
+
+    async fn list_ids(
+        &self,
+        bucket_id: u32,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredImage>> {
+        self.inner.list_ids(bucket_id, after, limit).await
+    }
+
+    async fn delete_variant(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<()> {
+        self.inner.delete_variant(bucket_id, image_id, kind, sizing_id).await?;
+        self.enqueue(Change::Variant { bucket_id, image_id, kind, sizing_id });
+        Ok(())
+    }
+
+    async fn store_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        self.inner.store_metadata(bucket_id, key, data).await?;
+        self.enqueue(Change::Metadata { bucket_id, key: key.to_string() });
+        Ok(())
+    }
+
+    async fn fetch_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<Option<Bytes>> {
+        self.inner.fetch_metadata(bucket_id, key).await
+    }
+
+    async fn delete_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        self.inner.delete_metadata(bucket_id, key).await?;
+        self.enqueue(Change::Metadata { bucket_id, key: key.to_string() });
+        Ok(())
+    }
+
+    async fn list_metadata(
+        &self,
+        bucket_id: u32,
+        dir: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        self.inner.list_metadata(bucket_id, dir, after, limit).await
+    }
+}
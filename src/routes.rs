@@ -1,17 +1,49 @@
 use std::fmt::Display;
 use bytes::Bytes;
-use poem_openapi::OpenApi;
-use poem::{Body, Result};
-use poem_openapi::{ApiResponse, Object};
+use poem_openapi::{ApiExtractor, ApiExtractorType, ExtractParamOptions, OpenApi};
+use poem::{Body, Request, Result};
+use poem::web::RequestBody;
+use poem::http::StatusCode;
+use poem_openapi::{ApiResponse, Multipart, Object};
 use poem_openapi::param::{Header, Path, Query};
-use poem_openapi::payload::{Binary, Json};
+use poem_openapi::payload::{Binary, Json, ParsePayload, Payload, Response};
+use poem_openapi::registry::{MetaMediaType, MetaRequest, Registry};
+use poem_openapi::types::multipart::Upload;
+use tokio::io::AsyncReadExt;
 use futures::StreamExt;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use uuid::Uuid;
 
-use crate::config::{config, ImageKind};
-use crate::controller::{BucketController, get_bucket_by_name, UploadInfo};
+use crate::auth::{self, Scope};
+use crate::config::{config, ImageKind, MissingImageStatus};
+use crate::controller::{BucketController, DeleteInfo, get_bucket_by_name, ImageIdConflict, ImageListing, ImageMetadata, ReplaceOutcome, UploadInfo, UploadJobInfo, UploadOptions, UploadOutcome};
+use crate::etags::{format_etag, Precondition};
+use crate::purge::{PurgeFilter, PurgeJobInfo};
 use crate::pipelines::ProcessingMode;
+use crate::processor::ProcessingError;
+use crate::processor::animation::AnimationLimitExceeded;
+use crate::remote::DownloadRejection;
+use crate::storage::{InsufficientStorage, StorageThrottled, StorageUnavailable};
+use crate::signing::{self, SignatureRejection};
+use crate::transforms::{self, TransformRejection};
 
+/// The `Warning` header value marking a response as stale.
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+/// The maximum number of characters of an upload's original filename or uploader.
+const MAX_UPLOAD_METADATA_LENGTH: usize = 255;
+
+/// The maximum number of characters kept of a requested filename.
+const MAX_FILENAME_LENGTH: usize = 200;
+
+/// The number of images listed per page if no limit is given.
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+/// The maximum number of images which can be listed per page.
+const MAX_LIST_LIMIT: usize = 1000;
+
+/// How long in seconds signed URLs are valid for if no expiry is given.
+const DEFAULT_SIGNED_URL_EXPIRY: u64 = 60 * 60;
 
 #[derive(Debug, Object)]
 pub struct Detail {
@@ -19,11 +51,34 @@ pub struct Detail {
     detail: String,
 }
 
+impl Detail {
+    pub fn new(detail: impl Display) -> Self {
+        Self {
+            detail: detail.to_string(),
+        }
+    }
+}
+
 
 #[derive(ApiResponse)]
 pub enum UploadResponse {
     #[oai(status = 200)]
-    Ok(Json<UploadInfo>),
+    Ok(
+        Json<UploadInfo>,
+        /// The checksum of the uploaded image, this can be given as the
+        /// `if-match` header to make later replaces and deletes conditional.
+        #[oai(header = "etag")] String,
+    ),
+
+    /// The upload exceeded the bucket's `async_upload_threshold` and
+    /// is being completed in the background.
+    ///
+    /// The status of the upload can be polled via the URL in the `location` header.
+    #[oai(status = 202)]
+    Accepted(
+        Json<UploadJobInfo>,
+        #[oai(header = "location")] String,
+    ),
 
     /// Bucket not found
     #[oai(status = 404)]
@@ -34,11 +89,19 @@ pub enum UploadResponse {
     #[oai(status = 400)]
     InvalidImageFormat,
 
-    /// The upload exceeds the configured maximum file size.
+    /// The upload exceeds the configured maximum file size
+    /// or the bucket's animation limits.
     #[oai(status = 413)]
     TooBig,
 
-    #[allow(unused)]
+    /// The upload options are invalid.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
+
+    /// An image already exists with the given custom image id.
+    #[oai(status = 409)]
+    Conflict(Json<Detail>),
+
     /// You are not authorized to complete this action.
     ///
     /// This normally means the `Authorization` bearer has been left out
@@ -47,240 +110,2155 @@ pub enum UploadResponse {
     Unauthorized,
 }
 
+#[derive(Debug, Multipart)]
+pub struct BatchUpload {
+    /// The images to upload.
+    ///
+    /// Each part's `content-type`, or otherwise its filename extension,
+    /// is used as the format hint for the image.
+    #[oai(validator(max_items = 100))]
+    files: Vec<Upload>,
+}
+
+/// An image uploaded as `multipart/form-data` rather than the raw body.
+#[derive(Debug, Multipart)]
+pub struct UploadForm {
+    /// The image to upload.
+    file: Upload,
+
+    /// The format that the uploaded image is encoded in.
+    ///
+    /// If not provided, the part's `content-type` or filename extension is used
+    /// as the format hint, otherwise lust will guess the encoding.
+    format: Option<ImageKind>,
+}
+
+/// The body of an upload, either the raw image or a `multipart/form-data` form.
+pub enum UploadBody {
+    Binary(Binary<Body>),
+    Form(UploadForm),
+}
+
+#[poem::async_trait]
+impl<'a> ApiExtractor<'a> for UploadBody {
+    const TYPE: ApiExtractorType = ApiExtractorType::RequestObject;
+
+    type ParamType = ();
+    type ParamRawType = ();
+
+    fn register(registry: &mut Registry) {
+        <Binary<Body> as Payload>::register(registry);
+        <UploadForm as Payload>::register(registry);
+    }
+
+    fn request_meta() -> Option<MetaRequest> {
+        Some(MetaRequest {
+            description: None,
+            content: vec![
+                MetaMediaType {
+                    content_type: <Binary<Body> as Payload>::CONTENT_TYPE,
+                    schema: <Binary<Body> as Payload>::schema_ref(),
+                },
+                MetaMediaType {
+                    content_type: <UploadForm as Payload>::CONTENT_TYPE,
+                    schema: <UploadForm as Payload>::schema_ref(),
+                },
+            ],
+            required: true,
+        })
+    }
+
+    async fn from_request(
+        request: &'a Request,
+        body: &mut RequestBody,
+        param_opts: ExtractParamOptions<Self::ParamType>,
+    ) -> Result<Self> {
+        if is_multipart_form(request) {
+            return Ok(Self::Form(<UploadForm as ParsePayload>::from_request(request, body).await?))
+        }
+
+        let file = <Binary<Body> as ApiExtractor>::from_request(request, body, param_opts).await?;
+        Ok(Self::Binary(file))
+    }
+}
+
+#[derive(Debug, Object)]
+pub struct BatchUploadResult {
+    /// The filename of the part, if given.
+    file_name: Option<String>,
+
+    /// The format the image was uploaded as.
+    format: Option<ImageKind>,
+
+    /// The upload info if the image was uploaded.
+    upload: Option<UploadInfo>,
+
+    /// The upload job if the image is being uploaded in the background.
+    job: Option<UploadJobInfo>,
+
+    /// The reason the image was rejected if applicable.
+    error: Option<String>,
+}
+
 #[derive(ApiResponse)]
-pub enum DeleteResponse {
+pub enum BatchUploadResponse {
+    /// The result of each uploaded part, in the order they were given.
     #[oai(status = 200)]
-    Ok,
+    Ok(Json<Vec<BatchUploadResult>>),
+
+    /// Bucket not found
+    #[oai(status = 404)]
+    NotFound,
 
-    #[allow(unused)]
     /// You are not authorized to complete this action.
     ///
     /// This normally means the `Authorization` bearer has been left out
     /// of the request or is invalid.
     #[oai(status = 401)]
     Unauthorized,
-
-    /// Bucket does not exist.
-    #[oai(status = 404)]
-    NotFound,
 }
 
 #[derive(ApiResponse)]
-pub enum FetchResponse {
+pub enum ReplaceResponse {
     #[oai(status = 200)]
     Ok(
-        Binary<Vec<u8>>,
-        #[oai(header = "content-type")] String,
+        Json<UploadInfo>,
+        /// The checksum of the new content of the image.
+        #[oai(header = "etag")] String,
     ),
 
-    /// The request is invalid with the current configuration.
-    ///
-    /// See the detail section for more info.
+    /// Bucket not found or the image does not exist.
+    #[oai(status = 404)]
+    NotFound,
+
+    /// The image format was incorrect or the system was
+    /// unable to guess the format of the image.
     #[oai(status = 400)]
-    UnsupportedOperation(Json<Detail>),
+    InvalidImageFormat,
 
-    /// Bucket does not exist or image does not exist.
+    /// The upload exceeds the configured maximum file size
+    /// or the bucket's animation limits.
+    #[oai(status = 413)]
+    TooBig,
+
+    /// The upload options are invalid.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
+
+    /// The image's current checksum does not match the `if-match` header.
+    #[oai(status = 412)]
+    PreconditionFailed(Json<Detail>),
+
+    /// You are not authorized to complete this action.
     ///
-    /// See the detail section for more info.
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
+}
+
+#[derive(ApiResponse)]
+pub enum UploadStatusResponse {
+    #[oai(status = 200)]
+    Ok(Json<UploadJobInfo>),
+
+    /// Bucket does not exist or the upload job does not exist.
+    ///
+    /// Upload jobs are only tracked for a limited time after they complete.
     #[oai(status = 404)]
     NotFound(Json<Detail>),
-}
 
-impl FetchResponse {
-    fn bucket_not_found(bucket: &str) -> Self {
-        let detail = Detail {
-            detail: format!("The bucket {:?} does not exist.", bucket),
-        };
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
+}
 
-        Self::NotFound(Json(detail))
-    }
+#[derive(ApiResponse)]
+pub enum PurgeResponse {
+    /// The dry run report of the images matching the filter.
+    #[oai(status = 200)]
+    Ok(Json<PurgeJobInfo>),
 
-    fn image_not_found(image_id: Uuid) -> Self {
-        let detail = Detail {
-            detail: format!("The image {:?} does not exist in bucket.", image_id),
-        };
+    /// The matching images are being deleted in the background.
+    ///
+    /// The progress of the purge can be polled via the URL in the `location` header.
+    #[oai(status = 202)]
+    Accepted(
+        Json<PurgeJobInfo>,
+        #[oai(header = "location")] String,
+    ),
 
-        Self::NotFound(Json(detail))
-    }
+    /// The filter is invalid or the bucket does not have an `index`.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
 
-    fn bad_request(msg: impl Display) -> Self {
-        let detail = Detail {
-            detail: msg.to_string(),
-        };
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
 
-        Self::UnsupportedOperation(Json(detail))
-    }
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
 }
 
+#[derive(ApiResponse)]
+pub enum PurgeStatusResponse {
+    #[oai(status = 200)]
+    Ok(Json<PurgeJobInfo>),
 
-pub struct LustApi ;
-
-#[OpenApi(prefix_path = "/:bucket")]
-impl LustApi {
-    /// Upload Image
+    /// Bucket does not exist or the purge job does not exist.
     ///
-    /// Upload an image to the given bucket.
-    /// The `content-type` header must be provided as well
-    /// as the `content-length` header otherwise the request will be rejected.
+    /// Purge jobs are only tracked for a limited time.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+
+    /// You are not authorized to complete this action.
     ///
-    /// The uploaded file must also not exceed the given `content-length`.
-    #[oai(path = "/", method = "post")]
-    pub async fn upload_image(
-        &self,
-        /// The bucket that the image should be uploaded.
-        bucket: Path<String>,
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
+}
 
-        /// The total size of the image in bytes.
-        #[oai(name = "content-length")] content_length: Header<usize>,
+#[derive(ApiResponse)]
+pub enum MetadataResponse {
+    #[oai(status = 200)]
+    Ok(Json<ImageMetadata>),
 
-        /// The format that the uploaded image is encoded in.
-        ///
-        /// If not provided, lust will guess the encoding.
-        format: Query<Option<ImageKind>>,
+    /// The bucket does not have an `index` to store metadata in.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
 
-        /// The raw binary data of the image.
-        file: Binary<Body>,
-    ) -> Result<UploadResponse> {
-        let bucket = match get_bucket_by_name(&*bucket) {
-            None => return Ok(UploadResponse::NotFound),
-            Some(b) => b,
-        };
+    /// Bucket does not exist or the image is not indexed.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
 
-        let length = if !config().valid_global_size(*content_length) {
-            return Ok(UploadResponse::TooBig)
-        } else {
-            let local_limit = bucket
-                .cfg()
-                .max_upload_size
-                .map(|v| (v * 1024) as usize)
-                .unwrap_or(u32::MAX as usize);
-
-            if *content_length > local_limit  {
-                return Ok(UploadResponse::TooBig)
-            }
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
+}
 
-            *content_length
-        };
+#[derive(ApiResponse)]
+pub enum ListResponse {
+    #[oai(status = 200)]
+    Ok(Json<ImageListing>),
 
-        let mut allocated_image = Vec::with_capacity(length);
-        let mut stream = file.0.into_bytes_stream();
-        while let Some(chunk) = stream.next().await {
-            let chunk: Bytes = chunk.map_err(anyhow::Error::from)?;
-            allocated_image.extend(chunk.into_iter());
+    /// The limit is outside of the allowed range.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
 
-            if allocated_image.len() > length {
-                return Ok(UploadResponse::TooBig)
-            }
-        }
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
 
-        let format = if let Some(format) = format.0 {
-            let validate = image::load_from_memory_with_format(&allocated_image, format.into());
-            if validate.is_err() {
-                return Ok(UploadResponse::InvalidImageFormat)
-            }
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
+}
 
-            format
-        } else {
-            let maybe_guessed = image::guess_format(&allocated_image)
-                .map(ImageKind::from_guessed_format)
-                .map_err(anyhow::Error::from)?;
+#[derive(Debug, Object)]
+pub struct ImportRequest {
+    /// The http or https URL to download the image from.
+    ///
+    /// The host must be one of the configured `remote_uploads` allowed hosts.
+    url: String,
 
-            if let Some(guessed) = maybe_guessed {
-                guessed
-            } else {
-                return Ok(UploadResponse::InvalidImageFormat)
-            }
-        };
+    /// The format that the image is encoded in.
+    ///
+    /// If not provided, lust will guess the encoding.
+    format: Option<ImageKind>,
 
-        let info = bucket.upload(format, allocated_image).await?;
-        Ok(UploadResponse::Ok(Json(info)))
-    }
+    /// The tags to index the image under.
+    #[oai(default)]
+    tags: Vec<String>,
+}
 
-    /// Fetch Image
+#[derive(ApiResponse)]
+pub enum ImportResponse {
+    #[oai(status = 200)]
+    Ok(
+        Json<UploadInfo>,
+        /// The checksum of the uploaded image.
+        #[oai(header = "etag")] String,
+    ),
+
+    /// The upload exceeded the bucket's `async_upload_threshold` and
+    /// is being completed in the background.
     ///
-    /// Fetch the image from the storage backend and apply and additional affects
-    /// if required.
-    #[allow(clippy::too_many_arguments)]
-    #[oai(path = "/:image_id", method = "get")]
-    pub async fn fetch_image(
-        &self,
-        /// The bucket to try fetch the image from.
-        bucket: Path<String>,
+    /// The status of the upload can be polled via the URL in the `location` header.
+    #[oai(status = 202)]
+    Accepted(
+        Json<UploadJobInfo>,
+        #[oai(header = "location")] String,
+    ),
 
-        /// The id of the image.
-        image_id: Path<Uuid>,
+    /// Bucket not found
+    #[oai(status = 404)]
+    NotFound,
 
-        /// The encoding format that the image should be returned as.
-        format: Query<Option<ImageKind>>,
+    /// The image format was incorrect or the system was
+    /// unable to guess the format of the image.
+    #[oai(status = 400)]
+    InvalidImageFormat,
 
-        /// The size preset that should be used when returning the image.
-        size: Query<Option<String>>,
+    /// The URL is invalid.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
 
-        /// A custom width to resize the returned image to.
-        width: Query<Option<u32>>,
+    /// The image exceeds the configured maximum file size
+    /// or the bucket's animation limits.
+    #[oai(status = 413)]
+    TooBig,
 
-        /// A custom height to resize the returned image to.
-        height: Query<Option<u32>>,
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
 
-        /// A set of `,` seperated content-types that could be sent as a response.
-        /// E.g. `image/png,image/webp,image/gif`
-        accept: Header<Option<String>>,
-    ) -> Result<FetchResponse> {
-        let bucket = match get_bucket_by_name(&*bucket) {
-            None => return Ok(FetchResponse::bucket_not_found(&*bucket)),
-            Some(b) => b,
-        };
+    /// Remote uploads are not enabled or the URL's host is not allowed.
+    #[oai(status = 403)]
+    Forbidden(Json<Detail>),
 
-        let kind = get_image_kind(format.0, accept.0, bucket);
-        let custom_sizing = match (width.0, height.0) {
-            (Some(w), Some(h)) => if bucket.cfg().mode != ProcessingMode::Realtime {
-                return Ok(FetchResponse::bad_request(
-                    "Custom resizing can only be done when bucket set to 'realtime' processing mode",
-                ))
-            } else {
-                Some((w, h))
-            },
-            (None, None) => None,
-            _ => return Ok(FetchResponse::bad_request(
-                "A custom size must include both the width and the height.",
-            ))
-        };
+    /// The remote host could not be reached or responded with an error.
+    #[oai(status = 502)]
+    BadGateway(Json<Detail>),
 
-        let img = bucket.fetch(image_id.0, kind, size.0, custom_sizing).await?;
-        match img {
-            None => Ok(FetchResponse::image_not_found(image_id.0)),
-            Some(img) => Ok(FetchResponse::Ok(Binary(img.data.to_vec()), img.kind.as_content_type()))
-        }
-    }
+    /// The image was not downloaded in time.
+    #[oai(status = 504)]
+    GatewayTimeout(Json<Detail>),
+}
 
-    /// Delete Image
-    ///
-    /// Delete the given image.
-    /// This will purge all variants of the image including sizing presets and formats.
+#[derive(Debug, Object)]
+pub struct SignRequest {
+    /// The images to sign fetch URLs for.
+    #[oai(validator(max_items = 1000))]
+    image_ids: Vec<Uuid>,
+
+    /// The format the images should be fetched as.
+    format: Option<ImageKind>,
+
+    /// The size preset the images should be fetched as.
+    size: Option<String>,
+
+    /// A custom width to resize the images to.
+    width: Option<u32>,
+
+    /// A custom height to resize the images to.
+    height: Option<u32>,
+
+    /// How long in seconds the URLs are valid for.
+    ///
+    /// Defaults to `3600`.
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Object)]
+pub struct SignedUrl {
+    /// The id of the image.
+    image_id: Uuid,
+
+    /// The signed fetch URL of the image.
+    url: String,
+}
+
+#[derive(Debug, Object)]
+pub struct SignedUrls {
+    /// The unix timestamp the URLs expire at.
+    expires: i64,
+
+    /// The signed URLs, in the order the images were given.
+    urls: Vec<SignedUrl>,
+}
+
+#[derive(ApiResponse)]
+pub enum SignResponse {
+    #[oai(status = 200)]
+    Ok(Json<SignedUrls>),
+
+    /// Bucket not found
+    #[oai(status = 404)]
+    NotFound,
+
+    /// The bucket does not require signed URLs or the fetch parameters are invalid.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
+
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid, signing URLs always requires an API key.
+    #[oai(status = 401)]
+    Unauthorized,
+}
+
+#[derive(ApiResponse)]
+pub enum DeleteResponse {
+    #[oai(status = 200)]
+    Ok(Json<DeleteInfo>),
+
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
+
+    /// Bucket does not exist.
+    #[oai(status = 404)]
+    NotFound,
+
+    /// The image's current checksum does not match the `if-match` header.
+    #[oai(status = 412)]
+    PreconditionFailed(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum RestoreResponse {
+    /// The image was restored.
+    #[oai(status = 204)]
+    Restored,
+
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid.
+    #[oai(status = 401)]
+    Unauthorized,
+
+    /// The bucket does not exist or the image is not in its trash.
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+pub enum CopyResponse {
+    /// The image was stored in the destination bucket.
+    #[oai(status = 200)]
+    Ok(
+        Json<UploadInfo>,
+        /// The checksum of the copied image.
+        #[oai(header = "etag")] String,
+    ),
+
+    /// The destination bucket is invalid.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
+
+    /// You are not authorized to complete this action.
+    ///
+    /// This normally means the `Authorization` bearer has been left out
+    /// of the request or is invalid for either bucket.
+    #[oai(status = 401)]
+    Unauthorized,
+
+    /// The bucket or the image's original does not exist.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+
+    /// An image already exists in the destination bucket with the same id.
+    #[oai(status = 409)]
+    Conflict(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum FetchResponse {
+    #[oai(status = 200)]
+    Ok(
+        Binary<Vec<u8>>,
+        #[oai(header = "content-type")] String,
+        /// Set to `zstd` when a pre-compressed variant is served.
+        #[oai(header = "content-encoding")] Option<String>,
+        /// Set to `110 - "Response is Stale"` when an expired cached copy
+        /// is served as the storage backend failed.
+        #[oai(header = "warning")] Option<String>,
+        /// Set when a `filename` is requested.
+        #[oai(header = "content-disposition")] Option<String>,
+    ),
+
+    /// The request is invalid with the current configuration.
+    ///
+    /// See the detail section for more info.
+    #[oai(status = 400)]
+    UnsupportedOperation(Json<Detail>),
+
+    /// Bucket does not exist or image does not exist.
+    ///
+    /// See the detail section for more info.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+
+    /// The image does not exist and the bucket's `missing_image` placeholder is returned.
+    #[oai(status = 404)]
+    MissingPlaceholder(
+        Binary<Vec<u8>>,
+        #[oai(header = "content-type")] String,
+    ),
+
+    /// The image does not exist and the bucket's `missing_image` placeholder
+    /// is returned with the `gone` status.
+    #[oai(status = 410)]
+    GonePlaceholder(
+        Binary<Vec<u8>>,
+        #[oai(header = "content-type")] String,
+    ),
+
+    /// The bucket has exceeded its monthly egress limit and is
+    /// configured to redirect to a placeholder.
+    #[oai(status = 302)]
+    Redirect(#[oai(header = "location")] String),
+
+    /// The bucket has exceeded its monthly egress limit.
+    #[oai(status = 429)]
+    EgressLimitExceeded(Json<Detail>),
+
+    /// The path transformation is neither allowed by the bucket nor signed,
+    /// or the original was requested from a bucket not serving originals.
+    #[oai(status = 403)]
+    Forbidden(Json<Detail>),
+
+    /// The bucket requires signed URLs and the URL is unsigned,
+    /// incorrectly signed or has expired.
+    #[oai(status = 401)]
+    Unauthorized(Json<Detail>),
+}
+
+impl FetchResponse {
+    pub(crate) fn bucket_not_found(bucket: &str) -> Self {
+        let detail = Detail {
+            detail: format!("The bucket {:?} does not exist.", bucket),
+        };
+
+        Self::NotFound(Json(detail))
+    }
+
+    fn image_not_found(image_id: Uuid) -> Self {
+        let detail = Detail {
+            detail: format!("The image {:?} does not exist in bucket.", image_id),
+        };
+
+        Self::NotFound(Json(detail))
+    }
+
+    pub(crate) fn unauthorized(rejection: SignatureRejection) -> Self {
+        Self::Unauthorized(Json(Detail::new(rejection)))
+    }
+
+    fn egress_limit_exceeded(bucket: &str) -> Self {
+        let detail = Detail {
+            detail: format!("The bucket {:?} has exceeded its monthly egress limit.", bucket),
+        };
+
+        Self::EgressLimitExceeded(Json(detail))
+    }
+
+    pub(crate) fn bad_request(msg: impl Display) -> Self {
+        let detail = Detail {
+            detail: msg.to_string(),
+        };
+
+        Self::UnsupportedOperation(Json(detail))
+    }
+
+    /// Suggests the filename the served image is saved as.
+    fn with_filename(mut self, filename: Option<&str>) -> Self {
+        if let (Self::Ok(_, ref content_type, _, _, ref mut disposition), Some(filename)) = (&mut self, filename) {
+            *disposition = ImageKind::from_content_type(content_type)
+                .and_then(|kind| content_disposition(filename, kind));
+        }
+
+        self
+    }
+}
+
+
+pub struct LustApi ;
+
+#[OpenApi(prefix_path = "/:bucket")]
+impl LustApi {
+    /// Upload Image
+    ///
+    /// Upload an image to the given bucket.
+    /// The `content-type` header must be provided otherwise the request will be rejected.
+    ///
+    /// If the `content-length` header is given the uploaded file must not exceed it,
+    /// otherwise the body can be sent with chunked transfer encoding and is rejected
+    /// once it exceeds the upload size limit.
+    ///
+    /// The image can also be sent as `multipart/form-data` with a `file` part and
+    /// an optional `format` field, the part's filename is used as the original
+    /// filename if the `x-original-filename` header is not given.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/", method = "post")]
+    pub async fn upload_image(
+        &self,
+        /// The bucket that the image should be uploaded.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+
+        /// The total size of the image in bytes.
+        ///
+        /// This can be omitted for uploads using chunked transfer encoding.
+        #[oai(name = "content-length")] content_length: Header<Option<usize>>,
+
+        /// The format that the uploaded image is encoded in.
+        ///
+        /// If not provided, lust will guess the encoding.
+        format: Query<Option<ImageKind>>,
+
+        /// A unique key identifying this upload.
+        ///
+        /// Retrying an upload with the same key returns the original upload
+        /// info and image id instead of creating a duplicate image.
+        #[oai(name = "idempotency-key")] idempotency_key: Header<Option<String>>,
+
+        /// A set of `,` seperated tags to index the image under.
+        ///
+        /// Tags can be used to purge images, this requires the bucket to have an `index`.
+        tags: Query<Option<String>>,
+
+        /// A set of `,` seperated `preset:format` pairs to generate in the
+        /// background once the image is stored, e.g. `thumb:webp,large:jpeg`.
+        ///
+        /// The format can be omitted to use the preset's default serving format.
+        /// This is only useful for `jit` buckets as `aot` buckets generate every variant.
+        pregenerate: Query<Option<String>>,
+
+        /// The filename the image was uploaded with, stored in the bucket's `index`.
+        ///
+        /// This should be percent-encoded if it contains non-ASCII characters.
+        #[oai(name = "x-original-filename")] original_filename: Header<Option<String>>,
+
+        /// An identifier of who uploaded the image, stored in the bucket's `index`.
+        #[oai(name = "x-uploader")] uploader: Header<Option<String>>,
+
+        /// The id to store the image under instead of a generated one.
+        ///
+        /// This can be a UUID or, if the bucket has a `slug_pattern`, a slug matching
+        /// the pattern. Requires the bucket to enable `custom_ids`.
+        #[oai(name = "image_id")] custom_id: Query<Option<String>>,
+
+        /// The same as the `image_id` query parameter, which takes precedence.
+        #[oai(name = "x-image-id")] custom_id_header: Header<Option<String>>,
+
+        /// The raw binary data of the image, or a `multipart/form-data` form.
+        file: UploadBody,
+    ) -> Result<UploadResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(UploadResponse::NotFound),
+            Some(b) => b,
+        };
+
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Write).await? {
+            return Ok(UploadResponse::Unauthorized)
+        }
+
+        let pregenerate = match parse_pregenerate(bucket, pregenerate.0.as_deref()) {
+            Err(e) => return Ok(UploadResponse::BadRequest(Json(Detail::new(e)))),
+            Ok(pregenerate) => pregenerate,
+        };
+
+        let image_id = match custom_id.0.or(custom_id_header.0).map(|id| bucket.resolve_custom_id(&id)) {
+            Some(Err(e)) => return Ok(UploadResponse::BadRequest(Json(Detail::new(e)))),
+            Some(Ok(image_id)) => Some(image_id),
+            None => None,
+        };
+
+        let (file, content_length, format, part_filename) = match file {
+            UploadBody::Binary(file) => (file, content_length.0, format.0, None),
+            UploadBody::Form(form) => {
+                let format = format.0
+                    .or(form.format)
+                    .or_else(|| part_format_hint(&form.file));
+                let part_filename = form.file.file_name().map(|v| v.to_string());
+
+                // The content length is of the whole form, so the
+                // size limits are enforced as the part is read instead.
+                (Binary(Body::from_async_read(form.file.into_async_read())), None, format, part_filename)
+            },
+        };
+
+        let original_filename = decode_original_filename(original_filename.0.as_deref()).or(part_filename);
+        let (original_filename, uploader) = match parse_upload_metadata(original_filename.as_deref(), uploader.0.as_deref()) {
+            Err(e) => return Ok(UploadResponse::BadRequest(Json(Detail::new(e)))),
+            Ok(metadata) => metadata,
+        };
+
+        let (format, allocated_image) = match read_upload(bucket, content_length, format, file).await? {
+            Err(UploadRejection::TooBig) => return Ok(UploadResponse::TooBig),
+            Err(UploadRejection::InvalidImageFormat) => return Ok(UploadResponse::InvalidImageFormat),
+            Ok(upload) => upload,
+        };
+
+        let options = UploadOptions {
+            idempotency_key: idempotency_key.0,
+            tags: parse_tags(tags.0),
+            pregenerate,
+            original_filename,
+            uploader,
+            image_id,
+        };
+
+        let outcome = match bucket.upload(format, allocated_image, options).await {
+            Err(e) if e.is::<AnimationLimitExceeded>() => return Ok(UploadResponse::TooBig),
+            Err(e) if e.is::<ImageIdConflict>() => return Ok(UploadResponse::Conflict(Json(Detail::new(e)))),
+            outcome => outcome.map_err(processing_error)?,
+        };
+        match outcome {
+            UploadOutcome::Complete(info) => {
+                let etag = format_etag(info.checksum());
+                Ok(UploadResponse::Ok(Json(info), etag))
+            },
+            UploadOutcome::Pending(job) => {
+                let status_url = upload_status_url(bucket.name(), job.job_id());
+                Ok(UploadResponse::Accepted(Json(job), status_url))
+            },
+        }
+    }
+
+    /// Batch Upload Images
+    ///
+    /// Upload several images to the given bucket as `multipart/form-data`.
+    ///
+    /// Each part is validated and uploaded independently, so a rejected part
+    /// does not prevent the other parts from being uploaded.
+    #[oai(path = "/batch", method = "post")]
+    pub async fn batch_upload(
+        &self,
+        /// The bucket that the images should be uploaded.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+
+        /// A set of `,` seperated tags to index every image under.
+        tags: Query<Option<String>>,
+
+        /// An identifier of who uploaded the images, stored in the bucket's `index`.
+        #[oai(name = "x-uploader")] uploader: Header<Option<String>>,
+
+        /// The images to upload.
+        batch: BatchUpload,
+    ) -> Result<BatchUploadResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(BatchUploadResponse::NotFound),
+            Some(b) => b,
+        };
+
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Write).await? {
+            return Ok(BatchUploadResponse::Unauthorized)
+        }
+
+        let tags = parse_tags(tags.0);
+        let limit = upload_limit(bucket);
+
+        let mut results = vec![];
+        for file in batch.files {
+            let mut result = BatchUploadResult {
+                file_name: file.file_name().map(|v| v.to_string()),
+                format: None,
+                upload: None,
+                job: None,
+                error: None,
+            };
+
+            let hint = part_format_hint(&file);
+            let mut data = vec![];
+            file.into_async_read()
+                .take(limit as u64 + 1)
+                .read_to_end(&mut data)
+                .await
+                .map_err(anyhow::Error::from)?;
+
+            if data.len() > limit {
+                result.error = Some("The image exceeds the maximum upload size.".to_string());
+                results.push(result);
+                continue
+            }
+
+            let format = match resolve_format(bucket, &data, hint) {
+                Err(UploadRejection::TooBig) => {
+                    result.error = Some("The image exceeds the decode limits.".to_string());
+                    results.push(result);
+                    continue
+                },
+                Err(UploadRejection::InvalidImageFormat) => {
+                    let error = invalid_format_error(hint);
+                    bucket.quarantine_upload(data.into(), hint, &error).await;
+                    result.error = Some(error);
+                    results.push(result);
+                    continue
+                },
+                Ok(format) => format,
+            };
+            result.format = Some(format);
+
+            let (original_filename, uploader) = match parse_upload_metadata(result.file_name.as_deref(), uploader.0.as_deref()) {
+                Err(e) => {
+                    result.error = Some(e);
+                    results.push(result);
+                    continue
+                },
+                Ok(metadata) => metadata,
+            };
+
+            let options = UploadOptions {
+                idempotency_key: None,
+                tags: tags.clone(),
+                original_filename,
+                uploader,
+                ..Default::default()
+            };
+
+            match bucket.upload(format, data, options).await {
+                Ok(UploadOutcome::Complete(info)) => result.upload = Some(info),
+                Ok(UploadOutcome::Pending(job)) => result.job = Some(job),
+                Err(e) => result.error = Some(format!("Failed to upload the image: {}", e)),
+            }
+            results.push(result);
+        }
+
+        Ok(BatchUploadResponse::Ok(Json(results)))
+    }
+
+    /// Import Image
+    ///
+    /// Download an image from a remote URL and upload it to the given bucket,
+    /// rather than the client having to proxy the image itself.
+    ///
+    /// Only hosts allowed by the `remote_uploads` config can be downloaded from
+    /// and redirects are not followed. The download is subject to the same size
+    /// limits as uploads and is abandoned once the `remote_uploads` timeout passes.
+    #[oai(path = "/import", method = "post")]
+    pub async fn import_image(
+        &self,
+        /// The bucket that the image should be uploaded.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+
+        /// A unique key identifying this upload.
+        ///
+        /// Retrying an import with the same key returns the original upload
+        /// info and image id instead of creating a duplicate image.
+        #[oai(name = "idempotency-key")] idempotency_key: Header<Option<String>>,
+
+        /// An identifier of who uploaded the image, stored in the bucket's `index`.
+        #[oai(name = "x-uploader")] uploader: Header<Option<String>>,
+
+        /// The URL of the image to import.
+        payload: Json<ImportRequest>,
+    ) -> Result<ImportResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(ImportResponse::NotFound),
+            Some(b) => b,
+        };
+
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Write).await? {
+            return Ok(ImportResponse::Unauthorized)
+        }
+
+        let request = payload.0;
+        let original_filename = request.url
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.rsplit_once('/'))
+            .map(|(_, name)| percent_decode_str(name).decode_utf8_lossy().into_owned())
+            .filter(|name| !name.is_empty());
+        let (original_filename, uploader) = match parse_upload_metadata(original_filename.as_deref(), uploader.0.as_deref()) {
+            Err(e) => return Ok(ImportResponse::BadRequest(Json(Detail::new(e)))),
+            Ok(metadata) => metadata,
+        };
+
+        let data = match crate::remote::download(&request.url, upload_limit(bucket)).await {
+            Ok(data) => data,
+            Err(rejection) => {
+                let detail = Json(Detail::new(&rejection));
+                return Ok(match rejection {
+                    DownloadRejection::InvalidUrl => ImportResponse::BadRequest(detail),
+                    DownloadRejection::Disabled | DownloadRejection::HostNotAllowed(_) => ImportResponse::Forbidden(detail),
+                    DownloadRejection::TooBig => ImportResponse::TooBig,
+                    DownloadRejection::BadStatus(_) | DownloadRejection::Unreachable => ImportResponse::BadGateway(detail),
+                    DownloadRejection::TimedOut => ImportResponse::GatewayTimeout(detail),
+                })
+            },
+        };
+
+        let format = match resolve_format(bucket, &data, request.format) {
+            Err(UploadRejection::TooBig) => return Ok(ImportResponse::TooBig),
+            Err(UploadRejection::InvalidImageFormat) => {
+                bucket.quarantine_upload(data.into(), request.format, &invalid_format_error(request.format)).await;
+                return Ok(ImportResponse::InvalidImageFormat)
+            },
+            Ok(format) => format,
+        };
+
+        let options = UploadOptions {
+            idempotency_key: idempotency_key.0,
+            tags: request.tags,
+            original_filename,
+            uploader,
+            ..Default::default()
+        };
+
+        let outcome = match bucket.upload(format, data, options).await {
+            Err(e) if e.is::<AnimationLimitExceeded>() => return Ok(ImportResponse::TooBig),
+            outcome => outcome.map_err(processing_error)?,
+        };
+        match outcome {
+            UploadOutcome::Complete(info) => {
+                let etag = format_etag(info.checksum());
+                Ok(ImportResponse::Ok(Json(info), etag))
+            },
+            UploadOutcome::Pending(job) => {
+                let status_url = upload_status_url(bucket.name(), job.job_id());
+                Ok(ImportResponse::Accepted(Json(job), status_url))
+            },
+        }
+    }
+
+    /// Replace Image
+    ///
+    /// Replace the content of an existing image, keeping its id.
+    /// Any variants generated from the previous content are removed.
+    ///
+    /// The `if-match` header can be set to the `etag` returned when the image was
+    /// uploaded so concurrent writers do not clobber each other's replacements.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/:image_id", method = "put")]
+    pub async fn replace_image(
+        &self,
+        /// The bucket the image belongs to.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+
+        /// The id of the image to replace.
+        image_id: Path<Uuid>,
+
+        /// The total size of the image in bytes.
+        ///
+        /// This can be omitted for uploads using chunked transfer encoding.
+        #[oai(name = "content-length")] content_length: Header<Option<usize>>,
+
+        /// The format that the uploaded image is encoded in.
+        ///
+        /// If not provided, lust will guess the encoding.
+        format: Query<Option<ImageKind>>,
+
+        /// Only replace the image if its current `etag` is one of the given values.
+        #[oai(name = "if-match")] if_match: Header<Option<String>>,
+
+        /// A set of `,` seperated tags to index the image under.
+        tags: Query<Option<String>>,
+
+        /// The filename the image was uploaded with, stored in the bucket's `index`.
+        ///
+        /// This should be percent-encoded if it contains non-ASCII characters.
+        #[oai(name = "x-original-filename")] original_filename: Header<Option<String>>,
+
+        /// An identifier of who uploaded the image, stored in the bucket's `index`.
+        #[oai(name = "x-uploader")] uploader: Header<Option<String>>,
+
+        /// The raw binary data of the image.
+        file: Binary<Body>,
+    ) -> Result<ReplaceResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(ReplaceResponse::NotFound),
+            Some(b) => b,
+        };
+
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Write).await? {
+            return Ok(ReplaceResponse::Unauthorized)
+        }
+
+        let original_filename = decode_original_filename(original_filename.0.as_deref());
+        let (original_filename, uploader) = match parse_upload_metadata(original_filename.as_deref(), uploader.0.as_deref()) {
+            Err(e) => return Ok(ReplaceResponse::BadRequest(Json(Detail::new(e)))),
+            Ok(metadata) => metadata,
+        };
+
+        let (format, allocated_image) = match read_upload(bucket, content_length.0, format.0, file).await? {
+            Err(UploadRejection::TooBig) => return Ok(ReplaceResponse::TooBig),
+            Err(UploadRejection::InvalidImageFormat) => return Ok(ReplaceResponse::InvalidImageFormat),
+            Ok(upload) => upload,
+        };
+
+        let options = UploadOptions {
+            idempotency_key: None,
+            tags: parse_tags(tags.0),
+            original_filename,
+            uploader,
+            ..Default::default()
+        };
+
+        let precondition = if_match.0.as_deref().map(Precondition::parse);
+        let outcome = match bucket.replace(*image_id, format, allocated_image, options, precondition).await {
+            Err(e) if e.is::<AnimationLimitExceeded>() => return Ok(ReplaceResponse::TooBig),
+            outcome => outcome.map_err(processing_error)?,
+        };
+        match outcome {
+            ReplaceOutcome::Replaced(info) => {
+                let etag = format_etag(info.checksum());
+                Ok(ReplaceResponse::Ok(Json(info), etag))
+            },
+            ReplaceOutcome::NotFound => Ok(ReplaceResponse::NotFound),
+            ReplaceOutcome::PreconditionFailed => {
+                Ok(ReplaceResponse::PreconditionFailed(Json(precondition_failed(*image_id))))
+            },
+        }
+    }
+
+    /// Upload Status
+    ///
+    /// Get the status of an upload which is being completed in the background.
+    /// Once complete the status contains the final upload info.
+    #[oai(path = "/uploads/:job_id", method = "get")]
+    pub async fn upload_status(
+        &self,
+        /// The bucket the image is being uploaded to.
+        bucket: Path<String>,
+
+        /// The id of the upload job.
+        job_id: Path<Uuid>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+    ) -> Result<UploadStatusResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => {
+                let detail = Detail {
+                    detail: format!("The bucket {:?} does not exist.", &*bucket),
+                };
+                return Ok(UploadStatusResponse::NotFound(Json(detail)))
+            },
+            Some(b) => b,
+        };
+
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Write).await? {
+            return Ok(UploadStatusResponse::Unauthorized)
+        }
+
+        match bucket.upload_job(*job_id) {
+            Some(job) => Ok(UploadStatusResponse::Ok(Json(job))),
+            None => {
+                let detail = Detail {
+                    detail: format!("The upload job {:?} does not exist.", *job_id),
+                };
+                Ok(UploadStatusResponse::NotFound(Json(detail)))
+            },
+        }
+    }
+
+    /// Sign Fetch URLs
+    ///
+    /// Sign the fetch URLs of many images at once for buckets requiring signed URLs,
+    /// e.g. when rendering a gallery, with the same fetch parameters for every image.
+    ///
+    /// Unlike uploads, signing always requires a valid API key or a JWT with the `read` scope.
+    #[oai(path = "/sign", method = "post")]
+    pub async fn sign_urls(
+        &self,
+        /// The bucket to sign the fetch URLs for.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        authorization: Header<Option<String>>,
+
+        /// The images and fetch parameters to sign.
+        payload: Json<SignRequest>,
+    ) -> Result<SignResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(SignResponse::NotFound),
+            Some(b) => b,
+        };
+
+        if !requires_authorization(bucket).await? || !is_authorized(bucket, authorization.0.as_deref(), Scope::Read).await? {
+            return Ok(SignResponse::Unauthorized)
+        }
+
+        let key = match bucket.cfg().url_signing {
+            None => return Ok(SignResponse::BadRequest(Json(Detail::new(
+                "The bucket does not require signed URLs.",
+            )))),
+            Some(ref cfg) => &cfg.key,
+        };
+
+        let request = payload.0;
+        if let Some(ref size) = request.size {
+            if !bucket.cfg().is_original(Some(size)) && !bucket.cfg().presets.contains_key(size) {
+                return Ok(SignResponse::BadRequest(Json(Detail::new(
+                    format!("The preset {:?} does not exist.", size),
+                ))))
+            }
+        }
+
+        let mut params = vec![];
+        if let Some(format) = request.format {
+            params.push(format!("format={}", format.as_file_extension()));
+        }
+        if let Some(ref size) = request.size {
+            params.push(format!("size={}", utf8_percent_encode(size, NON_ALPHANUMERIC)));
+        }
+        if let Some(width) = request.width {
+            params.push(format!("width={}", width));
+        }
+        if let Some(height) = request.height {
+            params.push(format!("height={}", height));
+        }
+        let query = params.join("&");
+
+        let expires_in = request.expires_in.unwrap_or(DEFAULT_SIGNED_URL_EXPIRY);
+        let expires_in = i64::try_from(expires_in).unwrap_or(i64::MAX);
+        let expires = chrono::Utc::now().timestamp().saturating_add(expires_in);
+        let urls = request.image_ids
+            .into_iter()
+            .map(|image_id| {
+                let path = format!("{}/{}", bucket.name(), image_id);
+                let query = signing::signed_query(key, &path, &query, expires);
+                SignedUrl {
+                    image_id,
+                    url: format!("/v1{}/{}?{}", config().base_serving_path.as_deref().unwrap_or(""), path, query),
+                }
+            })
+            .collect();
+
+        Ok(SignResponse::Ok(Json(SignedUrls { expires, urls })))
+    }
+
+    /// Purge Images
+    ///
+    /// Delete every image matching the given filter, this requires the bucket to have an `index`.
+    /// Images are deleted in the background and the progress can be polled via
+    /// the URL in the `location` header.
+    ///
+    /// Set `dry_run` to list the matching images without deleting them.
+    #[oai(path = "/purge", method = "post")]
+    pub async fn purge_images(
+        &self,
+        /// The bucket to purge the images from.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+
+        /// The images to purge.
+        filter: Json<PurgeFilter>,
+    ) -> Result<PurgeResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(PurgeResponse::NotFound(Json(Detail::new(format!("The bucket {:?} does not exist.", &*bucket))))),
+            Some(b) => b,
+        };
+
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Delete).await? {
+            return Ok(PurgeResponse::Unauthorized)
+        }
+
+        if let Some(reason) = crate::purge::validate(&filter) {
+            return Ok(PurgeResponse::BadRequest(Json(Detail::new(reason))))
+        }
+
+        let matched = match crate::purge::matching(bucket, &filter).await? {
+            None => return Ok(PurgeResponse::BadRequest(Json(Detail::new(format!(
+                "The bucket {:?} does not have an index to purge images with.",
+                bucket.name(),
+            ))))),
+            Some(matched) => matched,
+        };
+
+        if filter.dry_run() {
+            return Ok(PurgeResponse::Ok(Json(crate::purge::dry_run(matched))))
+        }
+
+        let job = crate::purge::start(bucket, matched);
+        let status_url = purge_status_url(bucket.name(), job.job_id());
+        Ok(PurgeResponse::Accepted(Json(job), status_url))
+    }
+
+    /// Purge Status
+    ///
+    /// Get the progress of a purge.
+    #[oai(path = "/purge/:job_id", method = "get")]
+    pub async fn purge_status(
+        &self,
+        /// The bucket the images are being purged from.
+        bucket: Path<String>,
+
+        /// The id of the purge job.
+        job_id: Path<Uuid>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+    ) -> Result<PurgeStatusResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(PurgeStatusResponse::NotFound(Json(Detail::new(format!("The bucket {:?} does not exist.", &*bucket))))),
+            Some(b) => b,
+        };
+
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Delete).await? {
+            return Ok(PurgeStatusResponse::Unauthorized)
+        }
+
+        match bucket.purge_job(*job_id) {
+            Some(job) => Ok(PurgeStatusResponse::Ok(Json(job))),
+            None => Ok(PurgeStatusResponse::NotFound(Json(Detail::new(format!(
+                "The purge job {:?} does not exist.",
+                *job_id,
+            ))))),
+        }
+    }
+
+    /// List Images
+    ///
+    /// List the bucket's images in ascending order of their id, a page at a time.
+    /// The `next_cursor` of each page is given as the `cursor` to fetch the next
+    /// page, and is omitted on the last page.
+    #[oai(path = "/", method = "get")]
+    pub async fn list_images(
+        &self,
+        /// The bucket to list the images of.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if the bucket requires API keys or signed URLs.
+        authorization: Header<Option<String>>,
+
+        /// Only list the images after this id.
+        cursor: Query<Option<Uuid>>,
+
+        /// The maximum number of images to list, between 1 and 1000. Defaults to 100.
+        limit: Query<Option<usize>>,
+    ) -> Result<ListResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(ListResponse::NotFound(Json(Detail::new(format!("The bucket {:?} does not exist.", &*bucket))))),
+            Some(b) => b,
+        };
+
+        if !is_authorized_to_read(bucket, authorization.0.as_deref()).await? {
+            return Ok(ListResponse::Unauthorized)
+        }
+
+        let limit = limit.0.unwrap_or(DEFAULT_LIST_LIMIT);
+        if limit == 0 || limit > MAX_LIST_LIMIT {
+            return Ok(ListResponse::BadRequest(Json(Detail::new(format!(
+                "The limit must be between 1 and {}.",
+                MAX_LIST_LIMIT,
+            )))))
+        }
+
+        let listing = bucket.list(cursor.0, limit).await.map_err(processing_error)?;
+        Ok(ListResponse::Ok(Json(listing)))
+    }
+
+    /// Image Metadata
+    ///
+    /// Get the metadata the image was uploaded with, this requires the bucket to have an `index`.
+    #[oai(path = "/:image_id/metadata", method = "get")]
+    pub async fn image_metadata(
+        &self,
+        /// The bucket the image belongs to.
+        bucket: Path<String>,
+
+        /// The id of the image.
+        image_id: Path<Uuid>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if the bucket requires API keys or signed URLs.
+        authorization: Header<Option<String>>,
+    ) -> Result<MetadataResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(MetadataResponse::NotFound(Json(Detail::new(format!("The bucket {:?} does not exist.", &*bucket))))),
+            Some(b) => b,
+        };
+
+        if !is_authorized_to_read(bucket, authorization.0.as_deref()).await? {
+            return Ok(MetadataResponse::Unauthorized)
+        }
+
+        if !bucket.is_indexed() {
+            return Ok(MetadataResponse::BadRequest(Json(Detail::new(format!(
+                "The bucket {:?} does not have an index to store metadata in.",
+                bucket.name(),
+            )))))
+        }
+
+        match bucket.image_record(*image_id).await.map_err(processing_error)? {
+            Some(record) => Ok(MetadataResponse::Ok(Json(ImageMetadata::new(*image_id, record)))),
+            None => Ok(MetadataResponse::NotFound(Json(Detail::new(format!(
+                "The image {:?} is not indexed.",
+                *image_id,
+            ))))),
+        }
+    }
+
+    /// Fetch Image
+    ///
+    /// Fetch the image from the storage backend and apply and additional affects
+    /// if required.
+    ///
+    /// If the bucket requires signed URLs the query must also contain an `expires`
+    /// unix timestamp and the `sig` signing the URL.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/:image_id", method = "get")]
+    pub async fn fetch_image(
+        &self,
+        req: &Request,
+
+        /// The bucket to try fetch the image from.
+        bucket: Path<String>,
+
+        /// The id of the image.
+        image_id: Path<Uuid>,
+
+        /// The encoding format that the image should be returned as.
+        format: Query<Option<ImageKind>>,
+
+        /// The size preset that should be used when returning the image.
+        size: Query<Option<String>>,
+
+        /// A custom width to resize the returned image to.
+        width: Query<Option<u32>>,
+
+        /// A custom height to resize the returned image to.
+        height: Query<Option<u32>>,
+
+        /// The filename browsers should suggest when saving the image, e.g. `holiday.jpg`.
+        ///
+        /// An extension not matching the served format is replaced.
+        filename: Query<Option<String>>,
+
+        /// A set of `,` seperated content-types that could be sent as a response.
+        /// E.g. `image/png,image/webp,image/gif`
+        accept: Header<Option<String>>,
+
+        /// The content encodings the client supports.
+        ///
+        /// Pre-compressed variants are served as is if `zstd` is accepted.
+        #[oai(name = "accept-encoding")]
+        accept_encoding: Header<Option<String>>,
+    ) -> Result<Response<FetchResponse>> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(Response::new(FetchResponse::bucket_not_found(&bucket))),
+            Some(b) => b,
+        };
+
+        let path = format!("{}/{}", bucket.name(), image_id.0);
+        if let Err(rejection) = signing::authorize(bucket, &path, req.uri().query()) {
+            return Ok(Response::new(FetchResponse::unauthorized(rejection)))
+        }
+
+        let resp = fetch_from_bucket(
+            bucket,
+            image_id.0,
+            format.0,
+            size.0,
+            width.0,
+            height.0,
+            accept.0,
+            accept_encoding.0,
+        ).await?;
+
+        Ok(with_response_headers(bucket, image_id.0, resp.with_filename(filename.0.as_deref())))
+    }
+
+    /// Fetch Transformed Image
+    ///
+    /// Fetch the image with the transformation given in the path rather than the query,
+    /// for CDNs which normalise or strip the query string when caching.
+    ///
+    /// The transformation is a `,` separated format and either a preset or a
+    /// `{width}x{height}` custom size, e.g. `300x300,webp` or `thumbnail,png`.
+    /// It must be one of the bucket's allowed `path_transforms` or end with a
+    /// `sig=` token signing it with the bucket's signing key.
+    ///
+    /// If the bucket requires signed URLs the query must also be signed as with
+    /// `Fetch Image`, the transformation is signed as part of the path.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/:image_id/:transform", method = "get")]
+    pub async fn fetch_transformed_image(
+        &self,
+        req: &Request,
+
+        /// The bucket to try fetch the image from.
+        bucket: Path<String>,
+
+        /// The id of the image.
+        image_id: Path<Uuid>,
+
+        /// The transformation to apply to the image.
+        transform: Path<String>,
+
+        /// The filename browsers should suggest when saving the image, e.g. `holiday.jpg`.
+        ///
+        /// An extension not matching the served format is replaced.
+        filename: Query<Option<String>>,
+
+        /// A set of `,` seperated content-types that could be sent as a response.
+        /// E.g. `image/png,image/webp,image/gif`
+        accept: Header<Option<String>>,
+
+        /// The content encodings the client supports.
+        ///
+        /// Pre-compressed variants are served as is if `zstd` is accepted.
+        #[oai(name = "accept-encoding")]
+        accept_encoding: Header<Option<String>>,
+    ) -> Result<Response<FetchResponse>> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(Response::new(FetchResponse::bucket_not_found(&bucket))),
+            Some(b) => b,
+        };
+
+        let path = format!("{}/{}/{}", bucket.name(), image_id.0, &*transform);
+        if let Err(rejection) = signing::authorize(bucket, &path, req.uri().query()) {
+            return Ok(Response::new(FetchResponse::unauthorized(rejection)))
+        }
+
+        let transform = match transforms::authorize(bucket, image_id.0, &transform) {
+            Ok(transform) => transform,
+            Err(TransformRejection::Disabled) => return Ok(Response::new(FetchResponse::bad_request(
+                "Path transformations are not enabled for this bucket.",
+            ))),
+            Err(TransformRejection::Invalid(e)) => return Ok(Response::new(FetchResponse::bad_request(e))),
+            Err(TransformRejection::Forbidden) => return Ok(Response::new(FetchResponse::Forbidden(Json(Detail::new(
+                "The transformation is not allowed, it must be allowed by the bucket or signed.",
+            ))))),
+        };
+
+        let (width, height) = match transform.size {
+            None => (None, None),
+            Some((width, height)) => (Some(width), Some(height)),
+        };
+
+        let resp = fetch_from_bucket(
+            bucket,
+            image_id.0,
+            transform.format,
+            transform.preset,
+            width,
+            height,
+            accept.0,
+            accept_encoding.0,
+        ).await?;
+
+        Ok(with_response_headers(bucket, image_id.0, resp.with_filename(filename.0.as_deref())))
+    }
+
+    /// Delete Image
+    ///
+    /// Delete the given image.
+    /// This will purge all variants of the image including sizing presets and formats,
+    /// returning the variants which were removed.
     ///
     /// Images that do not exist already will be ignored and will not return a 404.
+    /// If the `if-match` header is set the image must exist and match it.
     #[oai(path = "/:image_id", method = "delete")]
     pub async fn delete_image(
         &self,
         /// The bucket to try delete the image from.
         bucket: Path<String>,
 
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+
         /// The image to delete try delete.
         image_id: Path<Uuid>,
+
+        /// Only delete the image if its current `etag` is one of the given values.
+        #[oai(name = "if-match")] if_match: Header<Option<String>>,
     ) -> Result<DeleteResponse> {
         let bucket = match get_bucket_by_name(&*bucket) {
             None => return Ok(DeleteResponse::NotFound),
             Some(b) => b,
         };
 
-        bucket.delete(*image_id).await?;
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Delete).await? {
+            return Ok(DeleteResponse::Unauthorized)
+        }
+
+        let info = match if_match.0.as_deref().map(Precondition::parse) {
+            None => bucket.delete(*image_id).await.map_err(processing_error)?,
+            Some(precondition) => match bucket.delete_if(*image_id, &precondition).await.map_err(processing_error)? {
+                None => return Ok(DeleteResponse::PreconditionFailed(Json(precondition_failed(*image_id)))),
+                Some(info) => info,
+            },
+        };
+
+        Ok(DeleteResponse::Ok(Json(info)))
+    }
+
+    /// Restore Image
+    ///
+    /// Restore a deleted image from the trash of a bucket with `soft_delete` enabled.
+    ///
+    /// Images can be restored until the bucket's retention period has passed.
+    #[oai(path = "/:image_id/restore", method = "post")]
+    pub async fn restore_image(
+        &self,
+        /// The bucket the image was deleted from.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for the bucket.
+        authorization: Header<Option<String>>,
+
+        /// The image to restore.
+        image_id: Path<Uuid>,
+    ) -> Result<RestoreResponse> {
+        let bucket = match get_bucket_by_name(&*bucket) {
+            None => return Ok(RestoreResponse::NotFound),
+            Some(b) => b,
+        };
+
+        if !is_authorized(bucket, authorization.0.as_deref(), Scope::Delete).await? {
+            return Ok(RestoreResponse::Unauthorized)
+        }
+
+        match bucket.restore(*image_id).await.map_err(processing_error)? {
+            true => Ok(RestoreResponse::Restored),
+            false => Ok(RestoreResponse::NotFound),
+        }
+    }
+
+    /// Copy Image
+    ///
+    /// Copy an image into another bucket, re-running the destination bucket's
+    /// pipeline on the image's original.
+    ///
+    /// This requires the `read` scope on the source bucket and the `write`
+    /// scope on the destination bucket.
+    #[oai(path = "/:image_id/copy", method = "post")]
+    pub async fn copy_image(
+        &self,
+        /// The bucket to copy the image from.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for either bucket.
+        authorization: Header<Option<String>>,
+
+        /// The image to copy.
+        image_id: Path<Uuid>,
+
+        /// The bucket to copy the image to.
+        to: Query<String>,
+
+        /// Store the copy under the same id rather than a newly generated one.
+        keep_id: Query<Option<bool>>,
+    ) -> Result<CopyResponse> {
+        transfer_image(&bucket, authorization.0.as_deref(), *image_id, &to, keep_id.0.unwrap_or(false), false).await
+    }
+
+    /// Move Image
+    ///
+    /// Move an image into another bucket, re-running the destination bucket's
+    /// pipeline on the image's original before deleting it from the source bucket.
+    ///
+    /// This requires the `delete` scope on the source bucket and the `write`
+    /// scope on the destination bucket.
+    #[oai(path = "/:image_id/move", method = "post")]
+    pub async fn move_image(
+        &self,
+        /// The bucket to move the image from.
+        bucket: Path<String>,
+
+        /// The bearer token authorizing the request, e.g. `Bearer my-api-key`.
+        ///
+        /// Required if API keys are configured globally or for either bucket.
+        authorization: Header<Option<String>>,
+
+        /// The image to move.
+        image_id: Path<Uuid>,
+
+        /// The bucket to move the image to.
+        to: Query<String>,
+
+        /// Store the image under the same id rather than a newly generated one.
+        keep_id: Query<Option<bool>>,
+    ) -> Result<CopyResponse> {
+        transfer_image(&bucket, authorization.0.as_deref(), *image_id, &to, keep_id.0.unwrap_or(false), true).await
+    }
+}
+
+/// Copies, or moves if `remove_source` is set, the image between buckets.
+async fn transfer_image(
+    source: &str,
+    authorization: Option<&str>,
+    image_id: Uuid,
+    destination: &str,
+    keep_id: bool,
+    remove_source: bool,
+) -> Result<CopyResponse> {
+    let source = match get_bucket_by_name(source) {
+        None => return Ok(CopyResponse::NotFound(Json(Detail::new(format!("The bucket {:?} does not exist.", source))))),
+        Some(b) => b,
+    };
+
+    let destination = match get_bucket_by_name(destination) {
+        None => return Ok(CopyResponse::BadRequest(Json(Detail::new(format!("The bucket {:?} does not exist.", destination))))),
+        Some(b) => b,
+    };
+
+    if remove_source && source.name() == destination.name() {
+        return Ok(CopyResponse::BadRequest(Json(Detail::new("The image cannot be moved into the bucket it is in."))))
+    }
+
+    let source_scope = if remove_source { Scope::Delete } else { Scope::Read };
+    if !is_authorized(source, authorization, source_scope).await?
+        || !is_authorized(destination, authorization, Scope::Write).await? {
+        return Ok(CopyResponse::Unauthorized)
+    }
+
+    let result = if remove_source {
+        source.move_to(image_id, destination, keep_id).await
+    } else {
+        source.copy_to(image_id, destination, keep_id).await
+    };
+
+    match result {
+        Err(e) if e.is::<ImageIdConflict>() => Ok(CopyResponse::Conflict(Json(Detail::new(e)))),
+        Ok(None) => Ok(CopyResponse::NotFound(Json(Detail::new(format!(
+            "The image {:?} or its original does not exist.",
+            image_id,
+        ))))),
+        Ok(Some(info)) => {
+            let etag = format_etag(info.checksum());
+            Ok(CopyResponse::Ok(Json(info), etag))
+        },
+        Err(e) => Err(processing_error(e)),
+    }
+}
+
+
+/// If any API keys exist globally or for the bucket, or JWT authorization is enabled.
+async fn requires_authorization(bucket: &BucketController) -> Result<bool> {
+    Ok(auth::is_enabled() || bucket.has_api_keys().await.map_err(processing_error)?)
+}
+
+/// Checks the `Authorization` bearer token is either a JWT granting the scope
+/// on the bucket, or one of the global or bucket's API keys.
+///
+/// Any request is authorized if no API keys exist and JWT authorization is disabled.
+async fn is_authorized(bucket: &BucketController, authorization: Option<&str>, scope: Scope) -> Result<bool> {
+    if !requires_authorization(bucket).await? {
+        return Ok(true)
+    }
+
+    let token = match authorization.and_then(|v| v.strip_prefix("Bearer ")) {
+        None => return Ok(false),
+        Some(token) => token.trim(),
+    };
+
+    if auth::is_jwt(token) {
+        return Ok(auth::grants(token, bucket.name(), scope))
+    }
+
+    bucket.is_api_key(token).await.map_err(processing_error)
+}
+
+/// Checks the request may read the bucket's images outside of fetching them,
+/// e.g. listing them or their metadata.
+///
+/// Buckets requiring signed URLs are never readable without a token, so like
+/// signing this requires an API key or JWT to be configured.
+async fn is_authorized_to_read(bucket: &BucketController, authorization: Option<&str>) -> Result<bool> {
+    if bucket.cfg().url_signing.is_some() && !requires_authorization(bucket).await? {
+        return Ok(false)
+    }
+
+    is_authorized(bucket, authorization, Scope::Read).await
+}
+
+
+/// Attaches the bucket's static `response_headers` to the fetch response.
+///
+/// Buckets purging their CDN also tag the response with the image's
+/// `surrogate-key` so every variant of the image is purged together.
+pub(crate) fn with_response_headers(bucket: &BucketController, image_id: Uuid, resp: FetchResponse) -> Response<FetchResponse> {
+    let resp = bucket.cfg()
+        .response_headers
+        .iter()
+        .fold(Response::new(resp), |resp, (name, value)| resp.header(name.as_str(), value.as_str()));
+
+    // Caches must not serve a pre-compressed variant to clients which
+    // can't decode it, whichever encoding this response happened to use.
+    let resp = match bucket.cfg().precompression {
+        None => resp,
+        Some(_) => resp.header("vary", "accept-encoding"),
+    };
+
+    match bucket.cfg().cdn {
+        None => resp,
+        Some(_) => resp.header("surrogate-key", crate::cdn::surrogate_key(bucket.name(), image_id)),
+    }
+}
+
+/// The characters percent-encoded in the extended `filename*` parameter.
+const FILENAME_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'.').remove(b'-').remove(b'_');
+
+/// The `content-disposition` suggesting the image is saved under the given
+/// filename, or `None` if nothing of the filename remains once sanitized.
+///
+/// Any directories are stripped and an extension not matching the image's
+/// format is replaced. Clients not supporting the UTF-8 `filename*` fall back
+/// to the filename with any non-ASCII characters replaced.
+fn content_disposition(filename: &str, kind: ImageKind) -> Option<String> {
+    let name: String = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let name = name.trim().trim_start_matches('.');
+
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) => match ImageKind::from_file_extension(ext) {
+            Some(requested) if requested == kind => (stem, ext),
+            Some(_) => (stem, kind.as_file_extension()),
+            None => (name, kind.as_file_extension()),
+        },
+        None => (name, kind.as_file_extension()),
+    };
+    let stem: String = stem.trim_end().chars().take(MAX_FILENAME_LENGTH).collect();
+    if stem.is_empty() {
+        return None
+    }
+
+    let name = format!("{}.{}", stem, ext);
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii() && !matches!(c, '"' | '\\' | '%') { c } else { '_' })
+        .collect();
+
+    Some(format!(
+        "inline; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        utf8_percent_encode(&name, FILENAME_ENCODE_SET),
+    ))
+}
+
+/// Fetches the image from the bucket, responding with the bucket's placeholder
+/// if the image doesn't exist.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn fetch_from_bucket(
+    bucket: &BucketController,
+    image_id: Uuid,
+    format: Option<ImageKind>,
+    size: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    accept: Option<String>,
+    accept_encoding: Option<String>,
+) -> Result<FetchResponse> {
+    if bucket.egress_exceeded() {
+        let redirect = bucket.cfg()
+            .egress_limit
+            .as_ref()
+            .and_then(|v| v.exceeded_redirect.clone());
+
+        return match redirect {
+            Some(url) => Ok(FetchResponse::Redirect(url)),
+            None => Ok(FetchResponse::egress_limit_exceeded(bucket.name())),
+        }
+    }
+
+    let custom_sizing = match (width, height) {
+        (Some(w), Some(h)) => if bucket.cfg().mode != ProcessingMode::Realtime {
+            return Ok(FetchResponse::bad_request(
+                "Custom resizing can only be done when bucket set to 'realtime' processing mode",
+            ))
+        } else if let Err(e) = bucket.cfg().custom_sizing_id(w, h) {
+            return Ok(FetchResponse::bad_request(e))
+        } else {
+            Some((w, h))
+        },
+        (None, None) => None,
+        _ => return Ok(FetchResponse::bad_request(
+            "A custom size must include both the width and the height.",
+        ))
+    };
+
+    let mut kind = get_image_kind(format, accept, size.as_deref(), bucket);
+    let original = &bucket.cfg().original;
+    if custom_sizing.is_none() && bucket.cfg().is_original(size.as_deref()) {
+        if !bucket.cfg().serve_original {
+            let detail = Detail::new("The original image can't be fetched, request a preset instead.");
+            return Ok(FetchResponse::Forbidden(Json(detail)))
+        }
+
+        if let Some(ref formats) = original.formats {
+            if !formats.contains(&kind) {
+                match format {
+                    None => kind = formats[0],
+                    Some(_) => return Ok(FetchResponse::bad_request(
+                        format!("The original image can't be served as {:?}.", kind),
+                    )),
+                }
+            }
+        }
+    }
+
+    let accept_compressed = accept_encoding
+        .as_deref()
+        .map(accepts_zstd)
+        .unwrap_or(false);
+
+    let img = bucket.fetch(image_id, kind, size, custom_sizing, accept_compressed)
+        .await
+        .map_err(processing_error)?;
+    match img {
+        None => match bucket.placeholder() {
+            None => Ok(FetchResponse::image_not_found(image_id)),
+            Some(placeholder) => {
+                let data = Binary(placeholder.data.to_vec());
+                let content_type = placeholder.kind.as_content_type();
+                match placeholder.status {
+                    MissingImageStatus::NotFound => Ok(FetchResponse::MissingPlaceholder(data, content_type)),
+                    MissingImageStatus::Gone => Ok(FetchResponse::GonePlaceholder(data, content_type)),
+                }
+            },
+        },
+        Some(img) => {
+            let encoding = if crate::processor::compression::is_compressed(&img.data) {
+                Some("zstd".to_string())
+            } else {
+                None
+            };
+
+            let warning = if img.stale {
+                Some(STALE_WARNING.to_string())
+            } else {
+                None
+            };
+
+            Ok(FetchResponse::Ok(Binary(img.data.to_vec()), img.kind.as_content_type(), encoding, warning, None))
+        },
+    }
+}
+
+/// Converts processing failures caused by the image or requested operation
+/// into their respective status, other errors remain internal server errors.
+///
+/// - `422` if the image could not be decoded, resized or encoded.
+/// - `415` if the image cannot be converted to the requested format.
+/// - `504` if processing exceeded the bucket's `processing_timeout`.
+/// - `503` with a `Retry-After` header if the storage backend is throttling
+///   or its circuit breaker is open.
+/// - `507` if the storage backend is below its free space watermark.
+pub(crate) fn processing_error(e: anyhow::Error) -> poem::Error {
+    if e.is::<InsufficientStorage>() {
+        return poem::Error::from_string(e.to_string(), StatusCode::INSUFFICIENT_STORAGE)
+    }
+
+    let retry_after = match (e.downcast_ref::<StorageThrottled>(), e.downcast_ref::<StorageUnavailable>()) {
+        (Some(throttled), _) => Some(throttled.retry_after),
+        (_, Some(unavailable)) => Some(Some(unavailable.retry_after)),
+        _ => None,
+    };
+
+    if let Some(retry_after) = retry_after {
+        let retry_after = retry_after
+            .map(|delay| delay.as_secs_f64().ceil() as u64)
+            .unwrap_or(1)
+            .max(1);
+
+        let resp = poem::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("retry-after", retry_after)
+            .body(e.to_string());
+        return poem::Error::from_response(resp)
+    }
+
+    let status = match e.downcast_ref::<ProcessingError>() {
+        None => return e.into(),
+        Some(ProcessingError::UnsupportedConversion { .. }) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        Some(ProcessingError::Timeout { .. }) => StatusCode::GATEWAY_TIMEOUT,
+        Some(ProcessingError::LimitsExceeded { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+        Some(_) => StatusCode::UNPROCESSABLE_ENTITY,
+    };
+
+    poem::Error::from_string(e.to_string(), status)
+}
+
+/// The reason an upload's body was rejected.
+enum UploadRejection {
+    TooBig,
+    InvalidImageFormat,
+}
+
+/// Reads the uploaded image, enforcing the size limits and
+/// validating or guessing the format of the image.
+///
+/// Uploads without a `content-length` have the size limits
+/// enforced as the body is streamed in instead.
+async fn read_upload(
+    bucket: &BucketController,
+    content_length: Option<usize>,
+    format: Option<ImageKind>,
+    file: Binary<Body>,
+) -> Result<std::result::Result<(ImageKind, Vec<u8>), UploadRejection>> {
+    let length = match content_length {
+        Some(length) => {
+            if !config().valid_global_size(length) || length > local_upload_limit(bucket) {
+                return Ok(Err(UploadRejection::TooBig))
+            }
+
+            length
+        },
+        None => upload_limit(bucket),
+    };
+
+    let mut allocated_image = Vec::with_capacity(content_length.unwrap_or_default());
+    let mut stream = file.0.into_bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk: Bytes = chunk.map_err(anyhow::Error::from)?;
+        allocated_image.extend(chunk);
+
+        if allocated_image.len() > length {
+            return Ok(Err(UploadRejection::TooBig))
+        }
+    }
+
+    let resolved = resolve_format(bucket, &allocated_image, format);
+    if let Err(UploadRejection::InvalidImageFormat) = resolved {
+        bucket.quarantine_upload(allocated_image.clone().into(), format, &invalid_format_error(format)).await;
+    }
+
+    Ok(resolved.map(|format| (format, allocated_image)))
+}
+
+/// The bucket's max upload size in bytes.
+fn local_upload_limit(bucket: &BucketController) -> usize {
+    bucket
+        .cfg()
+        .max_upload_size
+        .map(|v| (v * 1024) as usize)
+        .unwrap_or(u32::MAX as usize)
+}
+
+/// The max upload size in bytes taking both the global and bucket limits into account.
+pub(crate) fn upload_limit(bucket: &BucketController) -> usize {
+    config()
+        .max_upload_size
+        .map(|limit| limit * 1024)
+        .unwrap_or(usize::MAX)
+        .min(local_upload_limit(bucket))
+}
+
+/// Validates the image is the hinted format, otherwise guesses
+/// the format from the image's magic bytes.
+fn resolve_format(
+    bucket: &BucketController,
+    data: &[u8],
+    hint: Option<ImageKind>,
+) -> std::result::Result<ImageKind, UploadRejection> {
+    if let Some(format) = hint {
+        let limits = crate::processor::decode_limits(bucket.cfg().decode_limits);
+        return match crate::processor::decode(data, format, &limits) {
+            Ok(_) => Ok(format),
+            Err(e) if matches!(e.downcast_ref(), Some(ProcessingError::LimitsExceeded { .. })) => {
+                Err(UploadRejection::TooBig)
+            },
+            Err(_) => Err(UploadRejection::InvalidImageFormat),
+        }
+    }
+
+    let maybe_guessed = image::guess_format(data)
+        .ok()
+        .and_then(ImageKind::from_guessed_format);
+
+    maybe_guessed.ok_or(UploadRejection::InvalidImageFormat)
+}
+
+fn invalid_format_error(hint: Option<ImageKind>) -> String {
+    match hint {
+        None => "The format of the image could not be guessed.".to_string(),
+        Some(hint) => format!("The image is not a valid {:?} image.", hint),
+    }
+}
+
+/// The format hint of a multipart part, from its `content-type`
+/// or otherwise its filename extension.
+fn part_format_hint(file: &Upload) -> Option<ImageKind> {
+    let from_content_type = file.content_type()
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .and_then(|v| ImageKind::from_content_type(&v));
+
+    from_content_type.or_else(|| {
+        let (_, ext) = file.file_name()?.rsplit_once('.')?;
+        ImageKind::from_file_extension(ext)
+    })
+}
+
+/// If the request body is a `multipart/form-data` form.
+fn is_multipart_form(req: &Request) -> bool {
+    req.content_type()
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().eq_ignore_ascii_case("multipart/form-data"))
+        .unwrap_or(false)
+}
+
+/// Percent-decodes the `x-original-filename` header, which is encoded
+/// so the filename can contain non-ASCII characters.
+fn decode_original_filename(header: Option<&str>) -> Option<String> {
+    header.map(|v| percent_decode_str(v).decode_utf8_lossy().into_owned())
+}
+
+/// Validates the original filename and uploader of an upload,
+/// reducing the filename to its final path segment.
+fn parse_upload_metadata(
+    original_filename: Option<&str>,
+    uploader: Option<&str>,
+) -> Result<(Option<String>, Option<String>), String> {
+    let original_filename = original_filename
+        .and_then(|v| v.rsplit(['/', '\\']).next())
+        .map(str::to_string);
+
+    let check = |name: &str, value: Option<String>| match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(v) if v.chars().count() > MAX_UPLOAD_METADATA_LENGTH || v.chars().any(char::is_control) => Err(format!(
+            "The {} must be at most {} characters and contain no control characters.",
+            name, MAX_UPLOAD_METADATA_LENGTH,
+        )),
+        Some(v) => Ok(Some(v.to_string())),
+    };
+
+    Ok((
+        check("original filename", original_filename)?,
+        check("uploader", uploader.map(str::to_string))?,
+    ))
+}
+
+fn parse_tags(tags: Option<String>) -> Vec<String> {
+    tags
+        .map(|v| {
+            v.split(',')
+                .map(|tag| tag.trim())
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| tag.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parses the `preset:format` pairs of the `pregenerate` parameter.
+fn parse_pregenerate(
+    bucket: &BucketController,
+    pregenerate: Option<&str>,
+) -> std::result::Result<Vec<(String, ImageKind)>, String> {
+    let entries = pregenerate
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty());
+
+    let mut variants = vec![];
+    for entry in entries {
+        let (preset, format) = match entry.split_once(':') {
+            None => (entry, None),
+            Some((preset, format)) => (preset, Some(format)),
+        };
+
+        if preset != "original" && !bucket.cfg().presets.contains_key(preset) {
+            return Err(format!("The preset {:?} does not exist.", preset))
+        }
+
+        let kind = match format {
+            None => bucket.cfg().serving_format(Some(preset)),
+            Some(format) => match ImageKind::from_file_extension(format) {
+                Some(kind) if bucket.cfg().formats.is_enabled(kind) => kind,
+                _ => return Err(format!("The format {:?} is not enabled for this bucket.", format)),
+            },
+        };
 
-        Ok(DeleteResponse::Ok)
+        variants.push((preset.to_string(), kind));
     }
+
+    Ok(variants)
 }
 
+fn precondition_failed(image_id: Uuid) -> Detail {
+    Detail::new(format!("The image {:?} does not match the `if-match` header.", image_id))
+}
+
+fn upload_status_url(bucket: &str, job_id: Uuid) -> String {
+    format!(
+        "/v1{}/{}/uploads/{}",
+        config().base_serving_path.as_deref().unwrap_or(""),
+        bucket,
+        job_id,
+    )
+}
+
+fn purge_status_url(bucket: &str, job_id: Uuid) -> String {
+    format!(
+        "/v1{}/{}/purge/{}",
+        config().base_serving_path.as_deref().unwrap_or(""),
+        bucket,
+        job_id,
+    )
+}
+
+/// Checks if the `accept-encoding` header allows `zstd` encoded responses.
+fn accepts_zstd(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|encoding| {
+        let mut parts = encoding.split(';').map(str::trim);
+        let accepted = parts.next() == Some("zstd");
+        let disabled = parts.any(|p| {
+            p.strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .map(|q| q <= 0.0)
+                .unwrap_or(false)
+        });
+
+        accepted && !disabled
+    })
+}
 
-fn get_image_kind(direct_format: Option<ImageKind>, accept: Option<String>, bucket: &BucketController) -> ImageKind {
+fn get_image_kind(
+    direct_format: Option<ImageKind>,
+    accept: Option<String>,
+    preset: Option<&str>,
+    bucket: &BucketController,
+) -> ImageKind {
     match direct_format {
         Some(kind) => kind,
         None => match accept {
@@ -292,13 +2270,9 @@ fn get_image_kind(direct_format: Option<ImageKind>, accept: Option<String>, buck
                     }
                 }
 
-                bucket.cfg()
-                    .default_serving_format
-                    .unwrap_or_else(|| bucket.cfg().formats.first_enabled_format())
+                bucket.cfg().serving_format(preset)
             },
-            None => bucket.cfg()
-                .default_serving_format
-                .unwrap_or_else(|| bucket.cfg().formats.first_enabled_format())
+            None => bucket.cfg().serving_format(preset),
         },
     }
 }
@@ -0,0 +1,168 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Args;
+use hyper::server::conn::Http;
+use poem::listener::{Acceptor, Listener, TcpListener};
+use poem::{Endpoint, EndpointExt, IntoEndpoint, Response};
+use tokio::sync::{Notify, Semaphore};
+
+/// The minimum buffer size hyper allows for reading requests.
+const MIN_HEADER_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Args)]
+pub struct ConnectionConfig {
+    #[clap(long, env)]
+    /// Disable serving HTTP/2 (prior knowledge) connections.
+    ///
+    /// Both HTTP/1.1 and HTTP/2 are served by default.
+    pub disable_http2: bool,
+
+    #[clap(long, env)]
+    /// Disable HTTP/1.1 keep-alive, closing connections after each response.
+    pub disable_keep_alive: bool,
+
+    #[clap(long, env, default_value = "30")]
+    /// The maximum time in seconds a client can take to send the request headers
+    /// before the HTTP/1.1 connection is closed.
+    pub header_read_timeout: u64,
+
+    #[clap(long, env)]
+    /// The interval in seconds HTTP/2 keep-alive pings are sent at.
+    ///
+    /// No pings are sent if unset.
+    pub http2_keep_alive_interval: Option<u64>,
+
+    #[clap(long, env, default_value = "20")]
+    /// The time in seconds to wait for a HTTP/2 keep-alive ping to be acknowledged
+    /// before the connection is closed.
+    pub http2_keep_alive_timeout: u64,
+
+    #[clap(long, env)]
+    /// The maximum number of concurrent streams per HTTP/2 connection.
+    ///
+    /// No limit is applied if unset.
+    pub max_concurrent_streams: Option<u32>,
+
+    #[clap(long, env)]
+    /// The maximum number of open connections.
+    ///
+    /// New connections wait to be accepted once the limit is reached.
+    /// No limit is applied if unset.
+    pub max_connections: Option<usize>,
+
+    #[clap(long, env)]
+    /// The maximum size in bytes of HTTP/1.1 request headers.
+    ///
+    /// This cannot be lower than 8192 bytes. Defaults to ~400KB if unset.
+    pub max_header_size: Option<usize>,
+}
+
+impl ConnectionConfig {
+    fn build_http(&self) -> Http {
+        let mut http = Http::new();
+        http.http1_only(self.disable_http2)
+            .http1_keep_alive(!self.disable_keep_alive)
+            .http1_header_read_timeout(Duration::from_secs(self.header_read_timeout))
+            .http2_keep_alive_interval(self.http2_keep_alive_interval.map(Duration::from_secs))
+            .http2_keep_alive_timeout(Duration::from_secs(self.http2_keep_alive_timeout))
+            .http2_max_concurrent_streams(self.max_concurrent_streams);
+
+        if let Some(max_header_size) = self.max_header_size {
+            http.max_buf_size(max_header_size.max(MIN_HEADER_SIZE));
+        }
+
+        http
+    }
+}
+
+/// Serves the endpoint with the configured connection options.
+///
+/// Once the signal completes no new connections are accepted, open
+/// connections are given up to the timeout to complete.
+pub async fn serve<E>(
+    listener: TcpListener<String>,
+    ep: E,
+    cfg: &ConnectionConfig,
+    signal: impl Future<Output = ()>,
+    timeout: Duration,
+) -> std::io::Result<()>
+where
+    E: IntoEndpoint,
+    E::Endpoint: 'static,
+{
+    let ep: Arc<dyn Endpoint<Output = Response>> = Arc::new(ep.into_endpoint().map_to_response());
+    let http = Arc::new(cfg.build_http());
+    let connection_limit = cfg.max_connections.map(|v| Arc::new(Semaphore::new(v.max(1))));
+    let alive_connections = Arc::new(AtomicUsize::new(0));
+    let idle = Arc::new(Notify::new());
+    let timeout_notify = Arc::new(Notify::new());
+
+    let mut acceptor = listener.into_acceptor().await?;
+    for addr in acceptor.local_addr() {
+        info!("Listening @ {}", addr);
+    }
+
+    tokio::pin!(signal);
+    loop {
+        let permit = match connection_limit.clone() {
+            None => None,
+            Some(limit) => tokio::select! {
+                _ = &mut signal => break,
+                permit = limit.acquire_owned() => permit.ok(),
+            },
+        };
+
+        let (socket, local_addr, remote_addr, scheme) = tokio::select! {
+            _ = &mut signal => break,
+            res = acceptor.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue
+                },
+            },
+        };
+
+        let ep = ep.clone();
+        let http = http.clone();
+        let alive_connections = alive_connections.clone();
+        let idle = idle.clone();
+        let timeout_notify = timeout_notify.clone();
+
+        alive_connections.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let service = hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                let ep = ep.clone();
+                let req = (req, local_addr.clone(), remote_addr.clone(), scheme.clone()).into();
+                async move {
+                    Ok::<hyper::Response<hyper::Body>, Infallible>(ep.get_response(req).await.into())
+                }
+            });
+
+            let conn = http.serve_connection(socket, service).with_upgrades();
+            tokio::select! {
+                _ = conn => {},
+                _ = timeout_notify.notified() => {},
+            }
+
+            if alive_connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+                idle.notify_one();
+            }
+        });
+    }
+
+    drop(acceptor);
+    if alive_connections.load(Ordering::SeqCst) > 0 {
+        info!("Waiting for open connections to close.");
+        if tokio::time::timeout(timeout, idle.notified()).await.is_err() {
+            timeout_notify.notify_waiters();
+        }
+    }
+
+    Ok(())
+}
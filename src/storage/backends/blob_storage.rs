@@ -1,15 +1,38 @@
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
-use rusoto_core::credential::{AutoRefreshingProvider, ChainProvider};
-use rusoto_core::{HttpClient, HttpConfig, Region};
-use rusoto_s3::{DeleteObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, S3, StreamingBody};
+use futures::FutureExt;
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use rusoto_core::credential::{AutoRefreshingProvider, ChainProvider, ProvideAwsCredentials};
+use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture, HttpDispatchError};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, HttpClient, HttpConfig, Region, RusotoError};
+use rusoto_s3::{
+    DeleteObjectRequest,
+    GetObjectError,
+    GetObjectRequest,
+    HeadObjectError,
+    HeadObjectRequest,
+    ListObjectsV2Request,
+    PutObjectRequest,
+    S3Client,
+    S3,
+    StreamingBody,
+};
 use tokio::io::AsyncReadExt;
+use tokio::sync::Semaphore;
 use uuid::Uuid;
 
 use crate::config::ImageKind;
 use crate::controller::get_bucket_by_id;
+use crate::storage::backends::register::{S3Addressing, S3Timeouts};
+use crate::storage::template::StoredImage;
+use crate::storage::StorageThrottled;
 use crate::StorageBackend;
 
 /// A credential timeout.
@@ -19,6 +42,8 @@ pub struct BlobStorageBackend {
     bucket_name: String,
     client: S3Client,
     store_public: bool,
+    timeouts: S3Timeouts,
+    in_flight: Option<Semaphore>,
 }
 
 impl BlobStorageBackend {
@@ -28,6 +53,11 @@ impl BlobStorageBackend {
         region: String,
         endpoint: String,
         store_public: bool,
+        addressing: S3Addressing,
+        ca_bundle: Option<&Path>,
+        insecure_skip_tls_verify: bool,
+        timeouts: S3Timeouts,
+        max_in_flight: Option<usize>,
     ) -> Result<Self> {
         let mut chain_provider = ChainProvider::new();
         chain_provider.set_timeout(Duration::from_secs(CREDENTIAL_TIMEOUT));
@@ -38,24 +68,56 @@ impl BlobStorageBackend {
         let mut http_config: HttpConfig = HttpConfig::default();
         http_config.pool_idle_timeout(std::time::Duration::from_secs(10));
 
-        let http_client = HttpClient::new_with_config(http_config)
-            .with_context(|| "Failed to create request dispatcher")?;
+        let connector = https_connector(ca_bundle, insecure_skip_tls_verify)?;
+        let http_client = HttpClient::from_connector_with_config(connector, http_config);
 
         let region = Region::Custom { name: region, endpoint };
 
-        let client = S3Client::new_with(
-            http_client,
-            credentials_provider,
-            region,
-        );
+        let client = match addressing {
+            S3Addressing::Path => S3Client::new_with(
+                http_client,
+                credentials_provider,
+                region,
+            ),
+            S3Addressing::VirtualHosted => {
+                let dispatcher = VirtualHostedDispatcher {
+                    inner: Arc::new(http_client),
+                    credentials: Arc::new(credentials_provider),
+                };
+
+                S3Client::new_with_client(Client::new_not_signing(dispatcher), region)
+            },
+        };
 
         Ok(Self {
             bucket_name: name,
             client,
             store_public,
+            timeouts,
+            in_flight: max_in_flight.map(Semaphore::new),
         })
     }
 
+    /// Runs the request once one of the in flight slots is free, failing it
+    /// as throttled if the request and the wait for a slot exceed the timeout.
+    async fn limited<T>(&self, timeout: Option<u64>, request: impl Future<Output = Result<T>>) -> Result<T> {
+        let run = async {
+            let _permit = match self.in_flight {
+                Some(ref in_flight) => Some(in_flight.acquire().await?),
+                None => None,
+            };
+
+            request.await
+        };
+
+        match timeout {
+            None => run.await,
+            Some(timeout) => tokio::time::timeout(Duration::from_millis(timeout), run)
+                .await
+                .unwrap_or_else(|_| Err(StorageThrottled { retry_after: None }.into())),
+        }
+    }
+
     #[inline]
     fn format_path(
         &self,
@@ -66,6 +128,179 @@ impl BlobStorageBackend {
     ) -> String {
         format!("{}/{}/{}.{}", bucket_id, sizing_id, image_id, format.as_file_extension())
     }
+
+    #[inline]
+    fn metadata_path(&self, bucket_id: u32, key: &str) -> String {
+        format!("{}/metadata/{}", bucket_id, key)
+    }
+
+    /// The prefix of the empty marker objects listing each variant of the image.
+    #[inline]
+    fn variants_prefix(&self, bucket_id: u32, image_id: Uuid) -> String {
+        format!("{}/variants/{}/", bucket_id, image_id)
+    }
+
+    #[inline]
+    fn variant_marker_path(
+        &self,
+        bucket_id: u32,
+        sizing_id: u32,
+        image_id: Uuid,
+        format: ImageKind,
+    ) -> String {
+        format!("{}{}.{}", self.variants_prefix(bucket_id, image_id), sizing_id, format.as_file_extension())
+    }
+
+    async fn delete_object(&self, key: String) -> Result<()> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key,
+            ..Default::default()
+        };
+        self.limited(self.timeouts.delete, async {
+            self.client.delete_object(request).await.map_err(request_error)
+        }).await?;
+
+        Ok(())
+    }
+
+    /// Checks each variant of the bucket's presets individually, for
+    /// images stored before their variants were listed by markers.
+    async fn probe_variants(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+    ) -> Result<Vec<(u32, ImageKind)>> {
+        let bucket = get_bucket_by_id(bucket_id)
+            .ok_or_else(|| anyhow!("Bucket does not exist."))?
+            .cfg();
+
+        let sizing_ids = bucket.presets
+            .keys()
+            .map(crate::utils::crc_hash)
+            .chain(std::iter::once(0));
+
+        let mut found = vec![];
+        for sizing_id in sizing_ids {
+            for kind in ImageKind::variants() {
+                let request = HeadObjectRequest {
+                    bucket: self.bucket_name.clone(),
+                    key: self.format_path(bucket_id, sizing_id, image_id, *kind),
+                    ..Default::default()
+                };
+
+                let exists = self.limited(self.timeouts.read, async {
+                    match self.client.head_object(request).await {
+                        Ok(_) => Ok(true),
+                        Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+                        Err(RusotoError::Unknown(ref res)) if res.status.as_u16() == 404 => Ok(false),
+                        Err(other) => Err(request_error(other)),
+                    }
+                }).await?;
+
+                if exists {
+                    found.push((sizing_id, *kind));
+                }
+            }
+        }
+
+        Ok(found)
+    }
+}
+
+/// Builds the connector requests are made with, trusting the CA bundle's
+/// certificates in addition to the system's.
+fn https_connector(
+    ca_bundle: Option<&Path>,
+    insecure_skip_tls_verify: bool,
+) -> Result<HttpsConnector<HttpConnector>> {
+    let mut tls = native_tls::TlsConnector::builder();
+    tls.danger_accept_invalid_certs(insecure_skip_tls_verify);
+
+    if let Some(path) = ca_bundle {
+        let bundle = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the CA bundle {}", path.display()))?;
+
+        for cert in split_pem_certificates(&bundle) {
+            let cert = native_tls::Certificate::from_pem(cert.as_bytes())
+                .with_context(|| format!("The CA bundle {} contains an invalid certificate", path.display()))?;
+            tls.add_root_certificate(cert);
+        }
+    }
+
+    let tls = tls.build().with_context(|| "Failed to create the TLS connector")?;
+
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+
+    Ok(HttpsConnector::from((http, tokio_native_tls::TlsConnector::from(tls))))
+}
+
+/// Splits a PEM bundle into its individual certificates.
+fn split_pem_certificates(bundle: &str) -> Vec<&str> {
+    const END_MARKER: &str = "-----END CERTIFICATE-----";
+
+    bundle
+        .split_inclusive(END_MARKER)
+        .filter(|cert| cert.contains(END_MARKER))
+        .map(str::trim)
+        .collect()
+}
+
+/// Dispatches requests using virtual-hosted addressing.
+///
+/// Rusoto always puts the bucket in the path, so requests are moved to
+/// the bucket's subdomain and signed once rewritten.
+struct VirtualHostedDispatcher {
+    inner: Arc<HttpClient<HttpsConnector<HttpConnector>>>,
+    credentials: Arc<AutoRefreshingProvider<ChainProvider>>,
+}
+
+impl DispatchSignedRequest for VirtualHostedDispatcher {
+    fn dispatch(&self, mut request: SignedRequest, timeout: Option<Duration>) -> DispatchSignedRequestFuture {
+        let inner = self.inner.clone();
+        let credentials = self.credentials.clone();
+
+        async move {
+            let path = request.path.trim_start_matches('/').to_string();
+            let (bucket, key) = path.split_once('/').unwrap_or((&path, ""));
+            let hostname = format!("{}.{}", bucket, request.hostname());
+            request.path = format!("/{}", key);
+            request.set_hostname(Some(hostname));
+
+            let credentials = credentials
+                .credentials()
+                .await
+                .map_err(|e| HttpDispatchError::new(e.to_string()))?;
+            request.sign(&credentials);
+
+            inner.dispatch(request, timeout).await
+        }.boxed()
+    }
+}
+
+/// Converts a failed request into an error, marking throttled requests
+/// as `StorageThrottled` so they're retried and surfaced as a `503`.
+///
+/// S3 signals throttling with a `503 SlowDown` response which isn't modelled
+/// by any of the operation specific errors.
+fn request_error<E>(e: RusotoError<E>) -> anyhow::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    if let RusotoError::Unknown(ref res) = e {
+        let status = res.status.as_u16();
+        if status == 503 || status == 429 || res.body_as_str().contains("SlowDown") {
+            let retry_after = res.headers
+                .get("retry-after")
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs);
+
+            return StorageThrottled { retry_after }.into()
+        }
+    }
+
+    e.into()
 }
 
 #[async_trait]
@@ -80,6 +315,20 @@ impl StorageBackend for BlobStorageBackend {
     ) -> anyhow::Result<()> {
         let store_in = self.format_path(bucket_id, sizing_id, image_id, kind);
 
+        // Objects are keyed by the sizing id first so can't be listed by image,
+        // an empty marker is stored first so the variant is always listed
+        // by `list_variants` and removed with the image.
+        let marker = PutObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: self.variant_marker_path(bucket_id, sizing_id, image_id, kind),
+            body: Some(StreamingBody::from(vec![])),
+            content_length: Some(0),
+            ..Default::default()
+        };
+        self.limited(self.timeouts.write, async {
+            self.client.put_object(marker).await.map_err(request_error)
+        }).await?;
+
         debug!("Storing image in bucket @ {}", &store_in);
 
         let request = PutObjectRequest {
@@ -91,7 +340,10 @@ impl StorageBackend for BlobStorageBackend {
             ..Default::default()
         };
 
-        self.client.put_object(request).await?;
+        self.limited(self.timeouts.write, async {
+            self.client.put_object(request).await.map_err(request_error)
+        }).await?;
+
         Ok(())
     }
 
@@ -110,50 +362,254 @@ impl StorageBackend for BlobStorageBackend {
             bucket: self.bucket_name.clone(),
             ..Default::default()
         };
-        let res = self.client.get_object(request).await?;
-        let content_length = res.content_length.unwrap_or(0) as usize;
+        self.limited(self.timeouts.read, async {
+            // Missing variants aren't a failure, JIT buckets regularly fetch
+            // variants which haven't been generated yet.
+            let res = match self.client.get_object(request).await {
+                Ok(res) => res,
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+                Err(RusotoError::Unknown(ref res)) if res.status.as_u16() == 404 => return Ok(None),
+                Err(other) => return Err(request_error(other)),
+            };
+            let content_length = res.content_length.unwrap_or(0) as usize;
 
-        if let Some(body) = res.body {
-            let mut buffer = Vec::with_capacity(content_length);
-            body
-                .into_async_read()
-                .read_to_end(&mut buffer)
-                .await?;
+            if let Some(body) = res.body {
+                let mut buffer = Vec::with_capacity(content_length);
+                body
+                    .into_async_read()
+                    .read_to_end(&mut buffer)
+                    .await?;
 
-            Ok(Some(buffer.into()))
-        } else {
-            Ok(None)
-        }
+                Ok(Some(buffer.into()))
+            } else {
+                Ok(None)
+            }
+        }).await
     }
 
-    async fn delete(
+    async fn list_variants(
         &self,
         bucket_id: u32,
         image_id: Uuid,
     ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
-        let bucket = get_bucket_by_id(bucket_id)
-            .ok_or_else(|| anyhow!("Bucket does not exist."))?
-            .cfg();
+        let prefix = self.variants_prefix(bucket_id, image_id);
+        let request = ListObjectsV2Request {
+            bucket: self.bucket_name.clone(),
+            prefix: Some(prefix.clone()),
+            ..Default::default()
+        };
 
-        let mut hit_entries = vec![];
-        for sizing_id in bucket.sizing_preset_ids().iter().copied() {
-            for kind in ImageKind::variants() {
-                let store_in = self.format_path(bucket_id, sizing_id, image_id, *kind);
+        let res = self.limited(self.timeouts.list, async {
+            self.client.list_objects_v2(request).await.map_err(request_error)
+        }).await?;
 
-                debug!("Purging file in bucket @ {}", &store_in);
-                let request = DeleteObjectRequest {
-                    bucket: self.bucket_name.clone(),
-                    key: store_in,
-                    ..Default::default()
+        let found: Vec<(u32, ImageKind)> = res.contents
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|object| object.key)
+            .filter_map(|key| {
+                let (sizing_id, ext) = key.strip_prefix(&prefix)?.split_once('.')?;
+                Some((sizing_id.parse().ok()?, ImageKind::from_file_extension(ext)?))
+            })
+            .collect();
+
+        if found.is_empty() {
+            return self.probe_variants(bucket_id, image_id).await
+        }
+
+        Ok(found)
+    }
+
+    async fn list_ids(
+        &self,
+        bucket_id: u32,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredImage>> {
+        let prefix = format!("{}/0/", bucket_id);
+
+        // Keys are listed in lexicographic order, which matches the order of
+        // the ids, and `/` sorts after the `.` of every format of the cursor.
+        let start_after = after.map(|after| format!("{}{}/", prefix, after));
+
+        let mut originals = vec![];
+        let mut seen = 0;
+        let mut last_seen = None;
+        let mut continuation_token = None;
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: self.bucket_name.clone(),
+                prefix: Some(prefix.clone()),
+                start_after: start_after.clone(),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            };
+
+            let res = self.limited(self.timeouts.list, async {
+                self.client.list_objects_v2(request).await.map_err(request_error)
+            }).await?;
+            for object in res.contents.unwrap_or_default() {
+                let image_id = object.key
+                    .as_deref()
+                    .and_then(|key| key.strip_prefix(&prefix))
+                    .and_then(|name| name.split('.').next())
+                    .and_then(|id| id.parse::<Uuid>().ok());
+
+                let image_id = match image_id {
+                    Some(image_id) => image_id,
+                    None => continue,
                 };
-                self.client.delete_object(request).await?;
-                hit_entries.push((sizing_id, *kind));
+
+                if last_seen != Some(image_id) {
+                    last_seen = Some(image_id);
+                    seen += 1;
+                }
+
+                let stored_at = object.last_modified
+                    .as_deref()
+                    .and_then(|v| chrono::DateTime::parse_from_rfc3339(v).ok())
+                    .map(|v| v.timestamp())
+                    .unwrap_or_default();
+
+                originals.push((image_id, object.size.unwrap_or_default() as u64, stored_at));
             }
+
+            // The variants of the last image may continue onto the next page.
+            if seen > limit || res.is_truncated != Some(true) {
+                break
+            }
+
+            continuation_token = match res.next_continuation_token {
+                Some(token) => Some(token),
+                None => break,
+            };
         }
 
-        Ok(hit_entries)
+        Ok(StoredImage::collect_page(originals, after, limit))
     }
-}
 
+    async fn delete_variant(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<()> {
+        let store_in = self.format_path(bucket_id, sizing_id, image_id, kind);
+
+        debug!("Purging file in bucket @ {}", &store_in);
+        self.delete_object(store_in).await?;
+
+        // Only removed once the variant is gone so a failed delete is retried.
+        self.delete_object(self.variant_marker_path(bucket_id, sizing_id, image_id, kind)).await
+    }
 
+    async fn store_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        let store_in = self.metadata_path(bucket_id, key);
 
+        debug!("Storing metadata in bucket @ {}", &store_in);
+        let request = PutObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: store_in,
+            body: Some(StreamingBody::from(data.to_vec())),
+            content_length: Some(data.len() as i64),
+            ..Default::default()
+        };
+
+        self.limited(self.timeouts.write, async {
+            self.client.put_object(request).await.map_err(request_error)
+        }).await?;
+
+        Ok(())
+    }
+
+    async fn fetch_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<Option<Bytes>> {
+        let store_in = self.metadata_path(bucket_id, key);
+
+        debug!("Retrieving metadata in bucket @ {}", &store_in);
+        let request = GetObjectRequest {
+            key: store_in,
+            bucket: self.bucket_name.clone(),
+            ..Default::default()
+        };
+        self.limited(self.timeouts.read, async {
+            let res = match self.client.get_object(request).await {
+                Ok(res) => res,
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+                Err(other) => return Err(request_error(other)),
+            };
+
+            let mut buffer = vec![];
+            if let Some(body) = res.body {
+                body
+                    .into_async_read()
+                    .read_to_end(&mut buffer)
+                    .await?;
+            }
+
+            Ok(Some(buffer.into()))
+        }).await
+    }
+
+    async fn delete_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        let store_in = self.metadata_path(bucket_id, key);
+
+        debug!("Purging metadata in bucket @ {}", &store_in);
+        let request = DeleteObjectRequest {
+            bucket: self.bucket_name.clone(),
+            key: store_in,
+            ..Default::default()
+        };
+        self.limited(self.timeouts.delete, async {
+            self.client.delete_object(request).await.map_err(request_error)
+        }).await?;
+
+        Ok(())
+    }
+
+    async fn list_metadata(
+        &self,
+        bucket_id: u32,
+        dir: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        let prefix = format!("{}/", self.metadata_path(bucket_id, dir));
+
+        debug!("Listing metadata in bucket @ {}", &prefix);
+        let request = ListObjectsV2Request {
+            bucket: self.bucket_name.clone(),
+            prefix: Some(prefix.clone()),
+            delimiter: Some("/".to_string()),
+            start_after: after.map(|after| format!("{}{}", prefix, after)),
+            max_keys: Some(limit.min(1000) as i64),
+            ..Default::default()
+        };
+
+        let res = self.limited(self.timeouts.list, async {
+            self.client.list_objects_v2(request).await.map_err(request_error)
+        }).await?;
+
+        let names = res.contents
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|object| object.key)
+            .filter_map(|key| key.strip_prefix(&prefix).map(ToString::to_string))
+            .collect();
+
+        Ok(names)
+    }
+}
@@ -1,22 +1,68 @@
 use std::io::ErrorKind;
-use std::path::PathBuf;
-use anyhow::anyhow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use async_trait::async_trait;
 use bytes::Bytes;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 use crate::config::ImageKind;
-use crate::controller::get_bucket_by_id;
+use crate::metrics::{STORAGE_FREE_SPACE, STORAGE_LOW_SPACE, STORAGE_LOW_SPACE_REJECTIONS};
+use crate::storage::InsufficientStorage;
+use crate::storage::backends::FreeSpaceWatermark;
+use crate::storage::template::StoredImage;
 use crate::StorageBackend;
 
 pub struct FileSystemBackend {
     directory: PathBuf,
+    watermark: Option<FreeSpaceWatermark>,
+    content_addressable: bool,
+
+    /// The free space in bytes as of the last check, `u64::MAX` if unknown.
+    available: AtomicU64,
 }
 
 impl FileSystemBackend {
-    pub fn new(dir: PathBuf) -> Self {
-        Self {
+    pub fn new(dir: PathBuf, watermark: Option<FreeSpaceWatermark>, content_addressable: bool) -> Self {
+        let backend = Self {
             directory: dir,
+            watermark,
+            content_addressable,
+            available: AtomicU64::new(u64::MAX),
+        };
+        backend.refresh_free_space();
+        backend
+    }
+
+    /// Re-checks the free space of the directory's filesystem if a
+    /// watermark is set, warning when it drops below the watermark.
+    fn refresh_free_space(&self) {
+        let watermark = match self.watermark {
+            None => return,
+            Some(watermark) => watermark.min_free_space * 1024 * 1024,
+        };
+
+        let available = match free_space(&self.directory) {
+            Ok(available) => available,
+            Err(e) => {
+                warn!("Failed to check the free space of {:?}: {}", &self.directory, e);
+                return
+            },
+        };
+
+        let previous = self.available.swap(available, Ordering::Relaxed);
+        STORAGE_FREE_SPACE.set(available.min(i64::MAX as u64) as i64);
+        STORAGE_LOW_SPACE.set((available < watermark) as i64);
+
+        if available < watermark && previous >= watermark {
+            warn!(
+                "The free space of {:?} is below the watermark with {}MB free, new uploads are refused.",
+                &self.directory, available / 1024 / 1024,
+            );
+        } else if available >= watermark && previous < watermark {
+            info!("The free space of {:?} has recovered, new uploads are accepted.", &self.directory);
         }
     }
 
@@ -26,10 +72,109 @@ impl FileSystemBackend {
             .join(bucket_id.to_string())
             .join(sizing_id.to_string())
     }
+
+    #[inline]
+    fn metadata_path(&self, bucket_id: u32, key: &str) -> PathBuf {
+        self.directory
+            .join(bucket_id.to_string())
+            .join("metadata")
+            .join(key)
+    }
+
+    #[inline]
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.directory
+            .join("blobs")
+            .join(&hash[..2])
+            .join(hash)
+    }
+
+    /// Stores the data once under its checksum and hard-links the path
+    /// to it, releasing the blob the path previously linked to.
+    async fn store_linked(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        let hash = content_hash(data);
+        let blob = self.blob_path(&hash);
+        let previous = self.linked_blob(path).await?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // A concurrent delete can release the blob between checking it
+        // exists and linking to it, in which case it's written again.
+        let tmp = tmp_path(path);
+        let mut attempts = 0;
+        loop {
+            if tokio::fs::metadata(&blob).await.is_err() {
+                write_atomic(&blob, data).await?;
+            }
+
+            match tokio::fs::hard_link(&blob, &tmp).await {
+                Ok(()) => break,
+                Err(ref e) if e.kind() == ErrorKind::NotFound && attempts == 0 => attempts += 1,
+                Err(other) => return Err(other.into()),
+            }
+        }
+
+        if let Err(e) = tokio::fs::rename(&tmp, path).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e.into())
+        }
+
+        match previous {
+            Some(previous) if previous != hash => self.release_blob(&previous).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// The checksum of the blob the path links to, if the path exists.
+    async fn linked_blob(&self, path: &Path) -> anyhow::Result<Option<String>> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(Some(content_hash(&data))),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(other) => Err(other.into()),
+        }
+    }
+
+    /// Removes the blob once no paths link to it.
+    async fn release_blob(&self, hash: &str) -> anyhow::Result<()> {
+        let blob = self.blob_path(hash);
+
+        let metadata = match tokio::fs::metadata(&blob).await {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(other) => return Err(other.into()),
+        };
+        if link_count(&metadata) > 1 {
+            return Ok(())
+        }
+
+        debug!("Purging unlinked blob @ {:?}", &blob);
+        match tokio::fs::remove_file(&blob).await {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(other) => Err(other.into()),
+        }
+    }
 }
 
 #[async_trait]
 impl StorageBackend for FileSystemBackend {
+    async fn check_capacity(&self) -> anyhow::Result<()> {
+        let watermark = match self.watermark {
+            None => return Ok(()),
+            Some(watermark) => watermark.min_free_space * 1024 * 1024,
+        };
+
+        let available = self.available.load(Ordering::Relaxed);
+        if available < watermark {
+            STORAGE_LOW_SPACE_REJECTIONS.inc();
+            return Err(InsufficientStorage { available }.into())
+        }
+
+        Ok(())
+    }
+
     async fn store(
         &self,
         bucket_id: u32,
@@ -42,15 +187,11 @@ impl StorageBackend for FileSystemBackend {
         let path = store_in.join(format!("{}.{}", image_id, kind.as_file_extension()));
 
         debug!("Storing image @ {:?}", &path);
-        match tokio::fs::write(&path, &data).await {
-            Ok(()) => Ok(()),
-            Err(ref e) if e.kind() == ErrorKind::NotFound => {
-                tokio::fs::create_dir_all(store_in).await?;
-                tokio::fs::write(&path, data).await?;
-                Ok(())
-            },
-            Err(other) => Err(other.into())
+        if self.content_addressable {
+            return self.store_linked(&path, &data).await
         }
+
+        write_atomic(&path, &data).await
     }
 
     async fn fetch(
@@ -71,35 +212,278 @@ impl StorageBackend for FileSystemBackend {
         }
     }
 
-    async fn delete(
+    async fn list_variants(
         &self,
         bucket_id: u32,
         image_id: Uuid,
     ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
-        let bucket = get_bucket_by_id(bucket_id)
-            .ok_or_else(|| anyhow!("Bucket does not exist."))?
-            .cfg();
+        let bucket_dir = self.directory.join(bucket_id.to_string());
+
+        let mut entries = match tokio::fs::read_dir(&bucket_dir).await {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(other) => return Err(other.into()),
+        };
+
+        // Every sizing directory is checked rather than just the configured
+        // presets so variants of since removed presets are also found.
+        let mut found = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let sizing_id = match entry.file_name().to_str().and_then(|v| v.parse::<u32>().ok()) {
+                Some(sizing_id) => sizing_id,
+                None => continue,
+            };
 
-        let mut hit_entries = vec![];
-        for sizing_id in bucket.sizing_preset_ids().iter().copied() {
             for kind in ImageKind::variants() {
-                let store_in = self.format_path(bucket_id, sizing_id);
-                let path = store_in.join(format!("{}.{}", image_id, kind.as_file_extension()));
-                debug!("Purging image  @ {:?}", &path);
-
-                 match tokio::fs::remove_file(&path).await {
-                    Ok(()) => {
-                        hit_entries.push((sizing_id, *kind));
-                    },
-                    Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
-                    Err(other) => return Err(other.into()),
+                let path = entry.path().join(format!("{}.{}", image_id, kind.as_file_extension()));
+                if tokio::fs::metadata(&path).await.is_ok() {
+                    found.push((sizing_id, *kind));
                 }
             }
         }
 
-        Ok(hit_entries)
+        Ok(found)
+    }
+
+    async fn list_ids(
+        &self,
+        bucket_id: u32,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredImage>> {
+        let mut entries = match tokio::fs::read_dir(self.format_path(bucket_id, 0)).await {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(other) => return Err(other.into()),
+        };
+
+        // Files aren't stored in any order, so every original is read to build a page.
+        let mut originals = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let image_id = entry.path()
+                .file_stem()
+                .and_then(|v| v.to_str())
+                .and_then(|v| v.parse::<Uuid>().ok());
+
+            let image_id = match image_id {
+                Some(image_id) => image_id,
+                None => continue,
+            };
+
+            let metadata = entry.metadata().await?;
+            let stored_at = metadata.modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs() as i64;
+
+            originals.push((image_id, metadata.len(), stored_at));
+        }
+
+        Ok(StoredImage::collect_page(originals, after, limit))
+    }
+
+    async fn delete_variant(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<()> {
+        let store_in = self.format_path(bucket_id, sizing_id);
+        let path = store_in.join(format!("{}.{}", image_id, kind.as_file_extension()));
+
+        let linked = match self.content_addressable {
+            true => self.linked_blob(&path).await?,
+            false => None,
+        };
+
+        debug!("Purging image  @ {:?}", &path);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(other) => return Err(other.into()),
+        }
+
+        match linked {
+            Some(hash) => self.release_blob(&hash).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn store_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        let path = self.metadata_path(bucket_id, key);
+
+        debug!("Storing metadata @ {:?}", &path);
+        write_atomic(&path, &data).await
+    }
+
+    async fn fetch_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<Option<Bytes>> {
+        let path = self.metadata_path(bucket_id, key);
+
+        debug!("Retrieving metadata @ {:?}", &path);
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(other) => Err(other.into()),
+        }
+    }
+
+    async fn delete_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        let path = self.metadata_path(bucket_id, key);
+
+        debug!("Purging metadata @ {:?}", &path);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(other) => Err(other.into()),
+        }
+    }
+
+    async fn list_metadata(
+        &self,
+        bucket_id: u32,
+        dir: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        let path = self.metadata_path(bucket_id, dir);
+
+        debug!("Listing metadata @ {:?}", &path);
+        let mut entries = match tokio::fs::read_dir(&path).await {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(other) => return Err(other.into()),
+        };
+
+        let mut names = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_file() {
+                continue
+            }
+
+            // Documents still being written are never listed.
+            let name = match entry.file_name().into_string() {
+                Ok(name) if !name.ends_with(".tmp") => name,
+                _ => continue,
+            };
+
+            if after.map(|after| name.as_str() > after).unwrap_or(true) {
+                names.push(name);
+            }
+        }
+
+        names.sort_unstable();
+        names.truncate(limit);
+
+        Ok(names)
     }
 }
 
+/// Re-checks the backend's free space every `check_interval`
+/// for as long as the backend is in use.
+pub fn monitor_free_space(backend: &Arc<FileSystemBackend>) {
+    let interval = match backend.watermark {
+        None => return,
+        Some(watermark) => Duration::from_secs(watermark.check_interval),
+    };
+
+    let backend = Arc::downgrade(backend);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let backend = match backend.upgrade() {
+                Some(backend) => backend,
+                None => break,
+            };
+            let _ = tokio::task::spawn_blocking(move || backend.refresh_free_space()).await;
+        }
+    });
+}
 
+/// Writes the file via a temporary file so a crash or a full disk
+/// mid-write never leaves a truncated file behind.
+async fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let tmp = tmp_path(path);
+
+    let written = match tokio::fs::write(&tmp, data).await {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            if let Some(parent) = tmp.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&tmp, data).await
+        },
+        other => other,
+    };
+
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e.into())
+    }
 
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// A unique temporary path alongside the given path, so concurrent
+/// writes of the same path never write to the same temporary file.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", Uuid::new_v4().to_simple()));
+    PathBuf::from(tmp)
+}
+
+fn content_hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+/// Link counts are unavailable so blobs are never released.
+#[cfg(not(unix))]
+fn link_count(_metadata: &std::fs::Metadata) -> u64 {
+    u64::MAX
+}
+
+/// The space in bytes available to unprivileged users on the filesystem
+/// containing the path, or its nearest existing ancestor.
+#[cfg(unix)]
+fn free_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path.ancestors()
+        .find(|p| p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(ErrorKind::Unsupported, "Free space checks are only supported on unix."))
+}
@@ -0,0 +1,253 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::Bytes;
+use hashbrown::HashMap;
+use uuid::Uuid;
+
+use crate::config::ImageKind;
+use crate::storage::template::StoredImage;
+use crate::StorageBackend;
+
+type ImageKey = (u32, Uuid, ImageKind, u32);
+
+/// If every memory backend fails its operations.
+static UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// Makes every operation of the memory backends fail until
+/// set back to `false`, simulating a storage backend outage.
+#[cfg(any(test, feature = "testing"))]
+pub fn set_unavailable(unavailable: bool) {
+    UNAVAILABLE.store(unavailable, Ordering::Relaxed);
+}
+
+/// If every memory backend fails to store images.
+static STORES_FAILING: AtomicBool = AtomicBool::new(false);
+
+/// Makes every image store of the memory backends fail until set
+/// back to `false`, simulating a failure part way through an upload.
+#[cfg(any(test, feature = "testing"))]
+pub fn set_stores_failing(failing: bool) {
+    STORES_FAILING.store(failing, Ordering::Relaxed);
+}
+
+fn check_available() -> anyhow::Result<()> {
+    if UNAVAILABLE.load(Ordering::Relaxed) {
+        return Err(anyhow!("The memory backend is unavailable."))
+    }
+
+    Ok(())
+}
+
+/// A backend holding everything in memory, nothing outlives the process.
+///
+/// This is intended for tests and local experimentation rather than
+/// any real deployment.
+#[derive(Default)]
+pub struct MemoryBackend {
+    /// The stored images and the unix timestamp they were stored at.
+    images: RwLock<HashMap<ImageKey, (Bytes, i64)>>,
+    metadata: RwLock<HashMap<(u32, String), Bytes>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn store(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        check_available()?;
+        if STORES_FAILING.load(Ordering::Relaxed) {
+            return Err(anyhow!("The memory backend failed to store the image."))
+        }
+
+        debug!("Storing image {} in memory", image_id);
+        self.images
+            .write()
+            .unwrap()
+            .insert((bucket_id, image_id, kind, sizing_id), (data, chrono::Utc::now().timestamp()));
+
+        Ok(())
+    }
+
+    async fn fetch(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<Option<Bytes>> {
+        check_available()?;
+
+        debug!("Retrieving image {} from memory", image_id);
+        let data = self.images
+            .read()
+            .unwrap()
+            .get(&(bucket_id, image_id, kind, sizing_id))
+            .map(|(data, _)| data.clone());
+
+        Ok(data)
+    }
+
+    async fn delete(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+    ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
+        check_available()?;
+
+        debug!("Purging image {} from memory", image_id);
+        let mut hit_entries = vec![];
+        self.images
+            .write()
+            .unwrap()
+            .retain(|&(bucket, image, kind, sizing_id), _| {
+                let hit = bucket == bucket_id && image == image_id;
+                if hit {
+                    hit_entries.push((sizing_id, kind));
+                }
+
+                !hit
+            });
+
+        Ok(hit_entries)
+    }
+
+    async fn list_variants(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+    ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
+        check_available()?;
+
+        let found = self.images
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|(bucket, image, ..)| *bucket == bucket_id && *image == image_id)
+            .map(|&(_, _, kind, sizing_id)| (sizing_id, kind))
+            .collect();
+
+        Ok(found)
+    }
+
+    async fn list_ids(
+        &self,
+        bucket_id: u32,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredImage>> {
+        check_available()?;
+
+        let images = self.images.read().unwrap();
+        let originals = images
+            .iter()
+            .filter(|((bucket, _, _, sizing_id), _)| *bucket == bucket_id && *sizing_id == 0)
+            .map(|((_, image_id, ..), (data, stored_at))| (*image_id, data.len() as u64, *stored_at));
+
+        Ok(StoredImage::collect_page(originals, after, limit))
+    }
+
+    async fn delete_variant(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<()> {
+        check_available()?;
+
+        debug!("Purging image {} from memory", image_id);
+        self.images
+            .write()
+            .unwrap()
+            .remove(&(bucket_id, image_id, kind, sizing_id));
+
+        Ok(())
+    }
+
+    async fn store_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        check_available()?;
+
+        self.metadata
+            .write()
+            .unwrap()
+            .insert((bucket_id, key.to_string()), data);
+
+        Ok(())
+    }
+
+    async fn fetch_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<Option<Bytes>> {
+        check_available()?;
+
+        let data = self.metadata
+            .read()
+            .unwrap()
+            .get(&(bucket_id, key.to_string()))
+            .cloned();
+
+        Ok(data)
+    }
+
+    async fn delete_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        check_available()?;
+
+        self.metadata
+            .write()
+            .unwrap()
+            .remove(&(bucket_id, key.to_string()));
+
+        Ok(())
+    }
+
+    async fn list_metadata(
+        &self,
+        bucket_id: u32,
+        dir: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        check_available()?;
+
+        let prefix = format!("{}/", dir);
+        let mut names: Vec<String> = self.metadata
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|(id, _)| *id == bucket_id)
+            .filter_map(|(_, key)| key.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .filter(|name| after.map(|after| *name > after).unwrap_or(true))
+            .map(ToString::to_string)
+            .collect();
+
+        names.sort_unstable();
+        names.truncate(limit);
+
+        Ok(names)
+    }
+}
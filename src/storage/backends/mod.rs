@@ -2,5 +2,10 @@ mod register;
 mod filesystem;
 mod blob_storage;
 mod scylladb;
+mod memory;
 
-pub use register::BackendConfigs;
\ No newline at end of file
+pub use register::{BackendConfigs, FreeSpaceWatermark, S3Timeouts};
+#[cfg(any(test, feature = "testing"))]
+pub use memory::set_unavailable as set_memory_unavailable;
+#[cfg(any(test, feature = "testing"))]
+pub use memory::set_stores_failing as set_memory_stores_failing;
\ No newline at end of file
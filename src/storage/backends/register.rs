@@ -13,10 +13,26 @@ pub enum BackendConfigs {
         password: Option<String>,
         keyspace: String,
         table: Option<String>,
+
+        #[serde(flatten)]
+        /// The consistency and load balancing settings of the session.
+        session: ScyllaSessionConfig,
     },
     FileSystem {
         /// The base output directory to store files.
         directory: PathBuf,
+
+        /// Refuses new uploads while the free space of the directory's
+        /// filesystem is below the watermark.
+        ///
+        /// If `None` uploads are accepted until writes fail.
+        free_space_watermark: Option<FreeSpaceWatermark>,
+
+        #[serde(default)]
+        /// Stores a single file per distinct content, hard-linked to the path
+        /// of every variant with that content, so duplicated uploads only
+        /// take up space once.
+        content_addressable: bool,
     },
     BlobStorage {
         /// The name of the bucket.
@@ -31,26 +47,197 @@ pub enum BackendConfigs {
         #[serde(default)]
         /// Store objects with the `public-read` acl.
         store_public: bool,
+
+        #[serde(default)]
+        /// How the bucket is addressed in request URLs.
+        addressing: S3Addressing,
+
+        /// A PEM file of the CA certificates to trust in addition to
+        /// the system's, for endpoints using a private PKI.
+        ca_bundle: Option<PathBuf>,
+
+        #[serde(default)]
+        /// Skip verifying the endpoint's TLS certificate.
+        ///
+        /// This should only be used for testing.
+        insecure_skip_tls_verify: bool,
+
+        #[serde(default)]
+        /// The time each kind of request can take before it's abandoned.
+        timeouts: S3Timeouts,
+
+        /// The maximum number of requests in flight at once, further
+        /// requests wait for one to complete.
+        ///
+        /// If `None` requests are not limited.
+        max_in_flight: Option<usize>,
+    },
+    /// Holds everything in memory, nothing is persisted between restarts.
+    ///
+    /// This is intended for testing rather than production use.
+    Memory,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum S3Addressing {
+    /// The bucket is part of the path, e.g. `https://s3.example.com/my-bucket/key`.
+    #[default]
+    Path,
+
+    /// The bucket is part of the host, e.g. `https://my-bucket.s3.example.com/key`.
+    VirtualHosted,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+pub struct FreeSpaceWatermark {
+    /// The free space in MB below which new uploads are refused.
+    pub min_free_space: u64,
+
+    #[serde(default = "default_free_space_check_interval")]
+    /// How often in seconds the free space is checked.
+    ///
+    /// Defaults to `10`.
+    pub check_interval: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default, Deserialize)]
+pub struct S3Timeouts {
+    /// The time in milliseconds fetching an object can take, including its body.
+    pub read: Option<u64>,
+
+    /// The time in milliseconds storing an object can take.
+    pub write: Option<u64>,
+
+    /// The time in milliseconds deleting an object can take.
+    pub delete: Option<u64>,
+
+    /// The time in milliseconds listing a page of objects can take.
+    pub list: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScyllaSessionConfig {
+    #[serde(default)]
+    /// The consistency level queries are ran with.
+    pub consistency: ScyllaConsistency,
+
+    /// The datacenter local to this instance.
+    ///
+    /// If set, queries are routed to nodes in this datacenter and
+    /// only fall back to remote datacenters if none are available.
+    pub local_datacenter: Option<String>,
+
+    #[serde(default = "default_token_aware")]
+    /// Route queries to the replicas owning the data where possible.
+    pub token_aware: bool,
+
+    /// The compression used for the frames sent to and from the cluster.
+    ///
+    /// If `None` frames are not compressed.
+    pub compression: Option<ScyllaCompression>,
+
+    /// The time in milliseconds a query can take before it's abandoned
+    /// and retried as if the cluster were overloaded.
+    ///
+    /// If `None` queries are not timed out.
+    pub request_timeout: Option<u64>,
+
+    /// The number of connections opened to each shard of every node.
+    ///
+    /// If `None` the driver's default of one connection per shard is used.
+    pub connections_per_shard: Option<usize>,
+}
+
+fn default_free_space_check_interval() -> u64 {
+    10
+}
+
+fn default_token_aware() -> bool {
+    true
+}
+
+#[derive(Debug, Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScyllaConsistency {
+    Any,
+    One,
+    Two,
+    Three,
+    Quorum,
+    All,
+    #[default]
+    LocalQuorum,
+    EachQuorum,
+    LocalOne,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScyllaCompression {
+    Lz4,
+    Snappy,
+}
+
+impl From<ScyllaCompression> for scylla::transport::Compression {
+    fn from(compression: ScyllaCompression) -> Self {
+        match compression {
+            ScyllaCompression::Lz4 => Self::Lz4,
+            ScyllaCompression::Snappy => Self::Snappy,
+        }
+    }
+}
+
+impl From<ScyllaConsistency> for scylla::frame::types::Consistency {
+    fn from(consistency: ScyllaConsistency) -> Self {
+        match consistency {
+            ScyllaConsistency::Any => Self::Any,
+            ScyllaConsistency::One => Self::One,
+            ScyllaConsistency::Two => Self::Two,
+            ScyllaConsistency::Three => Self::Three,
+            ScyllaConsistency::Quorum => Self::Quorum,
+            ScyllaConsistency::All => Self::All,
+            ScyllaConsistency::LocalQuorum => Self::LocalQuorum,
+            ScyllaConsistency::EachQuorum => Self::EachQuorum,
+            ScyllaConsistency::LocalOne => Self::LocalOne,
+        }
     }
 }
 
 impl BackendConfigs {
     pub async fn connect(&self) -> anyhow::Result<Arc<dyn StorageBackend>> {
         match self {
-            Self::FileSystem { directory } => {
-                Ok(Arc::new(super::filesystem::FileSystemBackend::new(directory.clone())))
+            Self::Memory => Ok(Arc::new(super::memory::MemoryBackend::new())),
+            Self::FileSystem { directory, free_space_watermark, content_addressable } => {
+                let backend = Arc::new(super::filesystem::FileSystemBackend::new(
+                    directory.clone(),
+                    *free_space_watermark,
+                    *content_addressable,
+                ));
+                super::filesystem::monitor_free_space(&backend);
+                Ok(backend)
             },
             Self::BlobStorage {
                 name,
                 region,
                 endpoint,
                 store_public,
+                addressing,
+                ca_bundle,
+                insecure_skip_tls_verify,
+                timeouts,
+                max_in_flight,
             } => {
                 let backend = super::blob_storage::BlobStorageBackend::new(
                     name.to_string(),
                     region.to_string(),
                     endpoint.to_string(),
                     *store_public,
+                    *addressing,
+                    ca_bundle.as_deref(),
+                    *insecure_skip_tls_verify,
+                    *timeouts,
+                    *max_in_flight,
                 )?;
 
                 Ok(Arc::new(backend))
@@ -61,6 +248,7 @@ impl BackendConfigs {
                 password,
                 keyspace,
                 table,
+                session,
             } => {
                 let backend = super::scylladb::ScyllaBackend::connect(
                     keyspace.clone(),
@@ -68,6 +256,7 @@ impl BackendConfigs {
                     nodes,
                     username.clone(),
                     password.clone(),
+                    session,
                 ).await?;
 
                 Ok(Arc::new(backend))
@@ -1,10 +1,24 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::anyhow;
 use bytes::Bytes;
 use uuid::Uuid;
 use async_trait::async_trait;
+use futures::TryStreamExt;
 use scylla::IntoTypedRows;
+use scylla::transport::session::PoolSize;
+use scylla::transport::load_balancing::{
+    DcAwareRoundRobinPolicy,
+    LoadBalancingPolicy,
+    RoundRobinPolicy,
+    TokenAwarePolicy,
+};
 use crate::config::ImageKind;
 use crate::controller::get_bucket_by_id;
+use crate::storage::backends::register::ScyllaSessionConfig;
+use crate::storage::template::StoredImage;
 use crate::StorageBackend;
 
 
@@ -20,27 +34,26 @@ impl ScyllaBackend {
         known_nodes: &[String],
         user: Option<String>,
         password: Option<String>,
+        session: &ScyllaSessionConfig,
     ) -> anyhow::Result<Self> {
         let mut cfg = scylla::SessionConfig::new();
         cfg.add_known_nodes(known_nodes);
         cfg.auth_password = user;
         cfg.auth_password = password;
+        cfg.default_consistency = session.consistency.into();
+        cfg.load_balancing = load_balancing_policy(session);
+        cfg.compression = session.compression.map(Into::into);
+        if let Some(connections) = session.connections_per_shard.and_then(NonZeroUsize::new) {
+            cfg.connection_pool_size = PoolSize::PerShard(connections);
+        }
 
         let base = scylla::Session::connect(cfg).await?;
         base.use_keyspace(keyspace, false).await?;
 
-        let connection = session::Session::from(base);
+        let connection = session::Session::new(base, session.request_timeout.map(Duration::from_millis));
 
         let table = table.unwrap_or_else(|| "lust_image".to_string());
-        let qry = format!("CREATE TABLE IF NOT EXISTS {} (\
-            bucket_id bigint, \
-            sizing_id bigint, \
-            image_id uuid, \
-            kind text, \
-            data blob, \
-            PRIMARY KEY ((bucket_id, sizing_id, image_id, kind))
-        )", table);
-        connection.query(&qry, &[]).await?;
+        migrations::run(&connection, &table).await?;
 
         Ok(Self {
             table,
@@ -49,13 +62,31 @@ impl ScyllaBackend {
     }
 }
 
+/// Builds the load balancing policy, preferring nodes in the local datacenter
+/// if one is set and wrapping it to be token aware if enabled.
+fn load_balancing_policy(session: &ScyllaSessionConfig) -> Arc<dyn LoadBalancingPolicy> {
+    match (session.token_aware, session.local_datacenter.clone()) {
+        (true, Some(local_dc)) => Arc::new(TokenAwarePolicy::new(Box::new(DcAwareRoundRobinPolicy::new(local_dc)))),
+        (true, None) => Arc::new(TokenAwarePolicy::new(Box::new(RoundRobinPolicy::new()))),
+        (false, Some(local_dc)) => Arc::new(DcAwareRoundRobinPolicy::new(local_dc)),
+        (false, None) => Arc::new(RoundRobinPolicy::new()),
+    }
+}
+
 #[async_trait]
 impl StorageBackend for ScyllaBackend {
     async fn store(&self, bucket_id: u32, image_id: Uuid, kind: ImageKind, sizing_id: u32, data: Bytes) -> anyhow::Result<()> {
-        let qry = format!("INSERT INTO {table} (bucket_id, sizing_id, image_id, kind, data) VALUES (?, ?, ?, ?, ?);", table = self.table);
+        // Listed before the data is written so a variant can never be left
+        // behind when the image is deleted.
+        let qry = format!("INSERT INTO {table}_variants (bucket_id, image_id, sizing_id, kind) VALUES (?, ?, ?, ?);", table = self.table);
+        self.connection
+            .query_prepared(&qry, (bucket_id as i64, image_id, sizing_id as i64, kind.as_file_extension()))
+            .await?;
+
+        let qry = format!("INSERT INTO {table} (bucket_id, sizing_id, image_id, kind, data, size) VALUES (?, ?, ?, ?, ?, ?);", table = self.table);
 
         self.connection
-            .query_prepared(&qry, (bucket_id as i64, sizing_id as i64,  image_id, kind.as_file_extension(), data.to_vec()))
+            .query_prepared(&qry, (bucket_id as i64, sizing_id as i64,  image_id, kind.as_file_extension(), data.to_vec(), data.len() as i64))
             .await?;
 
         Ok(())
@@ -77,53 +108,371 @@ impl StorageBackend for ScyllaBackend {
         Ok(buff)
     }
 
-    async fn delete(&self, bucket_id: u32, image_id: Uuid) -> anyhow::Result<Vec<(u32, ImageKind)>> {
-        let qry = format!("DELETE FROM {table} WHERE bucket_id = ? AND image_id = ? AND kind = ? AND sizing_id = ?;", table = self.table);
+    async fn list_variants(&self, bucket_id: u32, image_id: Uuid) -> anyhow::Result<Vec<(u32, ImageKind)>> {
+        let qry = format!("SELECT sizing_id, kind FROM {table}_variants WHERE bucket_id = ? AND image_id = ?;", table = self.table);
+
+        let found = self.connection
+            .query_prepared(&qry, (bucket_id as i64, image_id))
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_typed::<(i64, String)>()
+            .filter_map(|row| match row {
+                Ok((sizing_id, kind)) => ImageKind::from_file_extension(&kind)
+                    .map(|kind| Ok((sizing_id as u32, kind))),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !found.is_empty() {
+            return Ok(found)
+        }
+
+        // Images stored before their variants were listed are probed
+        // for each of the bucket's presets instead.
+        let qry = format!("SELECT sizing_id FROM {table} WHERE bucket_id = ? AND image_id = ? AND kind = ? AND sizing_id = ?;", table = self.table);
 
         let bucket = get_bucket_by_id(bucket_id)
             .ok_or_else(|| anyhow!("Bucket does not exist."))?
             .cfg();
 
-        let mut hit_entries = vec![];
-        for sizing_id in bucket.sizing_preset_ids().iter().copied() {
+        // Each variant is its own partition so they're checked individually
+        // rather than scanning the table.
+        let sizing_ids = bucket.presets
+            .keys()
+            .map(crate::utils::crc_hash)
+            .chain(std::iter::once(0));
+
+        let mut found = vec![];
+        for sizing_id in sizing_ids {
             for kind in ImageKind::variants() {
-                let values = (bucket_id as i64, image_id, kind.as_file_extension(), sizing_id as i64);
-                debug!("Purging image  @ {:?}", &values);
+                let exists = self.connection
+                    .query_prepared(&qry, (bucket_id as i64, image_id, kind.as_file_extension(), sizing_id as i64))
+                    .await?
+                    .rows
+                    .map(|rows| !rows.is_empty())
+                    .unwrap_or(false);
+
+                if exists {
+                    found.push((sizing_id, *kind));
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    async fn list_ids(&self, bucket_id: u32, after: Option<Uuid>, limit: usize) -> anyhow::Result<Vec<StoredImage>> {
+        let qry = format!("SELECT image_id, size, WRITETIME(data) FROM {table} WHERE bucket_id = ? AND sizing_id = ? ALLOW FILTERING;", table = self.table);
+
+        // Rows are returned in token order rather than by id, so every original
+        // in the bucket is scanned to build a page.
+        let originals = self.connection
+            .query_iter(&qry, (bucket_id as i64, 0i64))
+            .await?
+            .into_typed::<(Uuid, Option<i64>, i64)>()
+            .map_ok(|(image_id, size, written_at)| {
+                // Images stored before the size column was added are listed as empty.
+                (image_id, size.unwrap_or_default() as u64, written_at / 1_000_000)
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(StoredImage::collect_page(originals, after, limit))
+    }
+
+    async fn delete_variant(&self, bucket_id: u32, image_id: Uuid, kind: ImageKind, sizing_id: u32) -> anyhow::Result<()> {
+        let qry = format!("DELETE FROM {table} WHERE bucket_id = ? AND image_id = ? AND kind = ? AND sizing_id = ?;", table = self.table);
 
-                self.connection
-                    .query_prepared(&qry, values)
-                    .await?;
+        let values = (bucket_id as i64, image_id, kind.as_file_extension(), sizing_id as i64);
+        debug!("Purging image  @ {:?}", &values);
+        self.connection
+            .query_prepared(&qry, values)
+            .await?;
+
+        let qry = format!("DELETE FROM {table}_variants WHERE bucket_id = ? AND image_id = ? AND sizing_id = ? AND kind = ?;", table = self.table);
+        self.connection
+            .query_prepared(&qry, (bucket_id as i64, image_id, sizing_id as i64, kind.as_file_extension()))
+            .await?;
+
+        Ok(())
+    }
 
-                hit_entries.push((sizing_id, *kind))
+    async fn store_metadata(&self, bucket_id: u32, key: &str, data: Bytes) -> anyhow::Result<()> {
+        let qry = format!("INSERT INTO {table}_metadata (bucket_id, key, data) VALUES (?, ?, ?);", table = self.table);
+
+        self.connection
+            .query_prepared(&qry, (bucket_id as i64, key, data.to_vec()))
+            .await?;
+
+        // Documents are stored by key, so the names within each
+        // directory are tracked separately to be listed in order.
+        if let Some((dir, name)) = key.rsplit_once('/') {
+            let qry = format!("INSERT INTO {table}_metadata_dirs (bucket_id, dir, name) VALUES (?, ?, ?);", table = self.table);
+            self.connection
+                .query_prepared(&qry, (bucket_id as i64, dir, name))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_metadata(&self, bucket_id: u32, key: &str) -> anyhow::Result<Option<Bytes>> {
+        let qry = format!("SELECT data FROM {table}_metadata WHERE bucket_id = ? AND key = ?;", table = self.table);
+
+        let buff = self.connection
+            .query_prepared(&qry, (bucket_id as i64, key))
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_typed::<(Vec<u8>,)>()
+            .next()
+            .transpose()?
+            .map(|v| Bytes::from(v.0));
+
+        Ok(buff)
+    }
+
+    async fn delete_metadata(&self, bucket_id: u32, key: &str) -> anyhow::Result<()> {
+        if let Some((dir, name)) = key.rsplit_once('/') {
+            let qry = format!("DELETE FROM {table}_metadata_dirs WHERE bucket_id = ? AND dir = ? AND name = ?;", table = self.table);
+            self.connection
+                .query_prepared(&qry, (bucket_id as i64, dir, name))
+                .await?;
+        }
+
+        let qry = format!("DELETE FROM {table}_metadata WHERE bucket_id = ? AND key = ?;", table = self.table);
+
+        self.connection
+            .query_prepared(&qry, (bucket_id as i64, key))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_metadata(&self, bucket_id: u32, dir: &str, after: Option<&str>, limit: usize) -> anyhow::Result<Vec<String>> {
+        let qry = format!(
+            "SELECT name FROM {table}_metadata_dirs WHERE bucket_id = ? AND dir = ? AND name > ? LIMIT ?;",
+            table = self.table,
+        );
+
+        let names = self.connection
+            .query_prepared(&qry, (bucket_id as i64, dir, after.unwrap_or_default(), limit.min(i32::MAX as usize) as i32))
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_typed::<(String,)>()
+            .map(|row| row.map(|v| v.0))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(names)
+    }
+}
+
+
+mod migrations {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use anyhow::anyhow;
+    use scylla::IntoTypedRows;
+
+    use super::session::Session;
+
+    /// The table used to track which migrations have been applied.
+    ///
+    /// Versions are tracked per image table so multiple lust deployments
+    /// can share a keyspace with different table names.
+    const VERSION_TABLE: &str = "lust_schema_version";
+
+    /// A single, ordered schema change.
+    ///
+    /// Any `{table}` placeholders within the queries are replaced with
+    /// the configured image table name before being executed.
+    pub struct Migration {
+        pub version: i32,
+        pub description: &'static str,
+        pub queries: &'static [&'static str],
+    }
+
+    /// The set of migrations to apply, this must be in ascending version order.
+    ///
+    /// Migrations should be written so they can be safely re-ran, e.g. using
+    /// `IF NOT EXISTS`, as a partially applied migration will be re-attempted
+    /// on the next startup.
+    pub const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "Create the image table.",
+            queries: &[
+                "CREATE TABLE IF NOT EXISTS {table} (\
+                    bucket_id bigint, \
+                    sizing_id bigint, \
+                    image_id uuid, \
+                    kind text, \
+                    data blob, \
+                    PRIMARY KEY ((bucket_id, sizing_id, image_id, kind))
+                )",
+            ],
+        },
+        Migration {
+            version: 2,
+            description: "Create the bucket metadata table.",
+            queries: &[
+                "CREATE TABLE IF NOT EXISTS {table}_metadata (\
+                    bucket_id bigint, \
+                    key text, \
+                    data blob, \
+                    PRIMARY KEY ((bucket_id, key))
+                )",
+            ],
+        },
+        Migration {
+            version: 3,
+            description: "Add the size of each image for listing.",
+            queries: &[
+                "ALTER TABLE {table} ADD size bigint",
+            ],
+        },
+        Migration {
+            version: 4,
+            description: "Create the table listing the bucket metadata documents per directory.",
+            queries: &[
+                "CREATE TABLE IF NOT EXISTS {table}_metadata_dirs (\
+                    bucket_id bigint, \
+                    dir text, \
+                    name text, \
+                    PRIMARY KEY ((bucket_id, dir), name)
+                )",
+            ],
+        },
+        Migration {
+            version: 5,
+            description: "Create the table listing the stored variants of each image.",
+            queries: &[
+                "CREATE TABLE IF NOT EXISTS {table}_variants (\
+                    bucket_id bigint, \
+                    image_id uuid, \
+                    sizing_id bigint, \
+                    kind text, \
+                    PRIMARY KEY ((bucket_id, image_id), sizing_id, kind)
+                )",
+            ],
+        },
+    ];
+
+    /// Brings the schema for the given table up to the latest version.
+    pub async fn run(session: &Session, table: &str) -> anyhow::Result<()> {
+        let qry = format!("CREATE TABLE IF NOT EXISTS {} (\
+            table_name text, \
+            version int, \
+            description text, \
+            applied_at bigint, \
+            PRIMARY KEY ((table_name), version)
+        )", VERSION_TABLE);
+        session.query(&qry, &[]).await?;
+
+        let current = current_version(session, table).await?;
+        let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+
+        if current > latest {
+            return Err(anyhow!(
+                "Table {} is at schema version {} which is newer than the latest known version {}. \
+                Refusing to start with an older version of lust.",
+                table, current, latest,
+            ))
+        }
+
+        if current == latest {
+            debug!("Table {} is up to date at schema version {}", table, current);
+            return Ok(())
+        }
+
+        let qry = format!(
+            "INSERT INTO {} (table_name, version, description, applied_at) VALUES (?, ?, ?, ?);",
+            VERSION_TABLE,
+        );
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            info!(
+                "Applying schema migration {} to table {}: {}",
+                migration.version, table, migration.description,
+            );
+
+            for query in migration.queries {
+                session.query(&query.replace("{table}", table), &[]).await?;
             }
+
+            let applied_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)?
+                .as_millis() as i64;
+            session
+                .query(&qry, (table, migration.version, migration.description, applied_at))
+                .await?;
         }
 
-        Ok(hit_entries)
+        Ok(())
+    }
+
+    async fn current_version(session: &Session, table: &str) -> anyhow::Result<i32> {
+        let qry = format!("SELECT version FROM {} WHERE table_name = ?;", VERSION_TABLE);
+
+        let mut version = 0;
+        let rows = session
+            .query(&qry, (table,))
+            .await?
+            .rows
+            .unwrap_or_default()
+            .into_typed::<(i32,)>();
+
+        for row in rows {
+            version = version.max(row?.0);
+        }
+
+        Ok(version)
     }
 }
 
 mod session {
     use std::fmt::Debug;
+    use std::future::Future;
+    use std::time::Duration;
     use scylla::frame::value::ValueList;
     use scylla::query::Query;
     use scylla::transport::errors::{DbError, QueryError};
+    use scylla::transport::iterator::RowIterator;
     use scylla::QueryResult;
 
-    pub struct Session(scylla::CachingSession);
+    use crate::storage::StorageThrottled;
 
-    impl From<scylla::Session> for Session {
-        fn from(s: scylla::Session) -> Self {
-            Self(scylla::CachingSession::from(s, 100))
-        }
+    pub struct Session {
+        inner: scylla::CachingSession,
+
+        /// The time a query can take before it's abandoned.
+        request_timeout: Option<Duration>,
     }
 
     impl AsRef<scylla::Session> for Session {
         fn as_ref(&self) -> &scylla::Session {
-            &self.0.session
+            &self.inner.session
         }
     }
 
     impl Session {
+        pub fn new(s: scylla::Session, request_timeout: Option<Duration>) -> Self {
+            Self {
+                inner: scylla::CachingSession::from(s, 100),
+                request_timeout,
+            }
+        }
+
+        /// Fails the query with a timeout error once the request timeout passes.
+        async fn with_timeout<T>(&self, query: impl Future<Output = Result<T, QueryError>>) -> Result<T, QueryError> {
+            match self.request_timeout {
+                None => query.await,
+                Some(timeout) => tokio::time::timeout(timeout, query)
+                    .await
+                    .unwrap_or(Err(QueryError::TimeoutError)),
+            }
+        }
+
         #[instrument(skip(self, query), level = "debug")]
         pub async fn query(
             &self,
@@ -131,7 +480,7 @@ mod session {
             values: impl ValueList + Debug,
         ) -> Result<QueryResult, QueryError> {
             debug!("executing query {}", query);
-            let result = self.0.execute(query, &values).await;
+            let result = self.with_timeout(self.inner.execute(query, &values)).await;
 
             if let Err(ref e) = result {
                 consider_logging_error(e);
@@ -145,18 +494,47 @@ mod session {
             &self,
             query: &str,
             values: impl ValueList + Debug,
-        ) -> Result<QueryResult, QueryError> {
+        ) -> anyhow::Result<QueryResult> {
             debug!("preparing new statement: {}", query);
-            let result = self.0.execute(Query::from(query), &values).await;
+            let result = self.with_timeout(self.inner.execute(Query::from(query), &values)).await;
 
             match result {
                 Ok(res) => Ok(res),
                 Err(e) => {
                     consider_logging_error(&e);
-                    Err(e)
+                    Err(into_storage_error(e))
                 },
             }
         }
+
+        /// Executes the query, fetching the rows a page at a time.
+        #[instrument(skip(self, query), level = "debug")]
+        pub async fn query_iter(
+            &self,
+            query: &str,
+            values: impl ValueList + Debug,
+        ) -> anyhow::Result<RowIterator> {
+            debug!("executing paged query {}", query);
+            self.with_timeout(self.inner.execute_iter(Query::from(query), values))
+                .await
+                .map_err(|e| {
+                    consider_logging_error(&e);
+                    into_storage_error(e)
+                })
+        }
+    }
+
+    /// Marks the errors of an overloaded cluster as `StorageThrottled`
+    /// so the operation is retried and surfaced as a `503`.
+    fn into_storage_error(e: QueryError) -> anyhow::Error {
+        match e {
+            QueryError::DbError(DbError::Overloaded, _)
+            | QueryError::DbError(DbError::Unavailable { .. }, _)
+            | QueryError::DbError(DbError::ReadTimeout { .. }, _)
+            | QueryError::DbError(DbError::WriteTimeout { .. }, _)
+            | QueryError::TimeoutError => StorageThrottled { retry_after: None }.into(),
+            other => other.into(),
+        }
     }
 
     fn consider_logging_error(e: &QueryError) {
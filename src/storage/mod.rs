@@ -1,2 +1,102 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use anyhow::anyhow;
+use bytes::Bytes;
+use uuid::Uuid;
+
+use crate::config::ImageKind;
+use crate::storage::template::StorageBackend;
+
 pub mod backends;
-pub mod template;
\ No newline at end of file
+pub mod resilience;
+pub mod template;
+
+/// The storage backend rejected the operation as it's being
+/// throttled or is overloaded, the operation can be retried.
+#[derive(Debug, Clone)]
+pub struct StorageThrottled {
+    /// How long the backend asked to wait before retrying, if it did.
+    pub retry_after: Option<Duration>,
+}
+
+impl Display for StorageThrottled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The storage backend is throttling requests.")
+    }
+}
+
+impl std::error::Error for StorageThrottled {}
+
+/// The storage backend is too low on space to accept new uploads.
+#[derive(Debug, Clone)]
+pub struct InsufficientStorage {
+    /// The free space in bytes remaining.
+    pub available: u64,
+}
+
+impl Display for InsufficientStorage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The storage backend is low on space with {} bytes free.", self.available)
+    }
+}
+
+impl std::error::Error for InsufficientStorage {}
+
+/// The storage backend's circuit breaker is open after sustained failures,
+/// the operation was failed without calling the backend.
+#[derive(Debug, Clone)]
+pub struct StorageUnavailable {
+    /// The time until the backend is next probed.
+    pub retry_after: Duration,
+}
+
+impl Display for StorageUnavailable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The storage backend is currently unavailable.")
+    }
+}
+
+impl std::error::Error for StorageUnavailable {}
+
+/// The bucket id the validation roundtrip is performed under.
+///
+/// Config validation rejects any bucket whose name hashes to this id.
+const VALIDATION_BUCKET_ID: u32 = 0;
+
+/// Performs a write, read and delete roundtrip of both an image and a
+/// metadata document, checking the backend is reachable and correctly
+/// configured without touching any real bucket's data.
+pub async fn validate_roundtrip(backend: &dyn StorageBackend) -> anyhow::Result<()> {
+    let image_id = Uuid::new_v4();
+    let data = Bytes::from(image_id.to_string());
+
+    backend.store(VALIDATION_BUCKET_ID, image_id, ImageKind::Png, 0, data.clone()).await
+        .map_err(|e| anyhow!("Failed to write the validation image: {}", e))?;
+
+    let fetched = backend.fetch(VALIDATION_BUCKET_ID, image_id, ImageKind::Png, 0).await
+        .map_err(|e| anyhow!("Failed to read the validation image: {}", e))?;
+    if fetched.as_ref() != Some(&data) {
+        return Err(anyhow!("The validation image read back does not match what was written."))
+    }
+
+    backend.delete_variant(VALIDATION_BUCKET_ID, image_id, ImageKind::Png, 0).await
+        .map_err(|e| anyhow!("Failed to delete the validation image: {}", e))?;
+    if backend.fetch(VALIDATION_BUCKET_ID, image_id, ImageKind::Png, 0).await?.is_some() {
+        return Err(anyhow!("The validation image still exists after being deleted."))
+    }
+
+    let key = format!("validation/{}", image_id);
+    backend.store_metadata(VALIDATION_BUCKET_ID, &key, data.clone()).await
+        .map_err(|e| anyhow!("Failed to write the validation metadata: {}", e))?;
+
+    let fetched = backend.fetch_metadata(VALIDATION_BUCKET_ID, &key).await
+        .map_err(|e| anyhow!("Failed to read the validation metadata: {}", e))?;
+    if fetched.as_ref() != Some(&data) {
+        return Err(anyhow!("The validation metadata read back does not match what was written."))
+    }
+
+    backend.delete_metadata(VALIDATION_BUCKET_ID, &key).await
+        .map_err(|e| anyhow!("Failed to delete the validation metadata: {}", e))?;
+
+    Ok(())
+}
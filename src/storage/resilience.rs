@@ -0,0 +1,290 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::config::{CircuitBreakerConfig, ImageKind, StorageRetryConfig};
+use crate::metrics::{STORAGE_BREAKER_OPEN, STORAGE_BREAKER_REJECTIONS, STORAGE_DURATION, STORAGE_THROTTLED};
+use crate::storage::{StorageThrottled, StorageUnavailable};
+use crate::storage::template::StoredImage;
+use crate::StorageBackend;
+
+/// Wraps a storage backend, retrying operations the backend throttles
+/// and failing fast while the backend is persistently failing.
+pub struct ResilientBackend {
+    inner: Arc<dyn StorageBackend>,
+    cfg: StorageRetryConfig,
+    breaker: CircuitBreaker,
+}
+
+impl ResilientBackend {
+    pub fn new(
+        inner: Arc<dyn StorageBackend>,
+        cfg: StorageRetryConfig,
+        breaker: CircuitBreakerConfig,
+    ) -> Self {
+        Self {
+            inner,
+            cfg,
+            breaker: CircuitBreaker::new(
+                breaker.failure_threshold,
+                Duration::from_secs(breaker.cooldown),
+            ),
+        }
+    }
+
+    /// Runs the operation, retrying with exponential backoff and full jitter
+    /// while the backend reports it's being throttled.
+    ///
+    /// The time taken including any retries is recorded per bucket and operation.
+    async fn run<T, F, Fut>(&self, bucket_id: u32, operation: &'static str, op: F) -> anyhow::Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let start = Instant::now();
+        let result = self.run_with_retries(op).await;
+
+        let bucket = crate::controller::get_bucket_by_id(bucket_id)
+            .map(|bucket| bucket.name().to_string())
+            .unwrap_or_else(|| bucket_id.to_string());
+        STORAGE_DURATION
+            .with_label_values(&[&bucket, operation])
+            .observe(start.elapsed().as_secs_f64());
+
+        result
+    }
+
+    async fn run_with_retries<T, F, Fut>(&self, op: F) -> anyhow::Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        if let Err(remaining) = self.breaker.acquire() {
+            STORAGE_BREAKER_REJECTIONS.inc();
+            return Err(StorageUnavailable { retry_after: remaining }.into())
+        }
+
+        let mut retries = 0;
+        loop {
+            let e = match op().await {
+                Ok(v) => {
+                    self.breaker.record(false);
+                    return Ok(v)
+                },
+                Err(e) => e,
+            };
+
+            let retry_after = match e.downcast_ref::<StorageThrottled>() {
+                Some(throttled) => throttled.retry_after,
+                None => {
+                    self.breaker.record(true);
+                    return Err(e)
+                },
+            };
+
+            if retries >= self.cfg.max_retries {
+                STORAGE_THROTTLED.with_label_values(&["failed"]).inc();
+                self.breaker.record(true);
+                return Err(e)
+            }
+
+            retries += 1;
+            STORAGE_THROTTLED.with_label_values(&["retried"]).inc();
+
+            let max_delay = Duration::from_millis(self.cfg.max_delay);
+            let delay = self.backoff(retries)
+                .max(retry_after.unwrap_or_default().min(max_delay));
+
+            debug!("Storage operation was throttled, retrying in {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self.cfg.base_delay
+            .saturating_mul(1 << (retry - 1).min(16))
+            .min(self.cfg.max_delay);
+
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for ResilientBackend {
+    async fn check_capacity(&self) -> anyhow::Result<()> {
+        self.inner.check_capacity().await
+    }
+
+    async fn store(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        self.run(bucket_id, "store", || self.inner.store(bucket_id, image_id, kind, sizing_id, data.clone())).await
+    }
+
+    async fn fetch(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<Option<Bytes>> {
+        self.run(bucket_id, "fetch", || self.inner.fetch(bucket_id, image_id, kind, sizing_id)).await
+    }
+
+    async fn delete(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+    ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
+        self.run(bucket_id, "delete", || self.inner.delete(bucket_id, image_id)).await
+    }
+
+    async fn list_variants(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+    ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
+        self.run(bucket_id, "list_variants", || self.inner.list_variants(bucket_id, image_id)).await
+    }
+
+    async fn list_ids(
+        &self,
+        bucket_id: u32,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredImage>> {
+        self.run(bucket_id, "list_ids", || self.inner.list_ids(bucket_id, after, limit)).await
+    }
+
+    async fn delete_variant(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<()> {
+        self.run(bucket_id, "delete_variant", || self.inner.delete_variant(bucket_id, image_id, kind, sizing_id)).await
+    }
+
+    async fn store_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+        data: Bytes,
+    ) -> anyhow::Result<()> {
+        self.run(bucket_id, "store_metadata", || self.inner.store_metadata(bucket_id, key, data.clone())).await
+    }
+
+    async fn fetch_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<Option<Bytes>> {
+        self.run(bucket_id, "fetch_metadata", || self.inner.fetch_metadata(bucket_id, key)).await
+    }
+
+    async fn delete_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        self.run(bucket_id, "delete_metadata", || self.inner.delete_metadata(bucket_id, key)).await
+    }
+
+    async fn list_metadata(
+        &self,
+        bucket_id: u32,
+        dir: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        self.run(bucket_id, "list_metadata", || self.inner.list_metadata(bucket_id, dir, after, limit)).await
+    }
+}
+
+/// Opens once enough consecutive operations have failed,
+/// rejecting operations until the cooldown has passed.
+///
+/// Once the cooldown has passed a single operation is let through to probe
+/// the backend, closing the breaker if it succeeds or restarting the
+/// cooldown if it fails again.
+///
+/// Operations which find nothing still succeed, so misses never trip the breaker.
+struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Checks if an operation can be attempted, returning the remaining
+    /// cooldown if the breaker is open.
+    fn acquire(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let opened_at = match state.opened_at {
+            None => return Ok(()),
+            Some(opened_at) => opened_at,
+        };
+
+        let elapsed = opened_at.elapsed();
+        if elapsed < self.cooldown {
+            return Err(self.cooldown - elapsed)
+        }
+
+        // Restarting the cooldown lets only this operation through as the
+        // probe, which also means a probe that never completes can't leave
+        // the breaker stuck open.
+        state.opened_at = Some(Instant::now());
+        Ok(())
+    }
+
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        if !failed {
+            if state.opened_at.is_some() {
+                info!("Storage backend has recovered, closing the circuit breaker.");
+                STORAGE_BREAKER_OPEN.set(0);
+            }
+
+            *state = BreakerState::default();
+            return
+        }
+
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.threshold {
+            if state.opened_at.is_none() {
+                warn!(
+                    "Storage backend has failed {} consecutive operations, opening the circuit breaker for {:?}. \
+                    Only cached images are served while open.",
+                    state.consecutive_failures, self.cooldown,
+                );
+                STORAGE_BREAKER_OPEN.set(1);
+            }
+
+            state.opened_at = Some(Instant::now());
+        }
+    }
+}
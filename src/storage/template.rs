@@ -1,10 +1,58 @@
+use std::collections::BTreeMap;
 use async_trait::async_trait;
 use bytes::Bytes;
 use uuid::Uuid;
 use crate::config::ImageKind;
 
+/// An image of a bucket as listed by the storage backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredImage {
+    pub image_id: Uuid,
+
+    /// The combined size in bytes of the image's original sized variants.
+    pub size: u64,
+
+    /// The unix timestamp the image was stored at.
+    ///
+    /// Replacing the image's content updates this.
+    pub stored_at: i64,
+}
+
+impl StoredImage {
+    /// Merges the original sized variants of each image into a page of up to
+    /// `limit` images after the `after` cursor, in ascending order of their id.
+    ///
+    /// Each variant is given as its image id, size and unix timestamp.
+    pub fn collect_page(
+        variants: impl IntoIterator<Item = (Uuid, u64, i64)>,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> Vec<Self> {
+        let mut images: BTreeMap<Uuid, Self> = BTreeMap::new();
+        for (image_id, size, stored_at) in variants {
+            if after.map(|after| image_id <= after).unwrap_or(false) {
+                continue
+            }
+
+            let image = images.entry(image_id).or_insert(Self { image_id, size: 0, stored_at });
+            image.size += size;
+            image.stored_at = image.stored_at.min(stored_at);
+        }
+
+        images.into_values().take(limit).collect()
+    }
+}
+
 #[async_trait]
 pub trait StorageBackend: Sync + Send + 'static {
+    /// Checks the backend has the space to accept new uploads, erroring
+    /// with [`InsufficientStorage`](crate::storage::InsufficientStorage) if not.
+    ///
+    /// Backends without a capacity limit always accept uploads.
+    async fn check_capacity(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     async fn store(
         &self,
         bucket_id: u32,
@@ -22,9 +70,81 @@ pub trait StorageBackend: Sync + Send + 'static {
         sizing_id: u32,
     ) -> anyhow::Result<Option<Bytes>>;
     
+    /// Removes every variant of the image, returning the variants removed.
+    ///
+    /// Only the variants listed by `list_variants` are removed rather than
+    /// every preset and format combination.
     async fn delete(
         &self,
         bucket_id: u32,
         image_id: Uuid,
+    ) -> anyhow::Result<Vec<(u32, ImageKind)>> {
+        let existing = self.list_variants(bucket_id, image_id).await?;
+        for (sizing_id, kind) in existing.iter().copied() {
+            self.delete_variant(bucket_id, image_id, kind, sizing_id).await?;
+        }
+
+        Ok(existing)
+    }
+
+    /// Lists the variants of the image which currently exist.
+    async fn list_variants(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
     ) -> anyhow::Result<Vec<(u32, ImageKind)>>;
-}
\ No newline at end of file
+
+    /// Lists up to `limit` of the bucket's images in ascending order
+    /// of their id, starting after the `after` cursor if given.
+    async fn list_ids(
+        &self,
+        bucket_id: u32,
+        after: Option<Uuid>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredImage>>;
+
+    /// Removes a single variant of the image.
+    ///
+    /// Variants that do not exist are ignored.
+    async fn delete_variant(
+        &self,
+        bucket_id: u32,
+        image_id: Uuid,
+        kind: ImageKind,
+        sizing_id: u32,
+    ) -> anyhow::Result<()>;
+
+    /// Stores a bucket level metadata document under the given key,
+    /// replacing any existing document.
+    async fn store_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+        data: Bytes,
+    ) -> anyhow::Result<()>;
+
+    async fn fetch_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<Option<Bytes>>;
+
+    /// Removes the metadata document stored under the given key.
+    ///
+    /// Documents that do not exist are ignored.
+    async fn delete_metadata(
+        &self,
+        bucket_id: u32,
+        key: &str,
+    ) -> anyhow::Result<()>;
+
+    /// Lists up to `limit` names of the metadata documents stored directly
+    /// under `{dir}/`, in ascending order and after the `after` name if given.
+    async fn list_metadata(
+        &self,
+        bucket_id: u32,
+        dir: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<String>>;
+}
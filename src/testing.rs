@@ -0,0 +1,102 @@
+//! Helpers for integration testing against Lust.
+//!
+//! Configs are built programmatically and default to the in-memory storage
+//! backend, so tests need neither YAML fixtures nor directories on disk.
+//!
+//! ```ignore
+//! let config = ConfigBuilder::new()
+//!     .bucket("user-profiles", json!({ "mode": "jit" }))
+//!     .build()?;
+//!
+//! let client = lust::testing::client(config).await?;
+//! client.post("/v1/user-profiles").body(image).send().await.assert_status_is_ok();
+//! ```
+//!
+//! The config is global so it can only be initialised once per process,
+//! tests should be run with a runner which isolates each test in its own
+//! process such as `cargo nextest`.
+
+use poem::test::TestClient;
+use poem::{EndpointExt, IntoEndpoint, Route};
+use poem_openapi::OpenApiService;
+use serde_json::{json, Map, Value};
+
+use crate::config::RuntimeConfig;
+
+/// Builds a `RuntimeConfig` programmatically.
+///
+/// Options take the same shape as they do in the config file.
+pub struct ConfigBuilder {
+    config: Map<String, Value>,
+    buckets: Map<String, Value>,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigBuilder {
+    /// A config using the in-memory storage backend and no buckets.
+    pub fn new() -> Self {
+        let mut config = Map::new();
+        config.insert("backend".to_string(), json!("memory"));
+
+        Self {
+            config,
+            buckets: Map::new(),
+        }
+    }
+
+    /// Sets the storage backend, in place of the in-memory backend.
+    pub fn backend(self, backend: Value) -> Self {
+        self.option("backend", backend)
+    }
+
+    /// Adds a bucket with the given config.
+    pub fn bucket(mut self, name: &str, config: Value) -> Self {
+        self.buckets.insert(name.to_string(), config);
+        self
+    }
+
+    /// Sets any other top level config option.
+    pub fn option(mut self, key: &str, value: Value) -> Self {
+        self.config.insert(key.to_string(), value);
+        self
+    }
+
+    pub fn build(mut self) -> anyhow::Result<RuntimeConfig> {
+        self.config.insert("buckets".to_string(), Value::Object(self.buckets));
+        Ok(serde_json::from_value(Value::Object(self.config))?)
+    }
+}
+
+/// Initialises Lust with the given config and creates a `TestClient`
+/// serving the image API at `/v1` alongside the admin API and thumbor URLs.
+pub async fn client(config: RuntimeConfig) -> anyhow::Result<TestClient<Route>> {
+    crate::config::init_from(config)?;
+    crate::init_global_state()?;
+    crate::setup_buckets().await?;
+
+    let app = OpenApiService::new(
+        crate::routes::LustApi,
+        "Lust API",
+        env!("CARGO_PKG_VERSION"),
+    );
+
+    let app = Route::new().nest("/v1", app.into_endpoint().around(crate::admission::prioritize).around(crate::auth::enforce_scopes).around(crate::replica::forward_writes));
+    Ok(TestClient::new(crate::admin::mount(crate::thumbor::mount(app))))
+}
+
+/// Makes every operation of the in-memory storage backend fail until
+/// set back to `false`, simulating an outage of the storage backend.
+pub fn set_storage_unavailable(unavailable: bool) {
+    crate::storage::backends::set_memory_unavailable(unavailable);
+}
+
+/// Makes every image store of the in-memory storage backend fail
+/// until set back to `false`, simulating a partially stored upload.
+pub fn set_storage_stores_failing(failing: bool) {
+    crate::storage::backends::set_memory_stores_failing(failing);
+}
@@ -1,56 +1,61 @@
-use std::sync::Arc;
 use image::load_from_memory_with_format;
 use poem::Route;
 use poem::http::StatusCode;
 use poem_openapi::OpenApiService;
 use poem::test::{TestClient, TestResponse};
 use poem::web::headers;
-use tokio::sync::Semaphore;
 
-use crate::{BucketController, cache, config, controller, StorageBackend};
+use crate::config;
 
 const JIT_CONFIG: &str = include_str!("../tests/configs/jit-mode.yaml");
 const AOT_CONFIG: &str = include_str!("../tests/configs/aot-mode.yaml");
 const REALTIME_CONFIG: &str = include_str!("../tests/configs/realtime-mode.yaml");
+const COMPRESSION_CONFIG: &str = include_str!("../tests/configs/compression.yaml");
+const PRECOMPRESSION_CONFIG: &str = include_str!("../tests/configs/precompression.yaml");
+const ENCODER_CACHE_CONFIG: &str = include_str!("../tests/configs/encoder-cache.yaml");
+const EGRESS_LIMIT_CONFIG: &str = include_str!("../tests/configs/egress-limit.yaml");
+const PROCESSING_RULES_CONFIG: &str = include_str!("../tests/configs/processing-rules.yaml");
+const ASYNC_UPLOAD_CONFIG: &str = include_str!("../tests/configs/async-upload.yaml");
+const MISSING_IMAGE_CONFIG: &str = include_str!("../tests/configs/missing-image.yaml");
+const ACCESS_STATS_CONFIG: &str = include_str!("../tests/configs/access-stats.yaml");
+const LIFECYCLE_CONFIG: &str = include_str!("../tests/configs/lifecycle.yaml");
+const LIFECYCLE_RULES_CONFIG: &str = include_str!("../tests/configs/lifecycle-rules.yaml");
+const UPLOAD_LIMIT_CONFIG: &str = include_str!("../tests/configs/upload-limit.yaml");
+const PRESET_FORMATS_CONFIG: &str = include_str!("../tests/configs/preset-formats.yaml");
+const JIT_NO_PERSIST_CONFIG: &str = include_str!("../tests/configs/jit-no-persist.yaml");
+const INDEX_CONFIG: &str = include_str!("../tests/configs/index.yaml");
+const RESOLUTION_CAP_CONFIG: &str = include_str!("../tests/configs/resolution-cap.yaml");
+const ANIMATION_LIMITS_CONFIG: &str = include_str!("../tests/configs/animation-limits.yaml");
+const PROFILING_CONFIG: &str = include_str!("../tests/configs/profiling.yaml");
 const TEST_IMAGE: &[u8] = include_bytes!("../examples/example.jpeg");
 
 async fn setup_environment(cfg: &str) -> anyhow::Result<TestClient<Route>> {
     config::init_test(cfg)?;
 
-    let global_limiter = config::config()
-        .max_concurrency
-        .map(Semaphore::new)
-        .map(Arc::new);
+    crate::setup_buckets().await?;
 
-    let storage: Arc<dyn StorageBackend> = config::config()
-        .backend
-        .connect()
-        .await?;
+    let app = OpenApiService::new(
+        crate::routes::LustApi,
+        "Lust API",
+        env!("CARGO_PKG_VERSION")
+    );
 
-    let buckets = config::config()
-        .buckets
-        .iter()
-        .map(|(bucket, cfg)| {
-            let bucket_id = crate::utils::crc_hash(bucket);
-            let pipeline = cfg.mode.build_pipeline(cfg);
-            let cache = cfg.cache
-                .map(cache::new_cache)
-                .transpose()?
-                .flatten();
-
-            let controller = BucketController::new(
-                bucket_id,
-                cache,
-                global_limiter.clone(),
-                cfg.clone(),
-                pipeline,
-                storage.clone(),
-            );
-            Ok::<_, anyhow::Error>((bucket_id, controller))
-        })
-        .collect::<Result<hashbrown::HashMap<_, _>, anyhow::Error>>()?;
-
-    controller::init_buckets(buckets);
+    let app = Route::new().nest("/v1", app);
+    Ok(TestClient::new(app))
+}
+
+async fn setup_admin_environment(cfg: &str) -> anyhow::Result<TestClient<Route>> {
+    config::init_test(cfg)?;
+
+    crate::setup_buckets().await?;
+
+    Ok(TestClient::new(crate::admin::mount(Route::new())))
+}
+
+async fn setup_full_environment(cfg: &str) -> anyhow::Result<TestClient<Route>> {
+    config::init_test(cfg)?;
+
+    crate::setup_buckets().await?;
 
     let app = OpenApiService::new(
         crate::routes::LustApi,
@@ -59,7 +64,7 @@ async fn setup_environment(cfg: &str) -> anyhow::Result<TestClient<Route>> {
     );
 
     let app = Route::new().nest("/v1", app);
-    Ok(TestClient::new(app))
+    Ok(TestClient::new(crate::admin::mount(app)))
 }
 
 
@@ -75,6 +80,17 @@ async fn validate_image_content(
     Ok(())
 }
 
+/// Fetches an image straight from the storage backend of the bucket.
+async fn stored_image(
+    bucket: &str,
+    image_id: &str,
+    kind: config::ImageKind,
+    sizing_id: u32,
+) -> anyhow::Result<Option<bytes::Bytes>> {
+    let bucket = crate::controller::get_bucket_by_name(bucket).unwrap();
+    bucket.storage().fetch(bucket.bucket_id(), image_id.parse()?, kind, sizing_id).await
+}
+
 
 #[tokio::test]
 async fn test_basic_aot_upload_retrieval_without_guessing() -> anyhow::Result<()> {
@@ -82,7 +98,7 @@ async fn test_basic_aot_upload_retrieval_without_guessing() -> anyhow::Result<()
 
     let res = app.post("/v1/user-profiles")
         .body(TEST_IMAGE)
-        .content_type("application/octet-stream".to_string())
+        .content_type("application/octet-stream")
         .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
         .query("format".to_string(), &"jpeg".to_string())
         .send()
@@ -102,7 +118,7 @@ async fn test_basic_aot_upload_retrieval_without_guessing() -> anyhow::Result<()
         .await;
 
     res.assert_status(StatusCode::OK);
-    res.assert_content_type(&"image/webp".to_string());
+    res.assert_content_type("image/webp");
 
     validate_image_content(res, image::ImageFormat::WebP).await?;
 
@@ -115,7 +131,7 @@ async fn test_basic_aot_upload_retrieval_with_guessing() -> anyhow::Result<()> {
 
     let res = app.post("/v1/user-profiles")
         .body(TEST_IMAGE)
-        .content_type("application/octet-stream".to_string())
+        .content_type("application/octet-stream")
         .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
         .query("format".to_string(), &"jpeg".to_string())
         .send()
@@ -135,7 +151,7 @@ async fn test_basic_aot_upload_retrieval_with_guessing() -> anyhow::Result<()> {
         .await;
 
     res.assert_status(StatusCode::OK);
-    res.assert_content_type(&"image/webp".to_string());
+    res.assert_content_type("image/webp");
 
     validate_image_content(res, image::ImageFormat::WebP).await?;
 
@@ -148,7 +164,7 @@ async fn test_basic_jit_upload_retrieval() -> anyhow::Result<()> {
 
     let res = app.post("/v1/user-profiles")
         .body(TEST_IMAGE)
-        .content_type("application/octet-stream".to_string())
+        .content_type("application/octet-stream")
         .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
         .query("format".to_string(), &"jpeg".to_string())
         .send()
@@ -168,20 +184,85 @@ async fn test_basic_jit_upload_retrieval() -> anyhow::Result<()> {
         .await;
 
     res.assert_status(StatusCode::OK);
-    res.assert_content_type(&"image/jpeg".to_string());
+    res.assert_content_type("image/jpeg");
 
     validate_image_content(res, image::ImageFormat::Jpeg).await?;
 
     Ok(())
 }
 
+#[tokio::test]
+async fn test_jit_upload_pregenerates_variants() -> anyhow::Result<()> {
+    let app = setup_environment(JIT_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .query("pregenerate".to_string(), &"medium-square:png,unknown:png")
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .query("pregenerate".to_string(), &"medium-square:png")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+    crate::background::wait_until_idle().await;
+
+    let bucket_id = crate::utils::crc_hash("user-profiles");
+    let variant = format!("data/{}/{}/{}.png", bucket_id, crate::utils::crc_hash("medium-square"), &file_id);
+    assert!(std::path::Path::new(&variant).exists(), "The hinted variant should be generated at upload");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_jit_variants_not_persisted() -> anyhow::Result<()> {
+    let app = setup_environment(JIT_NO_PERSIST_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    for _ in 0..2 {
+        let res = app.get(format!("/v1/user-profiles/{}", &file_id))
+            .query("size".to_string(), &"medium-square")
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        validate_image_content(res, image::ImageFormat::Jpeg).await?;
+    }
+    crate::background::wait_until_idle().await;
+
+    let variant = stored_image("user-profiles", &file_id, config::ImageKind::Jpeg, crate::utils::crc_hash("medium-square")).await?;
+    assert!(variant.is_none(), "Generated variants should not be persisted");
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_jit_upload_custom_format_retrieval() -> anyhow::Result<()> {
     let app = setup_environment(JIT_CONFIG).await?;
 
     let res = app.post("/v1/user-profiles")
         .body(TEST_IMAGE)
-        .content_type("application/octet-stream".to_string())
+        .content_type("application/octet-stream")
         .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
         .query("format".to_string(), &"jpeg".to_string())
         .send()
@@ -202,10 +283,54 @@ async fn test_jit_upload_custom_format_retrieval() -> anyhow::Result<()> {
         .await;
 
     res.assert_status(StatusCode::OK);
-    res.assert_content_type(&"image/png".to_string());
+    res.assert_content_type("image/png");
+
+    validate_image_content(res, image::ImageFormat::Png).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_preset_default_serving_format() -> anyhow::Result<()> {
+    let app = setup_environment(PRESET_FORMATS_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .query("size".to_string(), &"thumbnail")
+        .send()
+        .await;
 
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/png");
     validate_image_content(res, image::ImageFormat::Png).await?;
 
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/jpeg");
+
+    // An explicitly requested format still takes precedence.
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .query("size".to_string(), &"thumbnail")
+        .query("format".to_string(), &"jpeg")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/jpeg");
+
     Ok(())
 }
 
@@ -215,7 +340,7 @@ async fn test_basic_realtime_upload_retrieval() -> anyhow::Result<()> {
 
     let res = app.post("/v1/user-profiles")
         .body(TEST_IMAGE)
-        .content_type("application/octet-stream".to_string())
+        .content_type("application/octet-stream")
         .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
         .query("format".to_string(), &"jpeg".to_string())
         .send()
@@ -235,7 +360,7 @@ async fn test_basic_realtime_upload_retrieval() -> anyhow::Result<()> {
         .await;
 
     res.assert_status(StatusCode::OK);
-    res.assert_content_type(&"image/png".to_string());
+    res.assert_content_type("image/png");
 
     validate_image_content(res, image::ImageFormat::Png).await?;
 
@@ -248,7 +373,7 @@ async fn test_realtime_resizing() -> anyhow::Result<()> {
 
     let res = app.post("/v1/user-profiles")
         .body(TEST_IMAGE)
-        .content_type("application/octet-stream".to_string())
+        .content_type("application/octet-stream")
         .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
         .query("format".to_string(), &"jpeg".to_string())
         .send()
@@ -270,7 +395,7 @@ async fn test_realtime_resizing() -> anyhow::Result<()> {
         .await;
 
     res.assert_status(StatusCode::OK);
-    res.assert_content_type(&"image/png".to_string());
+    res.assert_content_type("image/png");
 
     validate_image_content(res, image::ImageFormat::Png).await?;
 
@@ -278,31 +403,4545 @@ async fn test_realtime_resizing() -> anyhow::Result<()> {
 }
 
 #[tokio::test]
-async fn test_realtime_resizing_expect_err() -> anyhow::Result<()> {
-    let app = setup_environment(REALTIME_CONFIG).await?;
+async fn test_path_transforms_allowed_or_signed() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "realtime",
+            "formats": { "png": true, "jpeg": true, "webp": false, "gif": false },
+            "path_transforms": {
+                "allowed": ["100x100,png"],
+                "signing_key": "secret",
+            },
+        }))
+        .build()?;
+    let app = client(config).await?;
 
     let res = app.post("/v1/user-profiles")
         .body(TEST_IMAGE)
-        .content_type("application/octet-stream".to_string())
+        .content_type("application/octet-stream")
         .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
         .send()
         .await;
+    res.assert_status_is_ok();
+    let image_id: uuid::Uuid = res.json().await.value().object().get("image_id").string().parse()?;
 
-    res.assert_status(StatusCode::OK);
-    let info = res.json().await;
+    // Allowed transformations match regardless of their order.
+    let res = app.get(format!("/v1/user-profiles/{}/png,100x100", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/png");
+    validate_image_content(res, image::ImageFormat::Png).await?;
 
-    let file_id = info
-        .value()
-        .object()
-        .get("image_id")
-        .string();
+    let res = app.get(format!("/v1/user-profiles/{}/50x50,jpeg", image_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::FORBIDDEN);
 
-    let res = app.get(format!("/v1/user-profiles/{}", file_id))
-        .query("width".to_string(), &"500".to_string())
+    let signature = crate::transforms::sign("secret", "user-profiles", image_id, "50x50,jpeg");
+    let res = app.get(format!("/v1/user-profiles/{}/50x50,jpeg,sig={}", image_id, signature))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/jpeg");
+
+    // Signatures are only valid for the transformation they were made for.
+    let res = app.get(format!("/v1/user-profiles/{}/60x60,jpeg,sig={}", image_id, signature))
+        .send()
+        .await;
+    res.assert_status(StatusCode::FORBIDDEN);
+
+    let res = app.get(format!("/v1/user-profiles/{}/thumbnail,png", image_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_signed_urls_required() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("private", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "url_signing": { "key": "secret" },
+        }))
+        .option("thumbor", serde_json::json!({ "allow_unsafe": true }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/private")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id: uuid::Uuid = res.json().await.value().object().get("image_id").string().parse()?;
+
+    let path = format!("private/{}", image_id);
+    let res = app.get(format!("/v1/{}", path))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let expires = chrono::Utc::now().timestamp() + 60;
+    let query = crate::signing::signed_query("secret", &path, "format=png", expires);
+    let res = app.get(format!("/v1/{}?{}", path, query))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    validate_image_content(res, image::ImageFormat::Png).await?;
+
+    // Parameters can't be added to a signed URL.
+    let res = app.get(format!("/v1/{}?{}&size=original", path, query))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let query = crate::signing::signed_query("secret", &path, "", expires - 120);
+    let res = app.get(format!("/v1/{}?{}", path, query))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let query = crate::signing::signed_query("wrong", &path, "", expires);
+    let res = app.get(format!("/v1/{}?{}", path, query))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    // Thumbor URLs need signing by the bucket as well.
+    let res = app.get(format!("/unsafe/{}", path))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let query = crate::signing::signed_query("secret", &path, "", expires);
+    let res = app.get(format!("/unsafe/{}?{}", path, query))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    // The images can't be listed without a signed URL to fetch them with.
+    let res = app.get("/v1/private")
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_key_rotation() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let create_key = |expires_in: Option<u64>| {
+        app.post("/admin/buckets/user-profiles/keys")
+            .body_json(&serde_json::json!({ "name": "gallery-service", "expires_in": expires_in }))
+            .send()
+    };
+
+    let upload = |key: String| {
+        app.post("/v1/user-profiles")
+            .header("authorization", format!("Bearer {}", key))
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+    };
+
+    let res = create_key(None).await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let old_id = json.value().object().get("key_id").string().to_string();
+    let old_key = json.value().object().get("key").string().to_string();
+
+    // Once a key exists uploads must be authorized.
+    upload("wrong".to_string()).await.assert_status(StatusCode::UNAUTHORIZED);
+    upload(old_key.clone()).await.assert_status_is_ok();
+
+    // Both keys are valid until the old key is revoked.
+    let res = create_key(Some(3600)).await;
+    res.assert_status_is_ok();
+    let new_key = res.json().await.value().object().get("key").string().to_string();
+    upload(old_key.clone()).await.assert_status_is_ok();
+    upload(new_key.clone()).await.assert_status_is_ok();
+
+    let res = app.get("/admin/buckets/user-profiles/keys")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.json().await.value().array().assert_len(2);
+
+    let res = app.delete(format!("/admin/buckets/user-profiles/keys/{}", old_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::NO_CONTENT);
+    upload(old_key).await.assert_status(StatusCode::UNAUTHORIZED);
+    upload(new_key).await.assert_status_is_ok();
+
+    let res = create_key(Some(0)).await;
+    res.assert_status_is_ok();
+    let expired_key = res.json().await.value().object().get("key").string().to_string();
+    upload(expired_key).await.assert_status(StatusCode::UNAUTHORIZED);
+
+    // Keys created by another instance sharing the storage backend are looked up on use.
+    use sha2::Digest;
+    let other_key = "lust_created-by-another-instance";
+    let hash: String = sha2::Sha256::digest(other_key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let stored = serde_json::json!({
+        "key_id": "other-instance",
+        "name": null,
+        "created_at": chrono::Utc::now().timestamp(),
+        "expires_at": null,
+        "hash": &hash,
+    });
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    bucket.storage()
+        .store_metadata(bucket.bucket_id(), &format!("api_keys/{}", hash), serde_json::to_vec(&stored)?.into())
+        .await?;
+    upload(other_key.to_string()).await.assert_status_is_ok();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bulk_url_signing() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("private", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "url_signing": { "key": "secret" },
+            "api_keys": ["bucket-key"],
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let mut image_ids = vec![];
+    for _ in 0..2 {
+        let res = app.post("/v1/private")
+            .header("authorization", "Bearer bucket-key")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        image_ids.push(res.json().await.value().object().get("image_id").string().to_string());
+    }
+
+    let body = serde_json::json!({ "image_ids": image_ids, "format": "png" });
+    let res = app.post("/v1/private/sign")
+        .body_json(&body)
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = app.post("/v1/private/sign")
+        .header("authorization", "Bearer bucket-key")
+        .body_json(&body)
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let urls = json.value().object().get("urls").array();
+    urls.assert_len(2);
+
+    for (i, image_id) in image_ids.iter().enumerate() {
+        let signed = urls.get(i).object();
+        signed.get("image_id").assert_string(image_id);
+
+        let res = app.get(signed.get("url").string())
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        res.assert_content_type("image/png");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_keys_required() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let bucket = serde_json::json!({
+        "mode": "jit",
+        "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+    });
+    let mut keyed = bucket.clone();
+    keyed["api_keys"] = serde_json::json!(["bucket-key"]);
+
+    let config = ConfigBuilder::new()
+        .bucket("open", bucket)
+        .bucket("keyed", keyed)
+        .option("api_keys", serde_json::json!(["global-key"]))
+        .build()?;
+    let app = client(config).await?;
+
+    let upload = |bucket: &'static str, key: Option<&'static str>| {
+        let mut req = app.post(format!("/v1/{}", bucket))
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64));
+        if let Some(key) = key {
+            req = req.header("authorization", format!("Bearer {}", key));
+        }
+        req.send()
+    };
+
+    // Global keys apply to every bucket.
+    upload("open", None).await.assert_status(StatusCode::UNAUTHORIZED);
+    upload("open", Some("global-key")).await.assert_status_is_ok();
+    upload("keyed", Some("global-key")).await.assert_status_is_ok();
+    upload("open", Some("bucket-key")).await.assert_status(StatusCode::UNAUTHORIZED);
+    upload("keyed", None).await.assert_status(StatusCode::UNAUTHORIZED);
+    upload("keyed", Some("wrong")).await.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = upload("keyed", Some("bucket-key")).await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.delete(format!("/v1/keyed/{}", image_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = app.post("/v1/keyed/purge")
+        .body_json(&serde_json::json!({ "tag": "user-1" }))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    // Authorized, but the bucket has no index to purge with.
+    let res = app.post("/v1/keyed/purge")
+        .header("authorization", "Bearer bucket-key")
+        .body_json(&serde_json::json!({ "tag": "user-1" }))
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    let job_id = uuid::Uuid::new_v4();
+    let res = app.get(format!("/v1/keyed/uploads/{}", job_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = app.get(format!("/v1/keyed/uploads/{}", job_id))
+        .header("authorization", "Bearer bucket-key")
+        .send()
+        .await;
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    let res = app.get(format!("/v1/keyed/purge/{}", job_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = app.get(format!("/v1/keyed/purge/{}", job_id))
+        .header("authorization", "Bearer bucket-key")
+        .send()
+        .await;
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    let res = app.get("/v1/keyed")
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = app.get("/v1/keyed")
+        .header("authorization", "Bearer bucket-key")
         .send()
         .await;
+    res.assert_status_is_ok();
+    res.json().await.value().object().get("images").array().assert_len(2);
 
+    let res = app.get(format!("/v1/keyed/{}/metadata", image_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    // Authorized, but the bucket has no index to store metadata in.
+    let res = app.get(format!("/v1/keyed/{}/metadata", image_id))
+        .header("authorization", "Bearer bucket-key")
+        .send()
+        .await;
     res.assert_status(StatusCode::BAD_REQUEST);
 
+    // Fetches don't need to be authorized.
+    let res = app.get(format!("/v1/keyed/{}", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    let res = app.delete(format!("/v1/keyed/{}", image_id))
+        .header("authorization", "Bearer bucket-key")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_jwt_scopes_enforced() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let bucket = serde_json::json!({
+        "mode": "jit",
+        "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+    });
+    let config = ConfigBuilder::new()
+        .bucket("tenant-a", bucket.clone())
+        .bucket("tenant-b", bucket)
+        .option("jwt", serde_json::json!({
+            "keys": [{ "algorithm": "HS256", "key": "secret" }],
+            "public_read": false,
+        }))
+        .option("thumbor", serde_json::json!({ "allow_unsafe": true }))
+        .build()?;
+    let app = client(config).await?;
+
+    let token = |key: &str| {
+        let claims = serde_json::json!({
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "scopes": { "tenant-a": ["read", "write"] },
+        });
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(key.as_bytes()),
+        )
+    };
+    let valid = format!("Bearer {}", token("secret")?);
+    let forged = format!("Bearer {}", token("wrong")?);
+
+    let upload = |bucket: &'static str, authorization: &str| {
+        app.post(format!("/v1/{}", bucket))
+            .header("authorization", authorization)
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+    };
+
+    upload("tenant-a", &forged).await.assert_status(StatusCode::UNAUTHORIZED);
+    upload("tenant-b", &valid).await.assert_status(StatusCode::FORBIDDEN);
+
+    let res = upload("tenant-a", &valid).await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/tenant-a/{}", image_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = app.get(format!("/v1/tenant-a/{}", image_id))
+        .header("authorization", &valid)
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    // Thumbor URLs need the read scope as well.
+    let res = app.get(format!("/unsafe/tenant-a/{}", image_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = app.get(format!("/unsafe/tenant-a/{}", image_id))
+        .header("authorization", &forged)
+        .send()
+        .await;
+    res.assert_status(StatusCode::UNAUTHORIZED);
+
+    let res = app.get(format!("/unsafe/tenant-a/{}", image_id))
+        .header("authorization", &valid)
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    // The token doesn't grant the delete scope.
+    let res = app.delete(format!("/v1/tenant-a/{}", image_id))
+        .header("authorization", &valid)
+        .send()
+        .await;
+    res.assert_status(StatusCode::FORBIDDEN);
+
+    let res = app.post("/v1/tenant-a/purge")
+        .header("authorization", &valid)
+        .body_json(&serde_json::json!({ "tag": "user-1" }))
+        .send()
+        .await;
+    res.assert_status(StatusCode::FORBIDDEN);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_reports_possible_duplicates() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("assets", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "index": { "flush_interval": 10, "duplicates": { "max_distance": 5 } },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let img = image::load_from_memory(TEST_IMAGE)?;
+    let mut reencoded = vec![];
+    img.write_to(&mut std::io::Cursor::new(&mut reencoded), image::ImageOutputFormat::Png)?;
+    let mut flipped = vec![];
+    img.flipv().write_to(&mut std::io::Cursor::new(&mut flipped), image::ImageOutputFormat::Png)?;
+
+    let upload = |data: Vec<u8>| {
+        app.post("/v1/assets")
+            .typed_header(headers::ContentLength(data.len() as u64))
+            .body(data)
+            .content_type("application/octet-stream")
+            .send()
+    };
+
+    let res = upload(TEST_IMAGE.to_vec()).await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let original_id = json.value().object().get("image_id").string().to_string();
+    json.value().object().get("possible_duplicates").array().assert_is_empty();
+
+    // The same image in another format is reported without blocking the upload.
+    let res = upload(reencoded).await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let duplicates = json.value().object().get("possible_duplicates").array();
+    duplicates.assert_len(1);
+    duplicates.get(0).assert_string(&original_id);
+
+    let res = upload(flipped).await;
+    res.assert_status_is_ok();
+    res.json().await.value().object().get("possible_duplicates").array().assert_is_empty();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_metadata_stored() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "index": { "flush_interval": 10 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .header("x-original-filename", "C:\\photos\\caf%C3%A9.jpeg")
+        .header("x-uploader", "user-42")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}/metadata", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let metadata = json.value().object();
+    metadata.get("image_id").assert_string(&image_id);
+    metadata.get("original_filename").assert_string("café.jpeg");
+    metadata.get("uploader").assert_string("user-42");
+    metadata.get("size").assert_i64(TEST_IMAGE.len() as i64);
+
+    let res = app.get(format!("/v1/user-profiles/{}/metadata", uuid::Uuid::new_v4()))
+        .send()
+        .await;
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .header("x-uploader", "x".repeat(300))
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_filename_content_disposition() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": true, "webp": false, "gif": false },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id))
+        .query("format", &"jpeg")
+        .query("filename", &"holiday.jpg")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_header("content-disposition", "inline; filename=\"holiday.jpg\"; filename*=UTF-8''holiday.jpg");
+
+    // Directories are stripped and the extension follows the served format.
+    let res = app.get(format!("/v1/user-profiles/{}", image_id))
+        .query("format", &"png")
+        .query("filename", &"../photos/\"beach\" día.jpg")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_header(
+        "content-disposition",
+        "inline; filename=\"_beach_ d_a.png\"; filename*=UTF-8''%22beach%22%20d%C3%ADa.png",
+    );
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id))
+        .query("filename", &"../")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_header_is_not_exist("content-disposition");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_thumbor_urls() -> anyhow::Result<()> {
+    use hmac::Mac;
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "realtime",
+            "formats": { "png": true, "jpeg": true, "webp": false, "gif": false },
+        }))
+        .option("thumbor", serde_json::json!({
+            "security_key": "secret",
+            "bucket": "user-profiles",
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id: uuid::Uuid = res.json().await.value().object().get("image_id").string().parse()?;
+
+    let sign = |url: &str| {
+        let mut mac = hmac::Hmac::<sha1::Sha1>::new_from_slice(b"secret").unwrap();
+        mac.update(url.as_bytes());
+        base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE)
+    };
+
+    let url = format!("fit-in/100x0/filters:format(png):quality(80)/user-profiles/{}.jpg", image_id);
+    let res = app.get(format!("/{}/{}", sign(&url), url))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/png");
+    let img = image::load_from_memory(&res.0.into_body().into_vec().await?)?;
+    assert_eq!(img.width(), 100);
+
+    // The default bucket is used when the image path is only the id.
+    let url = format!("50x50/{}", image_id);
+    let res = app.get(format!("/{}/{}", sign(&url), url))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    let res = app.get(format!("/unsafe/{}", url))
+        .send()
+        .await;
+    res.assert_status(StatusCode::FORBIDDEN);
+
+    let res = app.get(format!("/{}/60x60/{}", sign(&url), image_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::FORBIDDEN);
+
+    let url = format!("10x10:90x90/{}", image_id);
+    let res = app.get(format!("/{}/{}", sign(&url), url))
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_images_paginated() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "soft_delete": { "retention": 3600 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let mut image_ids = vec![];
+    for _ in 0..3 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        image_ids.push(res.json().await.value().object().get("image_id").string().to_string());
+    }
+    image_ids.sort();
+
+    let res = app.get("/v1/user-profiles")
+        .query("limit", &2)
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let page = json.value().object();
+    let images = page.get("images").object_array();
+    assert_eq!(images.len(), 2);
+    images[0].get("image_id").assert_string(&image_ids[0]);
+    images[1].get("image_id").assert_string(&image_ids[1]);
+    assert!(images[0].get("size").i64() > 0);
+    let cursor = page.get("next_cursor").string().to_string();
+    assert_eq!(cursor, image_ids[1]);
+
+    let res = app.get("/v1/user-profiles")
+        .query("limit", &2)
+        .query("cursor", &cursor)
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let page = json.value().object();
+    let images = page.get("images").object_array();
+    assert_eq!(images.len(), 1);
+    images[0].get("image_id").assert_string(&image_ids[2]);
+    if let Some(next_cursor) = page.get_opt("next_cursor") {
+        next_cursor.assert_null();
+    }
+
+    // Trashed images are skipped without cutting the page short.
+    for image_id in &image_ids[..2] {
+        let res = app.delete(format!("/v1/user-profiles/{}", image_id))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+    }
+
+    let res = app.get("/v1/user-profiles")
+        .query("limit", &1)
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let page = json.value().object();
+    let images = page.get("images").object_array();
+    assert_eq!(images.len(), 1);
+    images[0].get("image_id").assert_string(&image_ids[2]);
+    if let Some(next_cursor) = page.get_opt("next_cursor") {
+        next_cursor.assert_null();
+    }
+
+    let res = app.get("/v1/user-profiles")
+        .query("limit", &0)
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_realtime_resizing_expect_err() -> anyhow::Result<()> {
+    let app = setup_environment(REALTIME_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+
+    let file_id = info
+        .value()
+        .object()
+        .get("image_id")
+        .string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .query("width".to_string(), &"500".to_string())
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compressed_original_retrieval() -> anyhow::Result<()> {
+    let app = setup_environment(COMPRESSION_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+
+    let file_id = info
+        .value()
+        .object()
+        .get("image_id")
+        .string();
+
+    let stored = stored_image("user-profiles", file_id, config::ImageKind::Png, 0)
+        .await?
+        .expect("The original should be stored");
+    assert!(
+        crate::processor::compression::is_compressed(&stored),
+        "Expected the stored original to be zstd compressed",
+    );
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/jpeg");
+
+    validate_image_content(res, image::ImageFormat::Jpeg).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_precompressed_variant_served_when_accepted() -> anyhow::Result<()> {
+    let app = setup_environment(PRECOMPRESSION_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .header("accept-encoding", "gzip, zstd")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/png");
+    res.assert_header("content-encoding", "zstd");
+    res.assert_header("vary", "accept-encoding");
+
+    let body = res.0.into_body().into_bytes().await?;
+    let body = crate::processor::compression::maybe_decompress(body)?;
+    load_from_memory_with_format(&body, image::ImageFormat::Png)
+        .expect("Invalid image returned for expected format");
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .header("accept-encoding", "gzip, zstd;q=0")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    assert!(res.0.headers().get("content-encoding").is_none());
+    res.assert_header("vary", "accept-encoding");
+    validate_image_content(res, image::ImageFormat::Png).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_aot_missing_variant_regenerated() -> anyhow::Result<()> {
+    let app = setup_environment(AOT_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+
+    let file_id = info
+        .value()
+        .object()
+        .get("image_id")
+        .string();
+
+    let variant_path = format!(
+        "data/{}/{}/{}.webp",
+        crate::utils::crc_hash("user-profiles"),
+        crate::utils::crc_hash("medium-square"),
+        file_id,
+    );
+    tokio::fs::remove_file(&variant_path).await?;
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/webp");
+
+    validate_image_content(res, image::ImageFormat::WebP).await?;
+
+    crate::background::wait_until_idle().await;
+    assert!(
+        tokio::fs::metadata(&variant_path).await.is_ok(),
+        "Expected the missing variant to be persisted again",
+    );
+
+    Ok(())
+}
+
+fn chunked_body(data: Vec<u8>) -> poem::Body {
+    let chunks: Vec<Result<bytes::Bytes, std::io::Error>> = data
+        .chunks(64 << 10)
+        .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+        .collect();
+
+    poem::Body::from_bytes_stream(futures::stream::iter(chunks))
+}
+
+#[tokio::test]
+async fn test_chunked_upload_without_content_length() -> anyhow::Result<()> {
+    let app = setup_environment(UPLOAD_LIMIT_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(chunked_body(TEST_IMAGE.to_vec()))
+        .content_type("application/octet-stream")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    validate_image_content(res, image::ImageFormat::Jpeg).await?;
+
+    // The body is rejected once it exceeds the bucket's limit.
+    let res = app.post("/v1/user-profiles")
+        .body(chunked_body(TEST_IMAGE.repeat(2)))
+        .content_type("application/octet-stream")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_downscaled_to_resolution_cap() -> anyhow::Result<()> {
+    let app = setup_environment(RESOLUTION_CAP_CONFIG).await?;
+
+    let original = load_from_memory_with_format(TEST_IMAGE, image::ImageFormat::Jpeg)?;
+    assert!(original.width() > 64 || original.height() > 64);
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let body = res.0.into_body().into_bytes().await?;
+    let img = load_from_memory_with_format(&body, image::ImageFormat::Jpeg)?;
+    assert!(img.width() <= 64 && img.height() <= 64);
+    assert!(img.width() == 64 || img.height() == 64);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_converted_to_srgb() -> anyhow::Result<()> {
+    use img_parts::{ImageICC, png::Png};
+    use lcms2::{CIExyY, CIExyYTRIPLE, Profile, ToneCurve};
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "realtime",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "convert_to_srgb": true,
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    // A linear AdobeRGB-like profile, far enough from sRGB to shift every channel.
+    let xy = |x, y| CIExyY { x, y, Y: 1.0 };
+    let primaries = CIExyYTRIPLE {
+        Red: xy(0.64, 0.33),
+        Green: xy(0.21, 0.71),
+        Blue: xy(0.15, 0.06),
+    };
+    let linear = ToneCurve::new(1.0);
+    let profile = Profile::new_rgb(&xy(0.3127, 0.3290), &primaries, &[&linear, &linear, &linear])?;
+
+    let pixel = [60, 120, 180];
+    let img = image::RgbImage::from_pixel(8, 8, image::Rgb(pixel));
+    let mut encoded = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut encoded, image::ImageFormat::Png)?;
+
+    let mut png = Png::from_bytes(encoded.into_inner().into())?;
+    png.set_icc_profile(Some(profile.icc()?.into()));
+    let mut tagged = Vec::new();
+    png.encoder().write_to(&mut tagged)?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(tagged.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(tagged.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let body = res.0.into_body().into_bytes().await?;
+
+    assert!(Png::from_bytes(body.clone())?.icc_profile().is_none());
+    let converted = load_from_memory_with_format(&body, image::ImageFormat::Png)?.into_rgb8();
+    let converted = converted.get_pixel(0, 0).0;
+    assert!(converted.iter().zip(pixel).all(|(a, b)| *a != b), "{:?}", converted);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_alpha_policies() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let formats = serde_json::json!({ "png": true, "jpeg": true, "webp": false, "gif": false });
+    let config = ConfigBuilder::new()
+        .bucket("flatten", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+            "alpha": { "policy": "flatten", "background": "#ff0000" },
+        }))
+        .bucket("fallback", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+            "alpha": { "policy": "fallback" },
+        }))
+        .bucket("reject", serde_json::json!({
+            "mode": "aot",
+            "formats": formats,
+            "alpha": { "policy": "reject" },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let img = image::RgbaImage::from_pixel(8, 8, image::Rgba([0, 0, 255, 0]));
+    let mut transparent = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut transparent, image::ImageFormat::Png)?;
+    let transparent = transparent.into_inner();
+
+    let upload = |bucket: &str| app.post(format!("/v1/{}", bucket))
+        .body(transparent.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(transparent.len() as u64))
+        .send();
+
+    let res = upload("flatten").await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/flatten/{}", image_id))
+        .query("format", &"jpeg".to_string())
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/jpeg");
+    let body = res.0.into_body().into_bytes().await?;
+    let flattened = load_from_memory_with_format(&body, image::ImageFormat::Jpeg)?.into_rgb8();
+    let [r, g, b] = flattened.get_pixel(4, 4).0;
+    assert!(r > 200 && g < 50 && b < 50, "{:?}", (r, g, b));
+
+    let res = upload("fallback").await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/fallback/{}", image_id))
+        .query("format", &"jpeg".to_string())
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/png");
+
+    let res = upload("reject").await;
+    res.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_preset_size_budget() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": true, "webp": true, "gif": false },
+            "presets": {
+                "thumbnail": {
+                    "width": 512,
+                    "height": 512,
+                    "max_size": { "jpeg": 10, "webp": 8 },
+                },
+            },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    for (format, max_size) in [("jpeg", 10 << 10), ("webp", 8 << 10)] {
+        let res = app.get(format!("/v1/user-profiles/{}", image_id))
+            .query("size", &"thumbnail".to_string())
+            .query("format", &format.to_string())
+            .send()
+            .await;
+        res.assert_status_is_ok();
+
+        let body = res.0.into_body().into_bytes().await?;
+        assert!(body.len() <= max_size, "{} is {} bytes", format, body.len());
+        image::load_from_memory(&body)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_jit_fetch_encodes_sibling_formats() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": true, "webp": true, "gif": false },
+            "presets": { "thumbnail": { "width": 64, "height": 64 } },
+            "jit": { "encode_siblings": true },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id))
+        .query("size", &"thumbnail".to_string())
+        .query("format", &"webp".to_string())
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/webp");
+    crate::background::wait_until_idle().await;
+
+    // The delete reports every stored variant, i.e. the original and the siblings.
+    let res = app.delete(format!("/v1/user-profiles/{}", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let info = res.json().await;
+    let removed = info.value().object().get("removed").array();
+
+    let sizing_id = crate::utils::crc_hash("thumbnail") as i64;
+    let siblings = removed
+        .iter()
+        .filter(|v| v.object().get("sizing_id").i64() == sizing_id)
+        .count();
+    assert_eq!(siblings, 3, "Every enabled format of the variant should be stored");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conversion_metrics() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": true, "webp": true, "gif": false },
+            "presets": { "thumbnail": { "width": 64, "height": 64 } },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id))
+        .query("size", &"thumbnail".to_string())
+        .query("format", &"webp".to_string())
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    let res = app.get("/metrics")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let body = res.0.into_body().into_string().await?;
+
+    for (from, to, sizing) in [("jpeg", "png", "original"), ("png", "webp", "thumbnail")] {
+        let conversion = format!(
+            "lust_conversions_total{{bucket=\"user-profiles\",from=\"{}\",sizing=\"{}\",to=\"{}\"}} 1",
+            from, sizing, to,
+        );
+        assert!(body.contains(&conversion), "Missing {}", conversion);
+    }
+    assert!(body.contains("lust_conversion_duration_seconds_count{bucket=\"user-profiles\""));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_metrics() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    let res = app.get("/metrics")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let body = res.0.into_body().into_string().await?;
+
+    assert!(body.contains("lust_storage_duration_seconds_count{bucket=\"user-profiles\",operation=\"store\"} 1"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrency_metrics() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "max_concurrency": 4,
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    let res = app.get("/metrics")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let body = res.0.into_body().into_string().await?;
+
+    assert!(body.contains("lust_concurrency_queue_wait_seconds_count{bucket=\"user-profiles\",operation=\"upload\"} 1"));
+    assert!(
+        body.contains("lust_concurrency_permits_in_use{bucket=\"user-profiles\",operation=\"upload\"} 0"),
+        "The permits should be released once the upload completes",
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_original_preset_config() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let formats = serde_json::json!({ "png": true, "jpeg": true, "webp": true, "gif": false });
+    let presets = serde_json::json!({ "thumbnail": { "width": 64, "height": 64 } });
+    let config = ConfigBuilder::new()
+        .bucket("private", serde_json::json!({
+            "mode": "aot",
+            "formats": formats,
+            "presets": presets,
+            "serve_original": false,
+        }))
+        .bucket("unstored", serde_json::json!({
+            "mode": "aot",
+            "formats": formats,
+            "presets": presets,
+            "original": { "store": false },
+        }))
+        .bucket("webp-only", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+            "presets": presets,
+            "original": { "formats": ["webp"] },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let upload = |bucket: &str| app.post(format!("/v1/{}", bucket))
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send();
+
+    let res = upload("private").await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    for size in [None, Some("original")] {
+        let mut req = app.get(format!("/v1/private/{}", image_id));
+        if let Some(size) = size {
+            req = req.query("size", &size.to_string());
+        }
+        req.send().await.assert_status(StatusCode::FORBIDDEN);
+    }
+
+    let res = app.get(format!("/v1/private/{}", image_id))
+        .query("size", &"thumbnail".to_string())
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    // Originals remain accessible via the admin API.
+    let res = app.get(format!("/admin/buckets/private/images/{}/original", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/png");
+
+    let res = upload("unstored").await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.delete(format!("/v1/unstored/{}", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let info = res.json().await;
+    let removed = info.value().object().get("removed").array();
+    removed.assert_len(3);
+    assert!(removed.iter().all(|v| v.object().get("sizing_id").i64() != 0), "The original should not be stored");
+
+    let res = upload("webp-only").await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/webp-only/{}", image_id))
+        .header("accept", "image/png")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/webp");
+
+    let res = app.get(format!("/v1/webp-only/{}", image_id))
+        .query("format", &"png".to_string())
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    let res = app.get(format!("/v1/webp-only/{}", image_id))
+        .query("size", &"thumbnail".to_string())
+        .query("format", &"png".to_string())
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/png");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_processing_errors_status_codes() -> anyhow::Result<()> {
+    let app = setup_environment(JIT_CONFIG).await?;
+
+    // The magic bytes are guessed as PNG but the image itself can't be decoded.
+    let mut corrupt = vec![];
+    corrupt.extend_from_slice(b"\x89PNG\r\n\x1a\n");
+    corrupt.extend_from_slice(&[0; 64]);
+
+    let res = app.post("/v1/user-profiles")
+        .body(corrupt.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(corrupt.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Failures shared between idempotent uploads keep their status.
+    let res = app.post("/v1/user-profiles")
+        .body(corrupt.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(corrupt.len() as u64))
+        .header("idempotency-key", "corrupt-upload")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+
+    // The JPEG encoder doesn't support 16-bit colour.
+    let mut deep_colour = std::io::Cursor::new(vec![]);
+    image::DynamicImage::new_rgb16(16, 16).write_to(&mut deep_colour, image::ImageFormat::Png)?;
+    let deep_colour = deep_colour.into_inner();
+
+    let res = app.post("/v1/user-profiles")
+        .body(deep_colour.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(deep_colour.len() as u64))
+        .query("format", &"png".to_string())
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .query("format", &"jpeg".to_string())
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_harness_with_memory_backend() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": true, "webp": false, "gif": false },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .query("format", &"png".to_string())
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    validate_image_content(res, image::ImageFormat::Png).await?;
+
+    let res = app.delete(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bucket_response_headers() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let bucket = |headers: serde_json::Value| serde_json::json!({
+        "mode": "aot",
+        "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+        "missing_image": { "colour": "#000000" },
+        "response_headers": headers,
+    });
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", bucket(serde_json::json!({ "Content-Type": "text/html" })))
+        .build()?;
+    assert!(config::init_from(config).is_err(), "Expected lust's own headers to be reserved");
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", bucket(serde_json::json!({
+            "X-Content-Type-Options": "nosniff",
+            "Content-Security-Policy": "default-src 'none'",
+        })))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    assert!(res.0.headers().get("x-content-type-options").is_none());
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_header("x-content-type-options", "nosniff");
+    res.assert_header("content-security-policy", "default-src 'none'");
+    res.assert_content_type("image/png");
+
+    // Placeholders are fetch responses too.
+    let res = app.get(format!("/v1/user-profiles/{}", uuid::Uuid::new_v4()))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::NOT_FOUND);
+    res.assert_header("x-content-type-options", "nosniff");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replication_and_catch_up() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let target = std::env::temp_dir().join(format!("lust-replica-{}", uuid::Uuid::new_v4()));
+    let config = ConfigBuilder::new()
+        .option("replication", serde_json::json!({
+            "target": { "filesystem": { "directory": target } },
+            "concurrency": 2,
+        }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "index": { "flush_interval": 10 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let upload = || async {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        let info = res.json().await;
+        info.value().object().get("image_id").string().to_string()
+    };
+
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    let replica_path = |image_id: &str| {
+        target
+            .join(bucket.bucket_id().to_string())
+            .join("0")
+            .join(format!("{}.png", image_id))
+    };
+
+    let file_id = upload().await;
+    crate::replication::wait_until_idle().await;
+    assert!(replica_path(&file_id).exists(), "Expected the upload to be replicated");
+
+    let res = app.delete(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    crate::replication::wait_until_idle().await;
+    assert!(!replica_path(&file_id).exists(), "Expected the delete to be replicated");
+
+    // Changes which never reached the target are replicated by catching up.
+    let file_id = upload().await;
+    crate::replication::wait_until_idle().await;
+    std::fs::remove_file(replica_path(&file_id))?;
+
+    let report = crate::replication::catch_up(bucket).await?;
+    assert_eq!(report.copied, 1);
+    assert!(replica_path(&file_id).exists(), "Expected the catch up to restore the image");
+
+    let report = crate::replication::catch_up(bucket).await?;
+    assert_eq!(report.copied, 0);
+
+    let _ = std::fs::remove_dir_all(&target);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_import_from_remote_url() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    // A stand in for the CMS serving the image.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let origin = format!("http://{}", listener.local_addr()?);
+    let server = hyper::Server::from_tcp(listener)?.serve(hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|req: hyper::Request<hyper::Body>| async move {
+            let resp = match req.uri().path() {
+                "/photo.jpeg" => hyper::Response::new(hyper::Body::from(TEST_IMAGE)),
+                _ => hyper::Response::builder().status(404).body(hyper::Body::empty()).unwrap(),
+            };
+            Ok::<_, std::convert::Infallible>(resp)
+        }))
+    }));
+    tokio::spawn(server);
+
+    let config = ConfigBuilder::new()
+        .option("remote_uploads", serde_json::json!({ "allowed_hosts": ["127.0.0.1"] }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "index": { "flush_interval": 10 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let import = |url: String| {
+        app.post("/v1/user-profiles/import")
+            .body_json(&serde_json::json!({ "url": url }))
+            .send()
+    };
+
+    let res = import(format!("{}/photo.jpeg", origin)).await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}/metadata", image_id)).send().await;
+    res.assert_status_is_ok();
+    res.json().await.value().object().get("original_filename").assert_string("photo.jpeg");
+
+    import(format!("{}/missing.jpeg", origin)).await.assert_status(StatusCode::BAD_GATEWAY);
+    import(origin.replace("127.0.0.1", "localhost") + "/photo.jpeg").await.assert_status(StatusCode::FORBIDDEN);
+    import("ftp://127.0.0.1/photo.jpeg".to_string()).await.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replica_forwards_writes_to_primary() -> anyhow::Result<()> {
+    use std::sync::{Arc, Mutex};
+    use crate::testing::{client, ConfigBuilder};
+
+    // A stand in for the primary which records the requests forwarded to it.
+    let forwarded: Arc<Mutex<Vec<String>>> = Arc::default();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let primary_url = format!("http://{}", listener.local_addr()?);
+    let recorder = forwarded.clone();
+    let primary = hyper::Server::from_tcp(listener)?.serve(hyper::service::make_service_fn(move |_| {
+        let recorder = recorder.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                recorder.lock().unwrap().push(format!("{} {}", req.method(), req.uri()));
+                async { Ok::<_, std::convert::Infallible>(hyper::Response::new(hyper::Body::from("primary"))) }
+            }))
+        }
+    }));
+    tokio::spawn(primary);
+
+    let config = ConfigBuilder::new()
+        .option("replica", serde_json::json!({ "primary_url": primary_url }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status_is_ok();
+    res.assert_text("primary").await;
+
+    let job_id = uuid::Uuid::new_v4();
+    let res = app.get(format!("/v1/user-profiles/uploads/{}", job_id))
+        .send()
+        .await;
+    res.assert_text("primary").await;
+
+    // Fetches are served from the shared storage backend.
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    let image_id = match bucket.upload(config::ImageKind::Jpeg, TEST_IMAGE.to_vec(), Default::default()).await? {
+        crate::controller::UploadOutcome::Complete(info) => info.image_id(),
+        crate::controller::UploadOutcome::Pending(_) => panic!("Expected the upload to complete"),
+    };
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/png");
+
+    let res = app.delete(format!("/v1/user-profiles/{}", image_id))
+        .send()
+        .await;
+    res.assert_text("primary").await;
+
+    assert_eq!(*forwarded.lock().unwrap(), vec![
+        "POST /v1/user-profiles".to_string(),
+        format!("GET /v1/user-profiles/uploads/{}", job_id),
+        format!("DELETE /v1/user-profiles/{}", image_id),
+    ]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_import_s3_notifications() -> anyhow::Result<()> {
+    use crate::controller::UploadOutcome;
+    use crate::import::{find_source, ingest, parse_notification};
+    use crate::testing::{client, ConfigBuilder};
+
+    let record = serde_json::json!({
+        "Records": [
+            {
+                "eventName": "ObjectCreated:Put",
+                "s3": {
+                    "bucket": { "name": "partner-drops" },
+                    "object": { "key": "profiles/new+photo%281%29.jpeg", "size": 1024, "sequencer": "0A1B" },
+                },
+            },
+            {
+                "eventName": "ObjectRemoved:Delete",
+                "s3": {
+                    "bucket": { "name": "partner-drops" },
+                    "object": { "key": "profiles/old.jpeg", "sequencer": "0A1C" },
+                },
+            },
+        ],
+    });
+    let objects = parse_notification(&record.to_string())?;
+    assert_eq!(objects.len(), 1);
+    assert_eq!(objects[0].key, "profiles/new photo(1).jpeg");
+    assert_eq!(objects[0].size, 1024);
+
+    // Notifications published through SNS are wrapped in its envelope.
+    let wrapped = serde_json::json!({ "Type": "Notification", "Message": record.to_string() });
+    assert_eq!(parse_notification(&wrapped.to_string())?, objects);
+
+    let test_event = serde_json::json!({ "Service": "Amazon S3", "Event": "s3:TestEvent" });
+    assert!(parse_notification(&test_event.to_string())?.is_empty());
+
+    let config = ConfigBuilder::new()
+        .option("import", serde_json::json!({
+            "queue_url": "https://sqs.eu-west-1.amazonaws.com/123456789012/lust-imports",
+            "region": "eu-west-1",
+            "endpoint": "https://s3.eu-west-1.amazonaws.com",
+            "sources": [
+                { "s3_bucket": "partner-drops", "prefix": "profiles/", "bucket": "user-profiles", "tags": ["partner"] },
+            ],
+        }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "index": { "flush_interval": 10 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let sources = &crate::config::config().import.as_ref().unwrap().sources;
+    let source = find_source(sources, &objects[0]).expect("Expected the object to be watched");
+
+    let mut unwatched = objects[0].clone();
+    unwatched.key = "banners/new.jpeg".to_string();
+    assert!(find_source(sources, &unwatched).is_none());
+
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    let image_id = match ingest(bucket, source, &objects[0], TEST_IMAGE.to_vec()).await? {
+        Some(UploadOutcome::Complete(info)) => info.image_id(),
+        _ => panic!("Expected the object to be imported"),
+    };
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/png");
+
+    // Redelivered notifications resolve to the same image.
+    match ingest(bucket, source, &objects[0], TEST_IMAGE.to_vec()).await? {
+        Some(UploadOutcome::Complete(info)) => assert_eq!(info.image_id(), image_id),
+        _ => panic!("Expected the redelivered object to be imported"),
+    }
+
+    assert!(ingest(bucket, source, &objects[0], b"not an image".to_vec()).await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_watched_directory_imported() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let directory = std::env::temp_dir().join(format!("lust-watch-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&directory)?;
+
+    // Dropped while Lust wasn't running.
+    std::fs::write(directory.join("existing.jpeg"), TEST_IMAGE)?;
+
+    let config = ConfigBuilder::new()
+        .option("watch", serde_json::json!({
+            "directory": directory,
+            "bucket": "user-profiles",
+            "tags": ["print"],
+            "settle_time": 1,
+        }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "index": { "flush_interval": 10 },
+        }))
+        .build()?;
+    let _app = client(config).await?;
+    crate::watch::start()?;
+
+    // Transfers write to a hidden file which is renamed once complete.
+    std::fs::write(directory.join(".dropped.jpeg.part"), TEST_IMAGE)?;
+    std::fs::rename(directory.join(".dropped.jpeg.part"), directory.join("dropped.jpeg"))?;
+    std::fs::write(directory.join("notes.txt"), b"not an image")?;
+
+    let processed = directory.join("processed");
+    let failed = directory.join("failed");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(120);
+    while !(processed.join("existing.jpeg").exists() && processed.join("dropped.jpeg").exists() && failed.join("notes.txt").exists()) {
+        assert!(std::time::Instant::now() < deadline, "Expected the dropped files to be handled");
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+    }
+
+    assert!(failed.join("notes.txt.error").exists());
+    assert!(!directory.join("existing.jpeg").exists());
+    assert!(!directory.join("dropped.jpeg").exists());
+    assert!(!directory.join("notes.txt").exists());
+
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    let images = bucket.indexed_images().await?.unwrap();
+    assert_eq!(images.len(), 2);
+    assert!(images.values().all(|record| record.tags == ["print"]));
+
+    std::fs::remove_dir_all(&directory)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backend_validation_roundtrip() -> anyhow::Result<()> {
+    let backend = crate::storage::backends::BackendConfigs::Memory.connect().await?;
+    crate::storage::validate_roundtrip(backend.as_ref()).await?;
+
+    Ok(())
+}
+
+/// A backend which fails the given number of operations with its fault
+/// before passing them through to the in-memory backend.
+struct FaultyBackend {
+    inner: std::sync::Arc<dyn crate::StorageBackend>,
+    fault: fn() -> anyhow::Error,
+    faults: std::sync::atomic::AtomicU32,
+    calls: std::sync::atomic::AtomicU32,
+}
+
+impl FaultyBackend {
+    async fn new(fault: fn() -> anyhow::Error) -> anyhow::Result<std::sync::Arc<Self>> {
+        Ok(std::sync::Arc::new(Self {
+            inner: crate::storage::backends::BackendConfigs::Memory.connect().await?,
+            fault,
+            faults: Default::default(),
+            calls: Default::default(),
+        }))
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        use std::sync::atomic::Ordering;
+
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        let failed = self.faults
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+
+        if failed {
+            return Err((self.fault)())
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::StorageBackend for FaultyBackend {
+    async fn store(&self, bucket_id: u32, image_id: uuid::Uuid, kind: config::ImageKind, sizing_id: u32, data: bytes::Bytes) -> anyhow::Result<()> {
+        self.check()?;
+        self.inner.store(bucket_id, image_id, kind, sizing_id, data).await
+    }
+
+    async fn fetch(&self, bucket_id: u32, image_id: uuid::Uuid, kind: config::ImageKind, sizing_id: u32) -> anyhow::Result<Option<bytes::Bytes>> {
+        self.check()?;
+        self.inner.fetch(bucket_id, image_id, kind, sizing_id).await
+    }
+
+    async fn delete(&self, bucket_id: u32, image_id: uuid::Uuid) -> anyhow::Result<Vec<(u32, config::ImageKind)>> {
+        self.check()?;
+        self.inner.delete(bucket_id, image_id).await
+    }
+
+    async fn list_variants(&self, bucket_id: u32, image_id: uuid::Uuid) -> anyhow::Result<Vec<(u32, config::ImageKind)>> {
+        self.check()?;
+        self.inner.list_variants(bucket_id, image_id).await
+    }
+
+    async fn list_ids(&self, bucket_id: u32, after: Option<uuid::Uuid>, limit: usize) -> anyhow::Result<Vec<crate::storage::template::StoredImage>> {
+        self.check()?;
+        self.inner.list_ids(bucket_id, after, limit).await
+    }
+
+    async fn delete_variant(&self, bucket_id: u32, image_id: uuid::Uuid, kind: config::ImageKind, sizing_id: u32) -> anyhow::Result<()> {
+        self.check()?;
+        self.inner.delete_variant(bucket_id, image_id, kind, sizing_id).await
+    }
+
+    async fn store_metadata(&self, bucket_id: u32, key: &str, data: bytes::Bytes) -> anyhow::Result<()> {
+        self.check()?;
+        self.inner.store_metadata(bucket_id, key, data).await
+    }
+
+    async fn fetch_metadata(&self, bucket_id: u32, key: &str) -> anyhow::Result<Option<bytes::Bytes>> {
+        self.check()?;
+        self.inner.fetch_metadata(bucket_id, key).await
+    }
+
+    async fn delete_metadata(&self, bucket_id: u32, key: &str) -> anyhow::Result<()> {
+        self.check()?;
+        self.inner.delete_metadata(bucket_id, key).await
+    }
+
+    async fn list_metadata(&self, bucket_id: u32, dir: &str, after: Option<&str>, limit: usize) -> anyhow::Result<Vec<String>> {
+        self.check()?;
+        self.inner.list_metadata(bucket_id, dir, after, limit).await
+    }
+}
+
+#[tokio::test]
+async fn test_throttled_storage_retried() -> anyhow::Result<()> {
+    use std::sync::atomic::Ordering;
+    use crate::storage::resilience::ResilientBackend;
+    use crate::storage::StorageThrottled;
+    use crate::StorageBackend;
+
+    let stub = FaultyBackend::new(|| {
+        let retry_after = Some(std::time::Duration::from_secs(2));
+        StorageThrottled { retry_after }.into()
+    }).await?;
+    let retry = config::StorageRetryConfig {
+        max_retries: 2,
+        base_delay: 1,
+        max_delay: 5,
+    };
+    let backend = ResilientBackend::new(stub.clone(), retry, Default::default());
+
+    // Throttles within the retry limit are retried transparently.
+    let image_id = uuid::Uuid::new_v4();
+    stub.faults.store(2, Ordering::Relaxed);
+    backend.store(1, image_id, config::ImageKind::Png, 0, bytes::Bytes::from_static(b"image")).await?;
+    assert_eq!(stub.calls.load(Ordering::Relaxed), 3);
+
+    // Once the retries are exhausted the throttling is surfaced as a 503.
+    stub.faults.store(u32::MAX, Ordering::Relaxed);
+    let err = backend.fetch(1, image_id, config::ImageKind::Png, 0).await.unwrap_err();
+    assert!(err.is::<StorageThrottled>());
+    assert_eq!(stub.calls.load(Ordering::Relaxed), 6);
+
+    let resp = crate::routes::processing_error(err).into_response();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers().get("retry-after").and_then(|v| v.to_str().ok()), Some("2"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_circuit_breaker() -> anyhow::Result<()> {
+    use std::sync::atomic::Ordering;
+    use crate::storage::resilience::ResilientBackend;
+    use crate::storage::StorageUnavailable;
+    use crate::StorageBackend;
+
+    let stub = FaultyBackend::new(|| anyhow::anyhow!("Connection refused")).await?;
+    let breaker = config::CircuitBreakerConfig {
+        failure_threshold: 2,
+        cooldown: 1,
+    };
+    let backend = ResilientBackend::new(stub.clone(), Default::default(), breaker);
+
+    // Misses aren't failures so never trip the breaker.
+    let image_id = uuid::Uuid::new_v4();
+    for _ in 0..3 {
+        assert!(backend.fetch(1, image_id, config::ImageKind::Png, 0).await?.is_none());
+    }
+    backend.store(1, image_id, config::ImageKind::Png, 0, bytes::Bytes::from_static(b"image")).await?;
+
+    // Sustained failures open the breaker, failing without calling the backend.
+    stub.faults.store(u32::MAX, Ordering::Relaxed);
+    for _ in 0..2 {
+        assert!(backend.fetch(1, image_id, config::ImageKind::Png, 0).await.is_err());
+    }
+
+    let calls = stub.calls.load(Ordering::Relaxed);
+    let err = backend.fetch(1, image_id, config::ImageKind::Png, 0).await.unwrap_err();
+    assert!(err.is::<StorageUnavailable>());
+    assert_eq!(stub.calls.load(Ordering::Relaxed), calls, "The open breaker should not call the backend.");
+
+    let resp = crate::routes::processing_error(err).into_response();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers().get("retry-after").and_then(|v| v.to_str().ok()), Some("1"));
+
+    // Once the cooldown passes a successful probe closes the breaker.
+    stub.faults.store(0, Ordering::Relaxed);
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let data = backend.fetch(1, image_id, config::ImageKind::Png, 0).await?;
+    assert_eq!(data.as_deref(), Some(&b"image"[..]));
+    backend.fetch(1, image_id, config::ImageKind::Png, 0).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stale_cached_copy_served_on_storage_failure() -> anyhow::Result<()> {
+    use crate::testing::{client, set_storage_unavailable, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "cache": { "max_images": 10, "ttl": 1, "stale_if_error": 60 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    assert!(res.0.headers().get("warning").is_none());
+
+    // Once expired the cached copy is only served if the backend fails.
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    set_storage_unavailable(true);
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_header("warning", "110 - \"Response is Stale\"");
+    validate_image_content(res, image::ImageFormat::Png).await?;
+
+    let res = app.get(format!("/v1/user-profiles/{}", uuid::Uuid::new_v4()))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+
+    set_storage_unavailable(false);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_blob_storage_ca_bundle_validated() -> anyhow::Result<()> {
+    use crate::storage::backends::BackendConfigs;
+
+    let dir = std::env::temp_dir().join(format!("lust-ca-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let bundle = dir.join("ca.pem");
+    std::fs::write(&bundle, "-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydA==\n-----END CERTIFICATE-----\n")?;
+
+    let backend = |ca_bundle: &std::path::Path| -> anyhow::Result<BackendConfigs> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "blobstorage": {
+                "name": "images",
+                "region": "us-east-1",
+                "endpoint": "https://minio.internal:9000",
+                "addressing": "virtual_hosted",
+                "ca_bundle": ca_bundle,
+            },
+        }))?)
+    };
+
+    let invalid = backend(&bundle)?.connect().await;
+    assert!(invalid.is_err(), "An invalid CA certificate should be rejected");
+
+    let missing = backend(&dir.join("missing.pem"))?.connect().await;
+    assert!(missing.is_err(), "A missing CA bundle should be rejected");
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_blob_storage_requests_time_out() -> anyhow::Result<()> {
+    use std::time::Duration;
+    use crate::storage::backends::BackendConfigs;
+    use crate::storage::StorageThrottled;
+
+    std::env::set_var("AWS_ACCESS_KEY_ID", "test");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "test");
+
+    // An endpoint which accepts requests but never responds.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    let server = hyper::Server::from_tcp(listener)?.serve(hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|_req: hyper::Request<hyper::Body>| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok::<_, std::convert::Infallible>(hyper::Response::new(hyper::Body::empty()))
+        }))
+    }));
+    tokio::spawn(server);
+
+    let backend: BackendConfigs = serde_json::from_value(serde_json::json!({
+        "blobstorage": {
+            "name": "images",
+            "region": "us-east-1",
+            "endpoint": endpoint,
+            "timeouts": { "read": 100 },
+            "max_in_flight": 1,
+        },
+    }))?;
+    let backend = backend.connect().await?;
+
+    let err = tokio::time::timeout(
+        Duration::from_secs(5),
+        backend.fetch_metadata(1, "api_keys"),
+    ).await?.expect_err("The hung request should time out");
+    assert!(err.is::<StorageThrottled>(), "Timed out requests should be retried as throttled");
+
+    Ok(())
+}
+
+/// Exercises the blob storage backend against a real S3 compatible service like MinIO.
+///
+/// Credentials are read from the usual `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+/// environment variables and the bucket must already exist.
+#[cfg(feature = "s3-tests")]
+#[tokio::test]
+async fn test_blob_storage_backend() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let env = |key: &str| std::env::var(key).map_err(|_| anyhow::anyhow!("{} must be set", key));
+    let backend = serde_json::json!({
+        "blobstorage": {
+            "name": env("LUST_TEST_S3_BUCKET")?,
+            "region": env("LUST_TEST_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            "endpoint": env("LUST_TEST_S3_ENDPOINT")?,
+            "addressing": env("LUST_TEST_S3_ADDRESSING").unwrap_or_else(|_| "path".to_string()),
+        },
+    });
+
+    let config = ConfigBuilder::new()
+        .backend(backend)
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": true, "webp": false, "gif": false },
+            "presets": { "small": { "width": 32, "height": 32 } },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    crate::validate_backend().await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .query("format", &"png".to_string())
+        .query("size", &"small".to_string())
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    validate_image_content(res, image::ImageFormat::Png).await?;
+
+    let res = app.delete(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_profiling() -> anyhow::Result<()> {
+    let app = setup_full_environment(PROFILING_CONFIG).await?;
+
+    let profile = app.post("/admin/profile/cpu")
+        .query("seconds", &1)
+        .send();
+
+    let workload = async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // Only a single capture can run at once.
+        let res = app.post("/admin/profile/cpu")
+            .query("seconds", &1)
+            .send()
+            .await;
+        res.assert_status(StatusCode::CONFLICT);
+
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+        res.assert_status(StatusCode::OK);
+    };
+
+    let (res, _) = tokio::join!(profile, workload);
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/svg+xml");
+    let body = res.0.into_body().into_string().await?;
+    assert!(body.contains("<svg"), "The profile should be rendered as a flamegraph");
+
+    let res = app.get("/admin/profile/heap")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let stats = res.json().await;
+    stats.value().object().get("allocator").assert_string(crate::allocator::name());
+    stats.value().object().get("resident").i64();
+
+    Ok(())
+}
+
+fn animated_gif(frames: usize, delay_ms: u32) -> anyhow::Result<Vec<u8>> {
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, RgbaImage};
+
+    let mut buff = vec![];
+    {
+        let mut encoder = GifEncoder::new(&mut buff);
+        for i in 0..frames {
+            let pixels = RgbaImage::from_pixel(8, 8, image::Rgba([(i * 40) as u8, 0, 0, 255]));
+            let delay = Delay::from_numer_denom_ms(delay_ms, 1);
+            encoder.encode_frame(Frame::from_parts(pixels, 0, 0, delay))?;
+        }
+    }
+
+    Ok(buff)
+}
+
+#[tokio::test]
+async fn test_upload_permits_weighted_by_size() -> anyhow::Result<()> {
+    use std::time::Duration;
+    use crate::admission::{ConcurrencyLimiter, Operation, Priority};
+    use crate::config::PermitWeights;
+
+    let weights = PermitWeights {
+        upload_size_step: Some(1024),
+        ..Default::default()
+    };
+    let limiter = ConcurrencyLimiter::new(Some(10), None, None, weights);
+
+    // A 5MB upload consumes 6 of the 10 permits.
+    let _large = limiter.acquire(Operation::Upload, 5 * 1024 * 1024, Priority::Normal).await?;
+
+    let blocked = tokio::time::timeout(
+        Duration::from_millis(50),
+        limiter.acquire(Operation::Upload, 5 * 1024 * 1024, Priority::Normal),
+    ).await;
+    assert!(blocked.is_err(), "A second large upload should wait for permits");
+
+    let small = tokio::time::timeout(
+        Duration::from_millis(50),
+        limiter.acquire(Operation::Upload, 1024, Priority::Normal),
+    ).await;
+    assert!(small.is_ok(), "A small upload should still be admitted");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_normal_priority_admitted_first() -> anyhow::Result<()> {
+    use std::time::Duration;
+    use crate::admission::{ConcurrencyLimiter, Operation, Priority};
+
+    let limiter = std::sync::Arc::new(ConcurrencyLimiter::new(Some(1), None, None, Default::default()));
+    let held = limiter.acquire(Operation::Fetch, 0, Priority::Normal).await?;
+
+    let (tx, mut admitted) = tokio::sync::mpsc::unbounded_channel();
+    for priority in [Priority::Low, Priority::Normal] {
+        let limiter = limiter.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let _permit = limiter.acquire(Operation::Fetch, 0, priority).await;
+            let _ = tx.send(priority);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // The low priority operation queued first but is admitted last.
+    drop(held);
+    assert_eq!(admitted.recv().await, Some(Priority::Normal));
+    assert_eq!(admitted.recv().await, Some(Priority::Low));
+
+    // Operations which give up waiting never hold up the queue.
+    let held = limiter.acquire(Operation::Fetch, 0, Priority::Normal).await?;
+    let abandoned = tokio::time::timeout(
+        Duration::from_millis(20),
+        limiter.acquire(Operation::Fetch, 0, Priority::Normal),
+    ).await;
+    assert!(abandoned.is_err());
+    drop(held);
+
+    let next = tokio::time::timeout(
+        Duration::from_millis(50),
+        limiter.acquire(Operation::Fetch, 0, Priority::Low),
+    ).await;
+    assert!(next.is_ok(), "Abandoned operations should release their place in the queue");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_priority_header_requires_authorization() -> anyhow::Result<()> {
+    use poem::{handler, EndpointExt, Route};
+    use crate::admission::{current_priority, prioritize};
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+        }))
+        .option("api_keys", serde_json::json!(["internal-key"]))
+        .option("max_low_priority_concurrency", serde_json::json!(1))
+        .build()?;
+    let _ = client(config).await?;
+
+    #[handler]
+    fn priority() -> String {
+        format!("{:?}", current_priority())
+    }
+
+    let app = poem::test::TestClient::new(Route::new().at("/:bucket", priority).around(prioritize));
+
+    let res = app.get("/user-profiles")
+        .header("x-lust-priority", "low")
+        .header("authorization", "Bearer internal-key")
+        .send()
+        .await;
+    res.assert_text("Low").await;
+
+    // The header is ignored for unauthorized requests.
+    let res = app.get("/user-profiles")
+        .header("x-lust-priority", "low")
+        .header("authorization", "Bearer wrong")
+        .send()
+        .await;
+    res.assert_text("Normal").await;
+
+    let res = app.get("/user-profiles").send().await;
+    res.assert_text("Normal").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_animation_limits_reject_and_truncate() -> anyhow::Result<()> {
+    let app = setup_environment(ANIMATION_LIMITS_CONFIG).await?;
+
+    let within_limits = animated_gif(3, 100)?;
+    let res = app.post("/v1/user-profiles")
+        .body(within_limits.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(within_limits.len() as u64))
+        .query("format", &"gif".to_string())
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+
+    let too_long = animated_gif(10, 100)?;
+    let res = app.post("/v1/user-profiles")
+        .body(too_long.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(too_long.len() as u64))
+        .query("format", &"gif".to_string())
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+
+    // The truncating bucket accepts it, keeping only the frames within the limit.
+    let truncated = crate::processor::animation::enforce_limits(
+        config::config().buckets["banners"].animation_limits.unwrap(),
+        config::ImageKind::Gif,
+        too_long.clone(),
+    )?;
+    let decoder = image::codecs::gif::GifDecoder::new(std::io::Cursor::new(truncated))?;
+    assert_eq!(image::AnimationDecoder::into_frames(decoder).count(), 2);
+
+    let res = app.post("/v1/banners")
+        .body(too_long.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(too_long.len() as u64))
+        .query("format", &"gif".to_string())
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+
+    Ok(())
+}
+
+fn multipart_part(body: &mut Vec<u8>, file_name: &str, content_type: Option<&str>, data: &[u8]) {
+    multipart_field(body, "files", Some(file_name), content_type, data)
+}
+
+fn multipart_field(body: &mut Vec<u8>, name: &str, file_name: Option<&str>, content_type: Option<&str>, data: &[u8]) {
+    body.extend_from_slice(b"--BOUNDARY\r\n");
+    match file_name {
+        None => body.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"\r\n", name).as_bytes()),
+        Some(file_name) => body.extend_from_slice(
+            format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n", name, file_name).as_bytes(),
+        ),
+    }
+    if let Some(content_type) = content_type {
+        body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+    }
+    body.extend_from_slice(b"\r\n");
+    body.extend_from_slice(data);
+    body.extend_from_slice(b"\r\n");
+}
+
+#[tokio::test]
+async fn test_multipart_upload() -> anyhow::Result<()> {
+    let app = setup_environment(JIT_CONFIG).await?;
+
+    let form = |format: &str| {
+        let mut body = vec![];
+        multipart_field(&mut body, "format", None, None, format.as_bytes());
+        multipart_field(&mut body, "file", Some("photo.bin"), None, TEST_IMAGE);
+        body.extend_from_slice(b"--BOUNDARY--\r\n");
+        app.post("/v1/user-profiles")
+            .body(body)
+            .content_type("multipart/form-data; boundary=BOUNDARY")
+            .send()
+    };
+
+    let res = form("jpeg").await;
+    res.assert_status_is_ok();
+    res.json().await.value().object().get("image_id").string();
+
+    // The format field is validated like the `format` query parameter.
+    form("png").await.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_upload_per_part_format_hints() -> anyhow::Result<()> {
+    let app = setup_environment(JIT_CONFIG).await?;
+
+    let mut body = vec![];
+    multipart_part(&mut body, "a.jpeg", Some("image/jpeg"), TEST_IMAGE);
+    multipart_part(&mut body, "b.png", Some("application/octet-stream"), TEST_IMAGE);
+    multipart_part(&mut body, "c.bin", None, b"not an image");
+    multipart_part(&mut body, "d", None, TEST_IMAGE);
+    body.extend_from_slice(b"--BOUNDARY--\r\n");
+
+    let res = app.post("/v1/user-profiles/batch")
+        .body(body)
+        .content_type("multipart/form-data; boundary=BOUNDARY")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let results = info.value().array();
+    results.assert_len(4);
+
+    // Hinted by the part's content type.
+    results.get(0).object().get("format").assert_string("jpeg");
+    results.get(0).object().get("upload").object().get("image_id").string();
+
+    // Hinted by the filename extension but the content doesn't match.
+    results.get(1).object().get("file_name").assert_string("b.png");
+    results.get(1).object().get("upload").assert_null();
+    results.get(1).object().get("error").string();
+
+    // No hint and the format can't be guessed.
+    results.get(2).object().get("error").string();
+
+    // No hint so the format is guessed from the content.
+    results.get(3).object().get("format").assert_string("jpeg");
+    results.get(3).object().get("upload").object().get("image_id").string();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idempotent_upload() -> anyhow::Result<()> {
+    let app = setup_environment(JIT_CONFIG).await?;
+
+    let mut image_ids = vec![];
+    for _ in 0..2 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .header("idempotency-key", "my-upload-key")
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        let info = res.json().await;
+
+        let file_id = info
+            .value()
+            .object()
+            .get("image_id")
+            .string()
+            .to_string();
+        image_ids.push(file_id);
+    }
+
+    assert_eq!(image_ids[0], image_ids[1], "Expected retries to map to the same image");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_idempotent_retry_keeps_image() -> anyhow::Result<()> {
+    use crate::testing::{client, set_storage_stores_failing, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            // Keys are forgotten as soon as the upload completes.
+            "idempotency_key_ttl": 0,
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let upload = || app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .header("idempotency-key", "my-upload-key")
+        .send();
+
+    let res = upload().await;
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    // Once the key is forgotten a failed retry must not roll back the original.
+    set_storage_stores_failing(true);
+    let res = upload().await;
+    set_storage_stores_failing(false);
+    assert!(res.0.status().is_server_error());
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    validate_image_content(res, image::ImageFormat::Png).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_duplicate_uploads_reuse_encodes() -> anyhow::Result<()> {
+    let app = setup_environment(ENCODER_CACHE_CONFIG).await?;
+
+    let mut images = vec![];
+    for _ in 0..2 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        let info = res.json().await;
+
+        let file_id = info
+            .value()
+            .object()
+            .get("image_id")
+            .string()
+            .to_string();
+
+        let res = app.get(format!("/v1/user-profiles/{}", file_id))
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        res.assert_content_type("image/webp");
+        images.push((file_id, res.0.into_body().into_bytes().await?));
+    }
+
+    assert_ne!(images[0].0, images[1].0, "Expected duplicate uploads to get distinct ids");
+    assert_eq!(images[0].1, images[1].1, "Expected duplicate uploads to share encodes");
+
+    let lookups = |result| crate::metrics::ENCODER_CACHE_REQUESTS
+        .with_label_values(&["user-profiles", "upload", result])
+        .get();
+    assert_eq!(lookups("miss"), 1);
+    assert_eq!(lookups("hit"), 1, "Expected the duplicate upload to hit the encoder cache");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_egress_limit_redirects() -> anyhow::Result<()> {
+    let app = setup_environment(EGRESS_LIMIT_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+
+    let file_id = info
+        .value()
+        .object()
+        .get("image_id")
+        .string()
+        .to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    // The limit is checked before serving so is hit within a few fetches.
+    for _ in 0..5 {
+        let res = app.get(format!("/v1/user-profiles/{}", file_id))
+            .send()
+            .await;
+
+        if res.0.status() == StatusCode::FOUND {
+            res.assert_header("location", "https://example.com/placeholder.png");
+            return Ok(())
+        }
+
+        res.assert_status(StatusCode::OK);
+    }
+
+    Err(anyhow::anyhow!("Expected the egress limit to be exceeded"))
+}
+
+#[tokio::test]
+async fn test_processing_rules_skip_variants() -> anyhow::Result<()> {
+    let app = setup_environment(PROCESSING_RULES_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+
+    let file_id = info
+        .value()
+        .object()
+        .get("image_id")
+        .string()
+        .to_string();
+
+    let small = stored_image("user-profiles", &file_id, config::ImageKind::Jpeg, crate::utils::crc_hash("small")).await?;
+    let huge = stored_image("user-profiles", &file_id, config::ImageKind::Jpeg, crate::utils::crc_hash("huge")).await?;
+    let webp = stored_image("user-profiles", &file_id, config::ImageKind::Webp, 0).await?;
+    assert!(small.is_some(), "Expected the small preset to be stored");
+    assert!(huge.is_none(), "Expected the upscaled preset to be skipped");
+    assert!(webp.is_none(), "Expected the webp variant to be skipped");
+
+    let fetches_processed = || crate::metrics::PROCESSING_DURATION
+        .with_label_values(&["user-profiles", "fetch"])
+        .get_sample_count();
+    let processed = fetches_processed();
+
+    for _ in 0..2 {
+        let res = app.get(format!("/v1/user-profiles/{}", file_id))
+            .query("size".to_string(), &"huge")
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        res.assert_content_type("image/jpeg");
+        validate_image_content(res, image::ImageFormat::Jpeg).await?;
+    }
+    assert_eq!(
+        fetches_processed() - processed, 1,
+        "Expected the skipped preset to be served from the cache once processed",
+    );
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .query("format".to_string(), &"webp")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/jpeg");
+    validate_image_content(res, image::ImageFormat::Jpeg).await?;
+
+    crate::background::wait_until_idle().await;
+    let huge = stored_image("user-profiles", &file_id, config::ImageKind::Jpeg, crate::utils::crc_hash("huge")).await?;
+    assert!(huge.is_none(), "Expected skipped variants to not be persisted");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_slow_upload_accepted_and_polled() -> anyhow::Result<()> {
+    let app = setup_environment(ASYNC_UPLOAD_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::ACCEPTED);
+    let status_url = res.0
+        .headers()
+        .get("location")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .ok_or_else(|| anyhow::anyhow!("Expected a status url"))?;
+
+    crate::background::wait_until_idle().await;
+
+    let res = app.get(&status_url)
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let job = info.value().object();
+    job.get("status").assert_string("complete");
+
+    let file_id = job
+        .get("upload")
+        .object()
+        .get("image_id")
+        .string()
+        .to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("image/webp");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_missing_image_placeholder() -> anyhow::Result<()> {
+    let app = setup_environment(MISSING_IMAGE_CONFIG).await?;
+
+    let res = app.get(format!("/v1/user-profiles/{}", uuid::Uuid::new_v4()))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::GONE);
+    res.assert_content_type("image/png");
+
+    let body = res.0.into_body().into_bytes().await?;
+    let img = image::load_from_memory_with_format(&body, image::ImageFormat::Png)?;
+    assert_eq!((img.width(), img.height()), (8, 4));
+    assert_eq!(img.to_rgba8().get_pixel(0, 0).0, [255, 0, 0, 255]);
+
+    Ok(())
+}
+
+#[test]
+fn test_client_ip_from_trusted_proxies() -> anyhow::Result<()> {
+    use poem::http::HeaderMap;
+    use crate::proxy::{resolve_client_ip, IpRange};
+
+    let trusted: Vec<IpRange> = vec!["10.0.0.0/8".parse()?, "192.168.1.1".parse()?];
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", "203.0.113.7, 10.1.2.3".parse()?);
+
+    let proxy = "192.168.1.1".parse()?;
+    assert_eq!(resolve_client_ip(&trusted, proxy, &headers), "203.0.113.7".parse::<std::net::IpAddr>()?);
+
+    // Headers sent directly by untrusted clients are ignored.
+    let untrusted = "198.51.100.1".parse()?;
+    assert_eq!(resolve_client_ip(&trusted, untrusted, &headers), untrusted);
+
+    headers.insert("forwarded", r#"for="[2001:db8::17]:4711";proto=https, for=10.0.0.1"#.parse()?);
+    assert_eq!(resolve_client_ip(&trusted, proxy, &headers), "2001:db8::17".parse::<std::net::IpAddr>()?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_list_buckets() -> anyhow::Result<()> {
+    let app = setup_admin_environment(JIT_CONFIG).await?;
+
+    let res = app.get("/admin/buckets")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let buckets = info.value().array();
+    buckets.assert_len(1);
+
+    let bucket = buckets.get(0).object();
+    bucket.get("name").assert_string("user-profiles");
+    bucket.get("mode").assert_string("jit");
+    bucket.get("bucket_id").assert_i64(crate::utils::crc_hash("user-profiles") as i64);
+
+    let res = app.get("/metrics")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let body = res.0.into_body().into_string().await?;
+    assert!(body.contains(&format!(
+        "lust_allocator_memory_bytes{{allocator=\"{}\",stat=\"resident\"}}",
+        crate::allocator::name(),
+    )));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_token_required() -> anyhow::Result<()> {
+    let app = setup_admin_environment(JIT_CONFIG).await?;
+    crate::admin::init_token("admin-secret".to_string());
+
+    for token in [None, Some("Bearer wrong")] {
+        let mut req = app.get("/admin/buckets");
+        if let Some(token) = token {
+            req = req.header("authorization", token);
+        }
+
+        req.send().await.assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    let res = app.get("/admin/buckets")
+        .header("authorization", "Bearer admin-secret")
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    // Prometheus scrapes the metrics endpoint without the admin token.
+    let res = app.get("/metrics")
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_bucket_presets() -> anyhow::Result<()> {
+    let app = setup_admin_environment(REALTIME_CONFIG).await?;
+
+    let res = app.get("/admin/buckets/user-profiles/presets")
+        .query("sizes".to_string(), &"800x600")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let registry = info.value().object();
+    registry.get("original_sizing_id").assert_i64(0);
+
+    let presets = registry.get("presets").array();
+    presets.assert_len(1);
+    let preset = presets.get(0).object();
+    preset.get("name").assert_string("medium-square");
+    preset.get("sizing_id").assert_i64(crate::utils::crc_hash("medium-square") as i64);
+    preset.get("width").assert_i64(500);
+
+    let custom = registry.get("custom").array();
+    custom.assert_len(1);
+    custom.get(0).object().get("sizing_id").assert_i64(crate::utils::crc_hash((800u32, 600u32)) as i64);
+
+    let res = app.get("/admin/buckets/user-profiles/presets")
+        .query("sizes".to_string(), &"800-600")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_access_stats_counted_and_flushed() -> anyhow::Result<()> {
+    let app = setup_full_environment(ACCESS_STATS_CONFIG).await?;
+
+    let mut image_ids = vec![];
+    for _ in 0..2 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        let info = res.json().await;
+        image_ids.push(info.value().object().get("image_id").string().to_string());
+    }
+
+    for (image_id, fetches) in image_ids.iter().zip([3, 1]) {
+        for _ in 0..fetches {
+            let res = app.get(format!("/v1/user-profiles/{}", image_id))
+                .send()
+                .await;
+
+            res.assert_status(StatusCode::OK);
+        }
+    }
+
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    bucket.flush_metadata().await?;
+
+    let stats_file = format!("access_counts/{}", crate::metadata::instance_id());
+    let stats = bucket.storage().fetch_metadata(bucket.bucket_id(), &stats_file).await?;
+    assert!(stats.is_some(), "Access stats should be flushed to the backend");
+
+    // The counts flushed by other instances are summed with this instance's.
+    let other = serde_json::json!({ &image_ids[1]: 2 });
+    bucket.storage()
+        .store_metadata(bucket.bucket_id(), "access_counts/other-instance", serde_json::to_vec(&other)?.into())
+        .await?;
+
+    // Fetches after the flush are still reported alongside the persisted counts.
+    let res = app.get(format!("/v1/user-profiles/{}", &image_ids[1]))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app.get("/admin/buckets/user-profiles/stats")
+        .query("limit".to_string(), &1)
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let top = info.value().array();
+    top.assert_len(1);
+    top.get(0).object().get("image_id").assert_string(&image_ids[1]);
+    top.get(0).object().get("fetches").assert_i64(4);
+
+    let res = app.get(format!("/admin/buckets/user-profiles/stats/{}", &image_ids[0]))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    info.value().object().get("fetches").assert_i64(3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lifecycle_rules_apply_to_idle_images() -> anyhow::Result<()> {
+    let app = setup_environment(LIFECYCLE_CONFIG).await?;
+
+    let mut image_ids = vec![];
+    for _ in 0..3 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        let info = res.json().await;
+        image_ids.push(info.value().object().get("image_id").string().to_string());
+    }
+
+    let res = app.get(format!("/v1/user-profiles/{}", &image_ids[0]))
+        .query("size".to_string(), &"small".to_string())
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+    crate::background::wait_until_idle().await;
+
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    bucket.flush_metadata().await?;
+
+    // Pretend the first image has been idle for 40 days and the second for 90 days.
+    let now = chrono::Utc::now().timestamp();
+    for (image_id, idle_days) in image_ids.iter().zip([40, 90]) {
+        let record_key = format!("access_records/{}", image_id);
+        let stored = bucket.storage().fetch_metadata(bucket.bucket_id(), &record_key).await?.unwrap();
+        let mut record: serde_json::Value = serde_json::from_slice(&stored)?;
+        record["last_access"] = (now - idle_days * 24 * 60 * 60).into();
+        bucket.storage().store_metadata(bucket.bucket_id(), &record_key, serde_json::to_vec(&record)?.into()).await?;
+    }
+
+    let report = crate::lifecycle::run(bucket, false).await?;
+    assert_eq!(report.deleted.len(), 1);
+    assert_eq!(report.tiered_down.len(), 1);
+
+    let exists = |sizing_id: u32, image_id: &str| {
+        let image_id = image_id.to_string();
+        async move {
+            stored_image("user-profiles", &image_id, config::ImageKind::Jpeg, sizing_id)
+                .await
+                .unwrap()
+                .is_some()
+        }
+    };
+    let small = crate::utils::crc_hash("small");
+    assert!(!exists(small, &image_ids[0]).await, "Variants of idle images should be dropped");
+    assert!(exists(0, &image_ids[0]).await, "The original of tiered down images should be kept");
+    assert!(!exists(0, &image_ids[1]).await, "Images idle for too long should be deleted");
+    assert!(exists(0, &image_ids[2]).await, "Recently accessed images should be kept");
+
+    let res = app.get(format!("/v1/user-profiles/{}", &image_ids[0]))
+        .query("size".to_string(), &"small".to_string())
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app.get(format!("/v1/user-profiles/{}", &image_ids[1]))
+        .send()
+        .await;
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    // The first image has now been fetched so is no longer idle.
+    let report = crate::lifecycle::run(bucket, false).await?;
+    assert!(report.deleted.is_empty());
+    assert!(report.tiered_down.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lifecycle_dry_run_and_eviction() -> anyhow::Result<()> {
+    let app = setup_full_environment(LIFECYCLE_RULES_CONFIG).await?;
+
+    let mut image_ids = vec![];
+    for _ in 0..4 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        let info = res.json().await;
+        image_ids.push(info.value().object().get("image_id").string().to_string());
+    }
+
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    bucket.flush_metadata().await?;
+
+    // The first image was uploaded 40 days ago but is still in use, the second
+    // and third images are the least recently used.
+    let now = chrono::Utc::now().timestamp();
+    let day = 24 * 60 * 60;
+    for (image_id, field, days) in [(&image_ids[0], "uploaded_at", 40), (&image_ids[1], "last_access", 10), (&image_ids[2], "last_access", 5)] {
+        let record_key = format!("access_records/{}", image_id);
+        let stored = bucket.storage().fetch_metadata(bucket.bucket_id(), &record_key).await?.unwrap();
+        let mut record: serde_json::Value = serde_json::from_slice(&stored)?;
+        record[field] = (now - days * day).into();
+        bucket.storage().store_metadata(bucket.bucket_id(), &record_key, serde_json::to_vec(&record)?.into()).await?;
+    }
+
+    let mut evicted = vec![image_ids[1].as_str(), image_ids[2].as_str()];
+    evicted.sort_unstable();
+
+    let original = |image_id: &str| {
+        let image_id = image_id.to_string();
+        async move {
+            stored_image("user-profiles", &image_id, config::ImageKind::Jpeg, 0)
+                .await
+                .unwrap()
+        }
+    };
+
+    // The bucket defaults to dry runs.
+    let res = app.post("/admin/buckets/user-profiles/lifecycle")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let report = res.json().await;
+    let report = report.value().object();
+    report.get("dry_run").assert_bool(true);
+    report.get("deleted").assert_string_array(&evicted);
+    report.get("archived").assert_string_array(&[image_ids[0].as_str()]);
+    for image_id in &image_ids {
+        assert!(original(image_id).await.is_some(), "Dry runs should not remove images");
+    }
+
+    let res = app.post("/admin/buckets/user-profiles/lifecycle")
+        .query("dry_run".to_string(), &false)
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let report = res.json().await;
+    let report = report.value().object();
+    report.get("dry_run").assert_bool(false);
+    report.get("deleted").assert_string_array(&evicted);
+
+    assert!(original(&image_ids[1]).await.is_none());
+    assert!(original(&image_ids[2]).await.is_none());
+    assert!(original(&image_ids[3]).await.is_some());
+
+    let archived = original(&image_ids[0]).await.expect("Archived originals should be kept");
+    assert!(crate::processor::compression::is_compressed(&archived), "Archived originals should be compressed");
+
+    let res = app.get(format!("/v1/user-profiles/{}", &image_ids[0]))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+    validate_image_content(res, image::ImageFormat::Jpeg).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_reports_removed_variants() -> anyhow::Result<()> {
+    let app = setup_environment(AOT_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    // Cache the default variant.
+    let res = app.get(format!("/v1/user-profiles/{}", &file_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    // A variant of a preset which has since been removed from the config.
+    let bucket_id = crate::utils::crc_hash("user-profiles");
+    let stray_dir = format!("data/{}/1234", bucket_id);
+    std::fs::create_dir_all(&stray_dir)?;
+    std::fs::write(format!("{}/{}.jpeg", stray_dir, &file_id), TEST_IMAGE)?;
+
+    let res = app.delete(format!("/v1/user-profiles/{}", &file_id))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let removed = info.value().object().get("removed").array();
+
+    // The original and preset in 3 formats each, plus the stray variant.
+    removed.assert_len(7);
+    let stray_removed = removed
+        .iter()
+        .any(|v| v.object().get("sizing_id").i64() == 1234);
+    assert!(stray_removed, "Variants of removed presets should also be deleted");
+
+    let res = app.get(format!("/v1/user-profiles/{}", &file_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conditional_replace_and_delete() -> anyhow::Result<()> {
+    let app = setup_environment(JIT_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let etag = res.0.headers().get("etag").unwrap().to_str()?.to_string();
+    assert_eq!(etag, format!("\"{:08x}\"", crc32fast::hash(TEST_IMAGE)));
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let mut replacement = vec![];
+    load_from_memory_with_format(TEST_IMAGE, image::ImageFormat::Jpeg)?
+        .write_to(&mut std::io::Cursor::new(&mut replacement), image::ImageFormat::Png)?;
+
+    let res = app.put(format!("/v1/user-profiles/{}", &file_id))
+        .body(replacement.clone())
+        .content_type("application/octet-stream")
+        .header("if-match", "\"deadbeef\"")
+        .typed_header(headers::ContentLength(replacement.len() as u64))
+        .send()
+        .await;
+    res.assert_status(StatusCode::PRECONDITION_FAILED);
+
+    let res = app.put(format!("/v1/user-profiles/{}", &file_id))
+        .body(replacement.clone())
+        .content_type("application/octet-stream")
+        .header("if-match", &etag)
+        .typed_header(headers::ContentLength(replacement.len() as u64))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+    let new_etag = res.0.headers().get("etag").unwrap().to_str()?.to_string();
+    assert_ne!(etag, new_etag, "Replacing the image should change its etag");
+
+    // A writer still holding the original etag must not clobber the replacement.
+    let res = app.delete(format!("/v1/user-profiles/{}", &file_id))
+        .header("if-match", &etag)
+        .send()
+        .await;
+    res.assert_status(StatusCode::PRECONDITION_FAILED);
+
+    let res = app.get(format!("/v1/user-profiles/{}", &file_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app.delete(format!("/v1/user-profiles/{}", &file_id))
+        .header("if-match", &new_etag)
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app.put(format!("/v1/user-profiles/{}", &file_id))
+        .body(replacement.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(replacement.len() as u64))
+        .send()
+        .await;
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deleted_jit_image_not_regenerated() -> anyhow::Result<()> {
+    let app = setup_environment(JIT_CONFIG).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let file_id = info.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", &file_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+    let variant = res.0.into_body().into_bytes().await?;
+    crate::background::wait_until_idle().await;
+
+    let res = app.delete(format!("/v1/user-profiles/{}", &file_id))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    // Simulate a derived copy surviving the delete.
+    let bucket_id = crate::utils::crc_hash("user-profiles");
+    let variant_dir = format!("data/{}/{}", bucket_id, crate::utils::crc_hash("medium-square"));
+    std::fs::create_dir_all(&variant_dir)?;
+    std::fs::write(format!("{}/{}.jpeg", variant_dir, &file_id), &variant)?;
+
+    for format in ["jpeg", "png"] {
+        let res = app.get(format!("/v1/user-profiles/{}", &file_id))
+            .query("format".to_string(), &format.to_string())
+            .send()
+            .await;
+        res.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    assert!(std::path::Path::new(&format!("data/{}/metadata/deleted/{}", bucket_id, &file_id)).exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_bucket_preview() -> anyhow::Result<()> {
+    let app = setup_full_environment(INDEX_CONFIG).await?;
+
+    let mut image_ids = vec![];
+    for _ in 0..2 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        let info = res.json().await;
+        image_ids.push(info.value().object().get("image_id").string().to_string());
+    }
+
+    let res = app.get("/admin/buckets/user-profiles/preview")
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let page = info.value().object();
+    page.get("total").assert_i64(2);
+
+    let images = page.get("images").array();
+    images.assert_len(2);
+    let url = images.get(0).object().get("url").string().to_string();
+    assert!(url.ends_with("?size=thumbnail"), "The preview should use the smallest preset");
+
+    let res = app.get(&url)
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app.get("/admin/buckets/user-profiles/preview")
+        .query("html".to_string(), &true)
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    res.assert_content_type("text/html; charset=utf-8");
+    let html = res.0.into_body().into_string().await?;
+    assert!(image_ids.iter().all(|id| html.contains(id.as_str())));
+
+    let res = app.get("/admin/buckets/user-profiles/preview")
+        .query("page".to_string(), &1)
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    info.value().object().get("images").array().assert_len(0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_purge_by_filter() -> anyhow::Result<()> {
+    let app = setup_environment(INDEX_CONFIG).await?;
+
+    let mut image_ids = vec![];
+    for tags in ["user-1,avatars", "user-1", "user-2"] {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .query("tags".to_string(), &tags.to_string())
+            .send()
+            .await;
+
+        res.assert_status(StatusCode::OK);
+        let info = res.json().await;
+        image_ids.push(info.value().object().get("image_id").string().to_string());
+    }
+
+    let res = app.post("/v1/user-profiles/purge")
+        .body_json(&serde_json::json!({ "dry_run": true }))
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    let mut matched = vec![image_ids[0].as_str(), image_ids[1].as_str()];
+    matched.sort_unstable();
+
+    let res = app.post("/v1/user-profiles/purge")
+        .body_json(&serde_json::json!({ "tag": "user-1", "dry_run": true }))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let info = info.value().object();
+    info.get("matched").assert_i64(2);
+    info.get("images").assert_string_array(&matched);
+
+    let res = app.get(format!("/v1/user-profiles/{}", &image_ids[0]))
+        .send()
+        .await;
+    res.assert_status(StatusCode::OK);
+
+    let res = app.post("/v1/user-profiles/purge")
+        .body_json(&serde_json::json!({
+            "tag": "user-1",
+            "uploaded_before": (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339(),
+        }))
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::ACCEPTED);
+    res.assert_header_exist("location");
+    let location = res.0.headers().get("location").unwrap().to_str()?.to_string();
+
+    crate::background::wait_until_idle().await;
+
+    let res = app.get(location)
+        .send()
+        .await;
+
+    res.assert_status(StatusCode::OK);
+    let info = res.json().await;
+    let info = info.value().object();
+    info.get("status").assert_string("complete");
+    info.get("deleted").assert_i64(2);
+    info.get("failed").assert_i64(0);
+
+    for (image_id, status) in image_ids.iter().zip([StatusCode::NOT_FOUND, StatusCode::NOT_FOUND, StatusCode::OK]) {
+        let res = app.get(format!("/v1/user-profiles/{}", image_id))
+            .send()
+            .await;
+        res.assert_status(status);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_scylla_session_config() -> anyhow::Result<()> {
+    use crate::storage::backends::BackendConfigs;
+
+    let backend: BackendConfigs = serde_json::from_value(serde_json::json!({
+        "scylla": {
+            "nodes": ["127.0.0.1:9042"],
+            "keyspace": "lust",
+            "consistency": "local_one",
+            "local_datacenter": "eu-west",
+            "compression": "lz4",
+            "request_timeout": 2000,
+            "connections_per_shard": 2,
+        }
+    }))?;
+
+    match backend {
+        BackendConfigs::Scylla { session, .. } => {
+            assert_eq!(format!("{:?}", session.consistency), "LocalOne");
+            assert_eq!(session.local_datacenter.as_deref(), Some("eu-west"));
+            assert!(session.token_aware, "Token awareness should be enabled by default");
+            assert_eq!(format!("{:?}", session.compression), "Some(Lz4)");
+            assert_eq!(session.request_timeout, Some(2000));
+            assert_eq!(session.connections_per_shard, Some(2));
+        },
+        other => panic!("Expected a scylla backend config, got {:?}", other),
+    }
+
+    let invalid = serde_json::from_value::<BackendConfigs>(serde_json::json!({
+        "scylla": { "nodes": ["127.0.0.1:9042"], "keyspace": "lust", "consistency": "most" }
+    }));
+    assert!(invalid.is_err(), "Unknown consistency levels should be rejected");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_image_ids() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let formats = serde_json::json!({ "png": true, "jpeg": false, "webp": false, "gif": false });
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+            "custom_ids": { "slug_pattern": "^[a-z0-9-]{1,32}$" },
+        }))
+        .bucket("generated", serde_json::json!({ "mode": "jit", "formats": formats }))
+        .build()?;
+    let app = client(config).await?;
+
+    let upload = |bucket: &str, query: &str, header: Option<&str>| {
+        let mut req = app.post(format!("/v1/{}{}", bucket, query))
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64));
+        if let Some(id) = header {
+            req = req.header("x-image-id", id);
+        }
+        req.send()
+    };
+
+    let custom_id = "6d3b9d0c-2f4e-4a8e-9a0b-3f1c2d4e5f60";
+    let res = upload("user-profiles", &format!("?image_id={}", custom_id), None).await;
+    res.assert_status_is_ok();
+    res.json().await.value().object().get("image_id").assert_string(custom_id);
+
+    let res = app.get(format!("/v1/user-profiles/{}", custom_id)).send().await;
+    res.assert_status_is_ok();
+
+    // Re-using the id of an existing image is rejected.
+    upload("user-profiles", &format!("?image_id={}", custom_id), None)
+        .await
+        .assert_status(StatusCode::CONFLICT);
+
+    // Slugs resolve to the same id every time.
+    let res = upload("user-profiles", "", Some("summer-banner")).await;
+    res.assert_status_is_ok();
+    let slug_id = res.json().await.value().object().get("image_id").string().to_string();
+    assert_ne!(slug_id, custom_id);
+    upload("user-profiles", "?image_id=summer-banner", None)
+        .await
+        .assert_status(StatusCode::CONFLICT);
+
+    upload("user-profiles", "?image_id=Not%20A%20Slug", None)
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+
+    // Concurrent retries of the same upload both get the original response.
+    let retry = || {
+        app.post("/v1/user-profiles?image_id=autumn-banner")
+            .header("idempotency-key", "autumn-banner-upload")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+    };
+    let (first, second) = tokio::join!(retry(), retry());
+    first.assert_status_is_ok();
+    second.assert_status_is_ok();
+    let first_id = first.json().await.value().object().get("image_id").string().to_string();
+    second.json().await.value().object().get("image_id").assert_string(&first_id);
+
+    // Buckets must opt in to custom ids.
+    upload("generated", &format!("?image_id={}", custom_id), None)
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_filesystem_free_space_watermark() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let directory = std::env::temp_dir().join(format!("lust-watermark-{}", uuid::Uuid::new_v4()));
+    let config = ConfigBuilder::new()
+        .backend(serde_json::json!({
+            "filesystem": {
+                "directory": directory,
+                // No disk has an exabyte free, so uploads are always refused.
+                "free_space_watermark": { "min_free_space": 1u64 << 40 },
+            }
+        }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status(StatusCode::INSUFFICIENT_STORAGE);
+
+    assert_eq!(crate::metrics::STORAGE_LOW_SPACE.get(), 1);
+    assert_eq!(crate::metrics::STORAGE_LOW_SPACE_REJECTIONS.get(), 1);
+
+    // Nothing is written once the upload is refused.
+    assert!(!directory.join(crate::utils::crc_hash("user-profiles").to_string()).exists());
+
+    let _ = std::fs::remove_dir_all(&directory);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_id_schemes() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let formats = serde_json::json!({ "png": true, "jpeg": false, "webp": false, "gif": false });
+    let config = ConfigBuilder::new()
+        .bucket("ulids", serde_json::json!({ "mode": "jit", "formats": formats, "id_scheme": "ulid" }))
+        .bucket("snowflakes", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+            "id_scheme": { "snowflake": { "worker_id": 5 } },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let upload = |bucket: &'static str| {
+        let req = app.post(format!("/v1/{}", bucket))
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64));
+        async move {
+            let res = req.send().await;
+            res.assert_status_is_ok();
+            let image_id = res.json().await.value().object().get("image_id").string().to_string();
+            image_id.parse::<uuid::Uuid>().unwrap().as_u128()
+        }
+    };
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_millis();
+    let first = upload("ulids").await;
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    let second = upload("ulids").await;
+    assert!(first < second, "ULIDs should be time sortable");
+    assert!((first >> 80).abs_diff(now) < 60_000, "ULIDs should start with the upload time");
+
+    let first = upload("snowflakes").await;
+    let second = upload("snowflakes").await;
+    assert!(first < second, "Snowflakes should be time sortable");
+    assert_eq!(first as u64, 0, "Snowflakes only use the first 64 bits of the id");
+    assert_eq!((first >> 64 >> 12) & 0x3ff, 5, "The snowflake should contain the worker id");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_filesystem_content_addressable() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let directory = std::env::temp_dir().join(format!("lust-dedup-{}", uuid::Uuid::new_v4()));
+    let config = ConfigBuilder::new()
+        .backend(serde_json::json!({
+            "filesystem": { "directory": directory, "content_addressable": true }
+        }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "presets": { "small": { "width": 32, "height": 32 } },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let count_blobs = || {
+        let blobs = directory.join("blobs");
+        std::fs::read_dir(&blobs)
+            .map(|dirs| {
+                dirs.flatten()
+                    .map(|dir| std::fs::read_dir(dir.path()).map(|v| v.count()).unwrap_or_default())
+                    .sum::<usize>()
+            })
+            .unwrap_or_default()
+    };
+
+    let upload = || async {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        res.json().await.value().object().get("image_id").string().to_string()
+    };
+
+    let first = upload().await;
+    let blobs = count_blobs();
+    assert!(blobs > 0, "Variants should be stored as blobs");
+
+    // The duplicate's variants link to the existing blobs.
+    let second = upload().await;
+    assert_ne!(first, second);
+    assert_eq!(count_blobs(), blobs);
+
+    app.delete(format!("/v1/user-profiles/{}", first)).send().await.assert_status_is_ok();
+    assert_eq!(count_blobs(), blobs, "Blobs still linked to an image should be kept");
+    app.get(format!("/v1/user-profiles/{}", second)).send().await.assert_status_is_ok();
+
+    app.delete(format!("/v1/user-profiles/{}", second)).send().await.assert_status_is_ok();
+    assert_eq!(count_blobs(), 0, "Unlinked blobs should be removed");
+
+    let _ = std::fs::remove_dir_all(&directory);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_soft_delete_and_restore() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "soft_delete": { "retention": 0 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    app.delete(format!("/v1/user-profiles/{}", image_id)).send().await.assert_status_is_ok();
+    app.get(format!("/v1/user-profiles/{}", image_id)).send().await.assert_status(StatusCode::NOT_FOUND);
+
+    let res = app.get("/v1/user-profiles").send().await;
+    res.assert_status_is_ok();
+    res.json().await.value().object().get("images").array().assert_len(0);
+
+    let restore = || app.post(format!("/v1/user-profiles/{}/restore", image_id)).send();
+    restore().await.assert_status(StatusCode::NO_CONTENT);
+    app.get(format!("/v1/user-profiles/{}", image_id)).send().await.assert_status_is_ok();
+
+    // Only trashed images can be restored.
+    restore().await.assert_status(StatusCode::NOT_FOUND);
+
+    app.delete(format!("/v1/user-profiles/{}", image_id)).send().await.assert_status_is_ok();
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    let purged = bucket.reap_trash().await?;
+    assert_eq!(purged, vec![image_id.parse::<uuid::Uuid>()?]);
+
+    restore().await.assert_status(StatusCode::NOT_FOUND);
+    app.get(format!("/v1/user-profiles/{}", image_id)).send().await.assert_status(StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_processing_pool_runs_at_low_priority() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .option("processing_pool", serde_json::json!({ "threads": 1, "niceness": 12 }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": true, "webp": false, "gif": false },
+            "presets": { "small": { "width": 32, "height": 32 } },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let niceness = || unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        libc::getpriority(libc::PRIO_PROCESS, tid)
+    };
+    assert_eq!(crate::processor::pool::run(niceness).await?, 12);
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    rayon::spawn(move || tx.send(niceness()).unwrap());
+    assert_eq!(rx.recv()?, 12);
+
+    // A single thread is enough to both run the pipeline and encode.
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_copy_and_move_between_buckets() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("staging", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+        }))
+        .bucket("production", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": false, "jpeg": false, "webp": true, "gif": false },
+            "presets": { "thumbnail": { "width": 64, "height": 64 } },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/staging")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.post(format!("/v1/staging/{}/copy", image_id))
+        .query("to", &"production".to_string())
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let copy_id = res.json().await.value().object().get("image_id").string().to_string();
+    assert_ne!(copy_id, image_id, "Copies should be given a new id by default");
+
+    let res = app.get(format!("/v1/production/{}", copy_id))
+        .query("size", &"thumbnail".to_string())
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.assert_content_type("image/webp");
+    app.get(format!("/v1/staging/{}", image_id)).send().await.assert_status_is_ok();
+
+    let res = app.post(format!("/v1/staging/{}/move", image_id))
+        .query("to", &"production".to_string())
+        .query("keep_id", &true)
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    res.json().await.value().object().get("image_id").assert_string(&image_id);
+
+    app.get(format!("/v1/staging/{}", image_id)).send().await.assert_status(StatusCode::NOT_FOUND);
+    app.get(format!("/v1/production/{}", image_id)).send().await.assert_status_is_ok();
+
+    let res = app.post(format!("/v1/production/{}/copy", image_id))
+        .query("to", &"production".to_string())
+        .query("keep_id", &true)
+        .send()
+        .await;
+    res.assert_status(StatusCode::CONFLICT);
+
+    let res = app.post(format!("/v1/staging/{}/copy", image_id))
+        .query("to", &"production".to_string())
+        .send()
+        .await;
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_decode_limits() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let formats = serde_json::json!({ "png": true, "jpeg": false, "webp": false, "gif": false });
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+        }))
+        .bucket("thumbnails", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+            "decode_limits": { "max_width": 1024 },
+        }))
+        .option("decode_limits", serde_json::json!({ "max_height": 4096 }))
+        .build()?;
+    let app = client(config).await?;
+
+    let upload = |bucket: &str, format: Option<&str>| {
+        let mut req = app.post(format!("/v1/{}", bucket))
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64));
+        if let Some(format) = format {
+            req = req.query("format", &format.to_string());
+        }
+        req.send()
+    };
+
+    // The example image is 1898x3051.
+    upload("user-profiles", None).await.assert_status_is_ok();
+    upload("thumbnails", None).await.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+    upload("thumbnails", Some("jpeg")).await.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_upload_quarantine() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let formats = serde_json::json!({ "png": true, "jpeg": false, "webp": false, "gif": false });
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+            "quarantine": { "max_size": 1 },
+        }))
+        .bucket("thumbnails", serde_json::json!({
+            "mode": "jit",
+            "formats": formats,
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let payload = b"definitely not a png".to_vec();
+    let res = app.post("/v1/user-profiles")
+        .body(payload.clone())
+        .content_type("application/octet-stream")
+        .query("format", &"png".to_string())
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    let res = app.get("/admin/buckets/user-profiles/quarantine").send().await;
+    res.assert_status_is_ok();
+    let json = res.json().await;
+    let uploads = json.value().array();
+    uploads.assert_len(1);
+    let upload = uploads.get(0).object();
+    upload.get("format").assert_string("png");
+    upload.get("size").assert_i64(payload.len() as i64);
+    upload.get("error").assert_string("The image is not a valid Png image.");
+    let upload_id = upload.get("upload_id").string().to_string();
+
+    let res = app.get(format!("/admin/buckets/user-profiles/quarantine/{}", upload_id)).send().await;
+    res.assert_status_is_ok();
+    res.assert_bytes(payload).await;
+
+    app.delete(format!("/admin/buckets/user-profiles/quarantine/{}", upload_id))
+        .send()
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    let res = app.get("/admin/buckets/user-profiles/quarantine").send().await;
+    res.json().await.value().array().assert_len(0);
+
+    // Uploads larger than the whole quarantine are not kept.
+    let res = app.post("/v1/user-profiles")
+        .body(vec![0u8; 2 * 1024 * 1024])
+        .content_type("application/octet-stream")
+        .send()
+        .await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+    let res = app.get("/admin/buckets/user-profiles/quarantine").send().await;
+    res.json().await.value().array().assert_len(0);
+
+    let res = app.get("/admin/buckets/thumbnails/quarantine").send().await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_strip_metadata() -> anyhow::Result<()> {
+    use img_parts::{ImageEXIF, jpeg::Jpeg};
+    use crate::testing::{client, ConfigBuilder};
+
+    let formats = serde_json::json!({ "png": false, "jpeg": true, "webp": false, "gif": false });
+    let config = ConfigBuilder::new()
+        .bucket("stripped", serde_json::json!({
+            "mode": "realtime",
+            "formats": formats,
+        }))
+        .bucket("copyright", serde_json::json!({
+            "mode": "realtime",
+            "formats": formats,
+            "preserve_metadata": { "copyright": true },
+        }))
+        .bucket("untouched", serde_json::json!({
+            "mode": "realtime",
+            "formats": formats,
+            "strip_metadata": false,
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    // IFD0 with an image description and a copyright notice.
+    let mut exif = b"II*\0\x08\0\0\0\x02\0".to_vec();
+    exif.extend_from_slice(b"\x0e\x01\x02\0\x07\0\0\0\x26\0\0\0");
+    exif.extend_from_slice(b"\x98\x82\x02\0\x05\0\0\0\x2d\0\0\0");
+    exif.extend_from_slice(b"\0\0\0\0");
+    exif.extend_from_slice(b"secret\0ACME\0");
+
+    let mut jpeg = Jpeg::from_bytes(std::fs::read("./examples/example.jpeg")?.into())?;
+    jpeg.set_exif(Some(exif.clone().into()));
+    let mut tagged = Vec::new();
+    jpeg.encoder().write_to(&mut tagged)?;
+
+    let mut served = Vec::new();
+    for bucket in ["stripped", "copyright", "untouched"] {
+        let res = app.post(format!("/v1/{}", bucket))
+            .body(tagged.clone())
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(tagged.len() as u64))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+        let res = app.get(format!("/v1/{}/{}", bucket, image_id))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        let body = res.0.into_body().into_bytes().await?;
+        served.push(Jpeg::from_bytes(body)?.exif());
+    }
+
+    assert!(served[0].is_none());
+
+    let kept = served[1].clone().expect("The copyright should be kept");
+    assert!(kept.windows(5).any(|w| w == b"ACME\0"));
+    assert!(!kept.windows(6).any(|w| w == b"secret"));
+
+    assert_eq!(served[2].as_deref(), Some(exif.as_slice()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lookup_cache() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "custom_ids": {},
+            "index": { "flush_interval": 10 },
+            "lookup_cache": { "ttl": 60 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    // Repeated polling for a missing image is answered by the lookup cache.
+    let image_id = "6d3b9d0c-2f4e-4a8e-9a0b-3f1c2d4e5f60";
+    for _ in 0..2 {
+        let res = app.get(format!("/v1/user-profiles/{}", image_id)).send().await;
+        res.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    // Uploading the image invalidates the cached miss.
+    let res = app.post(format!("/v1/user-profiles?image_id={}", image_id))
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id)).send().await;
+    res.assert_status_is_ok();
+
+    for _ in 0..2 {
+        let res = app.get(format!("/v1/user-profiles/{}/metadata", image_id)).send().await;
+        res.assert_status_is_ok();
+    }
+
+    // Deleting the image invalidates its cached metadata.
+    let res = app.delete(format!("/v1/user-profiles/{}", image_id)).send().await;
+    res.assert_status_is_ok();
+
+    let res = app.get(format!("/v1/user-profiles/{}/metadata", image_id)).send().await;
+    res.assert_status(StatusCode::NOT_FOUND);
+
+    let res = app.get("/metrics")
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let body = res.0.into_body().into_string().await?;
+
+    assert!(body.contains("lust_lookup_cache_requests_total{bucket=\"user-profiles\",lookup=\"existence\",result=\"hit\"} 1"));
+    assert!(body.contains("lust_lookup_cache_requests_total{bucket=\"user-profiles\",lookup=\"metadata\",result=\"hit\"} 1"));
+    assert!(body.contains("lust_lookup_cache_requests_total{bucket=\"user-profiles\",lookup=\"metadata\",result=\"miss\"} 2"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cdn_purges() -> anyhow::Result<()> {
+    use std::sync::{Arc, Mutex};
+    use crate::testing::{client, ConfigBuilder};
+
+    // A stand in for the CDN which records the purges sent to it.
+    let purges: Arc<Mutex<Vec<serde_json::Value>>> = Arc::default();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let webhook = format!("http://{}/purge", listener.local_addr()?);
+    let recorder = purges.clone();
+    let cdn = hyper::Server::from_tcp(listener)?.serve(hyper::service::make_service_fn(move |_| {
+        let recorder = recorder.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                let recorder = recorder.clone();
+                async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    recorder.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(hyper::Body::empty()))
+                }
+            }))
+        }
+    }));
+    tokio::spawn(cdn);
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": false, "gif": false },
+            "cdn": { "provider": "webhook", "url": webhook },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+    let res = app.get(format!("/v1/user-profiles/{}", image_id)).send().await;
+    res.assert_status_is_ok();
+    res.assert_header("surrogate-key", format!("user-profiles/{}", image_id));
+
+    let mut replacement = Vec::new();
+    image::RgbImage::from_pixel(16, 16, image::Rgb([255, 0, 0]))
+        .write_to(&mut std::io::Cursor::new(&mut replacement), image::ImageFormat::Png)?;
+
+    let res = app.put(format!("/v1/user-profiles/{}", image_id))
+        .body(replacement.clone())
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(replacement.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    let etag = res.0.headers().get("etag").unwrap().to_str()?.to_string();
+
+    let res = app.delete(format!("/v1/user-profiles/{}", image_id)).send().await;
+    res.assert_status_is_ok();
+
+    let start = std::time::Instant::now();
+    while purges.lock().unwrap().len() < 2 && start.elapsed() < std::time::Duration::from_secs(10) {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    let purges = purges.lock().unwrap().clone();
+    assert_eq!(purges.len(), 2, "{:?}", purges);
+
+    // The replace is soft purged with its new etag and the delete hard purged.
+    assert!(purges.iter().all(|purge| purge["surrogate_key"] == format!("user-profiles/{}", image_id)));
+    let soft = purges.iter().find(|purge| purge["soft"] == true).expect("The replace should be soft purged");
+    assert_eq!(soft["etag"], etag);
+    let hard = purges.iter().find(|purge| purge["soft"] == false).expect("The delete should be hard purged");
+    assert_eq!(hard["etag"], serde_json::Value::Null);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_icc_profile_preserved() -> anyhow::Result<()> {
+    use img_parts::{DynImage, ImageICC, png::Png};
+    use lcms2::{CIExyY, CIExyYTRIPLE, Profile, ToneCurve};
+    use crate::testing::{client, ConfigBuilder};
+
+    let formats = serde_json::json!({ "png": true, "jpeg": true, "webp": true, "gif": false });
+    let config = ConfigBuilder::new()
+        .bucket("preserved", serde_json::json!({
+            "mode": "aot",
+            "formats": formats,
+        }))
+        .bucket("discarded", serde_json::json!({
+            "mode": "aot",
+            "formats": formats,
+            "preserve_metadata": { "icc": false },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    // A wide gamut profile with Display P3 like primaries.
+    let xy = |x, y| CIExyY { x, y, Y: 1.0 };
+    let primaries = CIExyYTRIPLE {
+        Red: xy(0.68, 0.32),
+        Green: xy(0.265, 0.69),
+        Blue: xy(0.15, 0.06),
+    };
+    let curve = ToneCurve::new(2.2);
+    let profile = Profile::new_rgb(&xy(0.3127, 0.3290), &primaries, &[&curve, &curve, &curve])?;
+    let icc = profile.icc()?;
+
+    let img = image::RgbImage::from_pixel(8, 8, image::Rgb([60, 120, 180]));
+    let mut encoded = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut encoded, image::ImageFormat::Png)?;
+
+    let mut png = Png::from_bytes(encoded.into_inner().into())?;
+    png.set_icc_profile(Some(icc.clone().into()));
+    let mut tagged = Vec::new();
+    png.encoder().write_to(&mut tagged)?;
+
+    for (bucket, preserved) in [("preserved", true), ("discarded", false)] {
+        let res = app.post(format!("/v1/{}", bucket))
+            .body(tagged.clone())
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(tagged.len() as u64))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        let image_id = res.json().await.value().object().get("image_id").string().to_string();
+
+        for format in ["png", "jpeg", "webp"] {
+            let res = app.get(format!("/v1/{}/{}?format={}", bucket, image_id, format))
+                .send()
+                .await;
+            res.assert_status_is_ok();
+            let body = res.0.into_body().into_bytes().await?;
+
+            let kept = DynImage::from_bytes(body)?
+                .expect("A known format")
+                .icc_profile();
+            if preserved {
+                assert_eq!(kept.as_deref(), Some(icc.as_slice()), "{} should keep the profile", format);
+            } else {
+                assert!(kept.is_none(), "{} should drop the profile", format);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_bucket_export() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let config = ConfigBuilder::new()
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "aot",
+            "formats": { "png": true, "jpeg": false, "webp": true, "gif": false },
+            "presets": { "thumbnail": { "width": 32, "height": 32 } },
+            "index": { "flush_interval": 10 },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    let mut image_ids = vec![];
+    for _ in 0..3 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        image_ids.push(res.json().await.value().object().get("image_id").string().to_string());
+    }
+    image_ids.sort();
+
+    let res = app.get("/admin/buckets/user-profiles/export?limit=2").send().await;
+    res.assert_status_is_ok();
+    res.assert_content_type("application/x-ndjson");
+    let cursor = res.0.headers().get("x-next-cursor").unwrap().to_str()?.to_string();
+    let body = res.0.into_body().into_string().await?;
+    let mut lines: Vec<serde_json::Value> = body
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(lines.len(), 2);
+    assert_eq!(cursor, image_ids[1]);
+
+    let res = app.get(format!("/admin/buckets/user-profiles/export?cursor={}", cursor)).send().await;
+    res.assert_status_is_ok();
+    assert!(res.0.headers().get("x-next-cursor").is_none());
+    let body = res.0.into_body().into_string().await?;
+    for line in body.lines() {
+        lines.push(serde_json::from_str(line)?);
+    }
+
+    let exported: Vec<&str> = lines.iter().map(|line| line["image_id"].as_str().unwrap()).collect();
+    assert_eq!(exported, image_ids);
+
+    let image = &lines[0];
+    assert_eq!(image["size"], TEST_IMAGE.len() as u64);
+    assert_eq!(image["checksum"], crc32fast::hash(TEST_IMAGE));
+    assert!(image["uploaded_at"].as_str().unwrap().ends_with("+00:00"));
+
+    let variants = image["variants"].as_array().unwrap();
+    assert_eq!(variants.len(), 4);
+    assert!(variants.iter().any(|v| v["preset"] == "thumbnail" && v["format"] == "webp"));
+    assert!(variants.iter().any(|v| v["preset"] == "original" && v["sizing_id"] == 0));
+
+    let res = app.get("/admin/buckets/user-profiles/export?limit=0").send().await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_bucket_changes() -> anyhow::Result<()> {
+    use crate::testing::{client, ConfigBuilder};
+
+    let directory = std::env::temp_dir().join(format!("lust-changes-{}", uuid::Uuid::new_v4()));
+    let config = ConfigBuilder::new()
+        .backend(serde_json::json!({ "filesystem": { "directory": directory } }))
+        .bucket("user-profiles", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": true, "webp": false, "gif": false },
+            "change_log": { "max_events": 3 },
+        }))
+        .bucket("unlogged", serde_json::json!({
+            "mode": "jit",
+            "formats": { "png": true, "jpeg": true, "webp": false, "gif": false },
+        }))
+        .build()?;
+    let app = client(config).await?;
+
+    // Mirrors bookmark the latest cursor before their initial full sync.
+    let res = app.get("/admin/buckets/user-profiles/changes").send().await;
+    res.assert_status_is_ok();
+    let body = res.json().await;
+    body.value().object().get("cursor").assert_i64(0);
+    body.value().object().get("events").array().assert_len(0);
+
+    let mut image_ids = vec![];
+    for _ in 0..2 {
+        let res = app.post("/v1/user-profiles")
+            .body(TEST_IMAGE)
+            .content_type("application/octet-stream")
+            .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+            .send()
+            .await;
+        res.assert_status_is_ok();
+        image_ids.push(res.json().await.value().object().get("image_id").string().to_string());
+    }
+
+    let res = app.put(format!("/v1/user-profiles/{}", &image_ids[0]))
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+
+    let res = app.delete(format!("/v1/user-profiles/{}", &image_ids[1])).send().await;
+    res.assert_status_is_ok();
+
+    // Only the 3 most recent changes are kept, so the first upload is lost.
+    let res = app.get("/admin/buckets/user-profiles/changes?since=0").send().await;
+    res.assert_status(StatusCode::GONE);
+
+    let res = app.get("/admin/buckets/user-profiles/changes?since=1&limit=2").send().await;
+    res.assert_status_is_ok();
+    let body = res.json().await;
+    let page = body.value().object();
+    page.get("cursor").assert_i64(3);
+    page.get("has_more").assert_bool(true);
+    let events = page.get("events").array();
+    events.assert_len(2);
+    events.get(0).object().get("kind").assert_string("created");
+    events.get(0).object().get("image_id").assert_string(&image_ids[1]);
+    events.get(1).object().get("kind").assert_string("replaced");
+    events.get(1).object().get("image_id").assert_string(&image_ids[0]);
+
+    let res = app.get("/admin/buckets/user-profiles/changes?since=3").send().await;
+    res.assert_status_is_ok();
+    let body = res.json().await;
+    let page = body.value().object();
+    page.get("cursor").assert_i64(4);
+    page.get("has_more").assert_bool(false);
+    let events = page.get("events").array();
+    events.assert_len(1);
+    events.get(0).object().get("kind").assert_string("deleted");
+    events.get(0).object().get("image_id").assert_string(&image_ids[1]);
+
+    let res = app.get("/admin/buckets/user-profiles/changes?since=4").send().await;
+    res.assert_status_is_ok();
+    res.json().await.value().object().get("events").array().assert_len(0);
+
+    // A cursor ahead of the log means it was reset and the mirror must re-sync.
+    let res = app.get("/admin/buckets/user-profiles/changes?since=10").send().await;
+    res.assert_status(StatusCode::GONE);
+
+    let bucket = crate::controller::get_bucket_by_name("user-profiles").unwrap();
+    bucket.flush_metadata().await?;
+    let changes_file = directory.join(format!("{}/metadata/changes", bucket.bucket_id()));
+    assert!(changes_file.exists(), "The change log should be flushed to the backend");
+
+    // The log is written by a single instance, changes made through
+    // another instance are held back rather than overwriting it.
+    let mut log: serde_json::Value = serde_json::from_slice(&std::fs::read(&changes_file)?)?;
+    log["writer"] = "other-instance".into();
+    log["written_at"] = chrono::Utc::now().timestamp().into();
+    std::fs::write(&changes_file, serde_json::to_vec(&log)?)?;
+
+    let res = app.post("/v1/user-profiles")
+        .body(TEST_IMAGE)
+        .content_type("application/octet-stream")
+        .typed_header(headers::ContentLength(TEST_IMAGE.len() as u64))
+        .send()
+        .await;
+    res.assert_status_is_ok();
+    assert!(bucket.flush_metadata().await.is_err(), "Flushing a log claimed by another instance should fail");
+
+    let res = app.get("/admin/buckets/user-profiles/changes?since=4").send().await;
+    res.assert_status_is_ok();
+    let body = res.json().await;
+    body.value().object().get("cursor").assert_i64(4);
+    body.value().object().get("events").array().assert_len(0);
+
+    let res = app.get("/admin/buckets/unlogged/changes").send().await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    let res = app.get("/admin/buckets/user-profiles/changes?since=1&limit=0").send().await;
+    res.assert_status(StatusCode::BAD_REQUEST);
+
+    std::fs::remove_dir_all(&directory)?;
+    Ok(())
+}
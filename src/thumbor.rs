@@ -0,0 +1,248 @@
+//! Serves images via thumbor style URLs so deployments migrating
+//! from thumbor don't need to rewrite every stored URL, e.g.
+//! `/{signature}/fit-in/300x200/filters:format(webp)/user-profiles/:image_id`.
+//!
+//! Only the parts of the URL which map onto Lust's pipeline are supported,
+//! images are always resized to fit within the size and filters other than
+//! `format` are ignored. Trimming, manual crops, flips and `meta` requests
+//! are rejected.
+//!
+//! Buckets requiring signed URLs also need the image path, everything
+//! following the thumbor signature, signed with the bucket's key.
+
+use hmac::{Hmac, Mac};
+use poem::web::Path;
+use poem::{handler, IntoResponse, Request, Response, Route};
+use poem_openapi::payload::Json;
+use sha1::Sha1;
+use uuid::Uuid;
+
+use crate::auth;
+use crate::config::{config, ImageKind};
+use crate::controller::get_bucket_by_name;
+use crate::routes::{fetch_from_bucket, with_response_headers, Detail, FetchResponse};
+use crate::signing;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// The first segment of URLs which aren't signed.
+const UNSAFE: &str = "unsafe";
+
+const FIT_IN: &[&str] = &["fit-in", "adaptive-fit-in", "full-fit-in"];
+const HORIZONTAL_ALIGN: &[&str] = &["left", "right", "center"];
+const VERTICAL_ALIGN: &[&str] = &["top", "bottom", "middle"];
+
+/// The Lust request a thumbor URL maps onto.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ThumborUrl {
+    /// The bucket given in the image path, if any.
+    pub bucket: Option<String>,
+    pub image_id: Uuid,
+
+    /// The size to fit the image within, where `0` keeps
+    /// the side proportional to the other.
+    pub size: Option<(u32, u32)>,
+    pub format: Option<ImageKind>,
+}
+
+impl ThumborUrl {
+    /// Parses the URL following its signature, i.e. the options and image path.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let mut segments = url.split('/').peekable();
+
+        if segments.next_if_eq(&"meta").is_some() {
+            return Err("Metadata requests are not supported.".to_string())
+        }
+
+        if segments.next_if(|s| *s == "trim" || s.starts_with("trim:")).is_some() {
+            return Err("Trimming is not supported.".to_string())
+        }
+
+        if segments.next_if(|s| is_crop(s)).is_some() {
+            return Err("Manual crops are not supported.".to_string())
+        }
+
+        segments.next_if(|s| FIT_IN.contains(s));
+
+        let mut parsed = Self::default();
+        if let Some(size) = segments.peek().and_then(|s| parse_size(s)) {
+            segments.next();
+            parsed.size = size?;
+        }
+
+        // The image is always fit within the size so there's nothing to align.
+        segments.next_if(|s| HORIZONTAL_ALIGN.contains(s));
+        segments.next_if(|s| VERTICAL_ALIGN.contains(s));
+        segments.next_if_eq(&"smart");
+
+        if let Some(filters) = segments.next_if(|s| s.starts_with("filters:")) {
+            parsed.format = parse_format_filter(&filters["filters:".len()..])?;
+        }
+
+        let image_path: Vec<&str> = segments.collect();
+        let (bucket, image) = match image_path.as_slice() {
+            [image] => (None, *image),
+            [bucket, image] => (Some(bucket.to_string()), *image),
+            _ => return Err("The image path must be either {bucket}/{image_id} or {image_id}.".to_string()),
+        };
+
+        // The extension is the format of the original in thumbor's storage.
+        let image_id = image.split('.').next().unwrap_or_default();
+        parsed.image_id = image_id
+            .parse()
+            .map_err(|_| format!("The image id {:?} is not a valid id.", image_id))?;
+        parsed.bucket = bucket;
+
+        Ok(parsed)
+    }
+}
+
+fn is_crop(segment: &str) -> bool {
+    match segment.split_once(':') {
+        Some((top_left, bottom_right)) => {
+            is_size_pair(top_left) && is_size_pair(bottom_right)
+        },
+        None => false,
+    }
+}
+
+fn is_size_pair(v: &str) -> bool {
+    v.split_once('x')
+        .map(|(x, y)| x.parse::<u32>().is_ok() && y.parse::<u32>().is_ok())
+        .unwrap_or(false)
+}
+
+/// Parses a `{width}x{height}` segment, returning `None` if the segment
+/// isn't a size. Either side may be left empty or `0`.
+fn parse_size(segment: &str) -> Option<Result<Option<(u32, u32)>, String>> {
+    let (width, height) = segment.split_once('x')?;
+
+    let is_side = |v: &str| v.strip_prefix('-').unwrap_or(v).chars().all(|c| c.is_ascii_digit());
+    if !is_side(width) || !is_side(height) {
+        return None
+    }
+
+    if width.starts_with('-') || height.starts_with('-') {
+        return Some(Err("Flipping the image is not supported.".to_string()))
+    }
+
+    let side = |v: &str| if v.is_empty() { Ok(0) } else { v.parse::<u32>() };
+    let size = match (side(width), side(height)) {
+        (Ok(0), Ok(0)) => Ok(None),
+        (Ok(width), Ok(height)) => Ok(Some((width, height))),
+        _ => Err(format!("The size {:?} is invalid.", segment)),
+    };
+
+    Some(size)
+}
+
+fn parse_format_filter(filters: &str) -> Result<Option<ImageKind>, String> {
+    let mut format = None;
+    for filter in filters.split(':') {
+        let arg = match filter.strip_prefix("format(").and_then(|v| v.strip_suffix(')')) {
+            None => continue,
+            Some(arg) => arg,
+        };
+
+        let kind = ImageKind::from_file_extension(arg)
+            .ok_or_else(|| format!("The format {:?} is not supported.", arg))?;
+        format = Some(kind);
+    }
+
+    Ok(format)
+}
+
+/// Verifies the URL's signature, the URL-safe base64 encoded
+/// HMAC-SHA1 of the URL following it.
+fn verify(key: &str, signature: &str, url: &str) -> bool {
+    let signature = match base64::decode_config(signature, base64::URL_SAFE) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = HmacSha1::new_from_slice(key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(url.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Adds the thumbor URLs to the given routes if enabled.
+pub fn mount(route: Route) -> Route {
+    match config().thumbor {
+        None => route,
+        Some(ref cfg) => route.at(format!("{}/*path", cfg.base_path), fetch_image),
+    }
+}
+
+#[handler]
+async fn fetch_image(req: &Request, Path(path): Path<String>) -> poem::Result<Response> {
+    let cfg = config()
+        .thumbor
+        .as_ref()
+        .expect("Thumbor URLs are only mounted when configured");
+
+    let (signature, url) = path.split_once('/').unwrap_or((&path, ""));
+    let authorized = match (signature, cfg.security_key.as_deref()) {
+        (UNSAFE, _) => cfg.allow_unsafe,
+        (signature, Some(key)) => verify(key, signature, url),
+        (_, None) => false,
+    };
+
+    if !authorized {
+        let detail = Detail::new("The URL must be signed with the security key.");
+        return Ok(FetchResponse::Forbidden(Json(detail)).into_response())
+    }
+
+    let parsed = match ThumborUrl::parse(url) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(FetchResponse::bad_request(e).into_response()),
+    };
+
+    let bucket_name = match parsed.bucket.as_ref().or(cfg.bucket.as_ref()) {
+        Some(bucket) => bucket,
+        None => return Ok(FetchResponse::bad_request(
+            "The image path must include the bucket as no default bucket is configured.",
+        ).into_response()),
+    };
+
+    let bucket = match get_bucket_by_name(bucket_name) {
+        None => return Ok(FetchResponse::bucket_not_found(bucket_name).into_response()),
+        Some(b) => b,
+    };
+
+    if let Some(rejection) = auth::authorize_read(req, bucket_name).await? {
+        return Ok(rejection)
+    }
+
+    if let Err(rejection) = signing::authorize(bucket, url, req.uri().query()) {
+        return Ok(FetchResponse::unauthorized(rejection).into_response())
+    }
+
+    // Resizing keeps the aspect ratio, so leaving a side unbounded
+    // has it follow the other side like thumbor's `0`.
+    let (width, height) = match parsed.size {
+        None => (None, None),
+        Some((width, height)) => (
+            Some(if width == 0 { u32::MAX } else { width }),
+            Some(if height == 0 { u32::MAX } else { height }),
+        ),
+    };
+
+    let header = |name: &str| req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string);
+
+    let resp = fetch_from_bucket(
+        bucket,
+        parsed.image_id,
+        parsed.format,
+        None,
+        width,
+        height,
+        header("accept"),
+        header("accept-encoding"),
+    ).await?;
+
+    Ok(with_response_headers(bucket, parsed.image_id, resp).into_response())
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::metadata::MetadataStore;
+
+/// The metadata directory each image's tombstone is stored in.
+const TOMBSTONES_DIR: &str = "deleted";
+
+/// How long a looked up tombstone is trusted before it is re-read, this
+/// bounds how long another instance's delete or re-upload goes unnoticed.
+const TOMBSTONE_TTL: Duration = Duration::from_secs(5);
+
+/// The maximum number of looked up tombstones kept in memory.
+const MAX_CACHED_TOMBSTONES: u64 = 100_000;
+
+/// Records which images have been deleted so a surviving derived copy,
+/// or a fetch racing the delete, can never re-materialize the image.
+///
+/// Each tombstone is stored under its own key so instances sharing the
+/// storage backend never overwrite each other's deletes, lookups are
+/// cached briefly to keep them off the fetch path.
+pub struct Tombstones {
+    cached: moka::sync::Cache<Uuid, bool>,
+}
+
+impl Default for Tombstones {
+    fn default() -> Self {
+        Self {
+            cached: moka::sync::Cache::builder()
+                .max_capacity(MAX_CACHED_TOMBSTONES)
+                .time_to_live(TOMBSTONE_TTL)
+                .build(),
+        }
+    }
+}
+
+#[inline]
+fn tombstone_key(image_id: Uuid) -> String {
+    format!("{}/{}", TOMBSTONES_DIR, image_id)
+}
+
+impl Tombstones {
+    /// If the image has been deleted.
+    pub async fn contains(&self, store: &MetadataStore, image_id: Uuid) -> anyhow::Result<bool> {
+        if let Some(deleted) = self.cached.get(&image_id) {
+            return Ok(deleted)
+        }
+
+        let deleted = store.load_bytes(&tombstone_key(image_id)).await?.is_some();
+        self.cached.insert(image_id, deleted);
+
+        Ok(deleted)
+    }
+
+    /// Marks the image as deleted.
+    pub async fn insert(&self, store: &MetadataStore, image_id: Uuid) -> anyhow::Result<()> {
+        if self.contains(store, image_id).await? {
+            return Ok(())
+        }
+
+        store.save(&tombstone_key(image_id), &chrono::Utc::now().timestamp()).await?;
+        self.cached.insert(image_id, true);
+
+        Ok(())
+    }
+
+    /// Removes the image's tombstone, e.g. when the id is re-used by an upload.
+    pub async fn remove(&self, store: &MetadataStore, image_id: Uuid) -> anyhow::Result<()> {
+        // Read through the cache as another instance may have deleted the image.
+        let key = tombstone_key(image_id);
+        if store.load_bytes(&key).await?.is_some() {
+            store.remove(&key).await?;
+        }
+        self.cached.insert(image_id, false);
+
+        Ok(())
+    }
+}
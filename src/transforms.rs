@@ -0,0 +1,161 @@
+//! Transformations given in the URL path rather than the query,
+//! e.g. `/:image_id/300x300,webp`.
+//!
+//! As any transformation in the path can be cached by a CDN, only those
+//! allowed by the bucket or signed with its signing key can be requested,
+//! `sign` creates the signatures for applications linking against Lust.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::config::{BucketConfig, ImageKind};
+use crate::controller::BucketController;
+use crate::pipelines::ProcessingMode;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The prefix of the trailing token carrying the signature.
+const SIGNATURE_PREFIX: &str = "sig=";
+
+/// A transformation in its path form, a `,` separated format and
+/// either a preset or a `{width}x{height}` custom size, in any order.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PathTransform {
+    pub preset: Option<String>,
+    pub size: Option<(u32, u32)>,
+    pub format: Option<ImageKind>,
+}
+
+impl PathTransform {
+    pub fn parse(transform: &str) -> Result<Self, String> {
+        let mut parsed = Self::default();
+
+        for token in transform.split(',').map(str::trim) {
+            if token.is_empty() {
+                return Err("The transformation contains an empty token.".to_string())
+            }
+
+            if let Some(kind) = ImageKind::from_file_extension(token) {
+                if parsed.format.replace(kind).is_some() {
+                    return Err("The transformation sets the format more than once.".to_string())
+                }
+
+                continue
+            }
+
+            if parsed.size.is_some() || parsed.preset.is_some() {
+                return Err("The transformation sets the size more than once.".to_string())
+            }
+
+            match parse_size(token) {
+                Some(size) => parsed.size = Some(size),
+                None => parsed.preset = Some(token.to_string()),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Checks the transformation can be applied by the bucket.
+    pub fn validate(&self, cfg: &BucketConfig) -> Result<(), String> {
+        if let Some(ref preset) = self.preset {
+            if preset != "original" && !cfg.presets.contains_key(preset) {
+                return Err(format!("The preset {:?} does not exist.", preset))
+            }
+        }
+
+        if let Some((width, height)) = self.size {
+            if cfg.mode != ProcessingMode::Realtime {
+                return Err("Custom resizing can only be done when bucket set to 'realtime' processing mode".to_string())
+            }
+
+            cfg.custom_sizing_id(width, height).map_err(|e| e.to_string())?;
+        }
+
+        if let Some(format) = self.format {
+            if !cfg.formats.is_enabled(format) {
+                return Err(format!("The format {:?} is not enabled for this bucket.", format))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_size(token: &str) -> Option<(u32, u32)> {
+    let (width, height) = token.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// The reason a path transformation was rejected.
+pub(crate) enum TransformRejection {
+    /// The bucket has no `path_transforms`.
+    Disabled,
+
+    /// The transformation is malformed or can't be applied by the bucket.
+    Invalid(String),
+
+    /// The transformation is neither allowed nor correctly signed.
+    Forbidden,
+}
+
+/// Parses the transformation of the image, checking it's either one of
+/// the bucket's allowed transformations or signed with its signing key.
+pub(crate) fn authorize(
+    bucket: &BucketController,
+    image_id: Uuid,
+    transform: &str,
+) -> Result<PathTransform, TransformRejection> {
+    let cfg = bucket.cfg()
+        .path_transforms
+        .as_ref()
+        .ok_or(TransformRejection::Disabled)?;
+
+    let (unsigned, signature) = match transform.rsplit_once(',') {
+        Some((unsigned, last)) if last.starts_with(SIGNATURE_PREFIX) => {
+            (unsigned, Some(&last[SIGNATURE_PREFIX.len()..]))
+        },
+        _ => (transform, None),
+    };
+
+    let parsed = PathTransform::parse(unsigned)
+        .and_then(|t| t.validate(bucket.cfg()).map(|_| t))
+        .map_err(TransformRejection::Invalid)?;
+
+    let allowed = cfg.allowed
+        .iter()
+        .any(|allowed| PathTransform::parse(allowed).map(|t| t == parsed).unwrap_or(false));
+
+    let signed = match (signature, cfg.signing_key.as_deref()) {
+        (Some(signature), Some(key)) => verify(key, bucket.name(), image_id, unsigned, signature),
+        _ => false,
+    };
+
+    if allowed || signed {
+        Ok(parsed)
+    } else {
+        Err(TransformRejection::Forbidden)
+    }
+}
+
+fn mac(key: &str, bucket: &str, image_id: Uuid, transform: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}/{}/{}", bucket, image_id, transform).as_bytes());
+    mac
+}
+
+/// The signature of the image's transformation, the URL-safe base64
+/// encoded HMAC-SHA256 of `{bucket}/{image_id}/{transform}`.
+pub fn sign(key: &str, bucket: &str, image_id: Uuid, transform: &str) -> String {
+    let signature = mac(key, bucket, image_id, transform).finalize().into_bytes();
+    base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+}
+
+fn verify(key: &str, bucket: &str, image_id: Uuid, transform: &str, signature: &str) -> bool {
+    match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => mac(key, bucket, image_id, transform).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
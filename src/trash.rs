@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::sync::{Mutex, OnceCell};
+use uuid::Uuid;
+
+use crate::controller::buckets;
+use crate::metadata::MetadataStore;
+
+/// The metadata document the trashed images are stored in.
+const TRASH_KEY: &str = "trash";
+
+/// Records which images have been soft deleted and when, their variants
+/// are kept until the bucket's retention period passes so they can be restored.
+///
+/// Entries are loaded from the metadata store on first use and are
+/// written through on every change.
+#[derive(Default)]
+pub struct Trash {
+    entries: OnceCell<Mutex<HashMap<Uuid, i64>>>,
+}
+
+impl Trash {
+    async fn entries(&self, store: &MetadataStore) -> anyhow::Result<&Mutex<HashMap<Uuid, i64>>> {
+        self.entries
+            .get_or_try_init(|| async {
+                let entries: HashMap<Uuid, i64> = store.load(TRASH_KEY).await?;
+                Ok::<_, anyhow::Error>(Mutex::new(entries))
+            })
+            .await
+    }
+
+    /// If the image is in the trash.
+    pub async fn contains(&self, store: &MetadataStore, image_id: Uuid) -> anyhow::Result<bool> {
+        let entries = self.entries(store).await?.lock().await;
+        Ok(entries.contains_key(&image_id))
+    }
+
+    /// The images trashed at or before the given unix timestamp.
+    pub async fn expired(&self, store: &MetadataStore, before: i64) -> anyhow::Result<Vec<Uuid>> {
+        let entries = self.entries(store).await?.lock().await;
+        let mut expired: Vec<Uuid> = entries
+            .iter()
+            .filter(|(_, deleted_at)| **deleted_at <= before)
+            .map(|(image_id, _)| *image_id)
+            .collect();
+        expired.sort();
+
+        Ok(expired)
+    }
+
+    /// Moves the image to the trash.
+    pub async fn insert(&self, store: &MetadataStore, image_id: Uuid) -> anyhow::Result<()> {
+        let mut entries = self.entries(store).await?.lock().await;
+        if entries.contains_key(&image_id) {
+            return Ok(())
+        }
+
+        entries.insert(image_id, chrono::Utc::now().timestamp());
+        if let Err(e) = store.save(TRASH_KEY, &*entries).await {
+            entries.remove(&image_id);
+            return Err(e)
+        }
+
+        Ok(())
+    }
+
+    /// Removes the image from the trash, returning if it was trashed.
+    pub async fn remove(&self, store: &MetadataStore, image_id: Uuid) -> anyhow::Result<bool> {
+        let mut entries = self.entries(store).await?.lock().await;
+        let deleted_at = match entries.remove(&image_id) {
+            None => return Ok(false),
+            Some(deleted_at) => deleted_at,
+        };
+
+        if let Err(e) = store.save(TRASH_KEY, &*entries).await {
+            entries.insert(image_id, deleted_at);
+            return Err(e)
+        }
+
+        Ok(true)
+    }
+}
+
+/// Starts periodically purging the expired trash of each bucket.
+pub fn start() {
+    for bucket in buckets() {
+        let interval = match bucket.cfg().soft_delete {
+            None => continue,
+            Some(ref cfg) => Duration::from_secs(cfg.reap_interval),
+        };
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+
+                match bucket.reap_trash().await {
+                    Ok(purged) if purged.is_empty() => {},
+                    Ok(purged) => info!("Purged {} expired images from the trash of bucket {}.", purged.len(), bucket.name()),
+                    Err(e) => error!("Failed to purge the trash of bucket {}: {}", bucket.name(), e),
+                }
+            }
+        });
+    }
+}
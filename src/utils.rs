@@ -1,7 +1,26 @@
 use std::hash::Hash;
 
+use anyhow::anyhow;
+use image::Rgba;
+
 pub fn crc_hash<H: Hash>(v: H) -> u32 {
     let mut hasher = crc32fast::Hasher::default();
     v.hash(&mut hasher);
     hasher.finalize()
-}
\ No newline at end of file
+}
+
+/// Parses a `#rrggbb` or `#rrggbbaa` hex colour.
+pub fn parse_colour(colour: &str) -> anyhow::Result<Rgba<u8>> {
+    let hex = colour.trim_start_matches('#');
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return Err(anyhow!("Invalid colour {:?}, expected `#rrggbb` or `#rrggbbaa`.", colour))
+    }
+
+    let mut channels = [u8::MAX; 4];
+    for (i, channel) in channels.iter_mut().enumerate().take(hex.len() / 2) {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("Invalid colour {:?}.", colour))?;
+    }
+
+    Ok(Rgba(channels))
+}
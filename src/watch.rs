@@ -0,0 +1,211 @@
+//! Imports files dropped into a watched directory, the hot folder
+//! workflow common to on-premise deployments.
+//!
+//! Files are imported once they've gone unmodified for the settle time, then
+//! moved into the processed directory, or into the failed directory alongside
+//! a `.error` file describing why they couldn't be imported.
+
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use futures::StreamExt;
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, DebouncedEventKind};
+use tokio::sync::mpsc;
+
+use crate::config::{ImageKind, WatchConfig};
+use crate::controller::{get_bucket_by_name, BucketController, UploadOptions, UploadOutcome};
+use crate::metrics::WATCHED_FILES;
+
+struct FolderWatcher {
+    cfg: &'static WatchConfig,
+    bucket: &'static BucketController,
+    processed_directory: PathBuf,
+    failed_directory: PathBuf,
+
+    /// The files being imported, as a file can be announced by
+    /// both the initial scan and the watch.
+    in_flight: Mutex<HashSet<PathBuf>>,
+}
+
+impl FolderWatcher {
+    async fn handle(&self, path: PathBuf) {
+        // Transfers commonly write to a hidden file which is renamed once complete.
+        let hidden = path
+            .file_name()
+            .map(|name| name.to_string_lossy().starts_with('.'))
+            .unwrap_or(true);
+
+        let is_file = tokio::fs::metadata(&path)
+            .await
+            .map(|metadata| metadata.is_file())
+            .unwrap_or(false);
+
+        if hidden || !is_file || !self.in_flight.lock().unwrap().insert(path.clone()) {
+            return
+        }
+
+        self.process(&path).await;
+        self.in_flight.lock().unwrap().remove(&path);
+    }
+
+    async fn process(&self, path: &Path) {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            // The file was already handled.
+            Err(e) if e.kind() == ErrorKind::NotFound => return,
+            Err(e) => {
+                error!("Failed to read dropped file {}: {}", path.display(), e);
+                return
+            },
+        };
+
+        match self.import(path, data).await {
+            Ok(()) => {
+                WATCHED_FILES.with_label_values(&["processed"]).inc();
+
+                if let Err(e) = move_file(path, &self.processed_directory).await {
+                    error!("Failed to move imported file {} into {}: {}", path.display(), self.processed_directory.display(), e);
+                }
+            },
+            Err(reason) => {
+                WATCHED_FILES.with_label_values(&["failed"]).inc();
+                warn!("Failed to import dropped file {}: {}", path.display(), reason);
+
+                let result = async {
+                    let destination = move_file(path, &self.failed_directory).await?;
+
+                    let mut error_file = destination.into_os_string();
+                    error_file.push(".error");
+                    tokio::fs::write(error_file, format!("{:#}\n", reason)).await
+                };
+
+                if let Err(e) = result.await {
+                    error!("Failed to move file {} into {}: {}", path.display(), self.failed_directory.display(), e);
+                }
+            },
+        }
+    }
+
+    async fn import(&self, path: &Path, data: Vec<u8>) -> anyhow::Result<()> {
+        if data.len() > crate::routes::upload_limit(self.bucket) {
+            return Err(anyhow!("The file exceeds the upload limit of bucket {}.", self.bucket.name()))
+        }
+
+        let kind = image::guess_format(&data)
+            .ok()
+            .and_then(ImageKind::from_guessed_format)
+            .ok_or_else(|| anyhow!("The file is not a supported image format."))?;
+
+        let options = UploadOptions {
+            tags: self.cfg.tags.clone(),
+            original_filename: path.file_name().map(|name| name.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+
+        match self.bucket.upload(kind, data, options).await? {
+            UploadOutcome::Complete(info) => info!(
+                "Imported {} into bucket {} as image {}.",
+                path.display(), self.bucket.name(), info.image_id(),
+            ),
+            UploadOutcome::Pending(job) => info!(
+                "Importing {} into bucket {} with upload job {}.",
+                path.display(), self.bucket.name(), job.job_id(),
+            ),
+        }
+
+        Ok(())
+    }
+}
+
+/// Moves the file into the directory, suffixing its name with the
+/// current time if a file of the same name was moved there before.
+async fn move_file(path: &Path, directory: &Path) -> std::io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "The path has no file name."))?;
+
+    let mut destination = directory.join(name);
+    if tokio::fs::metadata(&destination).await.is_ok() {
+        let stem = path.file_stem().unwrap_or(name).to_string_lossy();
+        let suffix = chrono::Utc::now().timestamp_millis();
+
+        destination = match path.extension() {
+            None => directory.join(format!("{}-{}", stem, suffix)),
+            Some(ext) => directory.join(format!("{}-{}.{}", stem, suffix, ext.to_string_lossy())),
+        };
+    }
+
+    // Renames fail if the directories are on different filesystems.
+    if tokio::fs::rename(path, &destination).await.is_err() {
+        tokio::fs::copy(path, &destination).await?;
+        tokio::fs::remove_file(path).await?;
+    }
+
+    Ok(destination)
+}
+
+/// Starts watching the configured directory if any, importing
+/// the files dropped into it while Lust wasn't running.
+pub fn start() -> anyhow::Result<()> {
+    let cfg = match crate::config::config().watch {
+        None => return Ok(()),
+        Some(ref cfg) => cfg,
+    };
+
+    let bucket = get_bucket_by_name(&cfg.bucket)
+        .ok_or_else(|| anyhow!("Bucket {} was not setup.", cfg.bucket))?;
+
+    let watcher = FolderWatcher {
+        cfg,
+        bucket,
+        processed_directory: cfg.processed_directory(),
+        failed_directory: cfg.failed_directory(),
+        in_flight: Mutex::new(HashSet::new()),
+    };
+
+    for directory in [&cfg.directory, &watcher.processed_directory, &watcher.failed_directory] {
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create directory {}", directory.display()))?;
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let events = tx.clone();
+    let mut debouncer = new_debouncer(Duration::from_secs(cfg.settle_time), move |res: DebounceEventResult| {
+        match res {
+            Ok(settled) => {
+                for event in settled {
+                    if event.kind == DebouncedEventKind::Any {
+                        let _ = events.send(event.path);
+                    }
+                }
+            },
+            Err(e) => error!("Failed to watch {}: {}", cfg.directory.display(), e),
+        }
+    })?;
+
+    debouncer
+        .watcher()
+        .watch(&cfg.directory, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", cfg.directory.display()))?;
+
+    for entry in std::fs::read_dir(&cfg.directory)? {
+        let _ = tx.send(entry?.path());
+    }
+
+    info!("Importing files dropped into {} into bucket {}.", cfg.directory.display(), cfg.bucket);
+    tokio::spawn(async move {
+        // The directory is only watched while the debouncer is alive.
+        let _debouncer = debouncer;
+        let watcher = &watcher;
+
+        futures::stream::poll_fn(|cx| rx.poll_recv(cx))
+            .for_each_concurrent(cfg.concurrency, |path| watcher.handle(path))
+            .await;
+    });
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
-use std::fmt::{Debug, Error, Formatter};
-use std::ops::{Deref, DerefMut};
+use std::os::raw::c_int;
 
 use anyhow::{Result, anyhow};
+use bytes::Bytes;
 use image::{DynamicImage, RgbaImage};
 use libwebp_sys::WebPEncodingError::VP8_ENC_OK;
 use libwebp_sys::WebPPreset::WEBP_PRESET_DEFAULT;
@@ -9,18 +9,21 @@ use libwebp_sys::*;
 pub use libwebp_sys::WebPConfig;
 
 
-/// Inits the global encoder config.
+/// Creates a new encoder config.
 ///
-///     - quality:
-///         This parameter is the amount of effort put into the
-///         compression: 0 is the fastest but gives larger
-///         files compared to the slowest, but best, 100.
+/// Each encoder is given its own config so different callers
+/// can use different encoding settings.
 ///
-///     - method:
-///         The quality / speed trade-off (0=fast, 6=slower-better)
+/// - `quality`:
+///   This parameter is the amount of effort put into the
+///   compression: 0 is the fastest but gives larger
+///   files compared to the slowest, but best, 100.
 ///
-///     - multi_threading:
-///         If the system should to attempt to use in multi-threaded encoding.
+/// - `method`:
+///   The quality / speed trade-off (0=fast, 6=slower-better)
+///
+/// - `multi_threading`:
+///   If the system should to attempt to use in multi-threaded encoding.
 pub fn config(lossless: bool, quality: f32, method: i32, multi_threading: bool) -> WebPConfig {
     WebPConfig {
         lossless: if lossless { 1 } else { 0 },
@@ -174,15 +177,15 @@ impl<'a> Encoder<'a> {
         }
     }
 
-    /// Encode the image with the given global config.
-    pub fn encode(self) -> Result<WebPMemory> {
+    /// Encode the image with the encoder's config.
+    pub fn encode(self) -> Result<Bytes> {
         let (img, layout) = if let PixelLayout::Other(img) = &self.layout {
             (img.as_ref(), &PixelLayout::RGBA)
         } else {
             (self.image.as_ref(), &self.layout)
         };
 
-        unsafe { encode(self.cfg, img, layout, self.width, self.height) }
+        encode(self.cfg, img, layout, self.width, self.height)
     }
 }
 
@@ -194,106 +197,92 @@ macro_rules! check_ok {
     }};
 }
 
-unsafe fn encode(cfg: WebPConfig, image: &[u8], layout: &PixelLayout, width: u32, height: u32) -> Result<WebPMemory> {
-    let picture = empty_webp_picture();
-    let writer = WebPMemoryWriter {
-        mem: std::ptr::null_mut::<u8>(),
-        size: 0,
-        max_size: 0,
-        pad: [0],
-    };
+/// An initialised picture which is freed once dropped.
+struct Picture(WebPPicture);
 
-    let cfg_ptr = Box::into_raw(Box::from(cfg));
-    let picture_ptr = Box::into_raw(Box::from(picture));
-    let writer_ptr = Box::into_raw(Box::from(writer));
+impl Picture {
+    fn new() -> Result<Self> {
+        let mut picture = empty_webp_picture();
+        let ok = unsafe { WebPPictureInitInternal(&mut picture, WEBP_ENCODER_ABI_VERSION) };
+        check_ok!(ok, "picture init failed");
 
-    let ok = WebPConfigInitInternal(
-        cfg_ptr,
-        WEBP_PRESET_DEFAULT,
-        cfg.quality,
-        WEBP_ENCODER_ABI_VERSION,
-    );
-    check_ok!(ok, "config init failed");
-
-    let ok = WebPPictureInitInternal(picture_ptr, WEBP_ENCODER_ABI_VERSION);
-    check_ok!(ok, "picture init failed");
-
-    (*picture_ptr).use_argb = cfg.lossless;
-    (*cfg_ptr).lossless = cfg.lossless;
-    (*cfg_ptr).method = cfg.method;
-    (*cfg_ptr).thread_level = cfg.thread_level;
-
-    let width = width as _;
-    let height = height as _;
-
-    (*picture_ptr).width = width;
-    (*picture_ptr).height = height;
-    (*picture_ptr).writer = WebPWriterFunction::Some(WebPMemoryWrite);
-    (*picture_ptr).custom_ptr = writer_ptr as *mut _;
-    WebPMemoryWriterInit(writer_ptr);
-
-    let ok = match layout {
-        PixelLayout::RGB => {
-            let stride = width * 3;
-            WebPPictureImportRGB(picture_ptr, image.as_ptr(), stride)
-        },
-        PixelLayout::RGBA => {
-            let stride = width * 4;
-            WebPPictureImportRGBA(picture_ptr, image.as_ptr(), stride)
-        },
-        PixelLayout::BGR => {
-            let stride = width * 3;
-            WebPPictureImportBGR(picture_ptr, image.as_ptr(), stride)
-        },
-        PixelLayout::BGRA => {
-            let stride = width * 4;
-            WebPPictureImportBGRA(picture_ptr, image.as_ptr(), stride)
-        },
-        _ => unreachable!(),
-    };
-    check_ok!(ok, "failed to import image");
-
-    let ok = WebPEncode(cfg_ptr, picture_ptr);
-    WebPPictureFree(picture_ptr);
-    if ok == 0 {
-        WebPMemoryWriterClear(writer_ptr);
-        return Err(anyhow!(
-            "memory error. libwebp error code: {:?}",
-            (*picture_ptr).error_code
-        ))
+        Ok(Self(picture))
     }
-
-    Ok(WebPMemory((*writer_ptr).mem, (*writer_ptr).size))
 }
 
-/// This struct represents a safe wrapper around memory owned by libwebp.
-/// Its data contents can be accessed through the Deref and DerefMut traits.
-pub struct WebPMemory(pub(crate) *mut u8, pub(crate) usize);
-
-impl Debug for WebPMemory {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        f.debug_struct("WebpMemory").finish()
+impl Drop for Picture {
+    fn drop(&mut self) {
+        unsafe { WebPPictureFree(&mut self.0) }
     }
 }
 
-impl Drop for WebPMemory {
-    fn drop(&mut self) {
-        unsafe { WebPFree(self.0 as _) }
-    }
+/// Appends the encoded data to the `Vec<u8>` set as the picture's `custom_ptr`.
+///
+/// This lets the output be handed over as `Bytes` without copying it
+/// out of a libwebp owned buffer.
+unsafe extern "C" fn write_to_vec(data: *const u8, data_size: usize, picture: *const WebPPicture) -> c_int {
+    let buffer = &mut *((*picture).custom_ptr as *mut Vec<u8>);
+    buffer.extend_from_slice(std::slice::from_raw_parts(data, data_size));
+    1
 }
 
-impl Deref for WebPMemory {
-    type Target = [u8];
+fn encode(cfg: WebPConfig, image: &[u8], layout: &PixelLayout, width: u32, height: u32) -> Result<Bytes> {
+    let stride = match layout {
+        PixelLayout::RGB | PixelLayout::BGR => width * 3,
+        PixelLayout::RGBA | PixelLayout::BGRA => width * 4,
+        PixelLayout::Other(_) => return Err(anyhow!("unsupported pixel layout")),
+    };
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { std::slice::from_raw_parts(self.0, self.1) }
+    if image.len() < (stride as usize * height as usize) {
+        return Err(anyhow!("image buffer is smaller than the given dimensions"));
     }
-}
 
-impl DerefMut for WebPMemory {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { std::slice::from_raw_parts_mut(self.0, self.1) }
+    let mut config = cfg;
+    let ok = unsafe {
+        WebPConfigInitInternal(
+            &mut config,
+            WEBP_PRESET_DEFAULT,
+            cfg.quality,
+            WEBP_ENCODER_ABI_VERSION,
+        )
+    };
+    check_ok!(ok, "config init failed");
+
+    config.lossless = cfg.lossless;
+    config.method = cfg.method;
+    config.thread_level = cfg.thread_level;
+
+    let mut picture = Picture::new()?;
+    let mut output: Vec<u8> = Vec::new();
+
+    picture.0.use_argb = cfg.lossless;
+    picture.0.width = width as _;
+    picture.0.height = height as _;
+    picture.0.writer = WebPWriterFunction::Some(write_to_vec);
+    picture.0.custom_ptr = &mut output as *mut Vec<u8> as *mut _;
+
+    let stride = stride as _;
+    let ok = unsafe {
+        match layout {
+            PixelLayout::RGB => WebPPictureImportRGB(&mut picture.0, image.as_ptr(), stride),
+            PixelLayout::RGBA => WebPPictureImportRGBA(&mut picture.0, image.as_ptr(), stride),
+            PixelLayout::BGR => WebPPictureImportBGR(&mut picture.0, image.as_ptr(), stride),
+            PixelLayout::BGRA => WebPPictureImportBGRA(&mut picture.0, image.as_ptr(), stride),
+            PixelLayout::Other(_) => unreachable!(),
+        }
+    };
+    check_ok!(ok, "failed to import image");
+
+    let ok = unsafe { WebPEncode(&config, &mut picture.0) };
+    if ok == 0 {
+        return Err(anyhow!(
+            "encoding failed. libwebp error code: {:?}",
+            picture.0.error_code
+        ))
     }
+
+    drop(picture);
+    Ok(Bytes::from(output))
 }
 
 #[cfg(test)]
@@ -302,18 +291,17 @@ mod tests {
 
     use super::*;
 
-    fn ensure_global() {
+    fn test_config() -> WebPConfig {
         config(true, 50.0, 6, true)
     }
 
     #[test]
     fn test_basic_sample_1() {
         let image = image::open("./test_samples/news.png").expect("load image");
-        ensure_global();
 
-        let encoder = Encoder::from_image(&image);
+        let encoder = Encoder::from_image(test_config(), &image);
         let start = std::time::Instant::now();
-        let memory = encoder.encode();
+        let memory = encoder.encode().expect("encode image");
         println!("{:?}", start.elapsed());
         let buffer = memory.as_ref();
         write("./news.webp", buffer).expect("write image");
@@ -322,14 +310,27 @@ mod tests {
     #[test]
     fn test_basic_sample_2() {
         let image = image::open("./test_samples/release.png").expect("load image");
-        ensure_global();
 
-        let encoder = Encoder::from_image(&image);
+        let encoder = Encoder::from_image(test_config(), &image);
         let start = std::time::Instant::now();
-        let memory = encoder.encode();
+        let memory = encoder.encode().expect("encode image");
         println!("{:?}", start.elapsed());
         let buffer = memory.as_ref();
 
         write("./release.webp", buffer).expect("write image");
     }
+
+    #[test]
+    fn test_per_call_configs() {
+        let image = image::open("./test_samples/news.png").expect("load image");
+
+        let lossy = Encoder::from_image(config(false, 10.0, 0, false), &image)
+            .encode()
+            .expect("encode image");
+        let lossless = Encoder::from_image(config(true, 50.0, 0, false), &image)
+            .encode()
+            .expect("encode image");
+
+        assert_ne!(lossy.as_ref(), lossless.as_ref());
+    }
 }